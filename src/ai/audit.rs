@@ -0,0 +1,403 @@
+use std::{collections::HashSet, fs, path::Path};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::ai::client::{extract_json, ChatClient};
+use crate::analyzer::scanner::{self, Finding, Severity};
+use crate::error::{Error, Result};
+use crate::utils::{output::Record, source::{self, SourceFile, DEFAULT_OUTPUT_DIR}};
+
+/// The system prompt of the audit mode
+pub const AUDIT_PROMPT: &str = "You are a senior smart contract auditor. \
+You review Solidity or Vyper source code for exploitable vulnerabilities: access control, reentrancy, oracle manipulation, \
+flash loan attacks, unchecked calls, integer and rounding issues, signature replay, upgradeability and initialization flaws, \
+denial of service and broken business logic. Each line of the code starts with its line number. \
+The static scanner findings given as hints may be false positives, confirm or dismiss them. \
+Only report real issues, no style or gas remarks. \
+Answer with a JSON object only, no other text: \
+{\"findings\":[{\"title\":\"...\",\"severity\":\"high|medium|low\",\"file\":\"the path after `// File:`\",\"lines\":\"e.g. 120-134\",\
+\"description\":\"what is wrong and how it is exploited\",\"recommendation\":\"how to fix it\"}]}. \
+Answer {\"findings\":[]} if there is no issue.";
+
+/// The longest source chunk sent in one request, ~6k tokens so the prompt and the answer fit a 16k context.
+/// Use a model with a 16k context or more, e.g. `gpt-3.5-turbo-16k`
+pub const MAX_CHUNK_CHARS: usize = 24_000;
+
+/// @dev A finding of the model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditFinding {
+    pub title: String,
+    pub severity: Severity,
+    pub file: String,
+    pub lines: String, // e.g. "120-134", empty if not given
+    pub description: String,
+    pub recommendation: String,
+}
+
+impl Record for AuditFinding {
+    fn headers() -> Vec<&'static str> {
+        vec!["severity", "title", "file", "lines", "description", "recommendation"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            format!("{:?}", self.severity).to_lowercase(),
+            self.title.clone(),
+            self.file.clone(),
+            self.lines.clone(),
+            self.description.clone(),
+            self.recommendation.clone(),
+        ]
+    }
+}
+
+/// @dev The merged review of a contract
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditReport {
+    pub address: String,
+    pub contract_name: String,
+    pub chunks: usize, // How many requests the sources were split into
+    pub findings: Vec<AuditFinding>, // The most severe first
+    pub hints: Vec<Finding>, // The static scanner findings sent to the model
+    pub notes: Vec<String>, // The answers which are not valid JSON, and the failed chunks
+}
+
+impl AuditReport {
+    /// @dev Render the report in Markdown
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!("# Audit of {} ({})\n\n", self.contract_name, self.address);
+        md.push_str(&format!(
+            "Reviewed in {} chunk(s). {} finding(s), {} static scanner hint(s).\n\n",
+            self.chunks,
+            self.findings.len(),
+            self.hints.len()
+        ));
+        md.push_str("> Generated by a language model, confirm every finding before acting on it.\n\n");
+
+        md.push_str("## Summary\n\n| # | Severity | Title | Location |\n|---|---|---|---|\n");
+        for (i, finding) in self.findings.iter().enumerate() {
+            md.push_str(&format!(
+                "| {} | {:?} | {} | {} |\n",
+                i + 1,
+                finding.severity,
+                finding.title.replace('|', "\\|"),
+                location(finding)
+            ));
+        }
+
+        md.push_str("\n## Findings\n");
+        for (i, finding) in self.findings.iter().enumerate() {
+            md.push_str(&format!("\n### {}. [{:?}] {}\n\n", i + 1, finding.severity, finding.title));
+            md.push_str(&format!("**Location:** `{}`\n\n", location(finding)));
+            md.push_str(&format!("{}\n\n", finding.description));
+            if !finding.recommendation.is_empty() {
+                md.push_str(&format!("**Recommendation:** {}\n", finding.recommendation));
+            }
+        }
+
+        if !self.hints.is_empty() {
+            md.push_str("\n## Static scanner hints\n\n| Severity | Rule | Location | Message |\n|---|---|---|---|\n");
+            for hint in &self.hints {
+                md.push_str(&format!(
+                    "| {:?} | {} {} | {}:{} | {} |\n",
+                    hint.severity, hint.rule_id, hint.rule, hint.file, hint.line, hint.message.replace('|', "\\|")
+                ));
+            }
+        }
+
+        if !self.notes.is_empty() {
+            md.push_str("\n## Notes\n");
+            for note in &self.notes {
+                md.push_str(&format!("\n{}\n", note));
+            }
+        }
+
+        md
+    }
+}
+
+/// @dev Download the verified source of a contract and ask the model to review it chunk by chunk,
+/// with the static scanner findings of each chunk as hints. The libraries are not reviewed.
+/// The report is written to `output/<address>/audit.md`.
+/// @param client The model client
+/// @param api_key Etherscan API kEY
+/// @param address The contract address
+/// @return The report and the path of the Markdown file
+pub async fn audit_contract(client: &ChatClient, api_key: String, address: &str) -> Result<(AuditReport, String)> {
+    let (contract, _) = source::get_contract_solidity_code(api_key, address).await?;
+    let files: Vec<SourceFile> = contract
+        .files
+        .into_iter()
+        .filter(|file| !scanner::is_library(&file.path))
+        .collect();
+    if files.is_empty() {
+        return Err(Error::InvalidInput(format!("{} has no source file to review", address)));
+    }
+
+    let chunks = chunk_sources(&files, MAX_CHUNK_CHARS);
+    let client = client.clone().with_params(Some(0.0), None); // The same code gets the same review
+    let mut report = AuditReport {
+        address: contract.metadata.address.clone(),
+        contract_name: contract.metadata.contract_name.clone(),
+        chunks: chunks.len(),
+        findings: Vec::new(),
+        hints: Vec::new(),
+        notes: Vec::new(),
+    };
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        eprintln!("Reviewing chunk {}/{}", i + 1, chunks.len());
+        let hints: Vec<Finding> = chunk
+            .files
+            .iter()
+            .flat_map(|(path, content, first_line)| {
+                scanner::scan_source(path, content).into_iter().map(move |mut hint| {
+                    hint.line += first_line - 1;
+                    hint
+                })
+            })
+            .collect();
+
+        match review_chunk(&client, &chunk.text, &hints).await {
+            Ok(answer) => match parse_findings(&answer) {
+                Some(findings) => report.findings.extend(findings),
+                None => report.notes.push(format!("Chunk {}:\n\n{}", i + 1, answer.trim())),
+            },
+            // One failed chunk does not lose the review of the others
+            Err(e) => report.notes.push(format!("Chunk {} failed: {}", i + 1, e)),
+        }
+        report.hints.extend(hints);
+    }
+
+    report.findings = merge_findings(report.findings);
+
+    let path = Path::new(DEFAULT_OUTPUT_DIR).join(&report.address).join("audit.md");
+    fs::write(&path, report.to_markdown())
+        .map_err(|e| Error::Storage(format!("write {}: {}", path.display(), e)))?;
+
+    Ok((report, path.display().to_string()))
+}
+
+/// @dev Some source files with line numbers, small enough for one request
+pub struct Chunk {
+    pub text: String,
+    pub files: Vec<(String, String, usize)>, // The path, the original content and the first line number of the (parts of the) files, for the scanner
+}
+
+/// @dev Split the sources into chunks of at most `max_chars`, a file is only split when it is larger than a chunk itself
+/// @param files The source files
+/// @param max_chars The longest chunk
+/// @return The chunks, every file starts with `// File: <path>` and every line with its number
+pub fn chunk_sources(files: &[SourceFile], max_chars: usize) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut current = Chunk { text: String::new(), files: Vec::new() };
+
+    for file in files {
+        for (header, numbered, original, first_line) in file_parts(file, max_chars) {
+            let size = header.len() + numbered.len();
+            if !current.text.is_empty() && current.text.len() + size > max_chars {
+                chunks.push(std::mem::replace(&mut current, Chunk { text: String::new(), files: Vec::new() }));
+            }
+            current.text.push_str(&header);
+            current.text.push_str(&numbered);
+            current.files.push((file.path.clone(), original, first_line));
+        }
+    }
+    if !current.text.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// @dev Number the lines of a file, split into parts of at most `max_chars`
+/// @return The header, the numbered lines, the original lines and the first line number of every part
+fn file_parts(file: &SourceFile, max_chars: usize) -> Vec<(String, String, String, usize)> {
+    let mut parts = Vec::new();
+    let (mut numbered, mut original, mut first_line) = (String::new(), String::new(), 1);
+    for (i, line) in file.content.lines().enumerate() {
+        let line_numbered = format!("{}: {}\n", i + 1, line);
+        if !numbered.is_empty() && numbered.len() + line_numbered.len() > max_chars {
+            parts.push((std::mem::take(&mut numbered), std::mem::take(&mut original), first_line));
+            first_line = i + 1;
+        }
+        numbered.push_str(&line_numbered);
+        original.push_str(line);
+        original.push('\n');
+    }
+    if !numbered.is_empty() {
+        parts.push((numbered, original, first_line));
+    }
+
+    let total = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, (numbered, original, first_line))| {
+            let header = if total > 1 {
+                format!("// File: {} (part {}/{})\n", file.path, i + 1, total)
+            } else {
+                format!("// File: {}\n", file.path)
+            };
+            (header, numbered, original, first_line)
+        })
+        .collect()
+}
+
+/// @dev Ask the model to review a chunk
+async fn review_chunk(client: &ChatClient, chunk: &str, hints: &[Finding]) -> Result<String> {
+    let mut content = String::new();
+    if !hints.is_empty() {
+        content.push_str("Static scanner hints:\n");
+        for hint in hints {
+            content.push_str(&format!(
+                "- [{:?}] {} at {}:{}: {}\n",
+                hint.severity, hint.rule, hint.file, hint.line, hint.message
+            ));
+        }
+        content.push('\n');
+    }
+    content.push_str("Source code:\n");
+    content.push_str(chunk);
+
+    client.ask(AUDIT_PROMPT, content).await
+}
+
+/// @dev Parse the answer of the model, leniently: the JSON may be wrapped in a code fence or in text
+/// @return The findings, None if the answer has no valid `findings` JSON
+pub fn parse_findings(answer: &str) -> Option<Vec<AuditFinding>> {
+    let json = extract_json(answer)?;
+
+    let text = |finding: &Value, key: &str| -> String {
+        match &finding[key] {
+            Value::String(s) => s.trim().to_string(),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        }
+    };
+
+    let findings = json["findings"]
+        .as_array()?
+        .iter()
+        .filter(|finding| !text(finding, "title").is_empty())
+        .map(|finding| AuditFinding {
+            title: text(finding, "title"),
+            severity: parse_severity(&text(finding, "severity")),
+            file: text(finding, "file"),
+            lines: text(finding, "lines"),
+            description: text(finding, "description"),
+            recommendation: text(finding, "recommendation"),
+        })
+        .collect();
+
+    Some(findings)
+}
+
+/// @dev Map the severity of the model to the scanner's, critical is high and informational is low
+fn parse_severity(severity: &str) -> Severity {
+    match severity.to_lowercase().as_str() {
+        "critical" | "high" => Severity::High,
+        "medium" | "moderate" => Severity::Medium,
+        _ => Severity::Low,
+    }
+}
+
+/// @dev Drop the findings reported twice for the same file, e.g. by two parts of a file, and sort them
+fn merge_findings(findings: Vec<AuditFinding>) -> Vec<AuditFinding> {
+    let mut seen = HashSet::new();
+    let mut merged: Vec<AuditFinding> = findings
+        .into_iter()
+        .filter(|finding| seen.insert((finding.file.to_lowercase(), finding.title.to_lowercase())))
+        .collect();
+    merged.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+    merged
+}
+
+fn location(finding: &AuditFinding) -> String {
+    if finding.lines.is_empty() {
+        finding.file.clone()
+    } else {
+        format!("{}:{}", finding.file, finding.lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, lines: usize) -> SourceFile {
+        let content = (1..=lines).map(|i| format!("uint256 public v{};", i)).collect::<Vec<_>>().join("\n");
+        SourceFile { path: path.to_string(), content }
+    }
+
+    fn finding(title: &str, file: &str, severity: Severity) -> AuditFinding {
+        AuditFinding {
+            title: title.to_string(),
+            severity,
+            file: file.to_string(),
+            lines: String::new(),
+            description: String::new(),
+            recommendation: String::new(),
+        }
+    }
+
+    #[test]
+    fn small_files_share_a_chunk() {
+        let chunks = chunk_sources(&[file("A.sol", 2), file("B.sol", 1)], MAX_CHUNK_CHARS);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].text,
+            "// File: A.sol\n1: uint256 public v1;\n2: uint256 public v2;\n// File: B.sol\n1: uint256 public v1;\n"
+        );
+        assert_eq!(chunks[0].files[1], (String::from("B.sol"), String::from("uint256 public v1;\n"), 1));
+    }
+
+    #[test]
+    fn a_large_file_is_split_into_numbered_parts() {
+        // Each numbered line is 23 or 24 chars
+        let chunks = chunk_sources(&[file("Big.sol", 20), file("Small.sol", 1)], 200);
+        assert!(chunks.iter().all(|chunk| chunk.text.len() <= 200 + "// File: Big.sol (part 1/3)\n".len()));
+
+        let parts: Vec<&(String, String, usize)> = chunks.iter().flat_map(|chunk| &chunk.files).collect();
+        assert_eq!(parts.iter().filter(|(path, _, _)| path == "Big.sol").count(), 3);
+        assert!(chunks[0].text.starts_with("// File: Big.sol (part 1/3)\n1: "));
+        assert!(chunks[1].text.starts_with(&format!("// File: Big.sol (part 2/3)\n{}: ", parts[1].2)));
+        // The original lines keep their numbers through the first line of the part
+        let (_, original, first_line) = parts[1];
+        assert_eq!(original.lines().next().unwrap(), format!("uint256 public v{};", first_line));
+        assert_eq!(parts.last().unwrap().0, "Small.sol");
+    }
+
+    #[test]
+    fn parses_the_findings_leniently() {
+        let answer = "Here is my review:\n```json\n{\"findings\":[\
+            {\"title\":\"Reentrancy in withdraw\",\"severity\":\"Critical\",\"file\":\"Vault.sol\",\"lines\":120,\
+            \"description\":\" The balance is updated after the call \",\"recommendation\":\"Update it first\"},\
+            {\"title\":\"\",\"severity\":\"high\"},\
+            {\"title\":\"Spot price oracle\",\"severity\":\"moderate\",\"file\":\"Oracle.sol\"}]}\n```";
+        let findings = parse_findings(answer).unwrap();
+
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].severity, Severity::High);
+        assert_eq!(findings[0].lines, "120");
+        assert_eq!(findings[0].description, "The balance is updated after the call");
+        assert_eq!(location(&findings[0]), "Vault.sol:120");
+        assert_eq!(findings[1].severity, Severity::Medium);
+        assert_eq!(location(&findings[1]), "Oracle.sol");
+
+        assert!(parse_findings("I could not review this code").is_none());
+        assert!(parse_findings("{\"issues\":[]}").is_none());
+        assert_eq!(parse_findings("{\"findings\":[]}").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn merges_the_duplicate_findings() {
+        let merged = merge_findings(vec![
+            finding("Unchecked call", "Vault.sol", Severity::Low),
+            finding("Reentrancy", "Vault.sol", Severity::High),
+            finding("reentrancy", "vault.sol", Severity::High),
+            finding("Reentrancy", "Pool.sol", Severity::Medium),
+        ]);
+        let titles: Vec<(&str, &str)> = merged.iter().map(|f| (f.title.as_str(), f.file.as_str())).collect();
+        assert_eq!(titles, vec![("Reentrancy", "Vault.sol"), ("Reentrancy", "Pool.sol"), ("Unchecked call", "Vault.sol")]);
+    }
+}
@@ -1,67 +1,402 @@
 use std::io::{stdin, stdout, Write};
 use colored::*;
-use dotenv::dotenv;
-use openai::{
-    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole},
-    set_base_url, set_key,
+use openai::chat::{ChatCompletionMessage, ChatCompletionMessageRole};
+use crate::ai::{
+    audit::{self, AuditReport},
+    client::{extract_json, message, ChatClient, ModelConfig},
+    session::{self, Session, DEFAULT_SESSION_DIR},
+    tools::Tools,
 };
+use crate::error::{Error, Result};
+use serde_json::Value;
+
+/// The system prompt of the assistant
+pub const SYSTEM_PROMPT: &str = "You are SecHelper, a blockchain security analyst assistant built into a command line tool. \
+You help with incident response, threat monitoring and smart contract review on Ethereum. \
+You can call functions to query real chain data: transactions of an address, mixing service (e.g. Tornado Cash) interactions, \
+verified contract sources, the offline selector db and the SecHelper address db of hackers, protocols and mixing services. \
+Use the functions whenever a question is about a concrete address, transaction, contract or selector, instead of guessing. \
+Base your answers on the function results, cite the tx hashes, blocks and addresses they return, and say clearly when the data is missing or incomplete. \
+Be concise and precise, flag the risks you see and suggest the next steps of the investigation.";
+
+/// The REPL commands
+const REPL_HELP: &str = "/save [name]      Save the session, under a new name if given
+/load <name>      Resume a saved session
+/sessions [text]  List the saved sessions, only the ones mentioning the text if given
+/reset            Clear the conversation
+/system [prompt]  Show or replace the system prompt
+/export [file]    Export the session in Markdown, default output/sessions/<name>.md
+Ctrl-D            Quit";
+
+/// How many function calls the model can chain for one question
+const MAX_FUNCTION_CALLS: usize = 8;
 
 pub struct AI {
-    pub OPENAI_KEY: String,
-    pub OPENAI_BASE_URL: String,
+    client: ChatClient,
+    ETHERSCAN_API_KEY: Option<String>,
 }
 
 impl AI {
-    
-    /// @param openai_key OpenAI API KEY
-    /// @param openai_base_url A domestic(For china) proxy springboard for accessing OpenAI
-    pub fn new(openai_key: String, openai_base_url: String) -> Self {
-        AI {
-            OPENAI_KEY: openai_key,
-            OPENAI_BASE_URL: openai_base_url,
-        }
+
+    /// @param config The endpoint, the model and its parameters
+    pub fn new(config: ModelConfig) -> Result<Self> {
+        Ok(AI {
+            client: ChatClient::new(config)?,
+            ETHERSCAN_API_KEY: None,
+        })
+    }
+
+    /// @dev Let the assistant fetch txs and sources from Etherscan, without it only the offline dbs can be queried
+    /// @param api_key Etherscan API kEY
+    pub fn with_etherscan_key(mut self, api_key: String) -> Self {
+        self.ETHERSCAN_API_KEY = Some(api_key);
+        self
+    }
+
+    /// @dev Review the verified source of a contract, the report is written to `output/<address>/audit.md`
+    /// @param address The contract address
+    /// @return The report and the path of the Markdown file
+    pub async fn audit(&self, address: &str) -> Result<(AuditReport, String)> {
+        let api_key = self
+            .ETHERSCAN_API_KEY
+            .clone()
+            .ok_or_else(|| Error::Config(String::from("The Etherscan API KEY is required to download the source")))?;
+
+        audit::audit_contract(&self.client, api_key, address).await
     }
 
-    /// @dev Create a chatgpt client
-    pub async fn chatgpt(&self) {
-        dotenv().ok();
-        set_key(self.OPENAI_KEY.clone());
-        set_base_url(self.OPENAI_BASE_URL.clone());
-    
-        let mut messages = vec![ChatCompletionMessage {
-            role: ChatCompletionMessageRole::System,
-            content: Some("You are a large language model built into a command line interface as an example of what the `openai` Rust library made by Valentine Briese can do.".to_string()),
-            name: None,
-            function_call: None,
-        }];
-    
+    /// @dev Chat with the assistant until stdin is closed. A failed question is reported and dropped, the session goes on.
+    /// A named session is saved after each answer
+    /// @param name The session to resume or to start, None for an unnamed session
+    pub async fn chatgpt(&self, name: Option<String>) -> Result<()> {
+        let tools = Tools::new(self.ETHERSCAN_API_KEY.clone());
+        let mut session = match &name {
+            Some(name) => Session::open(DEFAULT_SESSION_DIR, name, SYSTEM_PROMPT)?,
+            None => Session::new(SYSTEM_PROMPT),
+        };
+        println!("{}", format!("Model {} at {}, `/help` for the commands", self.client.config().model, self.client.config().base_url).dimmed());
+        if !session.messages.is_empty() {
+            println!("{}", format!("Resumed session {} with {} messages", name.unwrap_or_default(), session.messages.len()).dimmed());
+        }
+
         loop {
             println!();
             print!("{}","User: ".green());
-            stdout().flush().unwrap();
-    
+            stdout().flush()?;
+
             let mut user_message_content = String::new();
-    
-            stdin().read_line(&mut user_message_content).unwrap();
-            messages.push(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::User,
-                content: Some(user_message_content),
-                name: None,
+            if stdin().read_line(&mut user_message_content)? == 0 {
+                return Ok(()); // EOF
+            }
+            let command = user_message_content.trim();
+            if command.is_empty() {
+                continue;
+            }
+            if command.starts_with('/') {
+                if let Err(e) = self.command(&mut session, command) {
+                    eprintln!("{}", format!("Error: {}", e).red());
+                }
+                continue;
+            }
+
+            let asked = session.messages.len();
+            session.messages.push(message(ChatCompletionMessageRole::User, user_message_content));
+
+            let mut started = false;
+            let answer = self
+                .answer(&mut session, &tools, |token| {
+                    if !started {
+                        print!("{}: ", self.client.config().model);
+                        started = true;
+                    }
+                    print!("{}", token);
+                    let _ = stdout().flush();
+                })
+                .await;
+            if started {
+                println!();
+            }
+
+            match answer {
+                Ok(_) => {
+                    if session.name.is_some() {
+                        if let Err(e) = session.save(DEFAULT_SESSION_DIR) {
+                            eprintln!("{}", format!("Error: {}", e).red());
+                        }
+                    }
+                }
+                Err(e) => {
+                    session.messages.truncate(asked); // Ask again without the failed question
+                    eprintln!("{}", format!("Error: {}", e).red());
+                }
+            }
+        }
+    }
+
+    /// @dev Answer one question and exit, for the shell scripts and the CI jobs.
+    /// The text answer is streamed to stdout, the function calls are logged to stderr
+    /// @param prompt The question, e.g. with a contract source piped in
+    /// @param schema A JSON schema the answer must follow, None for a text answer. The answer is asked again once if it does not
+    /// @param name The session to continue and save, None to not save
+    pub async fn one_shot(&self, prompt: String, schema: Option<Value>, name: Option<String>) -> Result<()> {
+        let tools = Tools::new(self.ETHERSCAN_API_KEY.clone());
+        let mut session = match &name {
+            Some(name) => Session::open(DEFAULT_SESSION_DIR, name, SYSTEM_PROMPT)?,
+            None => Session::new(SYSTEM_PROMPT),
+        };
+
+        match &schema {
+            None => {
+                session.messages.push(message(ChatCompletionMessageRole::User, prompt));
+                self.answer(&mut session, &tools, |token| {
+                    print!("{}", token);
+                    let _ = stdout().flush();
+                })
+                .await?;
+                println!();
+            }
+            Some(schema) => {
+                let question = format!(
+                    "{}\n\nAnswer with a JSON object only, no other text, following this JSON schema:\n{}",
+                    prompt, schema
+                );
+                session.messages.push(message(ChatCompletionMessageRole::User, question));
+                let answer = self.answer(&mut session, &tools, |_| {}).await?;
+
+                let json = match check_schema(&answer, schema) {
+                    Ok(json) => json,
+                    Err(problem) => {
+                        eprintln!("The answer does not follow the schema: {}, asking again", problem);
+                        let retry = format!("Your answer does not follow the schema: {}. Answer again with the JSON object only.", problem);
+                        session.messages.push(message(ChatCompletionMessageRole::User, retry));
+                        let answer = self.answer(&mut session, &tools, |_| {}).await?;
+                        check_schema(&answer, schema)
+                            .map_err(|problem| Error::Ai(format!("The answer does not follow the schema: {}\n{}", problem, answer)))?
+                    }
+                };
+                println!("{}", serde_json::to_string_pretty(&json)?);
+            }
+        }
+
+        if session.name.is_some() {
+            session.save(DEFAULT_SESSION_DIR)?;
+        }
+        Ok(())
+    }
+
+    /// @dev Run a REPL command
+    /// @param session The current session
+    /// @param command The line starting with `/`
+    fn command(&self, session: &mut Session, command: &str) -> Result<()> {
+        let (command, argument) = match command.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (command, ""),
+        };
+
+        match command {
+            "/help" => println!("{}", REPL_HELP),
+            "/save" => {
+                if !argument.is_empty() {
+                    session.name = Some(argument.to_string());
+                }
+                println!("Saved to {}", session.save(DEFAULT_SESSION_DIR)?);
+            }
+            "/load" => {
+                if argument.is_empty() {
+                    return Err(Error::InvalidInput(String::from("Usage: /load <name>")));
+                }
+                *session = Session::load(DEFAULT_SESSION_DIR, argument)?;
+                println!("Loaded session {} with {} messages", argument, session.messages.len());
+            }
+            "/sessions" => {
+                for info in session::list(DEFAULT_SESSION_DIR, (!argument.is_empty()).then_some(argument))? {
+                    println!("{}  {}  {} messages  {}", info.name, info.updated, info.messages, info.first_question);
+                }
+            }
+            "/reset" => {
+                session.reset();
+                println!("The conversation is cleared");
+            }
+            "/system" => {
+                if argument.is_empty() {
+                    println!("{}", session.system);
+                } else {
+                    session.system = argument.to_string();
+                    println!("The system prompt is replaced");
+                }
+            }
+            "/export" => {
+                let path = if argument.is_empty() {
+                    let name = session.name.clone().unwrap_or_else(|| String::from("unnamed"));
+                    format!("{}/{}.md", DEFAULT_SESSION_DIR, name)
+                } else {
+                    argument.to_string()
+                };
+                if let Some(dir) = std::path::Path::new(&path).parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                std::fs::write(&path, session.to_markdown())
+                    .map_err(|e| Error::Storage(format!("Failed to write {}: {}", path, e)))?;
+                println!("Exported to {}", path);
+            }
+            _ => return Err(Error::InvalidInput(format!("Unknown command {}, `/help` for the commands", command))),
+        }
+
+        Ok(())
+    }
+
+    /// @dev The model answers, or calls functions until it can answer
+    /// @param session The conversation, the answer and the function calls are appended
+    /// @param tools The functions the model can call
+    /// @param on_token Called with each piece of the answer text as it arrives
+    /// @return The answer
+    async fn answer(&self, session: &mut Session, tools: &Tools, mut on_token: impl FnMut(&str)) -> Result<String> {
+        let functions = tools.definitions();
+        if session.compact(&self.client, self.client.config().context_tokens).await {
+            eprintln!("{}", "[The older turns are summarised to fit the context]".dimmed());
+        }
+
+        for calls in 0..=MAX_FUNCTION_CALLS {
+            let functions = if calls < MAX_FUNCTION_CALLS { &functions[..] } else { &[] };
+            let returned_message = self.client.complete_stream(&session.context(), functions, &mut on_token).await?;
+            session.messages.push(returned_message.clone());
+
+            let Some(function_call) = returned_message.function_call else {
+                return Ok(returned_message.content.unwrap_or_default());
+            };
+
+            eprintln!("{}", format!("[{}({})]", function_call.name, function_call.arguments).dimmed());
+            let result = tools.call(&function_call.name, &function_call.arguments).await;
+            session.messages.push(ChatCompletionMessage {
+                role: ChatCompletionMessageRole::Function,
+                content: Some(result),
+                name: Some(function_call.name),
                 function_call: None,
             });
-    
-            let chat_completion = ChatCompletion::builder("gpt-3.5-turbo", messages.clone())
-                .create()
-                .await
-                .unwrap();
-            let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    
-            println!(
-                "ChatGPT: {}",
-                &returned_message.content.clone().unwrap().trim()
-            );
-    
-            messages.push(returned_message);
         }
+
+        Err(Error::Ai(format!("No answer after {} function calls", MAX_FUNCTION_CALLS)))
+    }
+}
+
+/// @dev Parse an answer and check it against a JSON schema
+/// @return The JSON, or what is wrong with it
+fn check_schema(answer: &str, schema: &Value) -> std::result::Result<Value, String> {
+    let json = extract_json(answer).ok_or_else(|| String::from("no JSON object found"))?;
+    validate(&json, schema, "$")?;
+    Ok(json)
+}
+
+/// @dev Check the `type`, `enum`, `required`, `properties` and `items` keywords, the others are ignored
+fn validate(value: &Value, schema: &Value, path: &str) -> std::result::Result<(), String> {
+    if let Some(expected) = schema["type"].as_str() {
+        let matched = match expected {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matched {
+            return Err(format!("{} should be of type {}", path, expected));
+        }
+    }
+    if let Some(options) = schema["enum"].as_array() {
+        if !options.contains(value) {
+            return Err(format!("{} should be one of {}", path, schema["enum"]));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        for key in schema["required"].as_array().into_iter().flatten().filter_map(|key| key.as_str()) {
+            if !object.contains_key(key) {
+                return Err(format!("{}.{} is missing", path, key));
+            }
+        }
+        if let Some(properties) = schema["properties"].as_object() {
+            for (key, property) in properties {
+                if let Some(field) = object.get(key) {
+                    validate(field, property, &format!("{}.{}", path, key))?;
+                }
+            }
+        }
+    }
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (i, item) in array.iter().enumerate() {
+            validate(item, items, &format!("{}[{}]", path, i))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::ai::client::mock;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["verdict", "findings"],
+            "properties": {
+                "verdict": { "type": "string", "enum": ["safe", "risky"] },
+                "score": { "type": "integer" },
+                "findings": {
+                    "type": "array",
+                    "items": { "type": "object", "required": ["title"], "properties": { "title": { "type": "string" } } }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn checks_the_answer_against_the_schema() {
+        let json = check_schema("```json\n{\"verdict\":\"risky\",\"score\":7,\"findings\":[{\"title\":\"Reentrancy\"}]}\n```", &schema()).unwrap();
+        assert_eq!(json["findings"][0]["title"], "Reentrancy");
+
+        let problem = |answer: &str| check_schema(answer, &schema()).unwrap_err();
+        assert_eq!(problem("It looks safe"), "no JSON object found");
+        assert_eq!(problem("{\"verdict\":\"safe\"}"), "$.findings is missing");
+        assert_eq!(problem("{\"verdict\":\"unknown\",\"findings\":[]}"), "$.verdict should be one of [\"safe\",\"risky\"]");
+        assert_eq!(problem("{\"verdict\":\"safe\",\"score\":7.5,\"findings\":[]}"), "$.score should be of type integer");
+        assert_eq!(problem("{\"verdict\":\"safe\",\"findings\":[{\"title\":1}]}"), "$.findings[0].title should be of type string");
+        assert_eq!(problem("{\"verdict\":\"safe\",\"findings\":[{}]}"), "$.findings[0].title is missing");
+    }
+
+    #[test]
+    fn ignores_the_unsupported_keywords() {
+        let schema = json!({ "type": "object", "properties": { "n": { "type": "number", "minimum": 10 } }, "additionalProperties": false });
+        assert!(validate(&json!({ "n": 1, "extra": true }), &schema, "$").is_ok());
+    }
+
+    #[tokio::test]
+    async fn streams_the_answer_after_a_function_call() {
+        let call = json!({ "choices": [{ "delta": { "function_call": { "name": "query_address_db", "arguments": "{}" } } }] });
+        let answer = json!({ "choices": [{ "delta": { "content": "No address given." } }] });
+        let (base_url, server) = mock::serve(vec![
+            (200, "text/event-stream", format!("data: {}\n\ndata: [DONE]\n\n", call)),
+            (200, "text/event-stream", format!("data: {}\n\ndata: [DONE]\n\n", answer)),
+        ])
+        .await;
+        let ai = AI::new(mock::config(&base_url, true)).unwrap();
+        let mut session = Session::new(SYSTEM_PROMPT);
+        session.messages.push(message(ChatCompletionMessageRole::User, String::from("Is it a hacker?")));
+
+        let mut streamed = String::new();
+        let answer = ai.answer(&mut session, &Tools::new(None), |token| streamed.push_str(token)).await.unwrap();
+
+        assert_eq!((answer.as_str(), streamed.as_str()), ("No address given.", "No address given."));
+        assert_eq!(session.messages.len(), 4);
+        // The function error is sent back to the model
+        let result = session.messages[2].content.as_deref().unwrap();
+        assert_eq!(result, "{\"error\":\"Invalid input: `address` is required\"}");
+        let requests = server.await.unwrap();
+        assert!(requests[0].contains("\"functions\":["));
+        assert!(requests[1].contains("\"role\":\"function\""));
     }
 }
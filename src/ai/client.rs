@@ -0,0 +1,535 @@
+use std::{future::Future, time::Duration};
+use openai::chat::{ChatCompletionFunctionCall, ChatCompletionFunctionDefinition, ChatCompletionMessage, ChatCompletionMessageRole};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::config::Profile;
+use crate::error::{Error, Result};
+
+/// The model used when neither the CLI nor the config file sets one
+pub const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+/// The official OpenAI API
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1/";
+/// How long a request may take when neither the CLI nor the config file sets it
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+/// The token budget of a chat context, leaving room for the answer in the 16k context of `gpt-3.5-turbo`
+pub const DEFAULT_CONTEXT_TOKENS: usize = 12_000;
+
+/// How many times a failed request is retried
+const RETRIES: u32 = 2;
+/// How long to wait before the first retry, doubled on each retry
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// @dev The endpoint, the model and its parameters
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub api_key: Option<String>, // None for the local servers which need no key
+    pub base_url: String, // Any OpenAI-compatible API, e.g. `http://localhost:11434/v1/` for Ollama
+    pub model: String,
+    pub temperature: Option<f32>, // None for the server default
+    pub max_tokens: Option<u64>, // None for the server default
+    pub timeout: Duration,
+    pub context_tokens: usize, // The token budget of a chat, the old turns are summarised beyond it
+    pub stream: bool, // Receive the chat answers token by token
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            api_key: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            temperature: None,
+            max_tokens: None,
+            timeout: DEFAULT_TIMEOUT,
+            context_tokens: DEFAULT_CONTEXT_TOKENS,
+            stream: true,
+        }
+    }
+}
+
+impl ModelConfig {
+    /// @dev Read the `openai` section of a profile, the missing values are the defaults
+    /// @param profile The profile
+    pub fn from_profile(profile: &Profile) -> Result<Self> {
+        let mut config = ModelConfig {
+            api_key: match profile.openai_key() {
+                Ok(api_key) => Some(api_key),
+                Err(Error::Config(_)) => None, // Not set, fine for a local server
+                Err(e) => return Err(e),
+            },
+            base_url: profile.openai_base_url(),
+            ..Default::default()
+        };
+
+        if let Some(openai) = &profile.openai {
+            if let Some(model) = &openai.model {
+                config.model = model.clone();
+            }
+            config.temperature = openai.temperature;
+            config.max_tokens = openai.max_tokens;
+            if let Some(timeout) = openai.timeout {
+                config.timeout = Duration::from_secs(timeout);
+            }
+            if let Some(context_tokens) = openai.context_tokens {
+                config.context_tokens = context_tokens;
+            }
+            if let Some(stream) = openai.stream {
+                config.stream = stream;
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// @dev The answer of `/chat/completions`, only the fields every compatible server returns
+#[derive(Debug, Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChoice {
+    message: ChatCompletionMessage,
+}
+
+/// @dev A client of an OpenAI-compatible chat completions API.
+/// Unlike the `openai` crate it keeps its own key and base url, so several clients can talk to several servers
+#[derive(Debug, Clone)]
+pub struct ChatClient {
+    config: ModelConfig,
+    http: reqwest::Client,
+}
+
+impl ChatClient {
+    /// @param config The endpoint, the model and its parameters
+    pub fn new(config: ModelConfig) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| Error::Ai(format!("Failed to build the HTTP client: {}", e)))?;
+
+        Ok(ChatClient { config, http })
+    }
+
+    /// @return The endpoint, the model and its parameters
+    pub fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+
+    /// @dev Use other parameters for one kind of requests, e.g. a low temperature for the reviews
+    /// @param temperature The temperature, None to keep the configured one
+    /// @param max_tokens The max tokens of an answer, None to keep the configured one
+    pub fn with_params(mut self, temperature: Option<f32>, max_tokens: Option<u64>) -> Self {
+        self.config.temperature = temperature.or(self.config.temperature);
+        self.config.max_tokens = max_tokens.or(self.config.max_tokens);
+        self
+    }
+
+    /// @dev Ask the model, the network errors, the timeouts, the rate limits and the server errors are retried
+    /// @param messages The conversation
+    /// @param functions The functions the model can call, empty for none
+    /// @return The message of the model
+    pub async fn complete(
+        &self,
+        messages: &[ChatCompletionMessage],
+        functions: &[ChatCompletionFunctionDefinition],
+    ) -> Result<ChatCompletionMessage> {
+        let body = self.request_body(messages, functions, false);
+        retry(|| self.post(&body)).await
+    }
+
+    /// @dev Ask the model and receive the answer token by token, or at once if `stream` is off.
+    /// Only the request is retried, a stream broken in the middle is an error
+    /// @param messages The conversation
+    /// @param functions The functions the model can call, empty for none
+    /// @param on_token Called with each piece of the answer text
+    /// @return The whole message of the model
+    pub async fn complete_stream(
+        &self,
+        messages: &[ChatCompletionMessage],
+        functions: &[ChatCompletionFunctionDefinition],
+        mut on_token: impl FnMut(&str),
+    ) -> Result<ChatCompletionMessage> {
+        if !self.config.stream {
+            let message = self.complete(messages, functions).await?;
+            if let Some(content) = &message.content {
+                on_token(content);
+            }
+            return Ok(message);
+        }
+
+        let body = self.request_body(messages, functions, true);
+        let mut response = retry(|| self.send(&body)).await?;
+
+        let mut content = String::new();
+        let mut function_call: Option<ChatCompletionFunctionCall> = None;
+        let mut events = 0;
+        let mut unparsed = String::new(); // The answer of a server which ignores `stream`
+        let mut buffer = Vec::new();
+        'stream: while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| Error::Ai(format!("The answer stream broke: {}", e)))?
+        {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    if events == 0 {
+                        unparsed.push_str(&line);
+                    }
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+                events += 1;
+
+                let event: Value = serde_json::from_str(data)
+                    .map_err(|e| Error::Ai(format!("Unexpected stream event {}: {}", data, e)))?;
+                if event["error"].is_object() {
+                    return Err(Error::Ai(error_message(data)));
+                }
+                let delta = &event["choices"][0]["delta"];
+                if let Some(token) = delta["content"].as_str() {
+                    on_token(token);
+                    content.push_str(token);
+                }
+                if delta["function_call"].is_object() {
+                    let call = function_call.get_or_insert_with(|| ChatCompletionFunctionCall {
+                        name: String::new(),
+                        arguments: String::new(),
+                    });
+                    call.name.push_str(delta["function_call"]["name"].as_str().unwrap_or_default());
+                    call.arguments.push_str(delta["function_call"]["arguments"].as_str().unwrap_or_default());
+                }
+            }
+        }
+
+        if events == 0 {
+            unparsed.push_str(&String::from_utf8_lossy(&buffer));
+            let message = parse_completion(&unparsed).map_err(|(e, _)| e)?;
+            if let Some(content) = &message.content {
+                on_token(content);
+            }
+            return Ok(message);
+        }
+
+        Ok(ChatCompletionMessage {
+            role: ChatCompletionMessageRole::Assistant,
+            content: (function_call.is_none() || !content.is_empty()).then_some(content),
+            name: None,
+            function_call,
+        })
+    }
+
+    /// @dev Ask the model one question
+    /// @param system The system prompt
+    /// @param user The question
+    /// @return The answer
+    pub async fn ask(&self, system: &str, user: String) -> Result<String> {
+        let messages = [message(ChatCompletionMessageRole::System, system.to_string()), message(ChatCompletionMessageRole::User, user)];
+        self.complete(&messages, &[])
+            .await?
+            .content
+            .ok_or_else(|| Error::Ai(String::from("Empty answer")))
+    }
+
+    fn request_body(&self, messages: &[ChatCompletionMessage], functions: &[ChatCompletionFunctionDefinition], stream: bool) -> Value {
+        let mut body = json!({ "model": self.config.model, "messages": messages });
+        if !functions.is_empty() {
+            body["functions"] = json!(functions);
+        }
+        if let Some(temperature) = self.config.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = self.config.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if stream {
+            body["stream"] = json!(true);
+        }
+        body
+    }
+
+    /// @return The message, or the error and whether it is worth retrying
+    async fn post(&self, body: &Value) -> std::result::Result<ChatCompletionMessage, (Error, bool)> {
+        let response = self.send(body).await?;
+        let text = response
+            .text()
+            .await
+            .map_err(|e| (Error::Ai(format!("Failed to read the answer: {}", e)), true))?;
+        parse_completion(&text)
+    }
+
+    /// @return The successful response, or the error and whether it is worth retrying
+    async fn send(&self, body: &Value) -> std::result::Result<reqwest::Response, (Error, bool)> {
+        let url = format!("{}/chat/completions", self.config.base_url.trim_end_matches('/'));
+        let mut request = self.http.post(url);
+        if let Some(api_key) = &self.config.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| (Error::Ai(format!("Request to {} failed: {}", self.config.base_url, e)), true))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let text = response.text().await.unwrap_or_default();
+        if status.as_u16() == 429 {
+            return Err((Error::RateLimit(error_message(&text)), true));
+        }
+        Err((Error::Ai(format!("{} ({})", error_message(&text), status)), status.is_server_error()))
+    }
+}
+
+/// @dev Retry a request while its error is worth retrying, up to `RETRIES` times
+async fn retry<T, F, Fut>(request: F) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = std::result::Result<T, (Error, bool)>>,
+{
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Ok(value) => return Ok(value),
+            Err((e, retryable)) if retryable && attempt < RETRIES => {
+                eprintln!("{}, retry in {}s", e, delay.as_secs());
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err((e, _)) => return Err(e),
+        }
+    }
+}
+
+/// @dev Parse a `/chat/completions` answer
+fn parse_completion(text: &str) -> std::result::Result<ChatCompletionMessage, (Error, bool)> {
+    let response: CompletionResponse = serde_json::from_str(text)
+        .map_err(|e| (Error::Ai(format!("Unexpected answer {}: {}", text, e)), false))?;
+    response
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.message)
+        .ok_or_else(|| (Error::Ai(String::from("The answer has no choices")), false))
+}
+
+/// @dev Find the JSON object in an answer, which may be wrapped in a code fence or in text
+/// @return None if the answer has no valid JSON object
+pub fn extract_json(answer: &str) -> Option<Value> {
+    let start = answer.find('{')?;
+    let end = answer.rfind('}')?;
+    serde_json::from_str(answer.get(start..=end)?).ok()
+}
+
+/// @dev A message without a name or a function call
+pub fn message(role: ChatCompletionMessageRole, content: String) -> ChatCompletionMessage {
+    ChatCompletionMessage {
+        role,
+        content: Some(content),
+        name: None,
+        function_call: None,
+    }
+}
+
+/// @dev The `error.message` of an error answer, or the answer itself
+fn error_message(text: &str) -> String {
+    serde_json::from_str::<Value>(text)
+        .ok()
+        .and_then(|value| value["error"]["message"].as_str().map(|message| message.to_string()))
+        .unwrap_or_else(|| text.to_string())
+}
+
+/// @dev An OpenAI-compatible server on localhost, to test the AI features offline
+#[cfg(test)]
+pub(crate) mod mock {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+    use super::*;
+
+    /// @dev Serve the answers in order, one per connection
+    /// @param answers The status, the content type and the body of each answer
+    /// @return The base url of the server, and the raw requests it received once every answer is served
+    pub async fn serve(answers: Vec<(u16, &'static str, String)>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1/", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for (status, content_type, body) in answers {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                requests.push(read_request(&mut stream).await);
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+            requests
+        });
+
+        (base_url, server)
+    }
+
+    /// @dev A successful `/chat/completions` answer
+    pub fn completion(content: &str) -> (u16, &'static str, String) {
+        let body = json!({ "choices": [{ "message": { "role": "assistant", "content": content } }] });
+        (200, "application/json", body.to_string())
+    }
+
+    /// @dev A config of the mock server
+    pub fn config(base_url: &str, stream: bool) -> ModelConfig {
+        ModelConfig { api_key: Some(String::from("sk-test")), base_url: base_url.to_string(), stream, ..Default::default() }
+    }
+
+    /// @return The headers and the body of a request
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text
+                    .lines()
+                    .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|n| n.trim().parse::<usize>().unwrap()))
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return text;
+                }
+            }
+            if read == 0 {
+                return text;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn body(request: &str) -> Value {
+        serde_json::from_str(&request[request.find("\r\n\r\n").unwrap() + 4..]).unwrap()
+    }
+
+    #[test]
+    fn reads_the_model_of_the_profile() {
+        let config: Config = toml::from_str(
+            "[profiles.local]\n[profiles.local.openai]\nbase_url = \"http://localhost:11434/v1/\"\nmodel = \"llama3\"\n\
+            temperature = 0.2\ntimeout = 30\nstream = false",
+        )
+        .unwrap();
+        let model = ModelConfig::from_profile(&config.profiles["local"]).unwrap();
+
+        assert_eq!(model.api_key, None);
+        assert_eq!((model.base_url.as_str(), model.model.as_str()), ("http://localhost:11434/v1/", "llama3"));
+        assert_eq!((model.temperature, model.max_tokens), (Some(0.2), None));
+        assert_eq!(model.timeout, Duration::from_secs(30));
+        assert_eq!(model.context_tokens, DEFAULT_CONTEXT_TOKENS);
+        assert!(!model.stream);
+    }
+
+    #[test]
+    fn extracts_the_json_of_an_answer() {
+        assert_eq!(extract_json("```json\n{\"a\": {\"b\": 1}}\n```").unwrap()["a"]["b"], 1);
+        assert!(extract_json("{not json}").is_none());
+        assert!(extract_json("no json").is_none());
+        assert_eq!(error_message("{\"error\":{\"message\":\"Invalid key\"}}"), "Invalid key");
+        assert_eq!(error_message("Bad gateway"), "Bad gateway");
+    }
+
+    #[tokio::test]
+    async fn sends_the_model_and_its_parameters() {
+        let (base_url, server) = mock::serve(vec![mock::completion("Hi")]).await;
+        let client = ChatClient::new(mock::config(&base_url, false)).unwrap().with_params(Some(0.0), Some(256));
+
+        assert_eq!(client.ask("You are an auditor", String::from("Hello")).await.unwrap(), "Hi");
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("POST /v1/chat/completions "));
+        assert!(requests[0].to_lowercase().contains("authorization: bearer sk-test"));
+        let body = body(&requests[0]);
+        assert_eq!(body["model"], DEFAULT_MODEL);
+        assert_eq!((body["temperature"].as_f64(), body["max_tokens"].as_u64()), (Some(0.0), Some(256)));
+        assert_eq!(body["messages"][1]["content"], "Hello");
+        assert!(body.get("stream").is_none() && body.get("functions").is_none());
+    }
+
+    #[tokio::test]
+    async fn streams_the_tokens_and_the_function_call() {
+        let events = [
+            json!({ "choices": [{ "delta": { "role": "assistant", "content": "Check " } }] }),
+            json!({ "choices": [{ "delta": { "content": "the tx" } }] }),
+            json!({ "choices": [{ "delta": { "function_call": { "name": "trace_tx", "arguments": "{\"hash\":" } } }] }),
+            json!({ "choices": [{ "delta": { "function_call": { "arguments": "\"0x01\"}" } } }] }),
+        ];
+        let stream = events.iter().map(|event| format!("data: {}\n\n", event)).collect::<String>() + "data: [DONE]\n\n";
+        let (base_url, server) = mock::serve(vec![(200, "text/event-stream", stream)]).await;
+        let client = ChatClient::new(mock::config(&base_url, true)).unwrap();
+
+        let mut tokens = Vec::new();
+        let messages = [message(ChatCompletionMessageRole::User, String::from("Trace 0x01"))];
+        let answer = client.complete_stream(&messages, &[], |token| tokens.push(token.to_string())).await.unwrap();
+
+        assert_eq!(tokens, vec!["Check ", "the tx"]);
+        assert_eq!(answer.content.as_deref(), Some("Check the tx"));
+        let call = answer.function_call.unwrap();
+        assert_eq!((call.name.as_str(), call.arguments.as_str()), ("trace_tx", "{\"hash\":\"0x01\"}"));
+        assert_eq!(body(&server.await.unwrap()[0])["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn reads_a_server_which_ignores_the_stream() {
+        let (base_url, _server) = mock::serve(vec![mock::completion("At once")]).await;
+        let client = ChatClient::new(mock::config(&base_url, true)).unwrap();
+
+        let mut tokens = Vec::new();
+        let messages = [message(ChatCompletionMessageRole::User, String::from("Hello"))];
+        let answer = client.complete_stream(&messages, &[], |token| tokens.push(token.to_string())).await.unwrap();
+        assert_eq!(answer.content.as_deref(), Some("At once"));
+        assert_eq!(tokens, vec!["At once"]);
+    }
+
+    #[tokio::test]
+    async fn reports_the_error_of_the_server() {
+        let error = json!({ "error": { "message": "The model `gpt-5` does not exist" } }).to_string();
+        let (base_url, _server) = mock::serve(vec![(404, "application/json", error)]).await;
+        let client = ChatClient::new(mock::config(&base_url, false)).unwrap();
+
+        match client.ask("", String::from("Hello")).await {
+            Err(Error::Ai(message)) => assert_eq!(message, "The model `gpt-5` does not exist (404 Not Found)"),
+            other => panic!("expected an AI error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_a_server_error() {
+        let (base_url, server) = mock::serve(vec![(503, "text/plain", String::from("Overloaded")), mock::completion("Hi")]).await;
+        let client = ChatClient::new(mock::config(&base_url, false)).unwrap();
+
+        assert_eq!(client.ask("", String::from("Hello")).await.unwrap(), "Hi");
+        assert_eq!(server.await.unwrap().len(), 2);
+    }
+}
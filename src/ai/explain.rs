@@ -0,0 +1,190 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+use crate::ai::client::{ChatClient, ModelConfig};
+use crate::error::{Error, Result};
+use crate::utils::{address_db, notifier::Alert};
+
+/// The system prompt of the alert explanations
+pub const EXPLAIN_PROMPT: &str = "You are an on-call blockchain security analyst. \
+You get an alert of a monitoring robot: the triggering rule, the monitored address, the (decoded) transactions with \
+the known labels of their counterparties, and sometimes the results of simulating them on a fork. \
+In at most 120 words, answer with two short paragraphs: `Hypothesis:` what is most likely happening (an exploit, a rug pull, \
+an admin action, normal activity...) and why, citing the transactions; `Action:` what the operator should do now. \
+Say so when the data is not enough to tell.";
+
+/// How long an explanation may take before the alert is sent without it
+pub const EXPLAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long to skip the explanations after a rate limit
+const RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// The longest alert sent to the model, the rest is cut
+const MAX_ALERT_CHARS: usize = 8_000;
+
+/// @dev Enrich the robot alerts with an AI incident hypothesis and a recommended action
+#[derive(Debug, Clone)]
+pub struct Explainer {
+    client: ChatClient,
+    timeout: Duration,
+    paused_until: Arc<Mutex<Option<Instant>>>, // Set after a rate limit, shared by the clones
+}
+
+impl Explainer {
+    /// @param config The endpoint, the model and its parameters
+    pub fn new(config: ModelConfig) -> Result<Self> {
+        Ok(Explainer {
+            client: ChatClient::new(config)?.with_params(Some(0.2), Some(300)),
+            timeout: EXPLAIN_TIMEOUT,
+            paused_until: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// @param timeout How long an explanation may take, default `EXPLAIN_TIMEOUT`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// @dev Attach the explanation to the alert. A failure, a timeout or a rate limit is only logged,
+    /// so the alert is always sent, at worst `timeout` later
+    /// @param alert The alert to enrich
+    pub async fn enrich(&self, alert: &mut Alert) {
+        if self.is_paused() {
+            eprintln!("explain alert: skipped, rate limited");
+            return;
+        }
+
+        match tokio::time::timeout(self.timeout, self.explain(alert)).await {
+            Ok(Ok(explanation)) => alert.explanation = Some(explanation),
+            Ok(Err(e)) => {
+                if matches!(e, Error::RateLimit(_)) {
+                    *self.paused_until.lock().unwrap() = Some(Instant::now() + RATE_LIMIT_COOLDOWN);
+                }
+                eprintln!("explain alert: {}", e);
+            }
+            Err(_) => eprintln!("explain alert: timed out after {}s", self.timeout.as_secs()),
+        }
+    }
+
+    /// @dev Ask the model for an incident hypothesis and a recommended action
+    /// @param alert The alert
+    /// @return The explanation
+    pub async fn explain(&self, alert: &Alert) -> Result<String> {
+        let explanation = self.client.ask(EXPLAIN_PROMPT, alert_prompt(alert)).await?;
+        Ok(explanation.trim().to_string())
+    }
+
+    fn is_paused(&self) -> bool {
+        let mut paused_until = self.paused_until.lock().unwrap();
+        match *paused_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                *paused_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// @dev The alert as sent to the model, with the db label of the monitored address
+fn alert_prompt(alert: &Alert) -> String {
+    let label = address_db::get_db_labels()
+        .ok()
+        .and_then(|mut labels| labels.remove(&alert.address.to_lowercase()));
+
+    let mut prompt = format!("Rule: {}\nMonitored address: {}", alert.rule, alert.address);
+    if let Some(label) = label {
+        prompt.push_str(&format!(" ({})", label));
+    }
+    prompt.push_str(&format!("\n\n{}", alert.message));
+    if !alert.txs.is_empty() {
+        prompt.push_str(&format!("\n\nTxs:\n{}", alert.txs.join("\n")));
+    }
+    for detail in &alert.details {
+        prompt.push_str(&format!("\n\n{}", detail));
+    }
+
+    if prompt.len() > MAX_ALERT_CHARS {
+        let mut end = MAX_ALERT_CHARS;
+        while !prompt.is_char_boundary(end) {
+            end -= 1;
+        }
+        prompt.truncate(end);
+        prompt.push_str("...(truncated)");
+    }
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::client::mock;
+
+    fn alert() -> Alert {
+        let mut alert = Alert::new(
+            String::from("warning: too many `removeLiquidity(...)` txs"),
+            String::from("0x00000000000000000000000000000000000000bb"),
+            String::from("31 removeLiquidity txs in 240 blocks"),
+        );
+        alert.txs.push(String::from("0x01: 0xaa -> 0xbb removeLiquidity(...)"));
+        alert.details.push(String::from("Simulation: the pool loses 100 WETH"));
+        alert
+    }
+
+    #[tokio::test]
+    async fn attaches_the_explanation() {
+        let (base_url, server) = mock::serve(vec![mock::completion("Hypothesis: a rug pull.\nAction: pause the pool.\n")]).await;
+        let explainer = Explainer::new(mock::config(&base_url, false)).unwrap();
+        let mut alert = alert();
+
+        explainer.enrich(&mut alert).await;
+        assert_eq!(alert.explanation.as_deref(), Some("Hypothesis: a rug pull.\nAction: pause the pool."));
+
+        let request = &server.await.unwrap()[0];
+        assert!(request.contains("\"temperature\":0.2") && request.contains("\"max_tokens\":300"));
+        assert!(request.contains("Txs:\\n0x01: 0xaa -> 0xbb removeLiquidity(...)"));
+        assert!(request.contains("Simulation: the pool loses 100 WETH"));
+    }
+
+    #[tokio::test]
+    async fn sends_the_alert_without_the_explanation_on_a_timeout() {
+        // A server which accepts the connection and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1/", listener.local_addr().unwrap());
+        let _server = tokio::spawn(async move {
+            let _connection = listener.accept().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let explainer = Explainer::new(mock::config(&base_url, false)).unwrap().with_timeout(Duration::from_millis(200));
+        let mut alert = alert();
+        explainer.enrich(&mut alert).await;
+        assert!(alert.explanation.is_none());
+    }
+
+    #[tokio::test]
+    async fn skips_the_explanations_after_a_rate_limit() {
+        // Nothing listens there, the explanation is not even asked
+        let explainer = Explainer::new(mock::config("http://127.0.0.1:1/v1/", false)).unwrap();
+        *explainer.clone().paused_until.lock().unwrap() = Some(Instant::now() + RATE_LIMIT_COOLDOWN);
+        assert!(explainer.is_paused());
+
+        let mut alert = alert();
+        explainer.enrich(&mut alert).await;
+        assert!(alert.explanation.is_none());
+
+        *explainer.paused_until.lock().unwrap() = Some(Instant::now());
+        assert!(!explainer.is_paused());
+        assert!(explainer.paused_until.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncates_a_long_alert() {
+        let mut alert = alert();
+        alert.txs = vec!["0x01 swap(...)".repeat(20); 100];
+        let prompt = alert_prompt(&alert);
+        assert!(prompt.starts_with("Rule: warning: too many `removeLiquidity(...)` txs\nMonitored address: 0x00000000000000000000000000000000000000bb"));
+        assert!(prompt.ends_with("...(truncated)"));
+        assert_eq!(prompt.len(), MAX_ALERT_CHARS + "...(truncated)".len());
+    }
+}
@@ -1 +1,6 @@
-pub mod chatgpt;
\ No newline at end of file
+pub mod chatgpt;
+pub mod client;
+pub mod session;
+pub mod tools;
+pub mod audit;
+pub mod explain;
\ No newline at end of file
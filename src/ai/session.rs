@@ -0,0 +1,439 @@
+use std::{fs, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+use openai::chat::{ChatCompletionMessage, ChatCompletionMessageRole};
+use serde::{Deserialize, Serialize};
+use crate::ai::client::{message, ChatClient};
+use crate::error::{Error, Result};
+use crate::utils::{output::Record, tools::format_time};
+
+/// Where the sessions are saved, one `<name>.json` for each
+pub const DEFAULT_SESSION_DIR: &str = "output/sessions";
+
+/// How many of the newest messages are always sent as they are, the older ones may be summarised
+const KEEP_RECENT: usize = 6;
+/// A rough token estimate of the OpenAI tokenizers for English text and code
+const CHARS_PER_TOKEN: usize = 4;
+
+/// The prompt used to summarise the old turns
+const SUMMARY_PROMPT: &str = "Summarise this part of a blockchain security investigation for the analyst who continues it. \
+Keep every address, tx hash, block number, amount, finding and open question, drop the small talk. At most 300 words.";
+
+/// @dev A chat with the assistant, saved to `output/sessions/<name>.json`.
+/// Every message is kept for the export, only the ones after `context_start` are sent to the model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub name: Option<String>, // None until it is saved
+    pub created: u64, // Unix seconds
+    pub updated: u64, // Unix seconds
+    pub system: String, // The system prompt
+    #[serde(default)]
+    pub summary: Option<String>, // The summary of the messages before `context_start`
+    #[serde(default)]
+    pub context_start: usize,
+    pub messages: Vec<ChatCompletionMessage>, // Without the system prompt
+}
+
+/// @dev A saved session, as listed by `list()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub name: String,
+    pub updated: String,
+    pub messages: usize,
+    pub first_question: String,
+    pub matched: String, // The matched line of `--search`, empty without it
+}
+
+impl Record for SessionInfo {
+    fn headers() -> Vec<&'static str> {
+        vec!["name", "updated", "messages", "first_question", "matched"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.name.clone(),
+            self.updated.clone(),
+            self.messages.to_string(),
+            self.first_question.clone(),
+            self.matched.clone(),
+        ]
+    }
+}
+
+impl Session {
+    /// @param system The system prompt
+    pub fn new(system: &str) -> Self {
+        Session {
+            name: None,
+            created: now(),
+            updated: now(),
+            system: system.to_string(),
+            summary: None,
+            context_start: 0,
+            messages: Vec::new(),
+        }
+    }
+
+    /// @dev Load a saved session
+    /// @param dir The session folder
+    /// @param name The session name
+    pub fn load(dir: &str, name: &str) -> Result<Self> {
+        let path = session_path(dir, name)?;
+        if !path.exists() {
+            return Err(Error::InvalidInput(format!("No session named `{}` in {}", name, dir)));
+        }
+        let content = fs::read_to_string(&path)
+            .map_err(|e| Error::Storage(format!("Failed to read {}: {}", path.display(), e)))?;
+        serde_json::from_str(&content)
+            .map_err(|e| Error::Storage(format!("Failed to parse {}: {}", path.display(), e)))
+    }
+
+    /// @dev Resume a saved session, or start a new one with this name
+    /// @param dir The session folder
+    /// @param name The session name
+    /// @param system The system prompt of a new session
+    pub fn open(dir: &str, name: &str, system: &str) -> Result<Self> {
+        if session_path(dir, name)?.exists() {
+            return Session::load(dir, name);
+        }
+        let mut session = Session::new(system);
+        session.name = Some(name.to_string());
+        Ok(session)
+    }
+
+    /// @dev Save the session under its name
+    /// @param dir The session folder
+    /// @return The session file
+    pub fn save(&mut self, dir: &str) -> Result<String> {
+        let name = self
+            .name
+            .clone()
+            .ok_or_else(|| Error::InvalidInput(String::from("The session has no name, use `/save <name>`")))?;
+        let path = session_path(dir, &name)?;
+        self.updated = now();
+
+        fs::create_dir_all(dir)?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| Error::Storage(format!("Failed to write {}: {}", path.display(), e)))?;
+        Ok(path.display().to_string())
+    }
+
+    /// @dev Forget the messages, keep the name and the system prompt
+    pub fn reset(&mut self) {
+        self.messages.clear();
+        self.summary = None;
+        self.context_start = 0;
+    }
+
+    /// @return The messages sent to the model: the system prompt, the summary and the messages after `context_start`
+    pub fn context(&self) -> Vec<ChatCompletionMessage> {
+        let mut context = vec![message(ChatCompletionMessageRole::System, self.system.clone())];
+        if let Some(summary) = &self.summary {
+            context.push(message(
+                ChatCompletionMessageRole::System,
+                format!("Summary of the earlier conversation:\n{}", summary),
+            ));
+        }
+        context.extend(self.messages[self.context_start..].iter().cloned());
+        context
+    }
+
+    /// @dev Keep the context under the token budget: the old turns are summarised by the model,
+    /// or dropped if it fails. The newest `KEEP_RECENT` messages are kept, and a turn is never split.
+    /// @param client The model client
+    /// @param max_tokens The token budget of the context
+    /// @return True if the context was compacted
+    pub async fn compact(&mut self, client: &ChatClient, max_tokens: usize) -> bool {
+        if estimate_tokens(&self.context()) <= max_tokens {
+            return false;
+        }
+
+        // Cut before a user message, so a function call stays with its result
+        let latest = self.messages.len().saturating_sub(KEEP_RECENT);
+        let Some(cut) = (self.context_start + 1..=latest)
+            .rev()
+            .find(|&i| matches!(self.messages[i].role, ChatCompletionMessageRole::User))
+        else {
+            return false;
+        };
+
+        let mut transcript = String::new();
+        if let Some(summary) = &self.summary {
+            transcript.push_str(&format!("Earlier summary:\n{}\n\n", summary));
+        }
+        for message in &self.messages[self.context_start..cut] {
+            transcript.push_str(&format!("{}: {}\n", role_name(message), message_text(message)));
+        }
+
+        // The summary request itself must fit, the newest part matters most
+        let max_chars = max_tokens * CHARS_PER_TOKEN;
+        if transcript.len() > max_chars {
+            let mut start = transcript.len() - max_chars;
+            while !transcript.is_char_boundary(start) {
+                start += 1;
+            }
+            transcript = transcript.split_off(start);
+        }
+
+        match client.ask(SUMMARY_PROMPT, transcript).await {
+            Ok(summary) => self.summary = Some(summary.trim().to_string()),
+            Err(e) => eprintln!("summarise the old turns: {}, they are dropped", e),
+        }
+        self.context_start = cut;
+        true
+    }
+
+    /// @dev Render the whole session in Markdown, the function results are folded
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!(
+            "# SecHelper session: {}\n\nCreated {}, updated {}\n\n<details><summary>System prompt</summary>\n\n{}\n\n</details>\n",
+            self.name.as_deref().unwrap_or("unnamed"),
+            format_time(self.created),
+            format_time(self.updated),
+            self.system
+        );
+
+        for message in &self.messages {
+            match message.role {
+                ChatCompletionMessageRole::User => {
+                    md.push_str(&format!("\n## User\n\n{}\n", message.content.as_deref().unwrap_or_default().trim()))
+                }
+                ChatCompletionMessageRole::Assistant => match &message.function_call {
+                    Some(call) => md.push_str(&format!("\n> Called `{}({})`\n", call.name, call.arguments)),
+                    None => md.push_str(&format!("\n## Assistant\n\n{}\n", message.content.as_deref().unwrap_or_default().trim())),
+                },
+                ChatCompletionMessageRole::Function => md.push_str(&format!(
+                    "\n<details><summary>{} result</summary>\n\n```json\n{}\n```\n\n</details>\n",
+                    message.name.as_deref().unwrap_or_default(),
+                    message.content.as_deref().unwrap_or_default()
+                )),
+                ChatCompletionMessageRole::System => {}
+            }
+        }
+
+        md
+    }
+}
+
+/// @dev List the saved sessions, the latest first
+/// @param dir The session folder
+/// @param search Only the sessions with a message containing this text (case insensitive)
+pub fn list(dir: &str, search: Option<&str>) -> Result<Vec<SessionInfo>> {
+    if !Path::new(dir).exists() {
+        return Ok(Vec::new());
+    }
+    let search = search.map(|search| search.to_lowercase());
+
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_stem().and_then(|name| name.to_str()) else { continue };
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let session = match Session::load(dir, name) {
+            Ok(session) => session,
+            Err(e) => {
+                eprintln!("{}", e); // A broken file does not hide the others
+                continue;
+            }
+        };
+
+        let matched = match &search {
+            Some(search) => {
+                let line = session
+                    .messages
+                    .iter()
+                    .filter_map(|message| message.content.as_deref())
+                    .flat_map(|content| content.lines())
+                    .find(|line| line.to_lowercase().contains(search.as_str()));
+                match line {
+                    Some(line) => line.trim().chars().take(120).collect(),
+                    None => continue,
+                }
+            }
+            None => String::new(),
+        };
+
+        let first_question = session
+            .messages
+            .iter()
+            .find(|message| matches!(message.role, ChatCompletionMessageRole::User))
+            .and_then(|message| message.content.as_deref())
+            .unwrap_or_default()
+            .trim()
+            .chars()
+            .take(80)
+            .collect();
+
+        sessions.push((
+            session.updated,
+            SessionInfo {
+                name: name.to_string(),
+                updated: format_time(session.updated),
+                messages: session.messages.len(),
+                first_question,
+                matched,
+            },
+        ));
+    }
+
+    sessions.sort_by_key(|(updated, _)| std::cmp::Reverse(*updated));
+    Ok(sessions.into_iter().map(|(_, info)| info).collect())
+}
+
+/// @dev The tokens of the messages, estimated from their length
+pub fn estimate_tokens(messages: &[ChatCompletionMessage]) -> usize {
+    messages.iter().map(|message| message_text(message).len() / CHARS_PER_TOKEN + 4).sum()
+}
+
+/// @dev A session name is a file name: letters, digits, `-` and `_`
+fn session_path(dir: &str, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(Error::InvalidInput(format!("Invalid session name `{}`, use letters, digits, `-` and `_`", name)));
+    }
+    Ok(Path::new(dir).join(format!("{}.json", name)))
+}
+
+fn message_text(message: &ChatCompletionMessage) -> String {
+    match &message.function_call {
+        Some(call) => format!("{}({})", call.name, call.arguments),
+        None => message.content.clone().unwrap_or_default(),
+    }
+}
+
+fn role_name(message: &ChatCompletionMessage) -> &'static str {
+    match message.role {
+        ChatCompletionMessageRole::System => "System",
+        ChatCompletionMessageRole::User => "User",
+        ChatCompletionMessageRole::Assistant => "Assistant",
+        ChatCompletionMessageRole::Function => "Function result",
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai::chat::ChatCompletionFunctionCall;
+    use crate::ai::client::mock;
+
+    /// @dev A session of `turns` questions, each answered after a function call
+    fn session(turns: usize) -> Session {
+        let mut session = Session::new("You are a security assistant");
+        for i in 0..turns {
+            session.messages.push(message(ChatCompletionMessageRole::User, format!("Question {} {}", i, "x".repeat(400))));
+            session.messages.push(ChatCompletionMessage {
+                role: ChatCompletionMessageRole::Assistant,
+                content: None,
+                name: None,
+                function_call: Some(ChatCompletionFunctionCall { name: String::from("trace_tx"), arguments: format!("{{\"n\":{}}}", i) }),
+            });
+            session.messages.push(ChatCompletionMessage {
+                role: ChatCompletionMessageRole::Function,
+                content: Some(String::from("{\"status\":true}")),
+                name: Some(String::from("trace_tx")),
+                function_call: None,
+            });
+            session.messages.push(message(ChatCompletionMessageRole::Assistant, format!("Answer {}", i)));
+        }
+        session
+    }
+
+    fn temp_dir(name: &str) -> String {
+        std::env::temp_dir().join(format!("sechelper-{}-{}", name, std::process::id())).display().to_string()
+    }
+
+    #[tokio::test]
+    async fn compacts_only_over_the_budget() {
+        // Nothing listens there, a request would fail
+        let client = ChatClient::new(mock::config("http://127.0.0.1:1/v1/", false)).unwrap();
+        let mut session = session(4);
+        assert!(!session.compact(&client, 10_000).await);
+        assert_eq!((session.context_start, session.summary.as_ref()), (0, None));
+    }
+
+    #[tokio::test]
+    async fn summarises_the_old_turns() {
+        let (base_url, server) = mock::serve(vec![mock::completion(" Traced 0x01 and 0x02. "), mock::completion("All traced.")]).await;
+        let client = ChatClient::new(mock::config(&base_url, false)).unwrap();
+        let mut session = session(4);
+
+        assert!(session.compact(&client, 200).await);
+        // The cut is the latest user message before the newest `KEEP_RECENT` messages
+        assert_eq!(session.context_start, 8);
+        assert!(matches!(session.messages[session.context_start].role, ChatCompletionMessageRole::User));
+        assert_eq!(session.summary.as_deref(), Some("Traced 0x01 and 0x02."));
+        let context = session.context();
+        assert_eq!(context.len(), 2 + 8);
+        assert!(context[1].content.as_deref().unwrap().ends_with("Traced 0x01 and 0x02."));
+        // Every message is kept for the export
+        assert_eq!(session.messages.len(), 16);
+
+        // The summary request keeps the earlier summary while it fits the budget
+        session.messages.extend(self::session(2).messages);
+        assert!(session.compact(&client, 400).await);
+        assert_eq!(session.context_start, 16);
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].contains("Assistant: trace_tx({\\\"n\\\":1})"));
+        assert!(!requests[0].contains("Question 2"));
+        assert!(requests[1].contains("Earlier summary:\\nTraced 0x01 and 0x02."));
+    }
+
+    #[tokio::test]
+    async fn drops_the_old_turns_if_the_summary_fails() {
+        let (base_url, _server) = mock::serve(vec![(400, "application/json", String::from("{}"))]).await;
+        let client = ChatClient::new(mock::config(&base_url, false)).unwrap();
+        let mut session = session(4);
+
+        assert!(session.compact(&client, 200).await);
+        assert_eq!((session.context_start, session.summary.as_ref()), (8, None));
+    }
+
+    #[tokio::test]
+    async fn never_splits_the_recent_messages() {
+        let client = ChatClient::new(mock::config("http://127.0.0.1:1/v1/", false)).unwrap();
+        // The only user messages are in the newest `KEEP_RECENT`
+        let mut session = session(1);
+        assert!(!session.compact(&client, 10).await);
+        assert_eq!(session.context_start, 0);
+    }
+
+    #[test]
+    fn saves_lists_and_searches_the_sessions() {
+        let dir = temp_dir("sessions");
+        let mut session = session(1);
+        assert!(session.save(&dir).is_err());
+
+        session.name = Some(String::from("exploit-0x01"));
+        session.messages.push(message(ChatCompletionMessageRole::User, String::from("Who funded the HACKER?\nThanks")));
+        session.save(&dir).unwrap();
+        let mut other = Session::open(&dir, "other", "prompt").unwrap();
+        other.messages.push(message(ChatCompletionMessageRole::User, String::from("Hello")));
+        other.save(&dir).unwrap();
+        fs::write(Path::new(&dir).join("broken.json"), "{").unwrap();
+
+        assert_eq!(Session::open(&dir, "exploit-0x01", "prompt").unwrap().messages.len(), 5);
+        assert_eq!(list(&dir, None).unwrap().len(), 2);
+        let found = list(&dir, Some("hacker")).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].name.as_str(), found[0].matched.as_str()), ("exploit-0x01", "Who funded the HACKER?"));
+        assert!(found[0].first_question.starts_with("Question 0"));
+
+        assert!(Session::load(&dir, "../secret").is_err());
+        assert!(Session::load(&dir, "missing").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exports_the_session_in_markdown() {
+        let md = session(1).to_markdown();
+        assert!(md.starts_with("# SecHelper session: unnamed\n"));
+        assert!(md.contains("\n## User\n\nQuestion 0"));
+        assert!(md.contains("\n> Called `trace_tx({\"n\":0})`\n"));
+        assert!(md.contains("<details><summary>trace_tx result</summary>\n\n```json\n{\"status\":true}\n```"));
+        assert!(md.contains("\n## Assistant\n\nAnswer 0\n"));
+    }
+}
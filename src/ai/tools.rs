@@ -0,0 +1,245 @@
+use openai::chat::ChatCompletionFunctionDefinition;
+use serde_json::{json, Value};
+use crate::error::{Error, Result};
+use crate::listener::fetcher::Fetch;
+use crate::utils::{address_db, etherscan, selector_db, source};
+
+/// The longest tool result sent back to the model, the rest is cut
+const MAX_RESULT_CHARS: usize = 12_000;
+/// How many txs `fetch_address_txs` returns at most
+const MAX_TXS: usize = 50;
+/// The block range used when the model gives neither `start_block` nor `last_blocks`
+const DEFAULT_LAST_BLOCKS: u64 = 10_000;
+
+/// @dev The SecHelper functions the assistant can call
+pub struct Tools {
+    api_key: Option<String>, // Etherscan API kEY, None to only offer the offline tools
+}
+
+impl Tools {
+    /// @param api_key Etherscan API kEY, None to only offer the selector and address db tools
+    pub fn new(api_key: Option<String>) -> Self {
+        Tools { api_key }
+    }
+
+    /// @dev The function definitions sent with every request
+    pub fn definitions(&self) -> Vec<ChatCompletionFunctionDefinition> {
+        let block_range = json!({
+            "start_block": { "type": "integer", "description": "The block to start from" },
+            "end_block": { "type": "integer", "description": "The block to end at, default the latest block" },
+            "last_blocks": { "type": "integer", "description": "Only the newest N blocks, used when start_block is not given. Default 10000" }
+        });
+        let with_range = |mut properties: Value| {
+            properties.as_object_mut().unwrap().extend(block_range.as_object().unwrap().clone());
+            properties
+        };
+
+        let mut definitions = vec![
+            function(
+                "lookup_selector",
+                "Find the text signatures of a 4-byte function selector (e.g. 0xa9059cbb) or a 32-byte event topic in the offline selector db",
+                json!({ "hash": { "type": "string", "description": "The selector or topic" } }),
+                &["hash"],
+            ),
+            function(
+                "query_address_db",
+                "Look up the label of an address in the SecHelper address db (hacker, protocol, mixing_service, potential_hacker), or list the addresses of a category",
+                json!({
+                    "address": { "type": "string", "description": "The address to look up" },
+                    "category": { "type": "string", "enum": ["hacker", "protocol", "mixing_service", "potential_hacker"], "description": "The category to list" }
+                }),
+                &[],
+            ),
+        ];
+
+        if self.api_key.is_some() {
+            definitions.extend([
+                function(
+                    "fetch_address_txs",
+                    "Fetch the normal and internal transactions of an address from Etherscan, with the db labels of the counterparties",
+                    with_range(json!({ "address": { "type": "string", "description": "The address" } })),
+                    &["address"],
+                ),
+                function(
+                    "is_invoke_mixing_service",
+                    "Check if an address sent to or received from a mixing service (e.g. Tornado Cash) in a block range",
+                    with_range(json!({ "address": { "type": "string", "description": "The address" } })),
+                    &["address"],
+                ),
+                function(
+                    "download_contract_source",
+                    "Download the verified source of a contract into output/<address>/, and return its compiler settings and file list. Give `file` to read one of the files",
+                    json!({
+                        "address": { "type": "string", "description": "The contract address" },
+                        "file": { "type": "string", "description": "The path of a file to read, as returned in the file list" }
+                    }),
+                    &["address"],
+                ),
+            ]);
+        }
+
+        definitions
+    }
+
+    /// @dev Run a function called by the model
+    /// @param name The function name
+    /// @param arguments The arguments in json
+    /// @return The result in json, an `error` field if it failed, so the model can explain or retry
+    pub async fn call(&self, name: &str, arguments: &str) -> String {
+        let result = match serde_json::from_str::<Value>(arguments) {
+            Ok(arguments) => self.dispatch(name, &arguments).await,
+            Err(e) => Err(Error::InvalidInput(format!("Invalid arguments {}: {}", arguments, e))),
+        };
+
+        let content = match result {
+            Ok(value) => value.to_string(),
+            Err(e) => json!({ "error": e.to_string() }).to_string(),
+        };
+        truncate(content)
+    }
+
+    async fn dispatch(&self, name: &str, arguments: &Value) -> Result<Value> {
+        let text = |key: &str| -> Result<String> {
+            arguments[key]
+                .as_str()
+                .map(|value| value.to_string())
+                .ok_or_else(|| Error::InvalidInput(format!("`{}` is required", key)))
+        };
+
+        match name {
+            "lookup_selector" => Ok(json!(selector_db::lookup(&text("hash")?)?)),
+            "query_address_db" => {
+                if let Some(category) = arguments["category"].as_str() {
+                    return Ok(json!({ "category": category, "addresses": address_db::get_db_address(category)? }));
+                }
+                let address = text("address")?.to_lowercase();
+                let label = address_db::get_db_labels()?.remove(&address);
+                Ok(json!({ "address": address, "label": label }))
+            }
+            "fetch_address_txs" => {
+                let address = text("address")?;
+                let (start_block, end_block) = self.block_range(arguments).await?;
+                let txs = Fetch::new(self.api_key()?).fetch_address_all_txs(&address, start_block, end_block).await?;
+                let total = txs.len();
+                let txs: Vec<Value> = txs
+                    .into_iter()
+                    .take(MAX_TXS)
+                    .map(|tx| {
+                        json!({
+                            "hash": tx.hash, "from": tx.from, "from_label": tx.from_label, "to": tx.to,
+                            "to_label": tx.to_label, "value": tx.value, "methodId": tx.methodId,
+                        })
+                    })
+                    .collect();
+                Ok(json!({ "start_block": start_block, "end_block": end_block, "total": total, "txs": txs }))
+            }
+            "is_invoke_mixing_service" => {
+                let address = text("address")?;
+                let (start_block, end_block) = self.block_range(arguments).await?;
+                let invoked = Fetch::new(self.api_key()?).is_invoke_mixing_service(&address, start_block, end_block).await?;
+                Ok(json!({ "address": address, "start_block": start_block, "end_block": end_block, "invoked": invoked }))
+            }
+            "download_contract_source" => {
+                let address = text("address")?;
+                let (contract, written) = source::get_contract_solidity_code(self.api_key()?, &address).await?;
+                if let Some(file) = arguments["file"].as_str() {
+                    let file = contract
+                        .files
+                        .iter()
+                        .find(|f| f.path == file || f.path.ends_with(file))
+                        .ok_or_else(|| Error::InvalidInput(format!("{} has no file {}", address, file)))?;
+                    return Ok(json!({ "path": file.path, "content": file.content }));
+                }
+                // The ABI is left out, it is often larger than the sources
+                let metadata = &contract.metadata;
+                Ok(json!({
+                    "contract_name": metadata.contract_name, "language": metadata.language,
+                    "compiler_version": metadata.compiler_version, "optimization_used": metadata.optimization_used,
+                    "runs": metadata.runs, "proxy": metadata.proxy, "implementation": metadata.implementation,
+                    "files": written,
+                }))
+            }
+            _ => Err(Error::InvalidInput(format!("Unknown function {}", name))),
+        }
+    }
+
+    /// @dev `start_block` / `end_block`, or the newest `last_blocks` blocks
+    async fn block_range(&self, arguments: &Value) -> Result<(u64, u64)> {
+        let end_block = match arguments["end_block"].as_u64() {
+            Some(end_block) => end_block,
+            None => etherscan::block_number(&self.api_key()?).await?,
+        };
+        let start_block = match arguments["start_block"].as_u64() {
+            Some(start_block) => start_block,
+            None => end_block.saturating_sub(arguments["last_blocks"].as_u64().unwrap_or(DEFAULT_LAST_BLOCKS)),
+        };
+        Ok((start_block, end_block))
+    }
+
+    fn api_key(&self) -> Result<String> {
+        self.api_key
+            .clone()
+            .ok_or_else(|| Error::Config(String::from("The Etherscan API KEY is not set")))
+    }
+}
+
+fn function(name: &str, description: &str, properties: Value, required: &[&str]) -> ChatCompletionFunctionDefinition {
+    ChatCompletionFunctionDefinition {
+        name: name.to_string(),
+        description: Some(description.to_string()),
+        parameters: Some(json!({ "type": "object", "properties": properties, "required": required })),
+    }
+}
+
+fn truncate(mut content: String) -> String {
+    if content.len() > MAX_RESULT_CHARS {
+        let mut end = MAX_RESULT_CHARS;
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        content.truncate(end);
+        content.push_str("...(truncated)");
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(tools: &Tools) -> Vec<String> {
+        tools.definitions().into_iter().map(|definition| definition.name).collect()
+    }
+
+    #[test]
+    fn offers_the_etherscan_tools_only_with_a_key() {
+        assert_eq!(names(&Tools::new(None)), vec!["lookup_selector", "query_address_db"]);
+
+        let tools = Tools::new(Some(String::from("key")));
+        assert_eq!(names(&tools).len(), 5);
+        let fetch = tools.definitions().into_iter().find(|definition| definition.name == "fetch_address_txs").unwrap();
+        let parameters = fetch.parameters.unwrap();
+        assert!(parameters["properties"]["last_blocks"].is_object());
+        assert_eq!(parameters["required"], json!(["address"]));
+    }
+
+    #[tokio::test]
+    async fn reports_the_errors_to_the_model() {
+        let tools = Tools::new(None);
+        let error = |result: String| serde_json::from_str::<Value>(&result).unwrap()["error"].as_str().unwrap().to_string();
+
+        assert!(error(tools.call("lookup_selector", "{hash:").await).starts_with("Invalid input: Invalid arguments {hash:"));
+        assert_eq!(error(tools.call("lookup_selector", "{}").await), "Invalid input: `hash` is required");
+        assert_eq!(error(tools.call("drain_wallet", "{}").await), "Invalid input: Unknown function drain_wallet");
+        let fetch = tools.call("fetch_address_txs", "{\"address\":\"0x01\",\"start_block\":1,\"end_block\":2}").await;
+        assert_eq!(error(fetch), "Config error: The Etherscan API KEY is not set");
+    }
+
+    #[test]
+    fn truncates_the_long_results() {
+        assert_eq!(truncate(String::from("short")), "short");
+        let long = truncate("é".repeat(MAX_RESULT_CHARS));
+        assert!(long.ends_with("...(truncated)"));
+        assert_eq!(long.len(), MAX_RESULT_CHARS + "...(truncated)".len());
+    }
+}
@@ -0,0 +1,387 @@
+use std::{collections::{HashMap, HashSet}, fmt, time::{SystemTime, UNIX_EPOCH}};
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use crate::analyzer::trace::{format_amount, label};
+use crate::error::{Error, Result};
+use crate::listener::fetcher::{Fetch, LATEST_BLOCK};
+use crate::utils::{address_db, etherscan, output::Record, source, tools::{self, format_time}};
+
+/// The most logs etherscan returns for one `getLogs` request
+const MAX_LOGS: usize = 1_000;
+
+/// A spender younger than this is recently deployed, in seconds
+pub const NEW_SPENDER_AGE: u64 = 7 * 86_400;
+
+/// `allowance(address,address)` and `isApprovedForAll(address,address)`
+const ALLOWANCE_SELECTOR: &str = "0xdd62ed3e";
+const APPROVED_FOR_ALL_SELECTOR: &str = "0xe985e9c5";
+
+/// @dev An `Approval(address,address,uint256)` or `ApprovalForAll(address,address,bool)` log of an owner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalLog {
+    pub token: String,
+    pub spender: String,
+    pub for_all: bool, // An `ApprovalForAll`, `value` is "1" or "0"
+    pub value: String, // In the smallest unit
+    pub blockNumber: u64,
+    pub timeStamp: u64,
+    pub logIndex: u64,
+    pub hash: String,
+}
+
+/// @dev The current approval of an owner to a spender on a token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub token: String,
+    pub token_label: String, // The label of the token in db
+    pub symbol: String, // From the transfers of the owner, empty if it never moved the token
+    pub decimals: Option<u32>,
+    pub spender: String,
+    pub spender_label: String, // The label of the spender in db
+    pub for_all: bool, // An NFT `setApprovalForAll`
+    pub allowance: String, // In the smallest unit, "1" for an approval for all
+    pub blockNumber: u64, // The last approval
+    pub timeStamp: u64,
+    pub hash: String,
+    pub risks: Vec<String>, // Why the spender is risky, empty if it is not
+}
+
+impl Approval {
+    /// @return The allowance in whole units, `unlimited` from 2^255, `all` for an approval for all
+    pub fn amount(&self) -> String {
+        if self.for_all {
+            return String::from("all");
+        }
+        match U256::from_dec_str(&self.allowance) {
+            Ok(allowance) if allowance.bit(255) => String::from("unlimited"),
+            _ => format_amount(&self.allowance, self.decimals),
+        }
+    }
+
+    /// @return True if the spender is a hacker, unverified or recently deployed
+    pub fn is_risky(&self) -> bool {
+        !self.risks.is_empty()
+    }
+}
+
+impl fmt::Display for Approval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token = if self.symbol.is_empty() { self.token.clone() } else { format!("{} {}", self.symbol, self.token) };
+        write!(
+            f,
+            "{} {}{} to {}{}, approved {} (tx {})",
+            self.amount(), token, label(&self.token_label), self.spender, label(&self.spender_label), format_time(self.timeStamp), self.hash
+        )?;
+        if self.is_risky() {
+            write!(f, " RISKY: {}", self.risks.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Record for Approval {
+    fn headers() -> Vec<&'static str> {
+        vec!["token", "symbol", "token_label", "spender", "spender_label", "allowance", "amount", "approved", "hash", "risks"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.token.clone(),
+            self.symbol.clone(),
+            self.token_label.clone(),
+            self.spender.clone(),
+            self.spender_label.clone(),
+            self.allowance.clone(),
+            self.amount(),
+            format_time(self.timeStamp),
+            self.hash.clone(),
+            self.risks.join("; "),
+        ]
+    }
+}
+
+/// @dev Rebuild the current approvals of an owner from its `Approval` and `ApprovalForAll` logs, refresh them with
+/// `allowance()` / `isApprovedForAll()` (a `transferFrom` lowers an allowance without a log on most tokens),
+/// and flag the spenders which are hackers, unverified or recently deployed
+/// @param api_key Etherscan API kEY
+/// @param owner The owner, e.g. a treasury
+/// @return The non-zero approvals, the risky ones first
+pub async fn scan_approvals(api_key: &str, owner: &str) -> Result<Vec<Approval>> {
+    let owner = owner.to_lowercase();
+    let fetcher = Fetch::new(api_key.to_string());
+
+    let logs = fetch_approval_logs(api_key, &owner, 0, LATEST_BLOCK).await?;
+    let mut approvals = current_approvals(&logs);
+
+    let tokens: HashMap<String, (String, Option<u32>)> = fetcher
+        .fetch_token_transfers(&owner, 0, LATEST_BLOCK)
+        .await?
+        .into_iter()
+        .map(|transfer| (transfer.token.to_lowercase(), (transfer.tokenSymbol, transfer.tokenDecimal)))
+        .collect();
+    for approval in approvals.iter_mut() {
+        if let Some((symbol, decimals)) = tokens.get(&approval.token) {
+            approval.symbol = symbol.clone();
+            approval.decimals = *decimals;
+        }
+        match current_allowance(&fetcher, &owner, approval).await {
+            Ok(allowance) => approval.allowance = allowance,
+            Err(e) => eprintln!("{}: read the allowance of {}: {}", approval.token, approval.spender, e), // The allowance of the last log is kept
+        }
+    }
+    approvals.retain(|approval| approval.allowance != "0");
+
+    assess_spenders(api_key, &mut approvals).await?;
+    approvals.sort_by_key(|approval| (!approval.is_risky(), std::cmp::Reverse(approval.blockNumber)));
+
+    Ok(approvals)
+}
+
+/// @dev Obtain the `Approval` (ERC20 only, the ERC721 one has an indexed token id) and `ApprovalForAll` logs of an owner
+/// @param api_key Etherscan API kEY
+/// @param owner The owner
+/// @param start_block The block to fetch logs from
+/// @param end_block The block to fetch logs to
+/// @return The logs, the oldest first
+pub async fn fetch_approval_logs(api_key: &str, owner: &str, start_block: u64, end_block: u64) -> Result<Vec<ApprovalLog>> {
+    let owner_topic = format!("0x{:0>64}", owner.trim_start_matches("0x").to_lowercase());
+    let mut logs = Vec::new();
+
+    for (event, for_all) in [("Approval(address,address,uint256)", false), ("ApprovalForAll(address,address,bool)", true)] {
+        let topic0 = tools::event_topic(event);
+        let mut seen = HashSet::new();
+        let mut from_block = start_block;
+        loop {
+            let url = format!("https://api.etherscan.io/api?module=logs&action=getLogs&fromBlock={}&toBlock={}&topic0={}&topic0_1_opr=and&topic1={}&page=1&offset={}&apikey={}",
+                from_block,
+                end_block,
+                topic0,
+                owner_topic,
+                MAX_LOGS,
+                api_key
+            );
+
+            let result = etherscan::get_result(&url).await?;
+            let page = result
+                .as_array()
+                .ok_or_else(|| Error::Decode(format!("Expected an array of logs, got: {}", result)))?;
+
+            let mut last_block = from_block;
+            for log in page {
+                let Some(log) = parse_approval_log(log, for_all) else { continue };
+                last_block = last_block.max(log.blockNumber);
+                if seen.insert((log.hash.clone(), log.logIndex)) {
+                    logs.push(log);
+                }
+            }
+
+            // A full page goes on from its last block, the logs of that block are fetched again and skipped
+            if page.len() < MAX_LOGS || last_block == from_block {
+                break;
+            }
+            from_block = last_block;
+        }
+    }
+
+    logs.sort_by_key(|log| (log.blockNumber, log.logIndex));
+    Ok(logs)
+}
+
+/// @dev Replay the logs: the last one of each token, spender and kind is the current approval
+/// @param logs The logs, the oldest first
+/// @return The non-zero approvals, without symbol, label nor risk
+pub fn current_approvals(logs: &[ApprovalLog]) -> Vec<Approval> {
+    let mut latest: HashMap<(String, String, bool), &ApprovalLog> = HashMap::new();
+    for log in logs {
+        latest.insert((log.token.clone(), log.spender.clone(), log.for_all), log);
+    }
+
+    let mut approvals: Vec<Approval> = latest
+        .into_values()
+        .filter(|log| !log.value.trim_start_matches('0').is_empty())
+        .map(|log| Approval {
+            token: log.token.clone(),
+            token_label: String::new(),
+            symbol: String::new(),
+            decimals: None,
+            spender: log.spender.clone(),
+            spender_label: String::new(),
+            for_all: log.for_all,
+            allowance: log.value.clone(),
+            blockNumber: log.blockNumber,
+            timeStamp: log.timeStamp,
+            hash: log.hash.clone(),
+            risks: Vec::new(),
+        })
+        .collect();
+    approvals.sort_by_key(|approval| (approval.blockNumber, approval.token.clone(), approval.spender.clone()));
+    approvals
+}
+
+/// @dev Label the tokens and the spenders, and flag the spenders in the `hacker` or `potential_hacker` category,
+/// the unverified contracts and the spenders deployed less than `NEW_SPENDER_AGE` ago. Each spender is checked once
+/// @param api_key Etherscan API kEY
+/// @param approvals The approvals
+pub async fn assess_spenders(api_key: &str, approvals: &mut [Approval]) -> Result<()> {
+    let labels = address_db::get_db_labels()?;
+    let fetcher = Fetch::new(api_key.to_string());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+
+    let mut risks: HashMap<String, Vec<String>> = HashMap::new();
+    for approval in approvals.iter_mut() {
+        approval.token_label = labels.get(&approval.token).cloned().unwrap_or_default();
+        approval.spender_label = labels.get(&approval.spender).cloned().unwrap_or_default();
+
+        if !risks.contains_key(&approval.spender) {
+            let mut spender_risks = Vec::new();
+            if approval.spender_label.starts_with("hacker") || approval.spender_label.starts_with("potential_hacker") {
+                spender_risks.push(format!("the spender is a {}", approval.spender_label));
+            }
+            if fetcher.fetch_code(&approval.spender).await?.len() > 2 {
+                match source::fetch_contract_source(api_key, &approval.spender).await {
+                    Ok(_) => {}
+                    Err(Error::Unverified(_)) => spender_risks.push(String::from("unverified contract")),
+                    Err(e) => return Err(e),
+                }
+            }
+            if let Some(first) = fetcher.fetch_first_tx(&approval.spender).await? {
+                if now.saturating_sub(first.timeStamp) < NEW_SPENDER_AGE {
+                    spender_risks.push(format!("recently deployed, first active {}", format_time(first.timeStamp)));
+                }
+            }
+            risks.insert(approval.spender.clone(), spender_risks);
+        }
+        approval.risks = risks[&approval.spender].clone();
+    }
+
+    Ok(())
+}
+
+/// @dev Read the current allowance, or approval for all, of an owner
+/// @return The allowance in the smallest unit, "1" or "0" for an approval for all
+async fn current_allowance(fetcher: &Fetch, owner: &str, approval: &Approval) -> Result<String> {
+    let selector = if approval.for_all { APPROVED_FOR_ALL_SELECTOR } else { ALLOWANCE_SELECTOR };
+    let data = format!(
+        "{}{:0>64}{:0>64}",
+        selector,
+        owner.trim_start_matches("0x"),
+        approval.spender.trim_start_matches("0x")
+    );
+
+    let result = fetcher.fetch_call(&approval.token, &data).await?;
+    let hex = result.trim_start_matches("0x");
+    if hex.len() < 64 {
+        return Err(Error::Decode(format!("Expected a uint256, got: {}", result)));
+    }
+    U256::from_str_radix(&hex[..64], 16)
+        .map(|allowance| allowance.to_string())
+        .map_err(|e| Error::Decode(format!("Invalid uint256 {}: {}", result, e)))
+}
+
+/// @dev Parse a log returned by the etherscan `getLogs` api, the numbers are hex
+/// @return None if it is not an ERC20 `Approval` or an `ApprovalForAll` log
+fn parse_approval_log(log: &serde_json::Value, for_all: bool) -> Option<ApprovalLog> {
+    let topics = log["topics"].as_array()?;
+    if topics.len() != 3 {
+        return None;
+    }
+    let hex = |key: &str| u64::from_str_radix(log[key].as_str().unwrap_or("0x0").trim_start_matches("0x"), 16).unwrap_or_default();
+    let spender = topics[2].as_str()?;
+    let data = log["data"].as_str()?.trim_start_matches("0x");
+    let value = U256::from_str_radix(if data.is_empty() { "0" } else { data }, 16).ok()?;
+
+    Some(ApprovalLog {
+        token: log["address"].as_str()?.to_lowercase(),
+        spender: format!("0x{}", spender.get(spender.len().checked_sub(40)?..)?).to_lowercase(),
+        for_all,
+        value: value.to_string(),
+        blockNumber: hex("blockNumber"),
+        timeStamp: hex("timeStamp"),
+        logIndex: hex("logIndex"),
+        hash: log["transactionHash"].as_str()?.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USDC: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+    const BAYC: &str = "0xbc4ca0eda7647a8ab7c2061c2e118a18a936f13d";
+    const ROUTER: &str = "0x7a250d5630b4cf539739df2c5dacb4c659f2488d";
+    const DRAINER: &str = "0x00000000000000000000000000000000000000dd";
+
+    fn log(token: &str, spender: &str, for_all: bool, value: &str, block: u64) -> ApprovalLog {
+        ApprovalLog {
+            token: token.to_string(),
+            spender: spender.to_string(),
+            for_all,
+            value: value.to_string(),
+            blockNumber: block,
+            timeStamp: 0,
+            logIndex: 0,
+            hash: format!("0x{:02x}", block),
+        }
+    }
+
+    #[test]
+    fn parses_the_etherscan_logs() {
+        let topic = |address: &str| format!("0x{:0>64}", address.trim_start_matches("0x"));
+        let approval = serde_json::json!({
+            "address": "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+            "topics": [tools::event_topic("Approval(address,address,uint256)"), topic("0x01"), topic(ROUTER)],
+            "data": format!("0x{:0>64}", "f4240"),
+            "blockNumber": "0x10",
+            "timeStamp": "0x65000000",
+            "logIndex": "0x",
+            "transactionHash": "0xabc",
+        });
+        let parsed = parse_approval_log(&approval, false).unwrap();
+        assert_eq!((parsed.token.as_str(), parsed.spender.as_str(), parsed.value.as_str()), (USDC, ROUTER, "1000000"));
+        assert_eq!((parsed.blockNumber, parsed.timeStamp, parsed.logIndex), (16, 0x65000000, 0));
+
+        // ERC721 `Approval` has the token id as a third indexed topic
+        let mut erc721 = approval.clone();
+        erc721["topics"].as_array_mut().unwrap().push(serde_json::json!(topic("0x07")));
+        assert!(parse_approval_log(&erc721, false).is_none());
+
+        // A revoked approval for all has no data on some contracts
+        let mut revoked = approval;
+        revoked["data"] = serde_json::json!("0x");
+        assert_eq!(parse_approval_log(&revoked, true).unwrap().value, "0");
+    }
+
+    #[test]
+    fn the_last_log_is_the_current_approval() {
+        let logs = [
+            log(USDC, ROUTER, false, "1000000", 1),
+            log(USDC, DRAINER, false, "5", 2),
+            log(BAYC, DRAINER, true, "1", 3),
+            log(USDC, ROUTER, false, "2000000", 4),
+            log(USDC, DRAINER, false, "0", 5), // Revoked
+        ];
+        let approvals = current_approvals(&logs);
+        let current: Vec<(&str, &str, bool, &str)> = approvals
+            .iter()
+            .map(|a| (a.token.as_str(), a.spender.as_str(), a.for_all, a.allowance.as_str()))
+            .collect();
+        assert_eq!(current, vec![(BAYC, DRAINER, true, "1"), (USDC, ROUTER, false, "2000000")]);
+        assert_eq!(approvals[1].hash, "0x04");
+    }
+
+    #[test]
+    fn formats_the_allowances() {
+        let mut approval = current_approvals(&[log(USDC, ROUTER, false, "1500000", 1)]).remove(0);
+        assert_eq!(approval.amount(), "1500000");
+        approval.decimals = Some(6);
+        assert_eq!(approval.amount(), "1.5");
+        approval.allowance = U256::MAX.to_string();
+        assert_eq!(approval.amount(), "unlimited");
+        approval.for_all = true;
+        assert_eq!(approval.amount(), "all");
+
+        assert!(!approval.is_risky());
+        approval.risks.push(String::from("unverified contract"));
+        assert!(approval.to_string().ends_with(" RISKY: unverified contract"));
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::{BTreeSet, HashMap};
+use ethers::{
+    providers::Middleware,
+    types::Address,
+    utils::hex,
+};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::utils::{output::Record, selector_db::SelectorDb};
+
+const DIV: u8 = 0x04;
+const EQ: u8 = 0x14;
+const SHR: u8 = 0x1c;
+const CALLDATALOAD: u8 = 0x35;
+const JUMPI: u8 = 0x57;
+const PUSH1: u8 = 0x60;
+const PUSH3: u8 = 0x62;
+const PUSH4: u8 = 0x63;
+const PUSH20: u8 = 0x73;
+const PUSH32: u8 = 0x7f;
+const CREATE: u8 = 0xf0;
+const CALLCODE: u8 = 0xf2;
+const DELEGATECALL: u8 = 0xf4;
+const CREATE2: u8 = 0xf5;
+const SELFDESTRUCT: u8 = 0xff;
+
+/// @dev One instruction of the bytecode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Instruction {
+    pub pc: usize,
+    pub opcode: u8,
+    pub name: String,
+    pub push: Option<String>, // The pushed value in hex
+}
+
+impl Record for Instruction {
+    fn headers() -> Vec<&'static str> {
+        vec!["pc", "opcode", "name", "push"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            format!("{:#06x}", self.pc),
+            format!("{:#04x}", self.opcode),
+            self.name.clone(),
+            self.push.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+/// @dev A selector of the function dispatcher
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DispatcherSelector {
+    pub selector: String,
+    pub signatures: Vec<String>, // From the selector db, empty if unknown
+}
+
+impl Record for DispatcherSelector {
+    fn headers() -> Vec<&'static str> {
+        vec!["selector", "signatures"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.selector.clone(), self.signatures.join(" | ")]
+    }
+}
+
+/// @dev An address pushed by `PUSH20`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddedAddress {
+    pub address: String,
+    pub label: String, // The label in the address db
+}
+
+/// @dev What the bytecode of a contract does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BytecodeSummary {
+    pub address: String,
+    pub size: usize,
+    pub selectors: Vec<DispatcherSelector>,
+    pub referenced_selectors: Vec<String>, // The other `PUSH4` values, e.g. the selectors of the called contracts
+    pub delegatecall: usize, // How many `DELEGATECALL` / `CALLCODE`
+    pub selfdestruct: usize,
+    pub create: usize,
+    pub create2: usize,
+    pub addresses: Vec<EmbeddedAddress>,
+}
+
+impl BytecodeSummary {
+    /// @return The known selectors, e.g. to match against the selectors of a protocol
+    pub fn selector_set(&self) -> BTreeSet<String> {
+        self.selectors.iter().map(|selector| selector.selector.clone()).collect()
+    }
+}
+
+/// @dev Fetch the runtime bytecode of a contract with `eth_getCode` and analyze it
+/// @param client The provider
+/// @param address The contract address
+pub async fn analyze_address<M: Middleware>(client: &M, address: &str) -> Result<BytecodeSummary> {
+    let code = get_code(client, address).await?;
+    if code.is_empty() {
+        return Err(Error::InvalidInput(format!("{} has no code", address)));
+    }
+
+    let labels = crate::utils::address_db::get_db_labels().unwrap_or_default();
+    Ok(analyze_code(address, &code, &SelectorDb::load()?, &labels))
+}
+
+/// @dev The runtime bytecode of a contract
+/// @param client The provider
+/// @param address The contract address
+pub async fn get_code<M: Middleware>(client: &M, address: &str) -> Result<Vec<u8>> {
+    let address: Address = address
+        .parse()
+        .map_err(|e| Error::InvalidInput(format!("Invalid address `{}`: {}", address, e)))?;
+    let code = client
+        .get_code(address, None)
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?;
+    Ok(code.to_vec())
+}
+
+/// @dev Analyze a runtime bytecode: the dispatcher selectors matched against the selector db,
+/// the dangerous opcodes and the embedded addresses
+/// @param address The contract address
+/// @param code The runtime bytecode
+/// @param selectors The selector db
+/// @param labels The labels of the address db
+pub fn analyze_code(address: &str, code: &[u8], selectors: &SelectorDb, labels: &HashMap<String, String>) -> BytecodeSummary {
+    let instructions = disassemble(strip_metadata(code));
+    let count = |opcodes: &[u8]| instructions.iter().filter(|i| opcodes.contains(&i.opcode)).count();
+
+    let dispatcher = dispatcher_selectors(&instructions);
+    let mut referenced_selectors = Vec::new();
+    for instruction in instructions.iter().filter(|i| i.opcode == PUSH4) {
+        let Some(value) = &instruction.push else { continue };
+        if value != "0xffffffff" && !dispatcher.contains(value) && !referenced_selectors.contains(value) {
+            referenced_selectors.push(value.clone());
+        }
+    }
+
+    let selectors = dispatcher
+        .into_iter()
+        .map(|selector| DispatcherSelector {
+            signatures: selectors
+                .lookup(&selector)
+                .map(|matches| matches.into_iter().map(|m| m.signature).filter(|s| !s.is_empty()).collect())
+                .unwrap_or_default(),
+            selector,
+        })
+        .collect();
+
+    let addresses = embedded_addresses(&instructions)
+        .into_iter()
+        .map(|address| EmbeddedAddress {
+            label: labels.get(&address).cloned().unwrap_or_default(),
+            address,
+        })
+        .collect();
+
+    BytecodeSummary {
+        address: address.to_string(),
+        size: code.len(),
+        selectors,
+        referenced_selectors,
+        delegatecall: count(&[DELEGATECALL, CALLCODE]),
+        selfdestruct: count(&[SELFDESTRUCT]),
+        create: count(&[CREATE]),
+        create2: count(&[CREATE2]),
+        addresses,
+    }
+}
+
+/// @dev Split a bytecode into instructions, the push data is not decoded as instructions
+pub fn disassemble(code: &[u8]) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut pc = 0;
+
+    while pc < code.len() {
+        let opcode = code[pc];
+        let size = if (PUSH1..=PUSH32).contains(&opcode) { (opcode - PUSH1 + 1) as usize } else { 0 };
+        // The push data may be cut at the end of the code
+        let push = (size > 0).then(|| format!("0x{}", hex::encode(&code[(pc + 1).min(code.len())..(pc + 1 + size).min(code.len())])));
+
+        instructions.push(Instruction {
+            pc,
+            opcode,
+            name: opcode_name(opcode).to_string(),
+            push,
+        });
+        pc += 1 + size;
+    }
+
+    instructions
+}
+
+/// @dev Drop the CBOR metadata appended by solc / vyper, its bytes are not instructions
+fn strip_metadata(code: &[u8]) -> &[u8] {
+    if code.len() < 2 {
+        return code;
+    }
+
+    let length = u16::from_be_bytes([code[code.len() - 2], code[code.len() - 1]]) as usize;
+    let start = code.len().checked_sub(length + 2);
+    match start.map(|start| code[start]) {
+        // A CBOR map with 1 to 5 entries
+        Some(0xa1..=0xa5) => &code[..start.unwrap_or_default()],
+        _ => code,
+    }
+}
+
+/// @dev The selectors compared by the function dispatcher: `PUSH4 selector`, then `EQ` and `JUMPI` within a few instructions.
+/// solc pushes a selector with a leading zero byte with `PUSH3`, e.g. `PUSH3 0xa718a9` for `0x00a718a9`.
+/// Only the comparisons after the selector is loaded from the calldata are dispatcher comparisons
+fn dispatcher_selectors(instructions: &[Instruction]) -> Vec<String> {
+    let mut selectors = Vec::new();
+    let Some(start) = selector_load(instructions) else { return selectors };
+
+    for (index, instruction) in instructions.iter().enumerate().skip(start) {
+        if instruction.opcode != PUSH3 && instruction.opcode != PUSH4 {
+            continue;
+        }
+        let Some(value) = &instruction.push else { continue };
+        let selector = format!("0x{:0>8}", &value[2..]);
+        if selector == "0xffffffff" || selectors.contains(&selector) {
+            continue;
+        }
+
+        // E.g. `DUP1 PUSH4 selector EQ PUSH2 dest JUMPI` or `PUSH4 selector DUP2 EQ PUSH2 dest JUMPI`
+        let next: Vec<u8> = instructions[index + 1..].iter().take(4).map(|i| i.opcode).collect();
+        let eq = next.iter().position(|&opcode| opcode == EQ);
+        let jumpi = next.iter().position(|&opcode| opcode == JUMPI);
+        if matches!((eq, jumpi), (Some(eq), Some(jumpi)) if eq < jumpi) {
+            selectors.push(selector);
+        }
+    }
+
+    selectors
+}
+
+/// @dev Where the dispatcher loads the selector: `CALLDATALOAD PUSH1 0xe0 SHR`, or `CALLDATALOAD PUSH29 0x01.. SWAP1 DIV` before solc 0.5
+/// @return The index of the `CALLDATALOAD`, None if the code has no dispatcher
+fn selector_load(instructions: &[Instruction]) -> Option<usize> {
+    instructions.iter().enumerate().position(|(index, instruction)| {
+        if instruction.opcode != CALLDATALOAD {
+            return false;
+        }
+        let next = &instructions[index + 1..instructions.len().min(index + 5)];
+        next.windows(2).any(|pair| pair[0].opcode == PUSH1 && pair[0].push.as_deref() == Some("0xe0") && pair[1].opcode == SHR)
+            || next.iter().any(|i| i.opcode == DIV)
+    })
+}
+
+/// @dev The addresses pushed by `PUSH20`, lowercase
+fn embedded_addresses(instructions: &[Instruction]) -> Vec<String> {
+    let mut addresses = Vec::new();
+
+    for instruction in instructions {
+        if instruction.opcode != PUSH20 {
+            continue;
+        }
+        let Some(address) = &instruction.push else { continue };
+        let hex = &address[2..];
+        // Masks and the zero address are not addresses
+        if hex.len() != 40 || hex.chars().all(|c| c == 'f') || hex.chars().all(|c| c == '0') {
+            continue;
+        }
+        if !addresses.contains(address) {
+            addresses.push(address.clone());
+        }
+    }
+
+    addresses
+}
+
+/// @return The mnemonic of an opcode
+pub fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        0x00 => "STOP",
+        0x01 => "ADD",
+        0x02 => "MUL",
+        0x03 => "SUB",
+        0x04 => "DIV",
+        0x05 => "SDIV",
+        0x06 => "MOD",
+        0x07 => "SMOD",
+        0x08 => "ADDMOD",
+        0x09 => "MULMOD",
+        0x0a => "EXP",
+        0x0b => "SIGNEXTEND",
+        0x10 => "LT",
+        0x11 => "GT",
+        0x12 => "SLT",
+        0x13 => "SGT",
+        0x14 => "EQ",
+        0x15 => "ISZERO",
+        0x16 => "AND",
+        0x17 => "OR",
+        0x18 => "XOR",
+        0x19 => "NOT",
+        0x1a => "BYTE",
+        0x1b => "SHL",
+        0x1c => "SHR",
+        0x1d => "SAR",
+        0x20 => "KECCAK256",
+        0x30 => "ADDRESS",
+        0x31 => "BALANCE",
+        0x32 => "ORIGIN",
+        0x33 => "CALLER",
+        0x34 => "CALLVALUE",
+        0x35 => "CALLDATALOAD",
+        0x36 => "CALLDATASIZE",
+        0x37 => "CALLDATACOPY",
+        0x38 => "CODESIZE",
+        0x39 => "CODECOPY",
+        0x3a => "GASPRICE",
+        0x3b => "EXTCODESIZE",
+        0x3c => "EXTCODECOPY",
+        0x3d => "RETURNDATASIZE",
+        0x3e => "RETURNDATACOPY",
+        0x3f => "EXTCODEHASH",
+        0x40 => "BLOCKHASH",
+        0x41 => "COINBASE",
+        0x42 => "TIMESTAMP",
+        0x43 => "NUMBER",
+        0x44 => "PREVRANDAO",
+        0x45 => "GASLIMIT",
+        0x46 => "CHAINID",
+        0x47 => "SELFBALANCE",
+        0x48 => "BASEFEE",
+        0x49 => "BLOBHASH",
+        0x4a => "BLOBBASEFEE",
+        0x50 => "POP",
+        0x51 => "MLOAD",
+        0x52 => "MSTORE",
+        0x53 => "MSTORE8",
+        0x54 => "SLOAD",
+        0x55 => "SSTORE",
+        0x56 => "JUMP",
+        0x57 => "JUMPI",
+        0x58 => "PC",
+        0x59 => "MSIZE",
+        0x5a => "GAS",
+        0x5b => "JUMPDEST",
+        0x5c => "TLOAD",
+        0x5d => "TSTORE",
+        0x5e => "MCOPY",
+        0x5f => "PUSH0",
+        0x60 => "PUSH1",
+        0x61 => "PUSH2",
+        0x62 => "PUSH3",
+        0x63 => "PUSH4",
+        0x64 => "PUSH5",
+        0x65 => "PUSH6",
+        0x66 => "PUSH7",
+        0x67 => "PUSH8",
+        0x68 => "PUSH9",
+        0x69 => "PUSH10",
+        0x6a => "PUSH11",
+        0x6b => "PUSH12",
+        0x6c => "PUSH13",
+        0x6d => "PUSH14",
+        0x6e => "PUSH15",
+        0x6f => "PUSH16",
+        0x70 => "PUSH17",
+        0x71 => "PUSH18",
+        0x72 => "PUSH19",
+        0x73 => "PUSH20",
+        0x74 => "PUSH21",
+        0x75 => "PUSH22",
+        0x76 => "PUSH23",
+        0x77 => "PUSH24",
+        0x78 => "PUSH25",
+        0x79 => "PUSH26",
+        0x7a => "PUSH27",
+        0x7b => "PUSH28",
+        0x7c => "PUSH29",
+        0x7d => "PUSH30",
+        0x7e => "PUSH31",
+        0x7f => "PUSH32",
+        0x80 => "DUP1",
+        0x81 => "DUP2",
+        0x82 => "DUP3",
+        0x83 => "DUP4",
+        0x84 => "DUP5",
+        0x85 => "DUP6",
+        0x86 => "DUP7",
+        0x87 => "DUP8",
+        0x88 => "DUP9",
+        0x89 => "DUP10",
+        0x8a => "DUP11",
+        0x8b => "DUP12",
+        0x8c => "DUP13",
+        0x8d => "DUP14",
+        0x8e => "DUP15",
+        0x8f => "DUP16",
+        0x90 => "SWAP1",
+        0x91 => "SWAP2",
+        0x92 => "SWAP3",
+        0x93 => "SWAP4",
+        0x94 => "SWAP5",
+        0x95 => "SWAP6",
+        0x96 => "SWAP7",
+        0x97 => "SWAP8",
+        0x98 => "SWAP9",
+        0x99 => "SWAP10",
+        0x9a => "SWAP11",
+        0x9b => "SWAP12",
+        0x9c => "SWAP13",
+        0x9d => "SWAP14",
+        0x9e => "SWAP15",
+        0x9f => "SWAP16",
+        0xa0 => "LOG0",
+        0xa1 => "LOG1",
+        0xa2 => "LOG2",
+        0xa3 => "LOG3",
+        0xa4 => "LOG4",
+        0xf0 => "CREATE",
+        0xf1 => "CALL",
+        0xf2 => "CALLCODE",
+        0xf3 => "RETURN",
+        0xf4 => "DELEGATECALL",
+        0xf5 => "CREATE2",
+        0xfa => "STATICCALL",
+        0xfd => "REVERT",
+        0xfe => "INVALID",
+        0xff => "SELFDESTRUCT",
+        _ => "UNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTER: &str = "7a250d5630b4cf539739df2c5dacb4c659f2488d";
+
+    /// @dev A dispatcher with `transfer(address,uint256)` (PUSH4) and `0x00a718a9` (PUSH3), a referenced `approve` selector,
+    /// an embedded address, the dangerous opcodes and a CBOR metadata
+    fn code() -> Vec<u8> {
+        let code = [
+            "60003560e01c",             // PUSH1 0 CALLDATALOAD PUSH1 0xe0 SHR
+            "8063a9059cbb1461004057",   // DUP1 PUSH4 0xa9059cbb EQ PUSH2 0x0040 JUMPI
+            "8062a718a91461005057",     // DUP1 PUSH3 0xa718a9 EQ PUSH2 0x0050 JUMPI
+            "63ffffffff16",             // PUSH4 0xffffffff AND
+            "63095ea7b350",             // PUSH4 0x095ea7b3 POP
+            &format!("73{}50", ROUTER), // PUSH20 router POP
+            "f4fff5f000",               // DELEGATECALL SELFDESTRUCT CREATE2 CREATE STOP
+        ]
+        .concat();
+        let mut code = hex::decode(code).unwrap();
+        // {"solc": ...} with its length, a PUSH4 inside must not be decoded
+        code.extend_from_slice(&[0xa1, 0x63, 0x12, 0x34, 0x56, 0x78, 0x00, 0x06]);
+        code
+    }
+
+    #[test]
+    fn disassembles_the_push_data() {
+        let instructions = disassemble(&hex::decode("6080604052600436106100").unwrap());
+        let names: Vec<&str> = instructions.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["PUSH1", "PUSH1", "MSTORE", "PUSH1", "CALLDATASIZE", "LT", "PUSH2"]);
+        assert_eq!(instructions[6].pc, 9);
+        // The push data is cut at the end of the code
+        assert_eq!(instructions[6].push.as_deref(), Some("0x00"));
+    }
+
+    #[test]
+    fn strips_the_metadata() {
+        let code = code();
+        assert_eq!(strip_metadata(&code).len(), code.len() - 8);
+        assert_eq!(strip_metadata(&[0x00, 0x00, 0x05]), &[0x00, 0x00, 0x05]);
+    }
+
+    #[test]
+    fn extracts_the_dispatcher_selectors() {
+        let labels = HashMap::from([(format!("0x{}", ROUTER), String::from("protocol: Uniswap V2 Router"))]);
+        let summary = analyze_code("0x01", &code(), &SelectorDb::bundled().unwrap(), &labels);
+
+        let selectors: Vec<&str> = summary.selectors.iter().map(|s| s.selector.as_str()).collect();
+        assert_eq!(selectors, vec!["0xa9059cbb", "0x00a718a9"]);
+        assert!(summary.selectors[0].signatures.contains(&String::from("transfer(address,uint256)")));
+        assert_eq!(summary.referenced_selectors, vec!["0x095ea7b3"]);
+
+        assert_eq!(summary.addresses.len(), 1);
+        assert_eq!(summary.addresses[0].label, "protocol: Uniswap V2 Router");
+        assert_eq!((summary.delegatecall, summary.selfdestruct, summary.create, summary.create2), (1, 1, 1, 1));
+    }
+
+    #[test]
+    fn ignores_the_pushes_not_compared_by_the_dispatcher() {
+        // PUSH1 0x01 DUP2 LT PUSH2 JUMPI: a comparison, not an `EQ`
+        let instructions = disassemble(&hex::decode("600181106100405700").unwrap());
+        assert!(dispatcher_selectors(&instructions).is_empty());
+
+        // PUSH1 0x01 EQ PUSH2 JUMPI after the selector load: a small constant, not a selector
+        let instructions = disassemble(&hex::decode("60003560e01c80600114610040576300000001").unwrap());
+        assert!(dispatcher_selectors(&instructions).is_empty());
+
+        // PUSH4 EQ PUSH2 JUMPI without a selector load: not a dispatcher
+        let instructions = disassemble(&hex::decode("8063a9059cbb1461004057").unwrap());
+        assert!(dispatcher_selectors(&instructions).is_empty());
+    }
+}
@@ -0,0 +1,640 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+use ethers::{
+    abi::{Abi, Event, Function, HumanReadableParser, RawLog, Token},
+    types::{H256, I256},
+    utils::hex,
+};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::listener::fetcher::TransactionInfo;
+use crate::utils::{etherscan, selector_db::SelectorDb};
+
+/// Where the ABIs from etherscan `getabi` are cached, `null` for an unverified contract
+pub const DEFAULT_ABI_CACHE_DIR: &str = "output/abis";
+
+/// How deep the nested calls of multicall / execute / execTransaction are decoded
+const MAX_DEPTH: usize = 4;
+
+/// @dev Where the function of a call was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecodeSource {
+    /// The verified ABI of the called contract
+    Abi,
+    /// The offline selector db, the argument names are unknown
+    SelectorDb,
+    /// Unknown selector, the input is kept raw
+    Unknown,
+}
+
+/// @dev A decoded argument
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedArg {
+    pub name: String, // Empty if decoded from the selector db
+    pub kind: String, // The solidity type, e.g. `address`, `(address,bytes)[]`
+    pub value: String,
+}
+
+/// @dev A call found inside another call, e.g. one call of a multicall
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InnerCall {
+    pub to: String,
+    pub value: String,
+    pub call: DecodedCall,
+}
+
+/// @dev A decoded calldata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedCall {
+    pub selector: String,
+    pub function: String, // The function name, empty if unknown
+    pub signature: String, // E.g. `transfer(address,uint256)`, empty if unknown
+    pub source: DecodeSource,
+    pub args: Vec<DecodedArg>,
+    pub calls: Vec<InnerCall>, // The nested calls of multicall / execute / execTransaction
+}
+
+impl DecodedCall {
+    /// @return True if this call, or a nested call, is the function
+    /// @param signature The function, e.g. `removeLiquidity(address,address,uint256,uint256,uint256,address,uint256)`, or its selector
+    pub fn contains(&self, signature: &str) -> bool {
+        let signature = signature.trim();
+        let matched = if signature.starts_with("0x") {
+            self.selector.eq_ignore_ascii_case(signature)
+        } else {
+            self.signature == signature.replace(' ', "")
+        };
+        matched || self.calls.iter().any(|inner| inner.call.contains(signature))
+    }
+
+    fn unknown(selector: String) -> Self {
+        DecodedCall {
+            selector,
+            function: String::new(),
+            signature: String::new(),
+            source: DecodeSource::Unknown,
+            args: Vec::new(),
+            calls: Vec::new(),
+        }
+    }
+}
+
+/// @dev `transfer(to: 0x..., amount: 1) [multicall: ...]`
+impl fmt::Display for DecodedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.source == DecodeSource::Unknown {
+            return write!(f, "{}", self.selector);
+        }
+
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| {
+                // Long calldata is shortened, its nested calls are shown after the call
+                let value = if arg.kind == "bytes" && arg.value.len() > 74 {
+                    format!("{}..({} bytes)", &arg.value[..10], (arg.value.len() - 2) / 2)
+                } else {
+                    arg.value.clone()
+                };
+                if arg.name.is_empty() { value } else { format!("{}: {}", arg.name, value) }
+            })
+            .collect();
+        write!(f, "{}({})", self.function, args.join(", "))?;
+
+        if !self.calls.is_empty() {
+            let calls: Vec<String> = self.calls.iter().map(|inner| format!("{} -> {}", inner.to, inner.call)).collect();
+            write!(f, " [{}]", calls.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// @dev A decoded event log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedEvent {
+    pub topic: String,
+    pub event: String, // The event name
+    pub signature: String, // E.g. `Transfer(address,address,uint256)`
+    pub source: DecodeSource,
+    pub args: Vec<DecodedArg>,
+}
+
+/// @dev `Transfer(from: 0x..., to: 0x..., value: 1)`
+impl fmt::Display for DecodedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| if arg.name.is_empty() { arg.value.clone() } else { format!("{}: {}", arg.name, arg.value) })
+            .collect();
+        write!(f, "{}({})", self.event, args.join(", "))
+    }
+}
+
+/// @dev Decode calldata with the verified ABI of the called contract, falling back to the selector db
+pub struct Decoder {
+    api_key: Option<String>,
+    selectors: SelectorDb,
+    cache_dir: PathBuf,
+    abis: HashMap<String, Option<Abi>>, // Lowercase address to its ABI, None if unverified
+}
+
+impl Decoder {
+    /// @param api_key Etherscan API kEY to fetch the ABIs, None to only use the cached ABIs and the selector db
+    pub fn new(api_key: Option<String>) -> Result<Self> {
+        Ok(Decoder {
+            api_key,
+            selectors: SelectorDb::load()?,
+            cache_dir: PathBuf::from(DEFAULT_ABI_CACHE_DIR),
+            abis: HashMap::new(),
+        })
+    }
+
+    /// @dev Cache the ABIs in another folder
+    /// @param dir The cache folder
+    pub fn with_cache_dir(mut self, dir: &str) -> Self {
+        self.cache_dir = PathBuf::from(dir);
+        self
+    }
+
+    /// @dev Decode the input of a transaction
+    /// @param to The called contract
+    /// @param input The calldata in hex
+    /// @return None if the input is empty (an ether transfer) or shorter than a selector
+    pub async fn decode(&mut self, to: &str, input: &str) -> Result<Option<DecodedCall>> {
+        let data = hex::decode(input.trim_start_matches("0x"))
+            .map_err(|e| Error::Decode(format!("Invalid calldata {}: {}", input, e)))?;
+        if data.len() < 4 {
+            return Ok(None);
+        }
+
+        Ok(Some(self.decode_call(to.to_string(), data, 0).await))
+    }
+
+    /// @dev Decode the inputs of fetched transactions into their `decoded` field.
+    /// A tx which cannot be decoded, e.g. with a malformed input, is logged and left undecoded
+    /// @param txs The transactions
+    pub async fn decode_transactions(&mut self, txs: &mut [TransactionInfo]) -> Result<()> {
+        for tx in txs.iter_mut() {
+            if tx.to.is_empty() {
+                continue; // Contract creation
+            }
+            match self.decode(&tx.to, &tx.input).await {
+                Ok(decoded) => tx.decoded = decoded,
+                Err(e) => eprintln!("decode tx {}: {}", tx.hash, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// @dev Decode an event log with the verified ABI of the emitter, falling back to the selector db
+    /// @param address The contract which emitted the log
+    /// @param topics The log topics, the first one is the event topic
+    /// @param data The log data
+    /// @return None if the log is anonymous or its event is unknown
+    pub async fn decode_event(&mut self, address: &str, topics: &[H256], data: &[u8]) -> Option<DecodedEvent> {
+        let topic = *topics.first()?;
+        let raw = RawLog { topics: topics.to_vec(), data: data.to_vec() };
+
+        let abi_event = self
+            .abi(address)
+            .await
+            .and_then(|abi| abi.events().find(|event| !event.anonymous && event.signature() == topic).cloned());
+        let (event, log, source) = abi_event
+            .and_then(|event| Some((event.clone(), parse_log(&event, &raw)?, DecodeSource::Abi)))
+            .or_else(|| self.selector_event(topic, &raw).map(|(event, log)| (event, log, DecodeSource::SelectorDb)))?;
+
+        let args = event
+            .inputs
+            .iter()
+            .zip(&log.params)
+            .map(|(param, value)| DecodedArg {
+                name: param.name.clone(),
+                kind: param.kind.to_string(),
+                value: format_token(&value.value),
+            })
+            .collect();
+
+        let kinds: Vec<String> = event.inputs.iter().map(|param| param.kind.to_string()).collect();
+        Some(DecodedEvent {
+            topic: format!("{:?}", topic),
+            event: event.name.clone(),
+            signature: format!("{}({})", event.name, kinds.join(",")),
+            source,
+            args,
+        })
+    }
+
+    /// @dev The first signature in the selector db which decodes the log.
+    /// The db does not know which params are indexed, the first ones are taken as indexed, as most events do
+    fn selector_event(&self, topic: H256, raw: &RawLog) -> Option<(Event, ethers::abi::Log)> {
+        let matches = self.selectors.lookup(&format!("{:?}", topic)).ok()?;
+        matches
+            .iter()
+            .filter(|m| !m.signature.is_empty())
+            .filter_map(|m| HumanReadableParser::parse_event(&format!("event {}", m.signature)).ok())
+            .find_map(|mut event| {
+                let indexed = raw.topics.len() - 1;
+                if indexed > event.inputs.len() {
+                    return None;
+                }
+                for (i, param) in event.inputs.iter_mut().enumerate() {
+                    param.indexed = i < indexed;
+                }
+                let log = parse_log(&event, raw)?;
+                Some((event, log))
+            })
+    }
+
+    /// @dev Decode a call and its nested calls
+    fn decode_call(&mut self, to: String, data: Vec<u8>, depth: usize) -> Pin<Box<dyn Future<Output = DecodedCall> + Send + '_>> {
+        Box::pin(async move {
+            let selector = format!("0x{}", hex::encode(&data[..4]));
+
+            let abi_function = self
+                .abi(&to)
+                .await
+                .and_then(|abi| abi.functions().find(|function| function.short_signature() == data[..4]).cloned());
+            let (function, source) = match abi_function {
+                Some(function) => (Some(function), DecodeSource::Abi),
+                None => (self.selector_function(&selector, &data), DecodeSource::SelectorDb),
+            };
+            let Some(function) = function else { return DecodedCall::unknown(selector) };
+            let Ok(tokens) = function.decode_input(&data[4..]) else { return DecodedCall::unknown(selector) };
+
+            let args = function
+                .inputs
+                .iter()
+                .zip(&tokens)
+                .map(|(param, token)| DecodedArg {
+                    name: param.name.clone(),
+                    kind: param.kind.to_string(),
+                    value: format_token(token),
+                })
+                .collect();
+
+            let mut calls = Vec::new();
+            if depth < MAX_DEPTH {
+                for (inner_to, value, inner_data) in nested_calls(&function.name, &to, &tokens) {
+                    if inner_data.len() < 4 {
+                        continue;
+                    }
+                    let call = self.decode_call(inner_to.clone(), inner_data, depth + 1).await;
+                    calls.push(InnerCall { to: inner_to, value, call });
+                }
+            }
+
+            DecodedCall {
+                selector,
+                function: function.name.clone(),
+                signature: function.signature().split(':').next().unwrap_or_default().to_string(),
+                source,
+                args,
+                calls,
+            }
+        })
+    }
+
+    /// @dev The first signature in the selector db which decodes and re-encodes to the same calldata
+    fn selector_function(&self, selector: &str, data: &[u8]) -> Option<Function> {
+        let matches = self.selectors.lookup(selector).ok()?;
+        matches
+            .iter()
+            .filter(|m| !m.signature.is_empty())
+            .filter_map(|m| HumanReadableParser::parse_function(&format!("function {}", m.signature)).ok())
+            .find(|function| {
+                function
+                    .decode_input(&data[4..])
+                    .ok()
+                    .and_then(|tokens| function.encode_input(&tokens).ok())
+                    .is_some_and(|encoded| encoded == data)
+            })
+    }
+
+    /// @dev The ABI of a contract: from memory, the cache folder, or etherscan `getabi`
+    async fn abi(&mut self, address: &str) -> Option<Abi> {
+        let address = address.to_lowercase();
+        if let Some(abi) = self.abis.get(&address) {
+            return abi.clone();
+        }
+
+        let path = self.cache_dir.join(format!("{}.json", address));
+        let abi = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str::<Option<Abi>>(&content).ok().flatten(),
+            Err(_) => {
+                let api_key = self.api_key.clone()?;
+                match fetch_abi(&api_key, &address).await {
+                    Ok(abi) => {
+                        write_cache(&path, &abi);
+                        abi
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to fetch the ABI of {}: {}", address, e);
+                        // A transient failure is fetched again next time, the others (e.g. an invalid ABI) are remembered
+                        if !e.is_recoverable() {
+                            self.abis.insert(address, None);
+                        }
+                        return None;
+                    }
+                }
+            }
+        };
+
+        self.abis.insert(address, abi.clone());
+        abi
+    }
+}
+
+/// @dev Fetch the ABI of a contract with etherscan `getabi`
+/// @param api_key ETHERSCAN API KEY
+/// @param address The contract address
+/// @return None if the contract is not verified
+pub async fn fetch_abi(api_key: &str, address: &str) -> Result<Option<Abi>> {
+    let url = format!("https://api.etherscan.io/api?module=contract&action=getabi&address={}&apikey={}",
+        address,
+        api_key
+    );
+
+    match etherscan::get_result(&url).await {
+        Ok(result) => {
+            let abi = result.as_str().unwrap_or_default();
+            let abi = serde_json::from_str(abi)
+                .map_err(|e| Error::Decode(format!("Invalid ABI of {}: {}", address, e)))?;
+            Ok(Some(abi))
+        }
+        Err(Error::Api { result, .. }) if result.contains("not verified") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// @dev `Event::parse_log()` matches the values to the params by name, so the unnamed params are named by their index first
+fn parse_log(event: &Event, raw: &RawLog) -> Option<ethers::abi::Log> {
+    let mut named = event.clone();
+    for (i, param) in named.inputs.iter_mut().enumerate() {
+        if param.name.is_empty() {
+            param.name = format!("_{}", i);
+        }
+    }
+    named.parse_log(raw.clone()).ok()
+}
+
+fn write_cache(path: &Path, abi: &Option<Abi>) {
+    let written = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(path, serde_json::to_string(abi).unwrap_or_default()));
+    if let Err(e) = written {
+        eprintln!("Failed to cache the ABI into {}: {}", path.display(), e);
+    }
+}
+
+/// @dev The calls wrapped by multicall / Multicall3 / execute / executeBatch / Safe execTransaction / MultiSend
+/// @param name The function name
+/// @param to The called contract, the target of `multicall(bytes[])`
+/// @param tokens The decoded arguments
+/// @return The target, the value and the calldata of every nested call
+fn nested_calls(name: &str, to: &str, tokens: &[Token]) -> Vec<(String, String, Vec<u8>)> {
+    let mut calls = Vec::new();
+
+    match name {
+        // multicall(bytes[]) and multicall(uint256 deadline, bytes[])
+        "multicall" => {
+            for token in tokens {
+                if let Token::Array(items) = token {
+                    for item in items {
+                        if let Token::Bytes(data) = item {
+                            calls.push((to.to_string(), String::from("0"), data.clone()));
+                        }
+                    }
+                }
+            }
+        }
+        // Multicall3: (address target, [bool allowFailure], [uint256 value], bytes callData)[]
+        "aggregate" | "tryAggregate" | "blockAndAggregate" | "tryBlockAndAggregate" | "aggregate3" | "aggregate3Value" => {
+            for token in tokens {
+                let Token::Array(items) = token else { continue };
+                for item in items {
+                    let Token::Tuple(fields) = item else { continue };
+                    let target = fields.iter().find_map(|field| if let Token::Address(a) = field { Some(*a) } else { None });
+                    let value = fields.iter().find_map(|field| if let Token::Uint(v) = field { Some(*v) } else { None });
+                    let data = fields.iter().rev().find_map(|field| if let Token::Bytes(d) = field { Some(d.clone()) } else { None });
+                    if let (Some(target), Some(data)) = (target, data) {
+                        calls.push((format!("{:?}", target), value.unwrap_or_default().to_string(), data));
+                    }
+                }
+            }
+        }
+        // Safe execTransaction(address to, uint256 value, bytes data, ...), smart accounts execute(address, uint256, bytes)
+        "execTransaction" | "execTransactionFromModule" | "execute" => {
+            if let [Token::Address(target), Token::Uint(value), Token::Bytes(data), ..] = tokens {
+                calls.push((format!("{:?}", target), value.to_string(), data.clone()));
+            }
+        }
+        // executeBatch(address[], uint256[], bytes[]) and executeBatch(address[], bytes[])
+        "executeBatch" => match tokens {
+            [Token::Array(targets), Token::Array(values), Token::Array(datas)] => {
+                for ((target, value), data) in targets.iter().zip(values).zip(datas) {
+                    if let (Token::Address(target), Token::Uint(value), Token::Bytes(data)) = (target, value, data) {
+                        calls.push((format!("{:?}", target), value.to_string(), data.clone()));
+                    }
+                }
+            }
+            [Token::Array(targets), Token::Array(datas)] => {
+                for (target, data) in targets.iter().zip(datas) {
+                    if let (Token::Address(target), Token::Bytes(data)) = (target, data) {
+                        calls.push((format!("{:?}", target), String::from("0"), data.clone()));
+                    }
+                }
+            }
+            _ => {}
+        },
+        // Safe MultiSend: packed (uint8 operation, address to, uint256 value, uint256 length, bytes data)
+        "multiSend" => {
+            if let [Token::Bytes(packed)] = tokens {
+                calls.extend(multi_send_calls(packed));
+            }
+        }
+        _ => {}
+    }
+
+    calls
+}
+
+/// @dev Split the packed transactions of Safe `multiSend(bytes)`
+fn multi_send_calls(packed: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+    let mut calls = Vec::new();
+    let mut offset = 0;
+
+    // operation (1) + to (20) + value (32) + data length (32)
+    while offset + 85 <= packed.len() {
+        let to = ethers::types::Address::from_slice(&packed[offset + 1..offset + 21]);
+        let value = ethers::types::U256::from_big_endian(&packed[offset + 21..offset + 53]);
+        let length = ethers::types::U256::from_big_endian(&packed[offset + 53..offset + 85]);
+        let start = offset + 85;
+        let Some(end) = usize::try_from(length).ok().and_then(|length| start.checked_add(length)) else { break };
+        if end > packed.len() {
+            break;
+        }
+
+        calls.push((format!("{:?}", to), value.to_string(), packed[start..end].to_vec()));
+        offset = end;
+    }
+
+    calls
+}
+
+/// @dev Format a decoded value: addresses and bytes in hex, integers in decimal
+pub fn format_token(token: &Token) -> String {
+    match token {
+        Token::Address(address) => format!("{:?}", address),
+        Token::FixedBytes(bytes) | Token::Bytes(bytes) => format!("0x{}", hex::encode(bytes)),
+        Token::Int(value) => I256::from_raw(*value).to_string(),
+        Token::Uint(value) => value.to_string(),
+        Token::Bool(value) => value.to_string(),
+        Token::String(value) => format!("{:?}", value),
+        Token::FixedArray(items) | Token::Array(items) => {
+            format!("[{}]", items.iter().map(format_token).collect::<Vec<String>>().join(", "))
+        }
+        Token::Tuple(items) => format!("({})", items.iter().map(format_token).collect::<Vec<String>>().join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Address, U256};
+
+    const TOKEN: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+    const SAFE: &str = "0x00000000000000000000000000000000000000aa";
+
+    /// @dev A decoder which only uses the selector db, with an empty ABI cache
+    fn decoder() -> Decoder {
+        let cache = std::env::temp_dir().join(format!("sechelper-abis-{}", std::process::id()));
+        let mut decoder = Decoder::new(None).unwrap().with_cache_dir(&cache.to_string_lossy());
+        for signature in [
+            "transfer(address,uint256)",
+            "approve(address,uint256)",
+            "multicall(bytes[])",
+            "multiSend(bytes)",
+            "aggregate3((address,bool,bytes)[])",
+            "execTransaction(address,uint256,bytes,uint8,uint256,uint256,uint256,address,address,bytes)",
+        ] {
+            decoder.selectors.insert_function(signature);
+        }
+        decoder
+    }
+
+    fn encode(signature: &str, tokens: &[Token]) -> Vec<u8> {
+        HumanReadableParser::parse_function(&format!("function {}", signature)).unwrap().encode_input(tokens).unwrap()
+    }
+
+    fn address(address: &str) -> Address {
+        address.parse().unwrap()
+    }
+
+    fn transfer(amount: u64) -> Vec<u8> {
+        encode("transfer(address,uint256)", &[Token::Address(address(SAFE)), Token::Uint(U256::from(amount))])
+    }
+
+    async fn decode(data: &[u8]) -> DecodedCall {
+        decoder().decode(TOKEN, &format!("0x{}", hex::encode(data))).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn decodes_with_the_selector_db() {
+        let call = decode(&transfer(5)).await;
+        assert_eq!(call.signature, "transfer(address,uint256)");
+        assert_eq!(call.source, DecodeSource::SelectorDb);
+        assert_eq!(call.args[1].value, "5");
+        assert_eq!(call.to_string(), format!("transfer({:?}, 5)", address(SAFE)));
+
+        assert_eq!(decode(&[0xde, 0xad, 0xbe, 0xef]).await.source, DecodeSource::Unknown);
+        assert!(decoder().decode(TOKEN, "0x").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn decodes_the_calls_of_a_multicall() {
+        let approve = encode("approve(address,uint256)", &[Token::Address(address(SAFE)), Token::Uint(U256::MAX)]);
+        let call = decode(&encode("multicall(bytes[])", &[Token::Array(vec![Token::Bytes(transfer(1)), Token::Bytes(approve)])])).await;
+
+        assert_eq!(call.calls.len(), 2);
+        assert!(call.calls.iter().all(|inner| inner.to == TOKEN));
+        assert_eq!(call.calls[1].call.function, "approve");
+        assert!(call.contains("approve(address, uint256)"));
+        assert!(call.contains("0xa9059cbb"));
+        assert!(!call.contains("transferFrom(address,address,uint256)"));
+    }
+
+    #[tokio::test]
+    async fn decodes_the_calls_of_multicall3() {
+        let calls = Token::Array(vec![Token::Tuple(vec![Token::Address(address(TOKEN)), Token::Bool(false), Token::Bytes(transfer(2))])]);
+        let call = decode(&encode("aggregate3((address,bool,bytes)[])", &[calls])).await;
+        assert_eq!(call.calls.len(), 1);
+        assert_eq!(call.calls[0].to, TOKEN);
+        assert_eq!(call.calls[0].call.args[1].value, "2");
+    }
+
+    #[tokio::test]
+    async fn decodes_a_safe_multi_send() {
+        let mut packed = Vec::new();
+        for (to, value, data) in [(TOKEN, 0u64, transfer(3)), (SAFE, 7, Vec::new())] {
+            packed.push(0u8);
+            packed.extend_from_slice(address(to).as_bytes());
+            packed.extend_from_slice(&ethers::abi::encode(&[Token::Uint(U256::from(value)), Token::Uint(U256::from(data.len()))]));
+            packed.extend_from_slice(&data);
+        }
+        let multi_send = encode("multiSend(bytes)", &[Token::Bytes(packed.clone())]);
+
+        let calls = multi_send_calls(&packed);
+        assert_eq!(calls.len(), 2);
+        assert_eq!((calls[1].0.as_str(), calls[1].1.as_str(), calls[1].2.len()), (SAFE, "7", 0));
+        assert!(multi_send_calls(&packed[..packed.len() - 1]).len() == 1, "a truncated tx is dropped");
+
+        // Safe execTransaction(multiSend) => transfer
+        let exec = encode(
+            "execTransaction(address,uint256,bytes,uint8,uint256,uint256,uint256,address,address,bytes)",
+            &[
+                Token::Address(address(SAFE)),
+                Token::Uint(U256::zero()),
+                Token::Bytes(multi_send),
+                Token::Uint(U256::one()),
+                Token::Uint(U256::zero()),
+                Token::Uint(U256::zero()),
+                Token::Uint(U256::zero()),
+                Token::Address(Address::zero()),
+                Token::Address(Address::zero()),
+                Token::Bytes(Vec::new()),
+            ],
+        );
+        let call = decode(&exec).await;
+        assert_eq!(call.calls[0].call.function, "multiSend");
+        assert_eq!(call.calls[0].call.calls[0].call.args[1].value, "3");
+    }
+
+    #[tokio::test]
+    async fn skips_the_txs_which_cannot_be_decoded() {
+        let tx = |input: &str| -> TransactionInfo {
+            serde_json::from_value(serde_json::json!({
+                "hash": "0x01", "from": SAFE, "to": TOKEN, "value": "0", "input": input, "methodId": "",
+            }))
+            .unwrap()
+        };
+        let mut txs = vec![tx("0xzz"), tx(&format!("0x{}", hex::encode(transfer(4))))];
+        decoder().decode_transactions(&mut txs).await.unwrap();
+        assert!(txs[0].decoded.is_none());
+        assert_eq!(txs[1].decoded.as_ref().map(|call| call.function.as_str()), Some("transfer"));
+    }
+
+    #[test]
+    fn formats_the_signed_integers() {
+        assert_eq!(format_token(&Token::Int(I256::from(-5).into_raw())), "-5");
+        assert_eq!(format_token(&Token::Tuple(vec![Token::Bool(true), Token::String(String::from("a"))])), "(true, \"a\")");
+    }
+}
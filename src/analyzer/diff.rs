@@ -0,0 +1,817 @@
+use std::{collections::{BTreeMap, HashMap}, sync::LazyLock};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use crate::analyzer::scanner::strip_comments;
+use crate::error::{Error, Result};
+use crate::utils::{
+    etherscan,
+    output::Record,
+    source::{self, ContractSource},
+    tools,
+};
+
+/// The lines of context around each hunk of the unified diff
+pub const DEFAULT_CONTEXT: usize = 3;
+
+/// The largest edit distance the diff computes, larger changes are shown as a whole replacement
+const MAX_EDITS: usize = 4000;
+
+/// @dev How something changed between the two versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// @dev The unified diff of one source file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub change: Change,
+    pub diff: String,
+}
+
+/// @dev An added, removed or changed function
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelectorChange {
+    pub selector: String,
+    pub signature: String,
+    pub change: Change,
+    pub old: String, // The state mutability and the outputs, empty if added
+    pub new: String, // The state mutability and the outputs, empty if removed
+}
+
+impl Record for SelectorChange {
+    fn headers() -> Vec<&'static str> {
+        vec!["change", "selector", "signature", "old", "new"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            format!("{:?}", self.change).to_lowercase(),
+            self.selector.clone(),
+            self.signature.clone(),
+            self.old.clone(),
+            self.new.clone(),
+        ]
+    }
+}
+
+/// @dev A state variable, in storage order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateVariable {
+    pub contract: String,
+    pub name: String,
+    pub type_name: String,
+}
+
+/// @dev How a storage layout change affects the existing state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageChangeKind {
+    /// Appended after the old variables, safe
+    Appended,
+    /// Same type under another name, safe
+    Renamed,
+    /// Another type in the same position, the old value is read as the new type
+    TypeChanged,
+    /// Inserted before the old variables, the following variables are shifted
+    Inserted,
+    /// Removed, the following variables are shifted
+    Removed,
+}
+
+/// @dev A change of the storage layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageChange {
+    pub kind: StorageChangeKind,
+    pub position: usize, // The index in the new layout, or in the old layout if removed
+    pub old: Option<StateVariable>,
+    pub new: Option<StateVariable>,
+}
+
+impl StorageChange {
+    /// @return True if the change can corrupt the existing state
+    pub fn is_dangerous(&self) -> bool {
+        !matches!(self.kind, StorageChangeKind::Appended | StorageChangeKind::Renamed)
+    }
+}
+
+impl Record for StorageChange {
+    fn headers() -> Vec<&'static str> {
+        vec!["kind", "position", "old", "new", "dangerous"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let variable = |variable: &Option<StateVariable>| {
+            variable
+                .as_ref()
+                .map(|v| format!("{}.{}: {}", v.contract, v.name, v.type_name))
+                .unwrap_or_default()
+        };
+        vec![
+            format!("{:?}", self.kind).to_lowercase(),
+            self.position.to_string(),
+            variable(&self.old),
+            variable(&self.new),
+            self.is_dangerous().to_string(),
+        ]
+    }
+}
+
+/// @dev What changed between two implementations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContractDiff {
+    pub old: String,
+    pub new: String,
+    pub files: Vec<FileDiff>,
+    pub selectors: Vec<SelectorChange>,
+    pub storage: Vec<StorageChange>,
+}
+
+impl ContractDiff {
+    /// @return True if no storage layout change can corrupt the existing state
+    pub fn is_storage_safe(&self) -> bool {
+        !self.storage.iter().any(|change| change.is_dangerous())
+    }
+
+    /// @return The unified diffs of all files
+    pub fn patch(&self) -> String {
+        self.files.iter().map(|file| file.diff.as_str()).collect()
+    }
+}
+
+/// @dev Compare the verified sources of two implementations
+/// @param api_key ETHERSCAN API KEY
+/// @param old The old implementation
+/// @param new The new implementation
+/// @param context The lines of context of the unified diff
+pub async fn diff_implementations(api_key: &str, old: &str, new: &str, context: usize) -> Result<ContractDiff> {
+    let old_source = source::fetch_contract_source(api_key, old).await?;
+    let new_source = source::fetch_contract_source(api_key, new).await?;
+
+    Ok(diff_sources(&old_source, &new_source, context))
+}
+
+/// @dev Find the implementations before and after an `Upgraded(address)` event of a proxy
+/// @param api_key ETHERSCAN API KEY
+/// @param proxy The proxy address
+/// @param tx_hash The upgrade transaction, None for the latest upgrade
+/// @return The old and the new implementation
+pub async fn find_upgrade(api_key: &str, proxy: &str, tx_hash: Option<&str>) -> Result<(String, String)> {
+    let url = format!("https://api.etherscan.io/api?module=logs&action=getLogs&fromBlock=0&toBlock=latest&address={}&topic0={}&apikey={}",
+        proxy,
+        tools::event_topic("Upgraded(address)"),
+        api_key
+    );
+
+    let result = etherscan::get_result(&url).await?;
+    let logs = result
+        .as_array()
+        .ok_or_else(|| Error::Decode(format!("Expected an array of logs, got: {}", result)))?;
+
+    let implementations: Vec<(String, String)> = logs
+        .iter()
+        .filter_map(|log| {
+            let topic = log["topics"].get(1)?.as_str()?;
+            let hash = log["transactionHash"].as_str()?;
+            let implementation = format!("0x{}", topic.get(topic.len().checked_sub(40)?..)?);
+            Some((hash.to_lowercase(), implementation))
+        })
+        .collect();
+
+    let index = match tx_hash {
+        Some(hash) => implementations
+            .iter()
+            .position(|(log_hash, _)| log_hash.eq_ignore_ascii_case(hash))
+            .ok_or_else(|| Error::InvalidInput(format!("No `Upgraded` event of {} in transaction {}", proxy, hash)))?,
+        None => implementations
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| Error::InvalidInput(format!("No `Upgraded` event of {}", proxy)))?,
+    };
+    if index == 0 {
+        return Err(Error::InvalidInput(format!("The first upgrade of {} has no previous implementation", proxy)));
+    }
+
+    Ok((implementations[index - 1].1.clone(), implementations[index].1.clone()))
+}
+
+/// @dev Compare two verified contracts: the files, the function selectors and the storage layout
+/// @param old The old version
+/// @param new The new version
+/// @param context The lines of context of the unified diff
+pub fn diff_sources(old: &ContractSource, new: &ContractSource, context: usize) -> ContractDiff {
+    ContractDiff {
+        old: old.metadata.address.clone(),
+        new: new.metadata.address.clone(),
+        files: diff_files(old, new, context),
+        selectors: diff_selectors(&old.metadata.abi, &new.metadata.abi),
+        storage: diff_storage(&storage_layout(old), &storage_layout(new)),
+    }
+}
+
+/// @dev Pair the files by path. A flattened single file is compared with the other single file even if the contract was renamed
+fn diff_files(old: &ContractSource, new: &ContractSource, context: usize) -> Vec<FileDiff> {
+    let mut files = Vec::new();
+
+    if old.files.len() == 1 && new.files.len() == 1 {
+        let (old_file, new_file) = (&old.files[0], &new.files[0]);
+        if old_file.content != new_file.content {
+            files.push(FileDiff {
+                path: new_file.path.clone(),
+                change: Change::Changed,
+                diff: unified_diff(&old_file.path, &new_file.path, &old_file.content, &new_file.content, context),
+            });
+        }
+        return files;
+    }
+
+    let old_files: BTreeMap<&str, &str> = old.files.iter().map(|f| (f.path.as_str(), f.content.as_str())).collect();
+    let new_files: BTreeMap<&str, &str> = new.files.iter().map(|f| (f.path.as_str(), f.content.as_str())).collect();
+
+    for (path, old_content) in &old_files {
+        match new_files.get(path) {
+            Some(new_content) if new_content == old_content => {}
+            Some(new_content) => files.push(FileDiff {
+                path: path.to_string(),
+                change: Change::Changed,
+                diff: unified_diff(path, path, old_content, new_content, context),
+            }),
+            None => files.push(FileDiff {
+                path: path.to_string(),
+                change: Change::Removed,
+                diff: unified_diff(path, "/dev/null", old_content, "", context),
+            }),
+        }
+    }
+    for (path, new_content) in &new_files {
+        if !old_files.contains_key(path) {
+            files.push(FileDiff {
+                path: path.to_string(),
+                change: Change::Added,
+                diff: unified_diff("/dev/null", path, "", new_content, context),
+            });
+        }
+    }
+
+    files
+}
+
+/// @dev Compare the functions of two ABIs. A function is changed if its state mutability or outputs changed
+fn diff_selectors(old: &serde_json::Value, new: &serde_json::Value) -> Vec<SelectorChange> {
+    let old_functions = abi_functions(old);
+    let new_functions = abi_functions(new);
+    let mut changes = Vec::new();
+
+    for (signature, old_detail) in &old_functions {
+        let change = match new_functions.get(signature) {
+            Some(new_detail) if new_detail == old_detail => continue,
+            Some(_) => Change::Changed,
+            None => Change::Removed,
+        };
+        changes.push(SelectorChange {
+            selector: tools::function_sig(signature),
+            signature: signature.clone(),
+            change,
+            old: old_detail.clone(),
+            new: new_functions.get(signature).cloned().unwrap_or_default(),
+        });
+    }
+    for (signature, new_detail) in &new_functions {
+        if !old_functions.contains_key(signature) {
+            changes.push(SelectorChange {
+                selector: tools::function_sig(signature),
+                signature: signature.clone(),
+                change: Change::Added,
+                old: String::new(),
+                new: new_detail.clone(),
+            });
+        }
+    }
+
+    changes
+}
+
+/// @return The signature of every function, and its state mutability and outputs
+fn abi_functions(abi: &serde_json::Value) -> BTreeMap<String, String> {
+    abi.as_array()
+        .into_iter()
+        .flatten()
+        .filter(|item| item["type"] == "function")
+        .filter_map(|item| {
+            let signature = tools::abi_signature(item)?;
+            let outputs: Vec<&str> = item["outputs"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|output| output["type"].as_str())
+                .collect();
+            let mutability = item["stateMutability"].as_str().unwrap_or("nonpayable");
+            Some((signature, format!("{} returns ({})", mutability, outputs.join(","))))
+        })
+        .collect()
+}
+
+/// @dev Compare two storage layouts
+fn diff_storage(old: &[StateVariable], new: &[StateVariable]) -> Vec<StorageChange> {
+    let key = |v: &StateVariable| format!("{} {}", v.type_name, v.name);
+    let old_keys: Vec<String> = old.iter().map(key).collect();
+    let new_keys: Vec<String> = new.iter().map(key).collect();
+    let edits = diff(&old_keys, &new_keys);
+
+    let mut changes = Vec::new();
+    let mut index = 0;
+    while index < edits.len() {
+        if let Edit::Equal(..) = edits[index] {
+            index += 1;
+            continue;
+        }
+
+        // A run of changes between two unchanged variables
+        let end = edits[index..]
+            .iter()
+            .position(|edit| matches!(edit, Edit::Equal(..)))
+            .map_or(edits.len(), |length| index + length);
+        let deleted: Vec<usize> = edits[index..end].iter().filter_map(|e| if let Edit::Delete(i) = e { Some(*i) } else { None }).collect();
+        let inserted: Vec<usize> = edits[index..end].iter().filter_map(|e| if let Edit::Insert(j) = e { Some(*j) } else { None }).collect();
+
+        // The variables replaced in place
+        for (&i, &j) in deleted.iter().zip(&inserted) {
+            let kind = if old[i].type_name == new[j].type_name {
+                StorageChangeKind::Renamed
+            } else {
+                StorageChangeKind::TypeChanged
+            };
+            changes.push(StorageChange { kind, position: j, old: Some(old[i].clone()), new: Some(new[j].clone()) });
+        }
+        for &i in deleted.iter().skip(inserted.len()) {
+            changes.push(StorageChange { kind: StorageChangeKind::Removed, position: i, old: Some(old[i].clone()), new: None });
+        }
+        // Only the variables after all old variables keep the layout
+        let kind = if end == edits.len() { StorageChangeKind::Appended } else { StorageChangeKind::Inserted };
+        for &j in inserted.iter().skip(deleted.len()) {
+            changes.push(StorageChange { kind, position: j, old: None, new: Some(new[j].clone()) });
+        }
+
+        index = end;
+    }
+
+    changes
+}
+
+/// @dev The state variables of the main contract in storage order: from the most base contract to the most derived.
+/// @notice Parsed from the source, the packing of variables into slots is not computed
+pub fn storage_layout(source: &ContractSource) -> Vec<StateVariable> {
+    let contracts: HashMap<String, ContractDecl> = source
+        .files
+        .iter()
+        .flat_map(|file| parse_contracts(&strip_comments(&file.content).join("\n")))
+        .map(|contract| (contract.name.clone(), contract))
+        .collect();
+
+    let linearization = linearize(&source.metadata.contract_name, &contracts, 0);
+    linearization
+        .iter()
+        .rev()
+        .filter_map(|name| contracts.get(name))
+        .flat_map(|contract| contract.variables.clone())
+        .collect()
+}
+
+/// `contract A is B, C(1) {` and the statements of a contract body which are not state variables
+static CONTRACT_HEADER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(contract|library|interface)\s+(\w+)\s*(\bis\b(.*))?$").unwrap());
+static NON_VARIABLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(function|modifier|event|error|using|struct|enum|constructor|fallback|receive|type)\b").unwrap());
+static CONSTANT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(constant|immutable)\b").unwrap());
+
+/// The visibility and override modifiers of a state variable, and the `uint` / `int` aliases
+static VARIABLE_MODIFIERS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(public|private|internal|transient|override(\s*\([^)]*\))?)\b").unwrap());
+static INT_ALIASES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(u?int)\b").unwrap());
+
+/// @dev A contract found in a source file
+struct ContractDecl {
+    name: String,
+    bases: Vec<String>,
+    variables: Vec<StateVariable>,
+}
+
+/// @dev Find the contracts, their bases and their state variables
+fn parse_contracts(text: &str) -> Vec<ContractDecl> {
+    let mut contracts = Vec::new();
+    let mut current: Option<ContractDecl> = None;
+    let mut statement = String::new();
+    let mut depth = 0usize;
+
+    for c in text.chars() {
+        match c {
+            '{' => {
+                if depth == 0 {
+                    let header_text = statement.split_whitespace().collect::<Vec<&str>>().join(" ");
+                    current = CONTRACT_HEADER.captures(&header_text).map(|captures| ContractDecl {
+                        name: captures[2].to_string(),
+                        bases: captures.get(4).map(|bases| parse_bases(bases.as_str())).unwrap_or_default(),
+                        variables: Vec::new(),
+                    });
+                }
+                depth += 1;
+                statement.clear();
+            }
+            '}' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    contracts.extend(current.take());
+                }
+                statement.clear();
+            }
+            ';' => {
+                if depth == 1 {
+                    let text = statement.trim();
+                    if let Some(contract) = current.as_mut() {
+                        if !NON_VARIABLE.is_match(text) && !CONSTANT.is_match(text) {
+                            if let Some((name, type_name)) = parse_variable(text) {
+                                contract.variables.push(StateVariable { contract: contract.name.clone(), name, type_name });
+                            }
+                        }
+                    }
+                }
+                statement.clear();
+            }
+            _ => statement.push(c),
+        }
+    }
+
+    contracts
+}
+
+/// @dev `A, B(1), C` => [A, B, C]
+fn parse_bases(bases: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut depth = 0usize;
+    let mut name = String::new();
+
+    for c in bases.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => names.push(std::mem::take(&mut name)),
+            _ if depth == 0 => name.push(c),
+            _ => {}
+        }
+    }
+    names.push(name);
+
+    names
+        .into_iter()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// @dev `mapping(address => uint256) public balances = ...` => (balances, mapping(address=>uint256))
+fn parse_variable(statement: &str) -> Option<(String, String)> {
+    let declaration = match statement.find('=').filter(|&i| !statement[i + 1..].starts_with('>')) {
+        Some(index) => &statement[..index],
+        None => statement,
+    };
+
+    let declaration = VARIABLE_MODIFIERS.replace_all(declaration, " ");
+    let mut tokens: Vec<&str> = declaration.split_whitespace().collect();
+    let name = tokens.pop()?;
+    if tokens.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    let type_name = tokens
+        .join(" ")
+        .replace("( ", "(")
+        .replace(" )", ")")
+        .replace(" =>", "=>")
+        .replace("=> ", "=>")
+        .replace(" [", "[");
+    let type_name = INT_ALIASES.replace_all(&type_name, "${1}256").to_string();
+
+    Some((name.to_string(), type_name))
+}
+
+/// @dev The C3 linearization of a contract, from the most derived to the most base.
+/// Falls back to a depth-first order if the bases cannot be linearized
+fn linearize(name: &str, contracts: &HashMap<String, ContractDecl>, depth: usize) -> Vec<String> {
+    let bases = match contracts.get(name) {
+        Some(contract) if depth < 32 => &contract.bases,
+        _ => return vec![name.to_string()],
+    };
+
+    // Solidity lists the bases from the most base to the most derived
+    let mut sequences: Vec<Vec<String>> = bases.iter().rev().map(|base| linearize(base, contracts, depth + 1)).collect();
+    sequences.push(bases.iter().rev().cloned().collect());
+
+    let mut result = vec![name.to_string()];
+    loop {
+        sequences.retain(|sequence| !sequence.is_empty());
+        if sequences.is_empty() {
+            return result;
+        }
+
+        let head = sequences
+            .iter()
+            .map(|sequence| &sequence[0])
+            .find(|candidate| !sequences.iter().any(|sequence| sequence[1..].contains(candidate)))
+            .cloned();
+        let Some(head) = head else {
+            for sequence in sequences {
+                for base in sequence {
+                    if !result.contains(&base) {
+                        result.push(base);
+                    }
+                }
+            }
+            return result;
+        };
+
+        for sequence in sequences.iter_mut() {
+            if sequence[0] == head {
+                sequence.remove(0);
+            }
+        }
+        result.push(head);
+    }
+}
+
+/// @dev One step of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// @dev The shortest edit script between two sequences (Myers' algorithm)
+fn diff<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    // The furthest x of every diagonal k in -(d+1)..=(d+1) before step d
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    for d in 0..=max.min(MAX_EDITS) as isize {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize]) {
+                v[(offset + k + 1) as usize]
+            } else {
+                v[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(offset + k) as usize] = x;
+
+            if x >= n && y >= m {
+                return backtrack(&trace, n, m);
+            }
+        }
+    }
+
+    // Too many changes, replace the whole sequence
+    (0..old.len()).map(Edit::Delete).chain((0..new.len()).map(Edit::Insert)).collect()
+}
+
+fn backtrack(trace: &[Vec<isize>], n: isize, m: isize) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal((x - 1) as usize, (y - 1) as usize));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert((y - 1) as usize));
+            } else {
+                edits.push(Edit::Delete((x - 1) as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+
+    edits.reverse();
+    edits
+}
+
+/// @dev A unified diff of two texts, like `diff -u`
+/// @param old_path The old file name in the header
+/// @param new_path The new file name in the header
+/// @param context The lines of context around each hunk
+pub fn unified_diff(old_path: &str, new_path: &str, old: &str, new: &str, context: usize) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let edits = diff(&old_lines, &new_lines);
+
+    // The old and new line index before each edit
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for edit in &edits {
+        positions.push((i, j));
+        match edit {
+            Edit::Equal(..) => {
+                i += 1;
+                j += 1;
+            }
+            Edit::Delete(_) => i += 1,
+            Edit::Insert(_) => j += 1,
+        }
+    }
+    positions.push((i, j));
+
+    // Group the changes which are close to each other into hunks
+    let changed: Vec<usize> = (0..edits.len()).filter(|&index| !matches!(edits[index], Edit::Equal(..))).collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for index in changed {
+        let start = index.saturating_sub(context);
+        let end = (index + context + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let header = |path: &str, prefix: &str| if path == "/dev/null" { path.to_string() } else { format!("{}/{}", prefix, path) };
+    let mut result = format!("--- {}\n+++ {}\n", header(old_path, "a"), header(new_path, "b"));
+    for (start, end) in hunks {
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        let range = |start: usize, count: usize| if count == 0 { format!("{},0", start) } else { format!("{},{}", start + 1, count) };
+        result.push_str(&format!("@@ -{} +{} @@\n", range(old_start, old_end - old_start), range(new_start, new_end - new_start)));
+
+        for edit in &edits[start..end] {
+            let line = match edit {
+                Edit::Equal(i, _) => format!(" {}", old_lines[*i]),
+                Edit::Delete(i) => format!("-{}", old_lines[*i]),
+                Edit::Insert(j) => format!("+{}", new_lines[*j]),
+            };
+            result.push_str(&line);
+            result.push('\n');
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    /// @dev Rebuild the new sequence from the old one and the edits
+    fn apply(old: &[char], new: &[char], edits: &[Edit]) -> Vec<char> {
+        edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Equal(i, j) => {
+                    assert_eq!(old[*i], new[*j]);
+                    Some(old[*i])
+                }
+                Edit::Delete(_) => None,
+                Edit::Insert(j) => Some(new[*j]),
+            })
+            .collect()
+    }
+
+    fn source(contract_name: &str, code: &str) -> ContractSource {
+        let detail = json!({"SourceCode": code, "ContractName": contract_name, "CompilerVersion": "v0.8.20"});
+        source::parse_contract_source("0x0000000000000000000000000000000000000001", &detail).unwrap()
+    }
+
+    fn variable(contract: &str, name: &str, type_name: &str) -> StateVariable {
+        StateVariable { contract: contract.to_string(), name: name.to_string(), type_name: type_name.to_string() }
+    }
+
+    #[test]
+    fn finds_the_shortest_edit_script() {
+        // The example of Myers' paper, 5 edits
+        let (old, new) = (chars("ABCABBA"), chars("CBABAC"));
+        let edits = diff(&old, &new);
+        assert_eq!(edits.iter().filter(|edit| !matches!(edit, Edit::Equal(..))).count(), 5);
+        assert_eq!(apply(&old, &new, &edits), new);
+
+        for (old, new) in [("", "abc"), ("abc", ""), ("same", "same"), ("kitten", "sitting")] {
+            let (old, new) = (chars(old), chars(new));
+            assert_eq!(apply(&old, &new, &diff(&old, &new)), new);
+        }
+    }
+
+    #[test]
+    fn writes_a_unified_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let new = "a\nb\nc\nD\ne\nf\ng\nh\ni\n";
+        let patch = unified_diff("A.sol", "A.sol", old, new, 1);
+        assert_eq!(patch, "--- a/A.sol\n+++ b/A.sol\n@@ -3,3 +3,3 @@\n c\n-d\n+D\n e\n@@ -8,1 +8,2 @@\n h\n+i\n");
+
+        assert_eq!(unified_diff("A.sol", "A.sol", old, old, 3), "");
+        assert!(unified_diff("/dev/null", "B.sol", "", "x\n", 3).starts_with("--- /dev/null\n+++ b/B.sol\n@@ -0,0 +1,1 @@\n"));
+    }
+
+    #[test]
+    fn linearizes_like_solc() {
+        // contract D is B, C: D, C, B, A
+        let code = "contract A {} contract B is A {} contract C is A {} contract D is B, C {}";
+        let contracts: HashMap<String, ContractDecl> = parse_contracts(code).into_iter().map(|c| (c.name.clone(), c)).collect();
+        assert_eq!(linearize("D", &contracts, 0), vec!["D", "C", "B", "A"]);
+        assert_eq!(linearize("Unknown", &contracts, 0), vec!["Unknown"]);
+    }
+
+    #[test]
+    fn lays_out_the_storage_from_the_most_base_contract() {
+        let code = "
+            abstract contract Ownable { address private _owner; }
+            contract Token is Ownable {
+                uint256 public constant DECIMALS = 18;
+                mapping(address => uint) public balances;
+                event Transfer(address from, address to);
+                function f() external { uint256 local = 1; }
+            }
+            contract Vault is Ownable(msg.sender), Token {
+                uint[] internal shares;
+                bool paused = false;
+            }";
+        let layout = storage_layout(&source("Vault", code));
+        assert_eq!(
+            layout,
+            vec![
+                variable("Ownable", "_owner", "address"),
+                variable("Token", "balances", "mapping(address=>uint256)"),
+                variable("Vault", "shares", "uint256[]"),
+                variable("Vault", "paused", "bool"),
+            ]
+        );
+    }
+
+    #[test]
+    fn classifies_the_storage_changes() {
+        let old = vec![variable("V", "owner", "address"), variable("V", "fee", "uint256"), variable("V", "paused", "bool")];
+
+        let appended = diff_storage(&old, &[old.clone(), vec![variable("V", "limit", "uint256")]].concat());
+        assert_eq!(appended.len(), 1);
+        assert_eq!(appended[0].kind, StorageChangeKind::Appended);
+        assert!(!appended[0].is_dangerous());
+
+        let renamed = diff_storage(&old, &[variable("V", "owner", "address"), variable("V", "feeBps", "uint256"), variable("V", "paused", "bool")]);
+        assert_eq!(renamed.iter().map(|c| c.kind).collect::<Vec<_>>(), vec![StorageChangeKind::Renamed]);
+
+        let retyped = diff_storage(&old, &[variable("V", "owner", "address"), variable("V", "fee", "uint128"), variable("V", "paused", "bool")]);
+        assert_eq!(retyped.iter().map(|c| c.kind).collect::<Vec<_>>(), vec![StorageChangeKind::TypeChanged]);
+
+        let inserted = diff_storage(&old, &[variable("V", "admin", "address"), old[0].clone(), old[1].clone(), old[2].clone()]);
+        assert_eq!(inserted.iter().map(|c| c.kind).collect::<Vec<_>>(), vec![StorageChangeKind::Inserted]);
+        assert!(inserted[0].is_dangerous());
+
+        let removed = diff_storage(&old, &[old[0].clone(), old[2].clone()]);
+        assert_eq!(removed.iter().map(|c| (c.kind, c.position)).collect::<Vec<_>>(), vec![(StorageChangeKind::Removed, 1)]);
+    }
+
+    #[test]
+    fn compares_the_abi_functions() {
+        let old = json!([
+            {"type": "function", "name": "deposit", "inputs": [], "outputs": [], "stateMutability": "payable"},
+            {"type": "function", "name": "pause", "inputs": [], "outputs": [], "stateMutability": "nonpayable"},
+        ]);
+        let new = json!([
+            {"type": "function", "name": "deposit", "inputs": [], "outputs": [{"type": "uint256"}], "stateMutability": "payable"},
+            {"type": "function", "name": "sweep", "inputs": [{"type": "address"}], "outputs": [], "stateMutability": "nonpayable"},
+        ]);
+        let mut changes: Vec<(String, Change)> = diff_selectors(&old, &new).into_iter().map(|c| (c.signature, c.change)).collect();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            changes,
+            vec![
+                (String::from("deposit()"), Change::Changed),
+                (String::from("pause()"), Change::Removed),
+                (String::from("sweep(address)"), Change::Added),
+            ]
+        );
+    }
+}
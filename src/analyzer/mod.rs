@@ -0,0 +1,9 @@
+pub mod scanner;
+pub mod diff;
+pub mod decoder;
+pub mod bytecode;
+pub mod trace;
+pub mod simulate;
+pub mod report;
+pub mod profile;
+pub mod approvals;
\ No newline at end of file
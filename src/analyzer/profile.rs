@@ -0,0 +1,560 @@
+use std::{collections::{HashMap, HashSet}, fmt, time::{SystemTime, UNIX_EPOCH}};
+use ethers::types::I256;
+use serde::{Deserialize, Serialize};
+use crate::analyzer::trace::{format_amount, label};
+use crate::error::{Error, Result};
+use crate::listener::fetcher::{Fetch, TransactionInfo, LATEST_BLOCK, MAX_RESULTS};
+use crate::utils::{address_db, output::Record, source, tools::format_time};
+
+/// How many funders are followed back from the address
+pub const MAX_FUNDING_HOPS: usize = 3;
+
+/// How many counterparties are kept
+const TOP_COUNTERPARTIES: usize = 10;
+
+/// An address younger than this is new, in seconds
+const NEW_ADDRESS_AGE: u64 = 7 * 86_400;
+
+/// @dev How many txs of each kind an address has, in or out
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TxCounts {
+    pub normal_in: usize,
+    pub normal_out: usize,
+    pub internal_in: usize,
+    pub internal_out: usize,
+    pub token_in: usize,
+    pub token_out: usize,
+    pub created: usize, // The contracts it deployed
+    pub failed: usize, // Its reverted normal txs
+}
+
+/// @dev An address the profiled one sent to or received from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Counterparty {
+    pub address: String,
+    pub label: String, // The label in db
+    pub txs_in: usize, // Received from it
+    pub txs_out: usize, // Sent to it
+}
+
+impl Record for Counterparty {
+    fn headers() -> Vec<&'static str> {
+        vec!["address", "label", "txs", "txs_in", "txs_out"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.address.clone(),
+            self.label.clone(),
+            (self.txs_in + self.txs_out).to_string(),
+            self.txs_in.to_string(),
+            self.txs_out.to_string(),
+        ]
+    }
+}
+
+/// @dev A token held by the address, the net of its fetched transfers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenHolding {
+    pub token: String,
+    pub symbol: String,
+    pub balance: String, // In the smallest unit
+    pub decimals: Option<u32>,
+    pub transfers: usize,
+}
+
+impl TokenHolding {
+    /// @return The balance in whole units
+    pub fn amount(&self) -> String {
+        format_amount(&self.balance, self.decimals)
+    }
+}
+
+/// @dev One step of the funding chain: who first sent ether to the previous address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FundingHop {
+    pub depth: usize, // 1 for the funder of the address, 2 for the funder of the funder...
+    pub from: String,
+    pub label: String, // The label of `from` in db
+    pub hash: String,
+    pub blockNumber: u64,
+    pub timeStamp: u64,
+    pub amount: String, // In ETH
+    pub mixer: bool, // True if `from` is a mixing service in db
+}
+
+/// @dev What is known about an address, printed as one view or JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressProfile {
+    pub address: String,
+    pub label: String, // The label in db
+    pub contract: bool,
+    pub verified: Option<bool>, // None for an EOA
+    pub contract_name: String, // Empty if unverified
+    pub deployer: Option<String>,
+    pub deployer_label: String,
+    pub creation_tx: Option<String>,
+    pub balance: String, // In ETH
+    pub first_activity: Option<(u64, String)>, // The time and the tx
+    pub last_activity: Option<(u64, String)>, // The time and the tx
+    pub counts: TxCounts,
+    pub counterparties: Vec<Counterparty>, // The most frequent first
+    pub hacker_counterparties: Vec<String>, // All the counterparties labeled as (potential) hackers, not only the top ones
+    pub holdings: Vec<TokenHolding>, // The most transferred first
+    pub funding: Vec<FundingHop>, // The funder first, then its funder...
+    pub mixer_txs: Vec<String>, // The txs with a mixing service
+    pub risk_score: u32, // 0 - 100
+    pub risk_factors: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+impl AddressProfile {
+    /// @return "low", "medium" or "high"
+    pub fn risk_level(&self) -> &'static str {
+        match self.risk_score {
+            60.. => "high",
+            30.. => "medium",
+            _ => "low",
+        }
+    }
+}
+
+impl fmt::Display for AddressProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Address: {}{}", self.address, label(&self.label))?;
+        match (self.contract, self.verified) {
+            (false, _) => writeln!(f, "Kind: EOA")?,
+            (true, Some(true)) => writeln!(f, "Kind: contract, verified ({})", self.contract_name)?,
+            (true, _) => writeln!(f, "Kind: contract, unverified")?,
+        }
+        if let Some(deployer) = &self.deployer {
+            writeln!(f, "Deployer: {}{} in tx {}", deployer, label(&self.deployer_label), self.creation_tx.as_deref().unwrap_or_default())?;
+        }
+        writeln!(f, "Balance: {} ETH", self.balance)?;
+        if let Some((time, hash)) = &self.first_activity {
+            writeln!(f, "First activity: {} ({})", format_time(*time), hash)?;
+        }
+        if let Some((time, hash)) = &self.last_activity {
+            writeln!(f, "Last activity: {} ({})", format_time(*time), hash)?;
+        }
+
+        let counts = &self.counts;
+        writeln!(
+            f,
+            "Txs: normal {} in / {} out, internal {} in / {} out, token transfers {} in / {} out, {} contract(s) created, {} failed",
+            counts.normal_in, counts.normal_out, counts.internal_in, counts.internal_out, counts.token_in, counts.token_out, counts.created, counts.failed
+        )?;
+
+        writeln!(f, "\nRisk: {}/100 ({})", self.risk_score, self.risk_level())?;
+        for factor in &self.risk_factors {
+            writeln!(f, "  - {}", factor)?;
+        }
+
+        writeln!(f, "\nFunding chain:")?;
+        if self.funding.is_empty() {
+            writeln!(f, "  unknown, it received no ether")?;
+        }
+        for hop in &self.funding {
+            let mixer = if hop.mixer { " [MIXER]" } else { "" };
+            writeln!(
+                f,
+                "  {}{}. {} ETH from {}{} at {} ({}){}",
+                "  ".repeat(hop.depth - 1), hop.depth, hop.amount, hop.from, label(&hop.label), format_time(hop.timeStamp), hop.hash, mixer
+            )?;
+        }
+
+        if !self.mixer_txs.is_empty() {
+            writeln!(f, "\nMixing service txs:")?;
+            for hash in &self.mixer_txs {
+                writeln!(f, "  {}", hash)?;
+            }
+        }
+
+        if !self.counterparties.is_empty() {
+            writeln!(f, "\nTop counterparties:")?;
+            for counterparty in &self.counterparties {
+                writeln!(
+                    f,
+                    "  {}{} {} txs ({} in, {} out)",
+                    counterparty.address, label(&counterparty.label), counterparty.txs_in + counterparty.txs_out, counterparty.txs_in, counterparty.txs_out
+                )?;
+            }
+        }
+
+        if !self.holdings.is_empty() {
+            writeln!(f, "\nToken holdings (the net of the transfers):")?;
+            for holding in &self.holdings {
+                let symbol = if holding.symbol.is_empty() { holding.token.as_str() } else { holding.symbol.as_str() };
+                writeln!(f, "  {} {} ({})", holding.amount(), symbol, holding.token)?;
+            }
+        }
+
+        for note in &self.notes {
+            writeln!(f, "\nNote: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+/// @dev Profile an address with etherscan: its kind, deployer, activity, counterparties, token holdings,
+/// funding chain, mixer exposure and a risk score
+/// @param api_key Etherscan API kEY
+/// @param address The address
+pub async fn profile_address(api_key: &str, address: &str) -> Result<AddressProfile> {
+    if address.len() != 42 || !address.starts_with("0x") {
+        return Err(Error::InvalidInput(format!("Invalid address {}", address)));
+    }
+    let address = address.to_lowercase();
+    let labels = address_db::get_db_labels()?;
+    let mixers: HashSet<String> = address_db::get_db_address("mixing_service")?
+        .into_iter()
+        .map(|address| address.to_lowercase())
+        .collect();
+    let label_of = |address: &str| labels.get(address).cloned().unwrap_or_default();
+    let fetcher = Fetch::new(api_key.to_string());
+
+    let mut profile = AddressProfile {
+        label: label_of(&address),
+        address: address.clone(),
+        contract: false,
+        verified: None,
+        contract_name: String::new(),
+        deployer: None,
+        deployer_label: String::new(),
+        creation_tx: None,
+        balance: format_amount(&fetcher.fetch_balance(&address).await?, Some(18)),
+        first_activity: None,
+        last_activity: None,
+        counts: TxCounts::default(),
+        counterparties: Vec::new(),
+        hacker_counterparties: Vec::new(),
+        holdings: Vec::new(),
+        funding: Vec::new(),
+        mixer_txs: Vec::new(),
+        risk_score: 0,
+        risk_factors: Vec::new(),
+        notes: Vec::new(),
+    };
+
+    profile.contract = fetcher.fetch_code(&address).await?.len() > 2;
+    if profile.contract {
+        match source::fetch_contract_source(api_key, &address).await {
+            Ok(contract) => {
+                profile.verified = Some(true);
+                profile.contract_name = contract.metadata.contract_name;
+            }
+            Err(Error::Unverified(_)) => profile.verified = Some(false),
+            Err(e) => return Err(e),
+        }
+        if let Some((deployer, hash)) = fetcher.fetch_contract_creation(&address).await? {
+            profile.deployer_label = label_of(&deployer);
+            profile.deployer = Some(deployer);
+            profile.creation_tx = Some(hash);
+        }
+    }
+
+    let normal = fetcher.fetch_address_normal_txs(&address, 0, LATEST_BLOCK).await?;
+    let internal = fetcher.fetch_address_internal_txs(&address, 0, LATEST_BLOCK).await?;
+    let transfers = fetcher.fetch_token_transfers(&address, 0, LATEST_BLOCK).await?;
+    let truncated = [normal.len(), internal.len(), transfers.len()].iter().any(|len| *len >= MAX_RESULTS);
+    if truncated {
+        profile.notes.push(format!(
+            "Etherscan returns at most {} results per kind, only the oldest ones are counted.",
+            MAX_RESULTS
+        ));
+    }
+
+    // The counts and the counterparties
+    let mut counterparties: HashMap<String, (usize, usize)> = HashMap::new();
+    // Count a tx with its counterparty, return true if it is outgoing
+    let count = |from: &str, to: &str, counterparties: &mut HashMap<String, (usize, usize)>| -> bool {
+        let (from, to) = (from.to_lowercase(), to.to_lowercase());
+        let outgoing = from == address;
+        let other = if outgoing { to } else { from };
+        if other.is_empty() || other == address {
+            return outgoing;
+        }
+        let entry = counterparties.entry(other).or_default();
+        if outgoing { entry.1 += 1 } else { entry.0 += 1 }
+        outgoing
+    };
+    for tx in &normal {
+        if count(&tx.from, &tx.to, &mut counterparties) {
+            profile.counts.normal_out += 1;
+        } else {
+            profile.counts.normal_in += 1;
+        }
+        if tx.isError {
+            profile.counts.failed += 1;
+        }
+    }
+    for tx in &internal {
+        if count(&tx.from, &tx.to, &mut counterparties) {
+            profile.counts.internal_out += 1;
+        } else {
+            profile.counts.internal_in += 1;
+        }
+    }
+    for transfer in &transfers {
+        if count(&transfer.from, &transfer.to, &mut counterparties) {
+            profile.counts.token_out += 1;
+        } else {
+            profile.counts.token_in += 1;
+        }
+    }
+    profile.counts.created = normal
+        .iter()
+        .chain(&internal)
+        .filter(|tx| tx.from.eq_ignore_ascii_case(&address) && !tx.contractAddress.is_empty() && !tx.isError)
+        .count();
+
+    let mut counterparties: Vec<Counterparty> = counterparties
+        .into_iter()
+        .map(|(address, (txs_in, txs_out))| Counterparty { label: label_of(&address), address, txs_in, txs_out })
+        .collect();
+    counterparties.sort_by(|a, b| (b.txs_in + b.txs_out).cmp(&(a.txs_in + a.txs_out)).then_with(|| a.address.cmp(&b.address)));
+    profile.hacker_counterparties = counterparties
+        .iter()
+        .filter(|counterparty| counterparty.label.starts_with("hacker") || counterparty.label.starts_with("potential_hacker"))
+        .map(|counterparty| counterparty.address.clone())
+        .collect();
+    counterparties.truncate(TOP_COUNTERPARTIES);
+    profile.counterparties = counterparties;
+
+    // The first and the last activity
+    let times = || {
+        normal
+            .iter()
+            .chain(&internal)
+            .map(|tx| (tx.timeStamp, tx.hash.clone()))
+            .chain(transfers.iter().map(|transfer| (transfer.timeStamp, transfer.hash.clone())))
+    };
+    profile.first_activity = times().min_by_key(|(time, _)| *time);
+    profile.last_activity = times().max_by_key(|(time, _)| *time);
+    if truncated {
+        if let Some(tx) = fetcher.fetch_last_tx(&address).await? {
+            if profile.last_activity.as_ref().is_none_or(|(time, _)| tx.timeStamp > *time) {
+                profile.last_activity = Some((tx.timeStamp, tx.hash));
+            }
+        }
+    }
+
+    // The token holdings
+    let mut holdings: Vec<(TokenHolding, I256)> = Vec::new();
+    for transfer in &transfers {
+        let value = I256::from_dec_str(&transfer.value).unwrap_or_default();
+        let value = if transfer.from.eq_ignore_ascii_case(&address) { -value } else { value };
+        let token = transfer.token.to_lowercase();
+        match holdings.iter_mut().find(|(holding, _)| holding.token == token) {
+            Some((holding, balance)) => {
+                *balance = balance.saturating_add(value);
+                holding.transfers += 1;
+            }
+            None => holdings.push((
+                TokenHolding {
+                    token,
+                    symbol: transfer.tokenSymbol.clone(),
+                    balance: String::new(),
+                    decimals: transfer.tokenDecimal,
+                    transfers: 1,
+                },
+                value,
+            )),
+        }
+    }
+    profile.holdings = holdings
+        .into_iter()
+        .filter(|(_, balance)| balance.is_positive())
+        .map(|(holding, balance)| TokenHolding { balance: balance.to_string(), ..holding })
+        .collect();
+    profile.holdings.sort_by_key(|holding| std::cmp::Reverse(holding.transfers));
+
+    // The mixer exposure and the funding chain
+    profile.mixer_txs = mixer_txs(normal.iter().chain(&internal), &mixers);
+    let mut funded = address.clone();
+    for depth in 1..=MAX_FUNDING_HOPS {
+        let Some(tx) = fetcher.fetch_first_funding(&funded, LATEST_BLOCK).await? else { break };
+        let from = tx.from.to_lowercase();
+        let hop = FundingHop {
+            depth,
+            label: label_of(&from),
+            mixer: mixers.contains(&from),
+            hash: tx.hash,
+            blockNumber: tx.blockNumber,
+            timeStamp: tx.timeStamp,
+            amount: format_amount(&tx.value, Some(18)),
+            from: from.clone(),
+        };
+        // A known address (e.g. a mixer or an exchange) ends the chain
+        let known = !hop.label.is_empty() || from == address || profile.funding.iter().any(|hop| hop.from == from);
+        profile.funding.push(hop);
+        if known {
+            break;
+        }
+        funded = from;
+    }
+
+    score(&mut profile);
+    Ok(profile)
+}
+
+/// @dev The hashes of the txs with a mixing service, without duplicates
+fn mixer_txs<'a>(txs: impl Iterator<Item = &'a TransactionInfo>, mixers: &HashSet<String>) -> Vec<String> {
+    let mut hashes: Vec<String> = Vec::new();
+    for tx in txs {
+        if (mixers.contains(&tx.from.to_lowercase()) || mixers.contains(&tx.to.to_lowercase())) && !hashes.contains(&tx.hash) {
+            hashes.push(tx.hash.clone());
+        }
+    }
+    hashes
+}
+
+/// @dev Score the risk of the profile from 0 to 100, and explain each point
+fn score(profile: &mut AddressProfile) {
+    let mut factors: Vec<(u32, String)> = Vec::new();
+
+    if profile.label.starts_with("hacker") {
+        factors.push((60, String::from("labeled as a hacker in db")));
+    } else if profile.label.starts_with("potential_hacker") {
+        factors.push((40, String::from("labeled as a potential hacker in db")));
+    } else if profile.label.starts_with("mixing_service") {
+        factors.push((40, String::from("it is a mixing service")));
+    }
+
+    if let Some(hop) = profile.funding.iter().find(|hop| hop.mixer) {
+        let points = if hop.depth == 1 { 30 } else { 15 };
+        factors.push((points, format!("funded through a mixing service, {} hop(s) away", hop.depth)));
+    }
+    if !profile.mixer_txs.is_empty() {
+        factors.push((20, format!("{} tx(s) with a mixing service", profile.mixer_txs.len())));
+    }
+
+    if !profile.hacker_counterparties.is_empty() {
+        factors.push((15, format!("interacted with (potential) hackers: {}", profile.hacker_counterparties.join(", "))));
+    }
+
+    if profile.deployer_label.starts_with("hacker") || profile.deployer_label.starts_with("potential_hacker") {
+        factors.push((30, format!("deployed by a {}", profile.deployer_label)));
+    }
+    if profile.verified == Some(false) {
+        factors.push((10, String::from("unverified contract")));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    if let Some((first, _)) = profile.first_activity {
+        if now.saturating_sub(first) < NEW_ADDRESS_AGE {
+            factors.push((10, format!("new address, first active {}", format_time(first))));
+        }
+    }
+
+    profile.risk_score = factors.iter().map(|(points, _)| points).sum::<u32>().min(100);
+    profile.risk_factors = factors.into_iter().map(|(points, factor)| format!("+{} {}", points, factor)).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> AddressProfile {
+        AddressProfile {
+            address: String::from("0x00000000000000000000000000000000000000aa"),
+            label: String::new(),
+            contract: false,
+            verified: None,
+            contract_name: String::new(),
+            deployer: None,
+            deployer_label: String::new(),
+            creation_tx: None,
+            balance: String::from("0"),
+            first_activity: Some((1_600_000_000, String::from("0x01"))),
+            last_activity: None,
+            counts: TxCounts::default(),
+            counterparties: Vec::new(),
+            hacker_counterparties: Vec::new(),
+            holdings: Vec::new(),
+            funding: Vec::new(),
+            mixer_txs: Vec::new(),
+            risk_score: 0,
+            risk_factors: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    fn hop(depth: usize, mixer: bool) -> FundingHop {
+        FundingHop {
+            depth,
+            from: String::from("0x00000000000000000000000000000000000000dd"),
+            label: String::new(),
+            hash: String::from("0x02"),
+            blockNumber: 1,
+            timeStamp: 0,
+            amount: String::from("1"),
+            mixer,
+        }
+    }
+
+    fn tx(hash: &str, from: &str, to: &str) -> TransactionInfo {
+        serde_json::from_value(serde_json::json!({
+            "hash": hash, "from": from, "to": to, "value": "0", "input": "0x", "methodId": "0x",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn an_old_unlabeled_address_is_low_risk() {
+        let mut profile = profile();
+        score(&mut profile);
+        assert_eq!((profile.risk_score, profile.risk_level()), (0, "low"));
+        assert!(profile.risk_factors.is_empty());
+    }
+
+    #[test]
+    fn scores_the_mixer_funding_by_its_distance() {
+        let mut profile = profile();
+        profile.funding = vec![hop(1, false), hop(2, true)];
+        score(&mut profile);
+        assert_eq!(profile.risk_factors, vec!["+15 funded through a mixing service, 2 hop(s) away"]);
+
+        profile.funding = vec![hop(1, true)];
+        profile.mixer_txs = vec![String::from("0x03")];
+        score(&mut profile);
+        assert_eq!((profile.risk_score, profile.risk_level()), (50, "medium"));
+    }
+
+    #[test]
+    fn counts_the_hackers_beyond_the_top_counterparties() {
+        let mut profile = profile();
+        // Not among the top counterparties, still a risk
+        profile.hacker_counterparties = vec![String::from("0x00000000000000000000000000000000000000ee (hacker: Euler Exploiter)")];
+        profile.verified = Some(false);
+        profile.deployer_label = String::from("potential_hacker: 0x01");
+        score(&mut profile);
+        assert_eq!(profile.risk_score, 55);
+        assert!(profile.risk_factors[0].starts_with("+15 interacted with (potential) hackers: 0x0000"));
+    }
+
+    #[test]
+    fn caps_the_score() {
+        let mut profile = profile();
+        profile.label = String::from("hacker: Euler Exploiter");
+        profile.funding = vec![hop(1, true)];
+        profile.mixer_txs = vec![String::from("0x03")];
+        profile.first_activity = Some((SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(), String::from("0x01")));
+        score(&mut profile);
+        assert_eq!((profile.risk_score, profile.risk_level()), (100, "high"));
+        assert_eq!(profile.risk_factors.len(), 4);
+        assert!(profile.risk_factors[3].starts_with("+10 new address, first active "));
+    }
+
+    #[test]
+    fn lists_each_mixer_tx_once() {
+        let mixers = HashSet::from([String::from("0x00000000000000000000000000000000000000dd")]);
+        let txs = [
+            tx("0x01", "0x00000000000000000000000000000000000000DD", "0xaa"),
+            tx("0x02", "0xaa", "0xbb"),
+            tx("0x01", "0x00000000000000000000000000000000000000dd", "0xaa"), // The internal tx of the same tx
+            tx("0x03", "0xaa", "0x00000000000000000000000000000000000000dd"),
+        ];
+        assert_eq!(mixer_txs(txs.iter(), &mixers), vec!["0x01", "0x03"]);
+    }
+}
@@ -0,0 +1,1038 @@
+use std::{collections::{HashMap, HashSet}, fs, path::Path, str::FromStr};
+use ethers::{providers::Middleware, types::{I256, U256}};
+use serde::{Deserialize, Serialize};
+use crate::ai::client::ChatClient;
+use crate::analyzer::{decoder::Decoder, trace::{self, format_amount, BalanceDelta, TraceCall, TxTrace, ETHER}};
+use crate::error::{Error, Result};
+use crate::listener::fetcher::{Fetch, TransactionInfo};
+use crate::utils::{address_db, output::Record, source::DEFAULT_OUTPUT_DIR, tools::format_time};
+
+/// The most exploit txs traced, the others are only listed
+pub const MAX_TRACED_TXS: usize = 20;
+
+/// The system prompt of the executive summary
+pub const SUMMARY_PROMPT: &str = "You are a blockchain incident responder. \
+You get the data of an incident report: the funding of the attacker, the attack contracts, the exploit transactions, \
+the losses and where the funds went, with the known labels of the addresses. \
+Write an executive summary for the management of the victim protocol in at most 200 words: what happened, \
+the probable root cause, the loss, where the funds are now, and the next steps (e.g. contact the exchanges receiving funds). \
+Only state what the data supports, and say so when it is not enough to tell.";
+
+/// The longest report sent for the summary, the rest (the call trees first) is cut
+const MAX_SUMMARY_CHARS: usize = 12_000;
+
+/// @dev The file format of an incident report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "md" | "markdown" => Ok(ReportFormat::Markdown),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(format!("Invalid report format `{}`, expected md or html", s)),
+        }
+    }
+}
+
+impl ReportFormat {
+    /// @return The file extension
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Markdown => "md",
+            ReportFormat::Html => "html",
+        }
+    }
+}
+
+/// @dev The first ether received by the attacker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Funding {
+    pub hash: String,
+    pub blockNumber: u64,
+    pub timeStamp: u64,
+    pub from: String,
+    pub from_label: String,
+    pub amount: String, // In ETH
+    pub mixer: bool, // True if `from` is a mixing service in db
+}
+
+/// @dev A contract deployed by the attacker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployment {
+    pub hash: String,
+    pub blockNumber: u64,
+    pub timeStamp: u64,
+    pub deployer: String,
+    pub address: String,
+}
+
+/// @dev One line of the timeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub timeStamp: u64,
+    pub blockNumber: u64,
+    pub event: String, // funding, mixer, deployment, exploit or outflow
+    pub hash: String,
+    pub description: String,
+}
+
+impl Record for TimelineEvent {
+    fn headers() -> Vec<&'static str> {
+        vec!["time", "blockNumber", "event", "hash", "description"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            format_time(self.timeStamp),
+            self.blockNumber.to_string(),
+            self.event.clone(),
+            self.hash.clone(),
+            self.description.clone(),
+        ]
+    }
+}
+
+/// @dev The funds moved from the attacker (or an attack contract) to a destination, out of the exploit txs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Flow {
+    pub from: String,
+    pub from_label: String,
+    pub to: String,
+    pub to_label: String,
+    pub asset: String, // `ETH` or the token address
+    pub symbol: String,
+    pub value: String, // The sum in the smallest unit
+    pub decimals: Option<u32>,
+    pub txs: usize,
+}
+
+impl Flow {
+    /// @return The sum in whole units
+    pub fn amount(&self) -> String {
+        format_amount(&self.value, self.decimals)
+    }
+}
+
+impl Record for Flow {
+    fn headers() -> Vec<&'static str> {
+        vec!["from", "from_label", "to", "to_label", "asset", "symbol", "amount", "txs"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.from.clone(),
+            self.from_label.clone(),
+            self.to.clone(),
+            self.to_label.clone(),
+            self.asset.clone(),
+            self.symbol.clone(),
+            self.amount(),
+            self.txs.to_string(),
+        ]
+    }
+}
+
+/// @dev An address of the incident, with its role and its label in db
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncidentAddress {
+    pub address: String,
+    pub role: String, // attacker, victim, attack contract, funder or destination
+    pub label: String,
+}
+
+/// @dev Everything known about an incident, rendered by `to_markdown()` / `to_html()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncidentReport {
+    pub attacker: String,
+    pub victim: String,
+    pub start_block: u64,
+    pub end_block: u64,
+    pub funding: Option<Funding>,
+    pub mixer_txs: Vec<String>, // The txs of the attacker with a mixing service
+    pub attack_contracts: Vec<Deployment>,
+    pub exploits: Vec<TxTrace>,
+    pub untraced: Vec<String>, // The exploit txs over `MAX_TRACED_TXS`, or which failed to trace
+    pub losses: Vec<BalanceDelta>, // The net loss of the victim per asset
+    pub profits: Vec<BalanceDelta>, // The net gain of the attacker and its contracts per asset
+    pub flows: Vec<Flow>,
+    pub timeline: Vec<TimelineEvent>,
+    pub addresses: Vec<IncidentAddress>,
+    pub summary: Option<String>, // The AI executive summary
+    pub notes: Vec<String>,
+}
+
+/// @dev A part of a section, rendered in Markdown or HTML
+enum Block {
+    Text(String),
+    List(Vec<String>),
+    Table(Vec<&'static str>, Vec<Vec<String>>),
+    Graph(String), // Mermaid
+    Code(String, String), // The summary and the folded text
+}
+
+/// @dev Gather an incident: the funding of the attacker, its contracts, the exploit txs with their decoded call trees
+/// and balance changes, and where the funds went afterwards
+/// @param client The provider, a node supporting `debug_traceTransaction` gives the whole call trees
+/// @param api_key Etherscan API kEY
+/// @param attacker The attacker EOA
+/// @param victim The victim contract
+/// @param start_block The first block of the incident
+/// @param end_block The last block of the incident, the fund flows are followed until it
+pub async fn build_report<M: Middleware>(
+    client: &M,
+    api_key: String,
+    attacker: &str,
+    victim: &str,
+    start_block: u64,
+    end_block: u64,
+) -> Result<IncidentReport> {
+    if start_block > end_block {
+        return Err(Error::InvalidInput(format!("The start block {} is after the end block {}", start_block, end_block)));
+    }
+    let attacker = attacker.to_lowercase();
+    let victim = victim.to_lowercase();
+    let labels = address_db::get_db_labels()?;
+    let mixers: HashSet<String> = address_db::get_db_address("mixing_service")?
+        .into_iter()
+        .map(|address| address.to_lowercase())
+        .collect();
+    let label = |address: &str| labels.get(address).cloned().unwrap_or_default();
+
+    let fetcher = Fetch::new(api_key.clone());
+    // The whole history, the funding is usually before the incident
+    let mut history = fetcher.fetch_address_all_txs(&attacker, 0, end_block).await?;
+    history.sort_by_key(|tx| (tx.blockNumber, tx.timeStamp));
+    let txs: HashMap<String, TransactionInfo> = history.iter().map(|tx| (tx.hash.clone(), tx.clone())).collect();
+
+    let mut report = IncidentReport {
+        attacker: attacker.clone(),
+        victim: victim.clone(),
+        start_block,
+        end_block,
+        funding: None,
+        mixer_txs: Vec::new(),
+        attack_contracts: Vec::new(),
+        exploits: Vec::new(),
+        untraced: Vec::new(),
+        losses: Vec::new(),
+        profits: Vec::new(),
+        flows: Vec::new(),
+        timeline: Vec::new(),
+        addresses: Vec::new(),
+        summary: None,
+        notes: Vec::new(),
+    };
+
+    report.funding = history
+        .iter()
+        .find(|tx| tx.to.to_lowercase() == attacker && !tx.isError && !is_zero(&tx.value))
+        .map(|tx| {
+            let from = tx.from.to_lowercase();
+            Funding {
+                hash: tx.hash.clone(),
+                blockNumber: tx.blockNumber,
+                timeStamp: tx.timeStamp,
+                from_label: label(&from),
+                mixer: mixers.contains(&from),
+                amount: format_amount(&tx.value, Some(18)),
+                from,
+            }
+        });
+    if report.funding.is_none() {
+        report.notes.push(String::from("No ether was received by the attacker, the funding source is unknown."));
+    }
+
+    for tx in &history {
+        if (mixers.contains(&tx.from.to_lowercase()) || mixers.contains(&tx.to.to_lowercase())) && !report.mixer_txs.contains(&tx.hash) {
+            report.mixer_txs.push(tx.hash.clone());
+        }
+    }
+
+    // The contracts created by the attacker, and by them in the same txs
+    let mut insiders: HashSet<String> = HashSet::from([attacker.clone()]);
+    for tx in &history {
+        let created = tx.contractAddress.to_lowercase();
+        if created.is_empty() || tx.isError || !insiders.contains(&tx.from.to_lowercase()) || insiders.contains(&created) {
+            continue;
+        }
+        insiders.insert(created.clone());
+        report.attack_contracts.push(Deployment {
+            hash: tx.hash.clone(),
+            blockNumber: tx.blockNumber,
+            timeStamp: tx.timeStamp,
+            deployer: tx.from.to_lowercase(),
+            address: created,
+        });
+    }
+
+    // The txs of the attacker calling the victim or its contracts, and the deployments (attacks in a constructor)
+    let mut candidates: Vec<String> = Vec::new();
+    for tx in history.iter().filter(|tx| (start_block..=end_block).contains(&tx.blockNumber)) {
+        let to = tx.to.to_lowercase();
+        let is_exploit = tx.from.to_lowercase() == attacker
+            && (to == victim || (to != attacker && insiders.contains(&to)) || !tx.contractAddress.is_empty());
+        if is_exploit && !candidates.contains(&tx.hash) {
+            candidates.push(tx.hash.clone());
+        }
+    }
+    if candidates.is_empty() {
+        report.notes.push(format!("The attacker sent no tx to the victim or its contracts in the blocks {}-{}.", start_block, end_block));
+    }
+
+    let mut decoder = Decoder::new(Some(api_key))?;
+    for (i, hash) in candidates.iter().enumerate() {
+        if i >= MAX_TRACED_TXS {
+            report.untraced.push(hash.clone());
+            continue;
+        }
+        eprintln!("Tracing exploit tx {}/{}: {}", i + 1, candidates.len().min(MAX_TRACED_TXS), hash);
+        match trace::trace_transaction(client, &mut decoder, hash).await {
+            Ok(exploit) => report.exploits.push(exploit),
+            Err(e) => {
+                report.notes.push(format!("Failed to trace {}: {}", hash, e));
+                report.untraced.push(hash.clone());
+            }
+        }
+    }
+    if report.exploits.iter().any(|exploit| !exploit.traced) {
+        report.notes.push(String::from(
+            "The node does not support debug_traceTransaction, the call trees only show the top calls and the ether moved by the inner calls is missing.",
+        ));
+    }
+    if !report.untraced.is_empty() {
+        report.notes.push(format!("{} exploit tx(s) are not traced, they are only listed in the timeline.", report.untraced.len()));
+    }
+
+    let succeeded: Vec<&TxTrace> = report.exploits.iter().filter(|exploit| exploit.status != Some(false)).collect();
+    report.losses = net_deltas(&succeeded, &HashSet::from([victim.clone()]), &victim, label(&victim))
+        .into_iter()
+        .filter(|delta| delta.delta.starts_with('-'))
+        .collect();
+    report.profits = net_deltas(&succeeded, &insiders, &attacker, label(&attacker))
+        .into_iter()
+        .filter(|delta| !delta.delta.starts_with('-'))
+        .collect();
+    if report.losses.is_empty() && !report.exploits.is_empty() {
+        report.notes.push(String::from(
+            "The victim itself lost nothing in the traced txs (the funds may be held by another contract), see the attacker profit instead.",
+        ));
+    }
+
+    // Where the funds went after the first exploit, out of the exploit txs
+    let exploit_hashes: HashSet<String> = candidates.iter().cloned().collect();
+    let flow_start = report.exploits.iter().filter_map(|exploit| exploit.block).min().unwrap_or(start_block);
+    let mut outflows: Vec<(u64, u64, String, Flow)> = Vec::new(); // The block, the time and the hash of each transfer
+    let mut sources: Vec<String> = vec![attacker.clone()];
+    sources.extend(report.attack_contracts.iter().map(|deployment| deployment.address.clone()));
+    for source in &sources {
+        let source_txs = if *source == attacker {
+            history.iter().filter(|tx| tx.blockNumber >= flow_start).cloned().collect()
+        } else {
+            fetcher.fetch_address_all_txs(source, flow_start, end_block).await?
+        };
+        for tx in source_txs {
+            let to = tx.to.to_lowercase();
+            if tx.from.to_lowercase() != *source || to.is_empty() || insiders.contains(&to) || tx.isError || is_zero(&tx.value) || exploit_hashes.contains(&tx.hash) {
+                continue;
+            }
+            outflows.push((tx.blockNumber, tx.timeStamp, tx.hash.clone(), Flow {
+                from: source.clone(),
+                from_label: label(source),
+                to_label: label(&to),
+                to,
+                asset: ETHER.to_string(),
+                symbol: ETHER.to_string(),
+                value: tx.value.clone(),
+                decimals: Some(18),
+                txs: 1,
+            }));
+        }
+
+        for transfer in fetcher.fetch_token_transfers(source, flow_start, end_block).await? {
+            let to = transfer.to.to_lowercase();
+            if transfer.from.to_lowercase() != *source || insiders.contains(&to) || is_zero(&transfer.value) || exploit_hashes.contains(&transfer.hash) {
+                continue;
+            }
+            outflows.push((transfer.blockNumber, transfer.timeStamp, transfer.hash.clone(), Flow {
+                from: source.clone(),
+                from_label: label(source),
+                to_label: label(&to),
+                to,
+                asset: transfer.token.to_lowercase(),
+                symbol: transfer.tokenSymbol.clone(),
+                value: transfer.value.clone(),
+                decimals: transfer.tokenDecimal,
+                txs: 1,
+            }));
+        }
+    }
+    outflows.sort_by_key(|(block, time, _, _)| (*block, *time));
+    report.flows = merge_flows(outflows.iter().map(|(_, _, _, flow)| flow.clone()));
+
+    // The timeline
+    if let Some(funding) = &report.funding {
+        let mixer = if funding.mixer { ", a mixing service" } else { "" };
+        report.timeline.push(TimelineEvent {
+            timeStamp: funding.timeStamp,
+            blockNumber: funding.blockNumber,
+            event: String::from("funding"),
+            hash: funding.hash.clone(),
+            description: format!("{} ETH from {}{}{}", funding.amount, funding.from, trace::label(&funding.from_label), mixer),
+        });
+    }
+    for hash in &report.mixer_txs {
+        if report.funding.as_ref().is_some_and(|funding| funding.hash == *hash) {
+            continue;
+        }
+        let tx = &txs[hash];
+        let (direction, mixer) = if mixers.contains(&tx.to.to_lowercase()) { ("to", &tx.to) } else { ("from", &tx.from) };
+        report.timeline.push(TimelineEvent {
+            timeStamp: tx.timeStamp,
+            blockNumber: tx.blockNumber,
+            event: String::from("mixer"),
+            hash: hash.clone(),
+            description: format!("{} ETH {} {}{}", format_amount(&tx.value, Some(18)), direction, mixer, trace::label(&label(&mixer.to_lowercase()))),
+        });
+    }
+    for deployment in &report.attack_contracts {
+        report.timeline.push(TimelineEvent {
+            timeStamp: deployment.timeStamp,
+            blockNumber: deployment.blockNumber,
+            event: String::from("deployment"),
+            hash: deployment.hash.clone(),
+            description: format!("{} deployed {}", deployment.deployer, deployment.address),
+        });
+    }
+    for hash in &candidates {
+        let tx = &txs[hash];
+        let exploit = report.exploits.iter().find(|exploit| exploit.hash == *hash);
+        let call = exploit
+            .and_then(|exploit| exploit.root.decoded.as_ref())
+            .map(|decoded| shorten(&decoded.to_string(), 120))
+            .unwrap_or_else(|| tx.methodId.clone());
+        let status = if tx.isError || exploit.is_some_and(|exploit| exploit.status == Some(false)) { " (reverted)" } else { "" };
+        let to = if tx.to.is_empty() { format!("create {}", tx.contractAddress) } else { tx.to.clone() };
+        report.timeline.push(TimelineEvent {
+            timeStamp: tx.timeStamp,
+            blockNumber: tx.blockNumber,
+            event: String::from("exploit"),
+            hash: hash.clone(),
+            description: format!("{} {}{}", to, call, status).trim().to_string(),
+        });
+    }
+    for (block, time, hash, flow) in &outflows {
+        let symbol = if flow.symbol.is_empty() { flow.asset.clone() } else { flow.symbol.clone() };
+        report.timeline.push(TimelineEvent {
+            timeStamp: *time,
+            blockNumber: *block,
+            event: String::from("outflow"),
+            hash: hash.clone(),
+            description: format!("{} {} from {} to {}{}", flow.amount(), symbol, flow.from, flow.to, trace::label(&flow.to_label)),
+        });
+    }
+    report.timeline.sort_by_key(|event| (event.blockNumber, event.timeStamp));
+
+    // The addresses
+    let mut roles = vec![(attacker.clone(), "attacker"), (victim.clone(), "victim")];
+    roles.extend(report.attack_contracts.iter().map(|deployment| (deployment.address.clone(), "attack contract")));
+    roles.extend(report.funding.iter().map(|funding| (funding.from.clone(), "funder")));
+    roles.extend(report.flows.iter().map(|flow| (flow.to.clone(), "destination")));
+    let mut seen = HashSet::new();
+    for (address, role) in roles {
+        if seen.insert(address.clone()) {
+            report.addresses.push(IncidentAddress { label: label(&address), address, role: role.to_string() });
+        }
+    }
+
+    Ok(report)
+}
+
+impl IncidentReport {
+    /// @dev Ask the model for an executive summary of the report
+    /// @param client The model client
+    pub async fn summarize(&mut self, client: &ChatClient) -> Result<()> {
+        let mut data = self.to_markdown();
+        if data.len() > MAX_SUMMARY_CHARS {
+            let mut end = MAX_SUMMARY_CHARS;
+            while !data.is_char_boundary(end) {
+                end -= 1;
+            }
+            data.truncate(end);
+            data.push_str("...(truncated)");
+        }
+
+        let summary = client.clone().with_params(Some(0.2), Some(500)).ask(SUMMARY_PROMPT, data).await?;
+        self.summary = Some(summary.trim().to_string());
+        Ok(())
+    }
+
+    /// @dev Write the report into `path`, default `output/<victim>/incident-<start_block>-<end_block>.<md|html>`
+    /// @param format Markdown or HTML
+    /// @param path The file
+    /// @return The path of the file
+    pub fn write(&self, format: ReportFormat, path: Option<&str>) -> Result<String> {
+        let path = match path {
+            Some(path) => Path::new(path).to_path_buf(),
+            None => Path::new(DEFAULT_OUTPUT_DIR)
+                .join(&self.victim)
+                .join(format!("incident-{}-{}.{}", self.start_block, self.end_block, format.extension())),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = match format {
+            ReportFormat::Markdown => self.to_markdown(),
+            ReportFormat::Html => self.to_html(),
+        };
+        fs::write(&path, content).map_err(|e| Error::Storage(format!("write {}: {}", path.display(), e)))?;
+        Ok(path.display().to_string())
+    }
+
+    /// @dev Render the report in Markdown, the flow graph is a Mermaid chart
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!("# {}\n", self.title());
+        for (title, blocks) in self.sections() {
+            md.push_str(&format!("\n## {}\n", title));
+            for block in blocks {
+                match block {
+                    Block::Text(text) => md.push_str(&format!("\n{}\n", text)),
+                    Block::List(items) => {
+                        md.push('\n');
+                        for item in items {
+                            md.push_str(&format!("- {}\n", item));
+                        }
+                    }
+                    Block::Table(headers, rows) => {
+                        md.push_str(&format!("\n| {} |\n|{}\n", headers.join(" | "), "---|".repeat(headers.len())));
+                        for row in rows {
+                            let cells: Vec<String> = row.iter().map(|cell| cell.replace('|', "\\|")).collect();
+                            md.push_str(&format!("| {} |\n", cells.join(" | ")));
+                        }
+                    }
+                    Block::Graph(graph) => md.push_str(&format!("\n```mermaid\n{}```\n", graph)),
+                    Block::Code(summary, code) => md.push_str(&format!(
+                        "\n<details><summary>{}</summary>\n\n```text\n{}```\n\n</details>\n",
+                        summary, code
+                    )),
+                }
+            }
+        }
+        md
+    }
+
+    /// @dev Render the report as a standalone HTML page, the flow graph is drawn by Mermaid
+    pub fn to_html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n\
+            body {{ font-family: sans-serif; max-width: 1200px; margin: auto; padding: 1em; }}\n\
+            table {{ border-collapse: collapse; margin: 1em 0; }}\n\
+            th, td {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; font-size: 14px; word-break: break-all; }}\n\
+            pre {{ background: #f6f8fa; padding: 1em; overflow-x: auto; }}\n\
+            </style>\n</head>\n<body>\n<h1>{}</h1>\n",
+            escape(&self.title()),
+            escape(&self.title())
+        );
+        for (title, blocks) in self.sections() {
+            html.push_str(&format!("<h2>{}</h2>\n", escape(&title)));
+            for block in blocks {
+                match block {
+                    Block::Text(text) => html.push_str(&format!("<p>{}</p>\n", escape(&text))),
+                    Block::List(items) => {
+                        html.push_str("<ul>\n");
+                        for item in items {
+                            html.push_str(&format!("<li>{}</li>\n", escape(&item)));
+                        }
+                        html.push_str("</ul>\n");
+                    }
+                    Block::Table(headers, rows) => {
+                        html.push_str("<table>\n<tr>");
+                        for header in headers {
+                            html.push_str(&format!("<th>{}</th>", escape(header)));
+                        }
+                        html.push_str("</tr>\n");
+                        for row in rows {
+                            html.push_str("<tr>");
+                            for cell in row {
+                                html.push_str(&format!("<td>{}</td>", escape(&cell)));
+                            }
+                            html.push_str("</tr>\n");
+                        }
+                        html.push_str("</table>\n");
+                    }
+                    Block::Graph(graph) => html.push_str(&format!("<pre class=\"mermaid\">\n{}</pre>\n", escape(&graph))),
+                    Block::Code(summary, code) => html.push_str(&format!(
+                        "<details><summary>{}</summary>\n<pre>{}</pre>\n</details>\n",
+                        escape(&summary),
+                        escape(&code)
+                    )),
+                }
+            }
+        }
+        html.push_str(
+            "<script type=\"module\">\nimport mermaid from 'https://cdn.jsdelivr.net/npm/mermaid@10/dist/mermaid.esm.min.mjs';\n\
+            mermaid.initialize({ startOnLoad: true });\n</script>\n</body>\n</html>\n",
+        );
+        html
+    }
+
+    fn title(&self) -> String {
+        let victim = self.addresses.iter().find(|address| address.address == self.victim);
+        match victim {
+            Some(victim) if !victim.label.is_empty() => format!("Incident report: {} ({})", victim.label, self.victim),
+            _ => format!("Incident report: {}", self.victim),
+        }
+    }
+
+    /// @dev The sections of the report, the call trees last so they are cut first from the summary request
+    fn sections(&self) -> Vec<(String, Vec<Block>)> {
+        let mut sections = Vec::new();
+
+        if let Some(summary) = &self.summary {
+            sections.push((String::from("Executive summary"), vec![
+                Block::Text(summary.clone()),
+                Block::Text(String::from("Written by a language model from the data below, verify it before relying on it.")),
+            ]));
+        }
+
+        let mut overview = vec![
+            format!("Attacker: {}{}", self.attacker, trace::label(&self.label_of(&self.attacker))),
+            format!("Victim: {}{}", self.victim, trace::label(&self.label_of(&self.victim))),
+            format!("Blocks: {} - {}", self.start_block, self.end_block),
+        ];
+        match &self.funding {
+            Some(funding) => overview.push(format!(
+                "Funding: {} ETH from {}{} at block {} ({}){}",
+                funding.amount,
+                funding.from,
+                trace::label(&funding.from_label),
+                funding.blockNumber,
+                format_time(funding.timeStamp),
+                if funding.mixer { ", FUNDED BY A MIXING SERVICE" } else { "" }
+            )),
+            None => overview.push(String::from("Funding: unknown")),
+        }
+        overview.push(format!("Mixing service txs: {}", self.mixer_txs.len()));
+        let contracts: Vec<&str> = self.attack_contracts.iter().map(|deployment| deployment.address.as_str()).collect();
+        overview.push(format!("Attack contracts: {}", if contracts.is_empty() { String::from("none") } else { contracts.join(", ") }));
+        overview.push(format!("Exploit txs: {} traced, {} not traced", self.exploits.len(), self.untraced.len()));
+        sections.push((String::from("Overview"), vec![Block::List(overview)]));
+
+        let mut loss_rows: Vec<Vec<String>> = Vec::new();
+        for (side, deltas) in [("victim loss", &self.losses), ("attacker profit", &self.profits)] {
+            for delta in deltas {
+                loss_rows.push(vec![side.to_string(), asset_name(&delta.symbol, &delta.asset), delta.asset.clone(), delta.amount()]);
+            }
+        }
+        let losses = if loss_rows.is_empty() {
+            Block::Text(String::from("No balance change in the traced exploit txs."))
+        } else {
+            Block::Table(vec!["Side", "Asset", "Address", "Amount"], loss_rows)
+        };
+        sections.push((String::from("Loss estimate"), vec![
+            Block::Text(String::from("The net balance changes per asset in the successful exploit txs, in whole units.")),
+            losses,
+        ]));
+
+        let rows = self
+            .timeline
+            .iter()
+            .map(|event| vec![format_time(event.timeStamp), event.blockNumber.to_string(), event.event.clone(), event.hash.clone(), event.description.clone()])
+            .collect();
+        sections.push((String::from("Timeline"), vec![Block::Table(vec!["Time", "Block", "Event", "Tx", "Details"], rows)]));
+
+        let mut flow = vec![Block::Graph(self.flow_graph())];
+        if !self.flows.is_empty() {
+            let rows = self
+                .flows
+                .iter()
+                .map(|flow| {
+                    vec![
+                        format!("{}{}", flow.from, trace::label(&flow.from_label)),
+                        format!("{}{}", flow.to, trace::label(&flow.to_label)),
+                        asset_name(&flow.symbol, &flow.asset),
+                        flow.amount(),
+                        flow.txs.to_string(),
+                    ]
+                })
+                .collect();
+            flow.push(Block::Table(vec!["From", "To", "Asset", "Amount", "Txs"], rows));
+        } else {
+            flow.push(Block::Text(String::from("No funds left the attacker or its contracts after the exploit.")));
+        }
+        sections.push((String::from("Fund flow"), flow));
+
+        let rows = self
+            .addresses
+            .iter()
+            .map(|address| vec![address.address.clone(), address.role.clone(), address.label.clone()])
+            .collect();
+        sections.push((String::from("Addresses"), vec![Block::Table(vec!["Address", "Role", "Label"], rows)]));
+
+        if !self.notes.is_empty() {
+            sections.push((String::from("Notes"), vec![Block::List(self.notes.clone())]));
+        }
+
+        let mut exploits = Vec::new();
+        for exploit in &self.exploits {
+            exploits.push(Block::Code(format!("{} (block {})", exploit.hash, exploit.block.unwrap_or_default()), exploit.to_string()));
+        }
+        if !self.untraced.is_empty() {
+            exploits.push(Block::Text(format!("Not traced: {}", self.untraced.join(", "))));
+        }
+        if !exploits.is_empty() {
+            sections.push((String::from("Exploit transactions"), exploits));
+        }
+
+        sections
+    }
+
+    /// @dev The funding, the deployments, the exploit calls, the loss and the fund flows as a Mermaid flowchart
+    fn flow_graph(&self) -> String {
+        let mut nodes: Vec<String> = Vec::new();
+        let mut edges: Vec<String> = Vec::new();
+        let attacker = node(&self.attacker, &mut nodes);
+        let victim = node(&self.victim, &mut nodes);
+        if let Some(funding) = &self.funding {
+            let funder = node(&funding.from, &mut nodes);
+            edges.push(format!("{} -->|\"funding {} ETH\"| {}", funder, funding.amount, attacker));
+        }
+        for deployment in &self.attack_contracts {
+            let (deployer, contract) = (node(&deployment.deployer, &mut nodes), node(&deployment.address, &mut nodes));
+            edges.push(format!("{} -->|deploy| {}", deployer, contract));
+        }
+
+        let mut calls: Vec<(String, String, usize)> = Vec::new();
+        for exploit in &self.exploits {
+            let mut targets = vec![exploit.to.to_lowercase()];
+            if targets[0].is_empty() {
+                targets[0] = exploit.root.to.to_lowercase();
+            }
+            if targets[0] != self.victim && calls_victim(&exploit.root, &self.victim) {
+                targets.push(self.victim.clone());
+            }
+            let mut from = exploit.from.to_lowercase();
+            for to in targets.into_iter().filter(|to| !to.is_empty()) {
+                match calls.iter_mut().find(|(f, t, _)| *f == from && *t == to) {
+                    Some(call) => call.2 += 1,
+                    None => calls.push((from.clone(), to.clone(), 1)),
+                }
+                from = to;
+            }
+        }
+        for (from, to, count) in calls {
+            let (from, to) = (node(&from, &mut nodes), node(&to, &mut nodes));
+            edges.push(format!("{} ==>|\"exploit x{}\"| {}", from, count, to));
+        }
+
+        if !self.losses.is_empty() {
+            let loss: Vec<String> = self.losses.iter().map(|delta| format!("{} {}", delta.amount(), asset_name(&delta.symbol, &delta.asset))).collect();
+            edges.push(format!("{} -.->|\"loss {}\"| {}", victim, loss.join(", "), attacker));
+        }
+        for flow in &self.flows {
+            let (from, to) = (node(&flow.from, &mut nodes), node(&flow.to, &mut nodes));
+            edges.push(format!("{} -->|\"{} {}\"| {}", from, flow.amount(), asset_name(&flow.symbol, &flow.asset), to));
+        }
+
+        let mut graph = String::from("flowchart LR\n");
+        for (i, address) in nodes.iter().enumerate() {
+            let label = self.label_of(address);
+            let role = self.addresses.iter().find(|a| a.address == *address).map(|a| a.role.clone()).unwrap_or_default();
+            let mut text = short(address);
+            for extra in [role.as_str(), label.as_str()] {
+                if !extra.is_empty() {
+                    text.push_str(&format!("<br/>{}", extra.replace('"', "#quot;")));
+                }
+            }
+            graph.push_str(&format!("  n{}[\"{}\"]\n", i, text));
+        }
+        for edge in edges {
+            graph.push_str(&format!("  {}\n", edge));
+        }
+        graph.push_str("  classDef risk fill:#fdd,stroke:#c00\n  classDef victim fill:#ffd,stroke:#cc0\n");
+        for (i, address) in nodes.iter().enumerate() {
+            let label = self.label_of(address);
+            if *address == self.victim {
+                graph.push_str(&format!("  class n{} victim\n", i));
+            } else if *address == self.attacker || label.starts_with("hacker") || label.starts_with("mixing_service") || label.starts_with("potential_hacker") {
+                graph.push_str(&format!("  class n{} risk\n", i));
+            }
+        }
+        graph
+    }
+
+    fn label_of(&self, address: &str) -> String {
+        self.addresses
+            .iter()
+            .find(|a| a.address == address)
+            .map(|a| a.label.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// @dev Sum the balance deltas of some addresses per asset
+/// @param traces The traced txs
+/// @param owners The addresses to sum
+/// @param address The address of the returned deltas
+/// @param label Its label
+fn net_deltas(traces: &[&TxTrace], owners: &HashSet<String>, address: &str, label: String) -> Vec<BalanceDelta> {
+    let mut sums: Vec<(BalanceDelta, I256)> = Vec::new();
+    for delta in traces.iter().flat_map(|trace| &trace.deltas).filter(|delta| owners.contains(&delta.address.to_lowercase())) {
+        let value = I256::from_dec_str(&delta.delta).unwrap_or_default();
+        match sums.iter_mut().find(|(sum, _)| sum.asset == delta.asset) {
+            Some((_, sum)) => *sum = sum.saturating_add(value),
+            None => sums.push((
+                BalanceDelta { address: address.to_string(), label: label.clone(), ..delta.clone() },
+                value,
+            )),
+        }
+    }
+
+    sums.into_iter()
+        .filter(|(_, sum)| !sum.is_zero())
+        .map(|(delta, sum)| BalanceDelta { delta: sum.to_string(), ..delta })
+        .collect()
+}
+
+/// @dev Merge the transfers with the same source, destination and asset, the first transfers first
+fn merge_flows(flows: impl Iterator<Item = Flow>) -> Vec<Flow> {
+    let mut merged: Vec<Flow> = Vec::new();
+    for flow in flows {
+        match merged.iter_mut().find(|m| m.from == flow.from && m.to == flow.to && m.asset == flow.asset) {
+            Some(m) => {
+                let sum = U256::from_dec_str(&m.value).unwrap_or_default().saturating_add(U256::from_dec_str(&flow.value).unwrap_or_default());
+                m.value = sum.to_string();
+                m.txs += 1;
+            }
+            None => merged.push(flow),
+        }
+    }
+    merged
+}
+
+/// @return True if the call tree calls the victim
+fn calls_victim(call: &TraceCall, victim: &str) -> bool {
+    call.to.to_lowercase() == victim || call.calls.iter().any(|inner| calls_victim(inner, victim))
+}
+
+/// @return The id of the node of an address in the Mermaid chart, e.g. `n0`
+fn node(address: &str, nodes: &mut Vec<String>) -> String {
+    let address = address.to_lowercase();
+    match nodes.iter().position(|node| *node == address) {
+        Some(i) => format!("n{}", i),
+        None => {
+            nodes.push(address);
+            format!("n{}", nodes.len() - 1)
+        }
+    }
+}
+
+fn is_zero(value: &str) -> bool {
+    U256::from_dec_str(value).map_or(true, |value| value.is_zero())
+}
+
+fn asset_name(symbol: &str, asset: &str) -> String {
+    if symbol.is_empty() { short(asset) } else { symbol.to_string() }
+}
+
+/// @dev `0x1234567890..` => `0x1234…7890`
+fn short(address: &str) -> String {
+    if address.len() == 42 && address.starts_with("0x") {
+        format!("{}…{}", &address[..6], &address[38..])
+    } else {
+        address.to_string()
+    }
+}
+
+fn shorten(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    format!("{}...", text.chars().take(max_chars).collect::<String>())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATTACKER: &str = "0x00000000000000000000000000000000000000aa";
+    const CONTRACT: &str = "0x00000000000000000000000000000000000000cc";
+    const VICTIM: &str = "0x00000000000000000000000000000000000000bb";
+    const EXCHANGE: &str = "0x00000000000000000000000000000000000000ee";
+    const USDC: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+
+    fn delta(address: &str, asset: &str, symbol: &str, delta: &str, decimals: u32) -> BalanceDelta {
+        BalanceDelta {
+            address: address.to_string(),
+            label: String::new(),
+            asset: asset.to_string(),
+            symbol: symbol.to_string(),
+            delta: delta.to_string(),
+            decimals: Some(decimals),
+        }
+    }
+
+    fn call(to: &str, calls: Vec<TraceCall>) -> TraceCall {
+        TraceCall {
+            kind: String::from("CALL"),
+            from: ATTACKER.to_string(),
+            to: to.to_string(),
+            label: String::new(),
+            value: String::from("0"),
+            gas_used: 0,
+            input: String::from("0x"),
+            output: String::new(),
+            error: String::new(),
+            decoded: None,
+            calls,
+        }
+    }
+
+    fn exploit(hash: &str, deltas: Vec<BalanceDelta>) -> TxTrace {
+        TxTrace {
+            hash: hash.to_string(),
+            block: Some(100),
+            from: ATTACKER.to_string(),
+            to: CONTRACT.to_string(),
+            status: Some(true),
+            gas_used: Some(21_000),
+            root: call(CONTRACT, vec![call(VICTIM, vec![])]),
+            traced: true,
+            logs: Vec::new(),
+            deltas,
+        }
+    }
+
+    fn flow(to: &str, value: &str) -> Flow {
+        Flow {
+            from: ATTACKER.to_string(),
+            from_label: String::new(),
+            to: to.to_string(),
+            to_label: String::from("exchange: Binance | hot wallet"),
+            asset: ETHER.to_string(),
+            symbol: ETHER.to_string(),
+            value: value.to_string(),
+            decimals: Some(18),
+            txs: 1,
+        }
+    }
+
+    fn report() -> IncidentReport {
+        let exploits = vec![
+            exploit("0x01", vec![delta(VICTIM, USDC, "USDC", "-1500000", 6), delta(CONTRACT, USDC, "USDC", "1500000", 6)]),
+            exploit("0x02", vec![delta(VICTIM, USDC, "USDC", "-500000", 6), delta(ATTACKER, ETHER, ETHER, "-1", 18)]),
+        ];
+        let traces: Vec<&TxTrace> = exploits.iter().collect();
+        let attackers = HashSet::from([ATTACKER.to_string(), CONTRACT.to_string()]);
+
+        IncidentReport {
+            attacker: ATTACKER.to_string(),
+            victim: VICTIM.to_string(),
+            start_block: 90,
+            end_block: 110,
+            funding: Some(Funding {
+                hash: String::from("0x00"),
+                blockNumber: 90,
+                timeStamp: 0,
+                from: String::from("0x00000000000000000000000000000000000000dd"),
+                from_label: String::from("mixing_service: Tornado Cash"),
+                amount: String::from("1"),
+                mixer: true,
+            }),
+            mixer_txs: vec![String::from("0x00")],
+            attack_contracts: vec![Deployment {
+                hash: String::from("0x0a"),
+                blockNumber: 95,
+                timeStamp: 0,
+                deployer: ATTACKER.to_string(),
+                address: CONTRACT.to_string(),
+            }],
+            losses: net_deltas(&traces, &HashSet::from([VICTIM.to_string()]), VICTIM, String::new()),
+            profits: net_deltas(&traces, &attackers, ATTACKER, String::new()),
+            exploits,
+            untraced: vec![String::from("0x03")],
+            flows: merge_flows([flow(EXCHANGE, "1000000000000000000"), flow(EXCHANGE, "500000000000000000")].into_iter()),
+            timeline: Vec::new(),
+            addresses: vec![
+                IncidentAddress { address: VICTIM.to_string(), role: String::from("victim"), label: String::from("protocol: <Vault>") },
+                IncidentAddress {
+                    address: String::from("0x00000000000000000000000000000000000000dd"),
+                    role: String::from("funder"),
+                    label: String::from("mixing_service: Tornado Cash"),
+                },
+            ],
+            summary: None,
+            notes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_the_report_format() {
+        assert_eq!("MD".parse::<ReportFormat>(), Ok(ReportFormat::Markdown));
+        assert_eq!("html".parse::<ReportFormat>().unwrap().extension(), "html");
+        assert!("pdf".parse::<ReportFormat>().is_err());
+    }
+
+    #[test]
+    fn sums_the_losses_and_the_profits_per_asset() {
+        let report = report();
+        assert_eq!(report.losses.len(), 1);
+        assert_eq!((report.losses[0].address.as_str(), report.losses[0].amount()), (VICTIM, String::from("-2")));
+
+        // The attacker and its contracts are summed as the attacker
+        let profits: Vec<(String, String)> = report.profits.iter().map(|delta| (delta.symbol.clone(), delta.amount())).collect();
+        assert_eq!(profits, vec![(String::from("USDC"), String::from("1.5")), (String::from("ETH"), String::from("-0.000000000000000001"))]);
+        assert!(report.profits.iter().all(|delta| delta.address == ATTACKER));
+    }
+
+    #[test]
+    fn merges_the_flows_to_the_same_destination() {
+        let flows = merge_flows([flow(EXCHANGE, "1"), flow(VICTIM, "5"), flow(EXCHANGE, "2")].into_iter());
+        let flows: Vec<(&str, &str, usize)> = flows.iter().map(|f| (f.to.as_str(), f.value.as_str(), f.txs)).collect();
+        assert_eq!(flows, vec![(EXCHANGE, "3", 2), (VICTIM, "5", 1)]);
+    }
+
+    #[test]
+    fn renders_the_markdown_report() {
+        let md = report().to_markdown();
+        assert!(md.starts_with("# Incident report: protocol: <Vault> (0x00000000000000000000000000000000000000bb)\n"));
+        assert!(md.contains("- Funding: 1 ETH from 0x00000000000000000000000000000000000000dd (mixing_service: Tornado Cash) at block 90"));
+        assert!(md.contains(", FUNDED BY A MIXING SERVICE\n"));
+        assert!(md.contains("| victim loss | USDC | 0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48 | -2 |\n"));
+        // The pipes of the cells do not break the table
+        assert!(md.contains("(exchange: Binance \\| hot wallet) | ETH | 1.5 | 2 |\n"));
+        assert!(md.contains("Not traced: 0x03"));
+        assert!(!md.contains("## Executive summary"));
+
+        // The exploits go through the attack contract to the victim
+        assert!(md.contains("```mermaid\nflowchart LR\n  n0[\"0x0000…00aa\"]\n  n1[\"0x0000…00bb<br/>victim<br/>protocol: <Vault>\"]\n"));
+        assert!(md.contains("  n0 ==>|\"exploit x2\"| n3\n  n3 ==>|\"exploit x2\"| n1\n"));
+        assert!(md.contains("  n1 -.->|\"loss -2 USDC\"| n0\n"));
+        assert!(md.contains("  class n0 risk\n  class n1 victim\n  class n2 risk\n"));
+    }
+
+    #[test]
+    fn escapes_the_html_report() {
+        let html = report().to_html();
+        assert!(html.contains("<title>Incident report: protocol: &lt;Vault&gt; (0x00000000000000000000000000000000000000bb)</title>"));
+        assert!(!html.contains("<Vault>"));
+        assert!(html.contains("<pre class=\"mermaid\">\nflowchart LR\n"));
+    }
+
+    #[test]
+    fn writes_the_report() {
+        let path = std::env::temp_dir().join(format!("sechelper-incident-{}.md", std::process::id()));
+        let written = report().write(ReportFormat::Markdown, Some(&path.to_string_lossy())).unwrap();
+        assert!(fs::read_to_string(&written).unwrap().starts_with("# Incident report"));
+        fs::remove_file(path).unwrap();
+    }
+}
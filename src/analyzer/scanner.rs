@@ -0,0 +1,525 @@
+use std::{fs, path::Path, sync::LazyLock};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::utils::output::Record;
+
+/// @dev How bad a finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+impl Severity {
+    /// @return The SARIF level
+    fn sarif_level(&self) -> &'static str {
+        match self {
+            Severity::High => "error",
+            Severity::Medium => "warning",
+            Severity::Low => "note",
+        }
+    }
+}
+
+/// @dev A rule of the scanner
+pub struct Rule {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub severity: Severity,
+    pub description: &'static str,
+}
+
+/// @dev All rules of the scanner
+pub const RULES: &[Rule] = &[
+    Rule { id: "SH001", name: "tx-origin-auth", severity: Severity::High, description: "`tx.origin` is used for authorization, a phishing contract can act on behalf of the origin" },
+    Rule { id: "SH002", name: "unchecked-call", severity: Severity::Medium, description: "The return value of a low-level `call` is not checked" },
+    Rule { id: "SH003", name: "delegatecall-user-input", severity: Severity::High, description: "`delegatecall` to an address given by the caller" },
+    Rule { id: "SH004", name: "selfdestruct", severity: Severity::High, description: "`selfdestruct` can remove the contract and send its ether away" },
+    Rule { id: "SH005", name: "unprotected-initializer", severity: Severity::High, description: "An initializer without the `initializer` modifier or an access check can be called by anyone" },
+    Rule { id: "SH006", name: "call-before-state-update", severity: Severity::Medium, description: "An external call happens before a state variable is updated (reentrancy)" },
+    Rule { id: "SH007", name: "spot-price-oracle", severity: Severity::Medium, description: "A spot price (`getReserves`, `slot0`, `balanceOf(address(this))`) can be manipulated with a flash loan" },
+    Rule { id: "SH008", name: "missing-access-control", severity: Severity::High, description: "A privileged function is public/external without an access modifier or a `msg.sender` check" },
+];
+
+/// @dev A finding of the scanner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+    pub rule_id: String,
+    pub rule: String,
+    pub severity: Severity,
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+    pub snippet: String,
+}
+
+impl Record for Finding {
+    fn headers() -> Vec<&'static str> {
+        vec!["severity", "rule_id", "rule", "file", "line", "message", "snippet"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            format!("{:?}", self.severity).to_lowercase(),
+            self.rule_id.clone(),
+            self.rule.clone(),
+            self.file.clone(),
+            self.line.to_string(),
+            self.message.clone(),
+            self.snippet.clone(),
+        ]
+    }
+}
+
+/// @dev A function found in a source file
+struct Function {
+    name: String,
+    params: Vec<String>, // The parameter names
+    header: String, // From `function` to `{`, with the visibility and the modifiers
+    start: usize, // The line index of `function`
+    body: Vec<(usize, String)>, // The line indexes and the lines without comments
+}
+
+/// The line rules
+static TX_ORIGIN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(require\s*\(|if\s*\(|==|!=)[^;]*tx\.origin|tx\.origin\s*(==|!=)").unwrap());
+static LOW_LEVEL_CALL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\.call(\{[^}]*\})?\s*\(").unwrap());
+static CHECKED_CALL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\(\s*bool|bool\s+\w+\s*,?|=|require\s*\(|if\s*\(|return\s)[^;]*\.call").unwrap());
+static SELFDESTRUCT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(selfdestruct|suicide)\s*\(").unwrap());
+static SPOT_PRICE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\.getReserves\s*\(|\.slot0\s*\(|balanceOf\s*\(\s*address\s*\(\s*this\s*\)\s*\)").unwrap());
+
+/// `msg.sender == tx.origin` only tells an EOA from a contract, it is not an authorization by `tx.origin`
+static SENDER_IS_ORIGIN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(msg\.sender|_msgSender\(\))\s*(==|!=)\s*tx\.origin|tx\.origin\s*(==|!=)\s*(msg\.sender|_msgSender\(\))").unwrap());
+
+/// The function rules
+static FUNCTION_START: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\bfunction\s+(\w+)\s*\(").unwrap());
+static DELEGATECALL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\w+)\s*\.delegatecall\s*\(").unwrap());
+static EXTERNAL_CALL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\.(call|transfer|send|safeTransfer|safeTransferFrom|transferFrom)\s*(\{[^}]*\})?\s*\(").unwrap());
+static LOCAL_DECLARATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*(uint\d*|int\d*|bool|address|bytes\d*|string|mapping|\w+\s+(memory|storage|calldata))\b").unwrap());
+static ASSIGNMENT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*(\w+)\s*(\[[^\]]*\])*\s*(\.\w+)*\s*(=|\+=|-=|\*=|/=)[^=]").unwrap());
+static ACCESS_MODIFIER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(only\w*|auth|requiresAuth|initializer|reinitializer|whenNotPaused\w*|restricted)\b").unwrap());
+static SENDER_CHECK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"msg\.sender|_msgSender\(\)|_checkOwner|_checkRole|hasRole|_authorize\w*|onlyOwner|isOwner").unwrap());
+static PRIVILEGED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(set|update|withdraw|mint|burn|pause|unpause|upgrade|transferOwnership|sweep|rescue|emergency|kill|destroy|migrate|changeOwner|setOwner|grant|revoke)").unwrap());
+
+/// @dev Scan all `.sol` files under a folder, e.g. `output/<address>/`
+/// @param dir The folder
+/// @param include_libs Also scan the libraries, i.e. paths starting with `@`, `lib/` or `node_modules/`
+/// @return The findings, the most severe first
+pub fn scan_dir(dir: &str, include_libs: bool) -> Result<Vec<Finding>> {
+    let root = Path::new(dir);
+    if !root.exists() {
+        return Err(Error::InvalidInput(format!("{} does not exist", dir)));
+    }
+
+    let mut files = Vec::new();
+    collect_files(root, &mut files)?;
+
+    let mut findings = Vec::new();
+    for path in files {
+        let relative = path.strip_prefix(root).unwrap_or(&path).display().to_string();
+        if !include_libs && is_library(&relative) {
+            continue;
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| Error::Storage(format!("read file {} error: {}", path.display(), e)))?;
+        findings.extend(scan_source(&relative, &content));
+    }
+
+    findings.sort_by(|a, b| b.severity.cmp(&a.severity).then(a.file.cmp(&b.file)).then(a.line.cmp(&b.line)));
+    Ok(findings)
+}
+
+/// @dev Scan one solidity file
+/// @param file The file name in the findings
+/// @param content The source code
+pub fn scan_source(file: &str, content: &str) -> Vec<Finding> {
+    let lines = strip_comments(content);
+    let mut findings = Vec::new();
+    let mut report = |rule_id: &str, line: usize, message: String| {
+        let rule = RULES.iter().find(|rule| rule.id == rule_id).expect("unknown rule id");
+        findings.push(Finding {
+            rule_id: rule.id.to_string(),
+            rule: rule.name.to_string(),
+            severity: rule.severity,
+            file: file.to_string(),
+            line: line + 1,
+            message,
+            snippet: content.lines().nth(line).unwrap_or_default().trim().to_string(),
+        });
+    };
+
+    for (index, line) in lines.iter().enumerate() {
+        if TX_ORIGIN.is_match(&SENDER_IS_ORIGIN.replace_all(line, "")) {
+            report("SH001", index, String::from("`tx.origin` is used in a condition"));
+        }
+        if LOW_LEVEL_CALL.is_match(line) && !CHECKED_CALL.is_match(line) {
+            report("SH002", index, String::from("The success of the low-level call is ignored"));
+        }
+        if SELFDESTRUCT.is_match(line) {
+            report("SH004", index, String::from("`selfdestruct` is reachable"));
+        }
+        if SPOT_PRICE.is_match(line) {
+            report("SH007", index, String::from("A spot price / balance is read, check it is not used as a price oracle"));
+        }
+    }
+
+    for function in parse_functions(&lines) {
+        scan_function(&function, &mut report);
+    }
+
+    findings
+}
+
+/// @dev The rules which need the function context
+fn scan_function(function: &Function, report: &mut impl FnMut(&str, usize, String)) {
+    // SH003: delegatecall to a parameter
+    for (index, line) in &function.body {
+        if let Some(captures) = DELEGATECALL.captures(line) {
+            let target = &captures[1];
+            if function.params.iter().any(|param| param == target) {
+                report("SH003", *index, format!("`{}` delegatecalls to its parameter `{}`", function.name, target));
+            }
+        }
+    }
+
+    let is_public = function.header.contains("public") || function.header.contains("external");
+    let is_view = function.header.contains(" view") || function.header.contains(" pure");
+    let has_modifier = ACCESS_MODIFIER.is_match(&function.header);
+    let checks_sender = function.body.iter().any(|(_, line)| SENDER_CHECK.is_match(line));
+
+    // SH005: unprotected initializer
+    if is_public && (function.name == "initialize" || function.name == "init") && !has_modifier && !checks_sender {
+        report("SH005", function.start, format!("`{}` has no `initializer` modifier or access check", function.name));
+    }
+
+    // SH008: privileged function without access control
+    if is_public && !is_view && PRIVILEGED.is_match(&function.name) && !has_modifier && !checks_sender {
+        report("SH008", function.start, format!("`{}` can be called by anyone", function.name));
+    }
+
+    // SH006: external call before a state update
+    let mut locals: Vec<String> = function.params.clone();
+    let mut first_call: Option<usize> = None;
+    for (index, line) in &function.body {
+        if LOCAL_DECLARATION.is_match(line) {
+            if let Some(name) = declared_name(line) {
+                locals.push(name);
+            }
+            continue;
+        }
+        if first_call.is_none() && EXTERNAL_CALL.is_match(line) {
+            first_call = Some(*index);
+            continue;
+        }
+        if let (Some(call), Some(captures)) = (first_call, ASSIGNMENT.captures(line)) {
+            let variable = &captures[1];
+            if !locals.iter().any(|local| local == variable) {
+                report("SH006", call, format!("`{}` makes an external call before updating `{}` (line {})", function.name, variable, index + 1));
+                break;
+            }
+        }
+    }
+}
+
+/// @dev Find the functions and their bodies
+fn parse_functions(lines: &[String]) -> Vec<Function> {
+    let mut functions = Vec::new();
+
+    let mut index = 0;
+    while index < lines.len() {
+        let Some(captures) = FUNCTION_START.captures(&lines[index]) else {
+            index += 1;
+            continue;
+        };
+        let name = captures[1].to_string();
+        let start = index;
+
+        // The header ends at `{`, or at `;` for a declaration without body
+        let mut header = String::new();
+        let mut end = index;
+        while end < lines.len() {
+            header.push_str(&lines[end]);
+            header.push(' ');
+            if lines[end].contains('{') || lines[end].contains(';') {
+                break;
+            }
+            end += 1;
+        }
+        let header = header.split('{').next().unwrap_or_default().to_string();
+        if !lines.get(end).is_some_and(|line| line.contains('{')) {
+            index = end + 1;
+            continue;
+        }
+
+        // The body ends when the braces are balanced
+        let mut depth = 0i32;
+        let mut body = Vec::new();
+        let mut line_index = end;
+        while line_index < lines.len() {
+            let line = &lines[line_index];
+            depth += line.matches('{').count() as i32 - line.matches('}').count() as i32;
+            body.push((line_index, line.clone()));
+            if depth <= 0 {
+                break;
+            }
+            line_index += 1;
+        }
+
+        functions.push(Function {
+            params: parse_params(&header),
+            name,
+            header,
+            start,
+            body,
+        });
+        index = line_index + 1;
+    }
+
+    functions
+}
+
+/// @dev The parameter names in `function f(uint a, address b) ...`
+fn parse_params(header: &str) -> Vec<String> {
+    let Some(open) = header.find('(') else { return Vec::new() };
+    let Some(close) = header[open..].find(')') else { return Vec::new() };
+
+    header[open + 1..open + close]
+        .split(',')
+        .filter_map(|param| param.split_whitespace().last())
+        .filter(|name| name.chars().all(|c| c.is_alphanumeric() || c == '_'))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// @dev The variable name in `uint256 amount = ...`
+fn declared_name(line: &str) -> Option<String> {
+    let declaration = line.split(['=', ';']).next()?;
+    declaration
+        .split_whitespace()
+        .last()
+        .map(|name| name.trim_matches(|c: char| !c.is_alphanumeric() && c != '_').to_string())
+}
+
+/// @dev Replace the comments with spaces, keep the line numbers
+pub(crate) fn strip_comments(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut in_block = false;
+
+    for line in content.lines() {
+        let mut result = String::new();
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if in_block {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    in_block = false;
+                }
+                continue;
+            }
+            if c == '/' && chars.peek() == Some(&'/') {
+                break;
+            }
+            if c == '/' && chars.peek() == Some(&'*') {
+                chars.next();
+                in_block = true;
+                continue;
+            }
+            result.push(c);
+        }
+        lines.push(result);
+    }
+
+    lines
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    if dir.is_file() {
+        files.push(dir.to_path_buf());
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(dir)
+        .map_err(|e| Error::Storage(format!("read dir {} error: {}", dir.display(), e)))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension().is_some_and(|extension| extension == "sol") {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// @return True if the path is a library, i.e. it starts with `@`, `lib/` or `node_modules/`
+pub(crate) fn is_library(path: &str) -> bool {
+    path.starts_with('@') || path.starts_with("lib/") || path.starts_with("node_modules/") || path.contains("/@")
+}
+
+/// @dev Convert the findings to a SARIF 2.1.0 log
+/// @param findings The findings
+/// @param base The folder the file paths are relative to
+pub fn to_sarif(findings: &[Finding], base: &str) -> serde_json::Value {
+    let rules: Vec<serde_json::Value> = RULES
+        .iter()
+        .map(|rule| {
+            serde_json::json!({
+                "id": rule.id,
+                "name": rule.name,
+                "shortDescription": { "text": rule.description },
+                "defaultConfiguration": { "level": rule.severity.sarif_level() },
+            })
+        })
+        .collect();
+
+    let results: Vec<serde_json::Value> = findings
+        .iter()
+        .map(|finding| {
+            serde_json::json!({
+                "ruleId": finding.rule_id,
+                "level": finding.severity.sarif_level(),
+                "message": { "text": finding.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": finding.file, "uriBaseId": "SRCROOT" },
+                        "region": { "startLine": finding.line, "snippet": { "text": finding.snippet } },
+                    }
+                }],
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": { "driver": { "name": "SecHelper", "informationUri": "https://github.com/chen4903/SecHelper", "rules": rules } },
+            "originalUriBaseIds": { "SRCROOT": { "uri": format!("file://{}/", base.trim_end_matches('/')) } },
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// @return The (rule id, line) of the findings
+    fn scan(content: &str) -> Vec<(String, usize)> {
+        scan_source("Test.sol", content).into_iter().map(|finding| (finding.rule_id, finding.line)).collect()
+    }
+
+    fn has(findings: &[(String, usize)], rule_id: &str, line: usize) -> bool {
+        findings.iter().any(|(id, at)| id == rule_id && *at == line)
+    }
+
+    #[test]
+    fn flags_tx_origin_authorization_only() {
+        let findings = scan(
+            "contract A {
+                function f() external {
+                    require(tx.origin == owner);
+                    require(msg.sender == tx.origin, \"no contracts\");
+                    if (tx.origin != msg.sender) revert();
+                    emit Called(tx.origin);
+                }
+            }",
+        );
+        assert!(has(&findings, "SH001", 3));
+        assert_eq!(findings.iter().filter(|(id, _)| id == "SH001").count(), 1, "{:?}", findings);
+    }
+
+    #[test]
+    fn flags_the_unchecked_low_level_calls() {
+        let findings = scan(
+            "contract A {
+                function f(address to) external {
+                    to.call{value: 1}(\"\");
+                    (bool ok, ) = to.call(\"\");
+                    require(ok);
+                }
+            }",
+        );
+        assert!(has(&findings, "SH002", 3));
+        assert!(!has(&findings, "SH002", 4));
+    }
+
+    #[test]
+    fn flags_delegatecall_to_a_parameter_and_selfdestruct() {
+        let findings = scan(
+            "contract A {
+                function run(address target, bytes calldata data) external {
+                    target.delegatecall(data);
+                }
+                function kill() external onlyOwner {
+                    selfdestruct(payable(msg.sender));
+                }
+            }",
+        );
+        assert!(has(&findings, "SH003", 3));
+        assert!(has(&findings, "SH004", 6));
+        assert!(!findings.iter().any(|(id, _)| id == "SH008"), "{:?}", findings);
+    }
+
+    #[test]
+    fn flags_the_unprotected_initializers_and_privileged_functions() {
+        let findings = scan(
+            "contract A {
+                function initialize(address owner_) external {
+                    owner = owner_;
+                }
+                function setFee(uint256 fee_) public {
+                    fee = fee_;
+                }
+                function setLimit(uint256 limit_) external {
+                    require(msg.sender == owner);
+                    limit = limit_;
+                }
+                function setView() external view returns (uint256) {
+                    return fee;
+                }
+            }",
+        );
+        assert!(has(&findings, "SH005", 2));
+        assert!(has(&findings, "SH008", 5));
+        assert!(!has(&findings, "SH008", 8));
+        assert!(!has(&findings, "SH008", 12));
+    }
+
+    #[test]
+    fn flags_a_call_before_a_state_update() {
+        let findings = scan(
+            "contract A {
+                function withdraw(uint256 amount) external {
+                    uint256 balance = balances[msg.sender];
+                    (bool ok, ) = msg.sender.call{value: amount}(\"\");
+                    require(ok);
+                    balances[msg.sender] = balance - amount;
+                }
+                function safeWithdraw(uint256 amount) external {
+                    balances[msg.sender] -= amount;
+                    payable(msg.sender).transfer(amount);
+                }
+            }",
+        );
+        assert!(has(&findings, "SH006", 4));
+        assert!(!findings.iter().any(|(id, line)| id == "SH006" && *line > 7), "{:?}", findings);
+    }
+
+    #[test]
+    fn ignores_the_comments() {
+        let findings = scan("// selfdestruct(owner);\n/* pair.getReserves()\n selfdestruct(owner); */\nuint x = pair.getReserves();");
+        assert_eq!(findings, vec![(String::from("SH007"), 4)]);
+    }
+
+    #[test]
+    fn tells_the_libraries() {
+        assert!(is_library("@openzeppelin/contracts/token/ERC20.sol"));
+        assert!(is_library("lib/forge-std/src/Test.sol"));
+        assert!(!is_library("src/Vault.sol"));
+    }
+}
@@ -0,0 +1,522 @@
+use std::fmt;
+use ethers::{
+    providers::{Middleware, Provider, Ws},
+    types::{
+        spoof, Address, Bytes, CallConfig, CallFrame, GethDebugBuiltInTracerConfig, GethDebugBuiltInTracerType,
+        GethDebugTracerConfig, GethDebugTracerType, GethDebugTracingCallOptions, GethDebugTracingOptions, GethTrace,
+        GethTraceFrame, Log, TransactionRequest, H256, U256,
+    },
+    utils::hex,
+};
+use serde::{Deserialize, Serialize};
+use crate::analyzer::{
+    decoder::Decoder,
+    trace::{self, BalanceDelta, TraceCall, TraceLog},
+};
+use crate::config::Rule;
+use crate::error::{Error, Result};
+use crate::utils::{address_db, output::Record, tools};
+
+/// How many functions of a new contract `probe_contract()` calls
+const MAX_PROBES: usize = 8;
+
+/// @dev The call to simulate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallRequest {
+    pub from: Address,
+    pub to: Option<Address>, // None for a contract creation
+    pub value: U256,
+    pub input: Bytes,
+}
+
+impl CallRequest {
+    /// @dev Replay a tx, e.g. a suspicious tx seen in another block or a pending tx
+    /// @param client The provider
+    /// @param hash The tx hash
+    pub async fn from_tx<M: Middleware>(client: &M, hash: &str) -> Result<Self> {
+        let tx_hash: H256 = hash
+            .parse()
+            .map_err(|e| Error::InvalidInput(format!("Invalid tx hash {}: {}", hash, e)))?;
+        let tx = client
+            .get_transaction(tx_hash)
+            .await
+            .map_err(|e| Error::Rpc(e.to_string()))?
+            .ok_or_else(|| Error::InvalidInput(format!("Tx {} is not found", hash)))?;
+
+        Ok(CallRequest { from: tx.from, to: tx.to, value: tx.value, input: tx.input })
+    }
+
+    /// @param from The caller
+    /// @param to The called contract
+    /// @param value The ether sent, in wei
+    /// @param data The calldata in hex
+    pub fn new(from: &str, to: &str, value: U256, data: &str) -> Result<Self> {
+        let address = |address: &str| {
+            address
+                .parse::<Address>()
+                .map_err(|e| Error::InvalidInput(format!("Invalid address {}: {}", address, e)))
+        };
+        let input = hex::decode(data.trim_start_matches("0x"))
+            .map_err(|e| Error::InvalidInput(format!("Invalid calldata {}: {}", data, e)))?;
+
+        Ok(CallRequest { from: address(from)?, to: Some(address(to)?), value, input: Bytes::from(input) })
+    }
+}
+
+/// @dev Whether a Guardian rule would fire on the simulated tx
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleMatch {
+    pub rule: String, // E.g. `warning:0x...:removeLiquidity(...)`
+    pub fired: bool,
+    pub detail: String,
+}
+
+impl Record for RuleMatch {
+    fn headers() -> Vec<&'static str> {
+        vec!["rule", "fired", "detail"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.rule.clone(), self.fired.to_string(), self.detail.clone()]
+    }
+}
+
+/// @dev The result of a simulation on the fork
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Simulation {
+    pub block: u64, // The fork block the call ran on
+    pub from: String,
+    pub to: String,
+    pub success: bool,
+    pub error: String, // Empty if succeeded
+    pub root: TraceCall,
+    pub logs: Vec<TraceLog>,
+    pub deltas: Vec<BalanceDelta>, // The balance changes of every address
+    pub watched: Vec<BalanceDelta>, // The balance changes of the watched contracts
+    pub rules: Vec<RuleMatch>,
+}
+
+impl Simulation {
+    /// @return The rules which would fire
+    pub fn fired(&self) -> Vec<&RuleMatch> {
+        self.rules.iter().filter(|rule| rule.fired).collect()
+    }
+
+    /// @return True if the call moves the assets of a watched contract, or fires a rule
+    pub fn is_harmful(&self) -> bool {
+        self.success && (!self.watched.is_empty() || !self.fired().is_empty())
+    }
+}
+
+impl fmt::Display for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.success { String::from("success") } else { format!("reverted: {}", self.error) };
+        writeln!(f, "Simulation of {} -> {} at block {} ({})", self.from, self.to, self.block, status)?;
+
+        writeln!(f, "Calls:")?;
+        trace::write_call(f, &self.root, 1)?;
+
+        if !self.watched.is_empty() {
+            writeln!(f, "Balance changes of the watched contracts:")?;
+            for delta in &self.watched {
+                let asset = if delta.symbol.is_empty() { delta.asset.clone() } else { delta.symbol.clone() };
+                writeln!(f, "  {}{} {} {}", delta.address, trace::label(&delta.label), delta.amount(), asset)?;
+            }
+        }
+
+        for rule in self.fired() {
+            writeln!(f, "Rule `{}` would fire: {}", rule.rule, rule.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// @dev The result of `probe_contract()`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Probes {
+    pub harmful: Vec<Simulation>, // The calls which move the assets of the watched contracts or fire a rule
+    pub errors: Vec<(String, String)>, // The selectors which could not be simulated, and why
+}
+
+/// @dev Simulate calls with `debug_traceCall` on a local fork at its latest block, e.g. `anvil --fork-url <mainnet rpc>`.
+/// The state is not changed, and the balance of the caller is overridden if it cannot pay the value
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    client: Provider<Ws>,
+    api_key: Option<String>, // To fetch the ABIs
+    watched: Vec<String>, // Lowercase addresses
+    rules: Vec<Rule>,
+}
+
+impl Simulator {
+    /// @param fork_url WSS URL of the fork, e.g. `ws://127.0.0.1:8545`
+    /// @param api_key Etherscan API kEY to decode with the verified ABIs, None to only use the selector db
+    pub async fn connect(fork_url: &str, api_key: Option<String>) -> Result<Self> {
+        let client = Provider::<Ws>::connect(fork_url).await?;
+        Ok(Simulator { client, api_key, watched: Vec::new(), rules: Vec::new() })
+    }
+
+    /// @dev Report the balance changes of these contracts
+    /// @param addresses The watched contracts
+    pub fn with_watched(mut self, addresses: &[String]) -> Self {
+        self.watched.extend(addresses.iter().map(|address| address.to_lowercase()));
+        self
+    }
+
+    /// @dev Report whether these rules would fire. Their addresses are watched too
+    /// @param rules The Guardian rules
+    pub fn with_rules(mut self, rules: &[Rule]) -> Self {
+        for rule in rules {
+            match rule {
+                Rule::Message { address } | Rule::Warning { address, .. } | Rule::Event { address, .. } => {
+                    self.watched.push(address.to_lowercase());
+                }
+                Rule::Deployment { protocols, .. } => {
+                    self.watched.extend(protocols.iter().map(|address| address.to_lowercase()));
+                }
+                Rule::Poisoning { addresses } | Rule::Approval { addresses } => {
+                    self.watched.extend(addresses.iter().map(|address| address.to_lowercase()));
+                }
+                Rule::MixingService => {}
+            }
+        }
+        self.rules.extend_from_slice(rules);
+        self
+    }
+
+    /// @return The fork connection, e.g. to fetch a tx to replay
+    pub fn client(&self) -> &Provider<Ws> {
+        &self.client
+    }
+
+    /// @dev Run a call on the fork and explain its effect
+    /// @param call The call
+    pub async fn simulate(&self, call: &CallRequest) -> Result<Simulation> {
+        let block = self.client.get_block_number().await?.as_u64();
+
+        let mut request = TransactionRequest::new().from(call.from).value(call.value).data(call.input.clone());
+        if let Some(to) = call.to {
+            request = request.to(to);
+        }
+
+        let state_overrides = if self.client.get_balance(call.from, None).await? < call.value {
+            Some(spoof::balance(call.from, call.value))
+        } else {
+            None
+        };
+
+        let options = GethDebugTracingCallOptions {
+            tracing_options: GethDebugTracingOptions {
+                tracer: Some(GethDebugTracerType::BuiltInTracer(GethDebugBuiltInTracerType::CallTracer)),
+                tracer_config: Some(GethDebugTracerConfig::BuiltInTracer(GethDebugBuiltInTracerConfig::CallTracer(
+                    CallConfig { only_top_call: None, with_log: Some(true) },
+                ))),
+                ..Default::default()
+            },
+            state_overrides,
+            block_overrides: None,
+        };
+
+        let frame = match self.client.debug_trace_call(request, Some(block.into()), options).await {
+            Ok(GethTrace::Known(GethTraceFrame::CallTracer(frame))) => frame,
+            Ok(other) => return Err(Error::Rpc(format!("Unexpected debug_traceCall result: {:?}", other))),
+            Err(e) => return Err(Error::Rpc(format!("debug_traceCall failed, the fork must support it (e.g. anvil): {}", e))),
+        };
+
+        let labels = address_db::get_db_labels().unwrap_or_default();
+        let mut decoder = Decoder::new(self.api_key.clone())?;
+
+        let mut raw_logs = Vec::new();
+        frame_logs(&frame, &mut raw_logs);
+
+        let root = trace::decode_frame(&frame, &mut decoder, &labels).await;
+        let logs = trace::decode_logs(&raw_logs, &mut decoder, &labels).await;
+        let mut deltas = trace::balance_deltas(&frame, &raw_logs, &labels);
+        trace::fill_token_metadata(&self.client, &mut deltas).await;
+
+        let watched = deltas
+            .iter()
+            .filter(|delta| self.watched.contains(&delta.address.to_lowercase()))
+            .cloned()
+            .collect();
+        let rules = self.rules.iter().filter_map(|rule| evaluate(rule, &root, &logs)).collect();
+
+        Ok(Simulation {
+            block,
+            from: format!("{:?}", call.from),
+            to: trace::frame_to(&frame),
+            success: frame.error.is_none(),
+            error: frame.error.clone().unwrap_or_default(),
+            root,
+            logs,
+            deltas,
+            watched,
+            rules,
+        })
+    }
+
+    /// @dev Call the functions of a new contract as its deployer, without arguments, to see what an attack contract would do.
+    /// A fork stays at the block it was forked from, so it is reset to the latest block if the contract is not on it yet
+    /// @param deployer The deployer
+    /// @param contract The new contract
+    /// @param selectors Its dispatcher selectors
+    /// @return The harmful simulations, and the selectors which could not be simulated
+    pub async fn probe_contract(&self, deployer: &str, contract: &str, selectors: &[String]) -> Result<Probes> {
+        let address: Address = contract
+            .parse()
+            .map_err(|e| Error::InvalidInput(format!("Invalid address {}: {}", contract, e)))?;
+        if self.client.get_code(address, None).await?.is_empty() {
+            self.reset_fork().await?;
+            if self.client.get_code(address, None).await?.is_empty() {
+                return Err(Error::Rpc(format!("{} is not deployed on the fork, even after a reset to the latest block", contract)));
+            }
+        }
+
+        let mut probes = Probes::default();
+        for selector in selectors.iter().take(MAX_PROBES) {
+            let call = CallRequest::new(deployer, contract, U256::zero(), selector)?;
+            match self.simulate(&call).await {
+                Ok(simulation) if simulation.is_harmful() => probes.harmful.push(simulation),
+                Ok(_) => {}
+                Err(e) => probes.errors.push((selector.clone(), e.to_string())),
+            }
+        }
+        Ok(probes)
+    }
+
+    /// @dev Move the fork to the latest block of the chain it forks, with `anvil_reset`
+    pub async fn reset_fork(&self) -> Result<()> {
+        self.client
+            .request::<_, serde_json::Value>("anvil_reset", [serde_json::json!({ "forking": {} })])
+            .await
+            .map_err(|e| Error::Rpc(format!("anvil_reset failed, the fork must support it (e.g. anvil): {}", e)))?;
+        Ok(())
+    }
+}
+
+/// @dev The logs of the successful calls, in their order
+fn frame_logs(frame: &CallFrame, logs: &mut Vec<Log>) {
+    if frame.error.is_some() {
+        return; // A reverted call undoes its logs
+    }
+
+    for log in frame.logs.iter().flatten() {
+        logs.push(Log {
+            address: log.address.unwrap_or_default(),
+            topics: log.topics.clone().unwrap_or_default(),
+            data: log.data.clone().unwrap_or_default(),
+            log_index: Some(U256::from(logs.len())),
+            ..Default::default()
+        });
+    }
+    for inner in frame.calls.iter().flatten() {
+        frame_logs(inner, logs);
+    }
+}
+
+/// @dev Whether a rule would fire on the call tree
+/// @return None if the rule does not look at single txs, e.g. `deployment`, `poisoning` or `approval`
+fn evaluate(rule: &Rule, root: &TraceCall, logs: &[TraceLog]) -> Option<RuleMatch> {
+    let mut calls = Vec::new();
+    successful_calls(root, &mut calls);
+
+    match rule {
+        Rule::Message { address } => {
+            let count = calls
+                .iter()
+                .filter(|call| call.to.eq_ignore_ascii_case(address) || call.from.eq_ignore_ascii_case(address))
+                .count();
+            Some(RuleMatch {
+                rule: format!("message:{}", address.to_lowercase()),
+                fired: count > 0,
+                detail: format!("{} calls from / to {}", count, address),
+            })
+        }
+        Rule::Warning { address, call, limit } => {
+            let selector = tools::function_sig(call);
+            let count = calls
+                .iter()
+                .filter(|c| c.to.eq_ignore_ascii_case(address) && c.input.starts_with(&selector))
+                .count() as u32;
+            Some(RuleMatch {
+                rule: format!("warning:{}:{}", address.to_lowercase(), call),
+                fired: count > *limit,
+                detail: format!("{} `{}` calls in one tx, the limit is {} in 240 blocks", count, call, limit),
+            })
+        }
+        Rule::Event { address, event } => {
+            let topic = tools::event_topic(event);
+            let count = logs
+                .iter()
+                .filter(|log| log.address.eq_ignore_ascii_case(address) && log.topics.first() == Some(&topic))
+                .count();
+            Some(RuleMatch {
+                rule: format!("event:{}:{}", address.to_lowercase(), event),
+                fired: count > 0,
+                detail: format!("{} `{}` logs", count, event),
+            })
+        }
+        Rule::MixingService => {
+            let mixers = address_db::get_db_address("mixing_service").unwrap_or_default();
+            let mixer = calls.iter().find_map(|call| {
+                mixers.iter().find(|mixer| call.to.eq_ignore_ascii_case(mixer) || call.from.eq_ignore_ascii_case(mixer))
+            });
+            Some(RuleMatch {
+                rule: String::from("mixing_service"),
+                fired: mixer.is_some(),
+                detail: mixer.map_or(String::from("no mixing service call"), |mixer| format!("calls the mixing service {}", mixer)),
+            })
+        }
+        Rule::Deployment { .. } | Rule::Poisoning { .. } | Rule::Approval { .. } => None,
+    }
+}
+
+fn successful_calls<'a>(call: &'a TraceCall, calls: &mut Vec<&'a TraceCall>) {
+    if !call.error.is_empty() {
+        return;
+    }
+    calls.push(call);
+    for inner in &call.calls {
+        successful_calls(inner, calls);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::CallLogFrame;
+
+    const VAULT: &str = "0x00000000000000000000000000000000000000bb";
+    const WITHDRAW: &str = "withdraw(uint256)";
+
+    fn call(from: &str, to: &str, input: &str, error: &str, calls: Vec<TraceCall>) -> TraceCall {
+        TraceCall {
+            kind: String::from("CALL"),
+            from: from.to_string(),
+            to: to.to_string(),
+            label: String::new(),
+            value: String::from("0"),
+            gas_used: 0,
+            input: input.to_string(),
+            output: String::new(),
+            error: error.to_string(),
+            decoded: None,
+            calls,
+        }
+    }
+
+    fn simulation(root: TraceCall, rules: Vec<RuleMatch>) -> Simulation {
+        Simulation {
+            block: 1,
+            from: root.from.clone(),
+            to: root.to.clone(),
+            success: root.error.is_empty(),
+            error: root.error.clone(),
+            root,
+            logs: Vec::new(),
+            deltas: Vec::new(),
+            watched: Vec::new(),
+            rules,
+        }
+    }
+
+    #[test]
+    fn evaluates_the_rules_on_the_successful_calls() {
+        let withdraw = format!("{}{}", tools::function_sig(WITHDRAW), "0".repeat(64));
+        // Two withdrawals, the third one reverted
+        let root = call(
+            "0x00000000000000000000000000000000000000aa",
+            "0x00000000000000000000000000000000000000cc",
+            "0x",
+            "",
+            vec![
+                call("0x00000000000000000000000000000000000000cc", VAULT, &withdraw, "", vec![]),
+                call("0x00000000000000000000000000000000000000cc", VAULT, &withdraw, "", vec![]),
+                call("0x00000000000000000000000000000000000000cc", VAULT, &withdraw, "execution reverted", vec![]),
+            ],
+        );
+
+        let warning = |limit| Rule::Warning { address: VAULT.replace("bb", "BB"), call: WITHDRAW.to_string(), limit };
+        let fired = evaluate(&warning(1), &root, &[]).unwrap();
+        assert!(fired.fired);
+        assert_eq!(fired.detail, "2 `withdraw(uint256)` calls in one tx, the limit is 1 in 240 blocks");
+        assert!(!evaluate(&warning(2), &root, &[]).unwrap().fired);
+
+        let message = evaluate(&Rule::Message { address: VAULT.to_string() }, &root, &[]).unwrap();
+        assert_eq!((message.fired, message.detail.as_str()), (true, "2 calls from / to 0x00000000000000000000000000000000000000bb"));
+
+        let event = Rule::Event { address: VAULT.to_string(), event: String::from("Withdraw(address,uint256)") };
+        assert!(!evaluate(&event, &root, &[]).unwrap().fired);
+        assert!(evaluate(&Rule::Approval { addresses: vec![] }, &root, &[]).is_none());
+    }
+
+    #[test]
+    fn harmful_only_if_succeeded() {
+        let fired = RuleMatch { rule: String::from("message:0xbb"), fired: true, detail: String::new() };
+        let root = call("0x00000000000000000000000000000000000000aa", VAULT, "0x", "", vec![]);
+        assert!(simulation(root.clone(), vec![fired.clone()]).is_harmful());
+        assert!(!simulation(root.clone(), vec![RuleMatch { fired: false, ..fired.clone() }]).is_harmful());
+
+        let reverted = call("0x00000000000000000000000000000000000000aa", VAULT, "0x", "execution reverted", vec![]);
+        assert!(!simulation(reverted, vec![fired]).is_harmful());
+    }
+
+    #[test]
+    fn the_logs_of_the_reverted_calls_are_undone() {
+        let log = |data: u8| CallLogFrame {
+            address: Some(VAULT.parse().unwrap()),
+            topics: Some(vec![H256::zero()]),
+            data: Some(Bytes::from(vec![data])),
+        };
+        let frame: CallFrame = serde_json::from_value(serde_json::json!({
+            "type": "CALL",
+            "from": "0x00000000000000000000000000000000000000aa",
+            "to": VAULT,
+            "input": "0x",
+            "calls": [
+                { "type": "CALL", "from": VAULT, "to": VAULT, "input": "0x", "error": "execution reverted", "logs": [log(2)] },
+                { "type": "CALL", "from": VAULT, "to": VAULT, "input": "0x", "logs": [log(3)] }
+            ],
+            "logs": [log(1)]
+        }))
+        .unwrap();
+
+        let mut logs = Vec::new();
+        frame_logs(&frame, &mut logs);
+        let data: Vec<u8> = logs.iter().map(|log| log.data[0]).collect();
+        assert_eq!(data, vec![1, 3]);
+        assert_eq!(logs[1].log_index, Some(U256::one()));
+    }
+
+    #[test]
+    fn rejects_an_invalid_call() {
+        assert!(CallRequest::new("0xaa", VAULT, U256::zero(), "0x").is_err());
+        assert!(CallRequest::new(VAULT, VAULT, U256::zero(), "0xzz").is_err());
+        let call = CallRequest::new(VAULT, VAULT, U256::zero(), "0x2e1a7d4d").unwrap();
+        assert_eq!(call.input.to_vec(), vec![0x2e, 0x1a, 0x7d, 0x4d]);
+    }
+
+    /// @dev Needs `anvil` listening on `ws://127.0.0.1:8545`
+    #[tokio::test]
+    #[ignore = "needs anvil"]
+    async fn simulates_a_transfer_on_anvil() {
+        let simulator = Simulator::connect("ws://127.0.0.1:8545", None).await.unwrap();
+        let accounts = simulator.client().get_accounts().await.unwrap();
+        let receiver = format!("{:?}", accounts[1]);
+        let simulator = simulator.with_rules(&[Rule::Message { address: receiver.clone() }]);
+
+        // The caller cannot pay the value, its balance is overridden
+        let caller = "0x00000000000000000000000000000000000000aa";
+        let call = CallRequest::new(caller, &receiver, U256::exp10(18), "0x").unwrap();
+        let balance = simulator.client().get_balance(call.from, None).await.unwrap();
+        let simulation = simulator.simulate(&call).await.unwrap();
+
+        assert!(simulation.success);
+        assert!(simulation.is_harmful());
+        assert_eq!(simulation.watched.len(), 1);
+        assert_eq!(simulation.watched[0].amount(), "1");
+        assert_eq!(simulation.fired().len(), 1);
+        // The state is not changed
+        assert_eq!(simulator.client().get_balance(call.from, None).await.unwrap(), balance);
+    }
+}
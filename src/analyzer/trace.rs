@@ -0,0 +1,620 @@
+use std::{collections::{BTreeMap, HashMap}, fmt};
+use ethers::{
+    abi::{ParamType, Token},
+    providers::Middleware,
+    types::{
+        Address, Bytes, CallFrame, GethDebugBuiltInTracerType, GethDebugTracerType, GethDebugTracingOptions, GethTrace,
+        GethTraceFrame, Log, NameOrAddress, Transaction, TransactionReceipt, TransactionRequest, H256, I256, U256,
+    },
+    utils::{format_ether, format_units, hex},
+};
+use serde::{Deserialize, Serialize};
+use crate::analyzer::decoder::{DecodedCall, DecodedEvent, Decoder};
+use crate::error::{Error, Result};
+use crate::utils::{address_db, output::Record, tools};
+
+/// The asset name of ether in `BalanceDelta`
+pub const ETHER: &str = "ETH";
+
+/// @dev A call frame of the `callTracer` trace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceCall {
+    pub kind: String, // CALL, DELEGATECALL, STATICCALL, CREATE, CREATE2, SELFDESTRUCT...
+    pub from: String,
+    pub to: String,
+    pub label: String, // The label of `to` in db
+    pub value: String, // In wei
+    pub gas_used: u64,
+    pub input: String,
+    pub output: String,
+    pub error: String, // Empty if the call succeeded
+    pub decoded: Option<DecodedCall>,
+    pub calls: Vec<TraceCall>,
+}
+
+/// @dev An event log of the receipt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceLog {
+    pub index: u64,
+    pub address: String,
+    pub label: String, // The label of `address` in db
+    pub topics: Vec<String>,
+    pub data: String,
+    pub decoded: Option<DecodedEvent>,
+}
+
+/// @dev How much of an asset an address gained (positive) or lost (negative) in the tx
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceDelta {
+    pub address: String,
+    pub label: String, // The label in db
+    pub asset: String, // `ETH` or the token address
+    pub symbol: String, // Empty if unknown
+    pub delta: String, // In the smallest unit, e.g. wei
+    pub decimals: Option<u32>,
+}
+
+impl BalanceDelta {
+    /// @return The delta in whole units, e.g. `-1.5` for -1.5 ETH
+    pub fn amount(&self) -> String {
+        format_amount(&self.delta, self.decimals)
+    }
+}
+
+impl Record for BalanceDelta {
+    fn headers() -> Vec<&'static str> {
+        vec!["address", "label", "asset", "symbol", "delta", "amount"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.address.clone(),
+            self.label.clone(),
+            self.asset.clone(),
+            self.symbol.clone(),
+            self.delta.clone(),
+            self.amount(),
+        ]
+    }
+}
+
+/// @dev What a transaction did: its call tree, events and balance changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxTrace {
+    pub hash: String,
+    pub block: Option<u64>, // None if pending
+    pub from: String,
+    pub to: String, // Empty for a contract creation
+    pub status: Option<bool>, // None if pending
+    pub gas_used: Option<u64>,
+    pub root: TraceCall,
+    pub traced: bool, // False if the node does not support `debug_traceTransaction`, then `root` has no inner calls
+    pub logs: Vec<TraceLog>,
+    pub deltas: Vec<BalanceDelta>,
+}
+
+/// @dev The call tree, the events and the balance changes, e.g.
+/// ```text
+/// CALL 0xabc.. -> 0xdef.. (protocol: Router) 1 ETH swap(...)
+///   STATICCALL 0xdef.. -> 0x123.. balanceOf(account: 0xdef..)
+/// ```
+impl fmt::Display for TxTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Some(true) => "success",
+            Some(false) => "reverted",
+            None => "pending",
+        };
+        writeln!(f, "Tx {} ({})", self.hash, status)?;
+        if let Some(block) = self.block {
+            writeln!(f, "Block: {}", block)?;
+        }
+        if let Some(gas_used) = self.gas_used {
+            writeln!(f, "Gas used: {}", gas_used)?;
+        }
+        if !self.traced {
+            writeln!(f, "The node does not support debug_traceTransaction, only the top call is shown")?;
+        }
+
+        writeln!(f, "\nCalls:")?;
+        write_call(f, &self.root, 1)?;
+
+        if !self.logs.is_empty() {
+            writeln!(f, "\nEvents:")?;
+            for log in &self.logs {
+                let event = match &log.decoded {
+                    Some(decoded) => decoded.to_string(),
+                    None => format!("{} {}", log.topics.first().cloned().unwrap_or_default(), log.data),
+                };
+                writeln!(f, "  [{}] {}{} {}", log.index, log.address, label(&log.label), event)?;
+            }
+        }
+
+        if !self.deltas.is_empty() {
+            writeln!(f, "\nBalance changes:")?;
+            for delta in &self.deltas {
+                let asset = if delta.symbol.is_empty() { delta.asset.clone() } else { delta.symbol.clone() };
+                let amount = delta.amount();
+                let sign = if amount.starts_with('-') { "" } else { "+" };
+                writeln!(f, "  {}{} {}{} {}", delta.address, label(&delta.label), sign, amount, asset)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write_call(f: &mut fmt::Formatter<'_>, call: &TraceCall, depth: usize) -> fmt::Result {
+    let value = U256::from_dec_str(&call.value).unwrap_or_default();
+    let value = if value.is_zero() { String::new() } else { format!(" {} ETH", trim_zeros(&format_ether(value))) };
+    let function = match &call.decoded {
+        Some(decoded) => format!(" {}", decoded),
+        None if call.input.len() > 2 && !call.kind.starts_with("CREATE") => format!(" {}", &call.input[..call.input.len().min(10)]),
+        None => String::new(),
+    };
+    let error = if call.error.is_empty() { String::new() } else { format!(" => REVERT: {}", call.error) };
+
+    writeln!(f, "{}{} {} -> {}{}{}{}{}", "  ".repeat(depth), call.kind, call.from, call.to, label(&call.label), value, function, error)?;
+    for inner in &call.calls {
+        write_call(f, inner, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// @dev Format a signed amount in the smallest unit as whole units, e.g. `-1500000` with 6 decimals => `-1.5`
+/// @param raw The amount, e.g. in wei
+/// @param decimals The decimals of the asset, None to keep `raw`
+pub fn format_amount(raw: &str, decimals: Option<u32>) -> String {
+    let Some(decimals) = decimals else { return raw.to_string() };
+    let (sign, unsigned) = match raw.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", raw),
+    };
+    match U256::from_dec_str(unsigned).ok().and_then(|unsigned| format_units(unsigned, decimals).ok()) {
+        Some(amount) => format!("{}{}", sign, trim_zeros(&amount)),
+        None => raw.to_string(),
+    }
+}
+
+/// @dev `1.500000` => `1.5`
+fn trim_zeros(amount: &str) -> String {
+    if !amount.contains('.') {
+        return amount.to_string();
+    }
+    amount.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+pub(crate) fn label(label: &str) -> String {
+    if label.is_empty() { String::new() } else { format!(" ({})", label) }
+}
+
+/// @dev Fetch a transaction, its receipt and its `callTracer` trace, then decode and explain it.
+/// A node without `debug_traceTransaction` (e.g. most public RPCs) still gives the top call, the events and the token deltas
+/// @param client The provider, e.g. a local anvil node (`ws://127.0.0.1:8545`)
+/// @param decoder Decode the calls and the events
+/// @param hash The tx hash
+pub async fn trace_transaction<M: Middleware>(client: &M, decoder: &mut Decoder, hash: &str) -> Result<TxTrace> {
+    let tx_hash: H256 = hash
+        .parse()
+        .map_err(|e| Error::InvalidInput(format!("Invalid tx hash {}: {}", hash, e)))?;
+
+    let tx = client
+        .get_transaction(tx_hash)
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?
+        .ok_or_else(|| Error::InvalidInput(format!("Tx {} is not found", hash)))?;
+    let receipt = client
+        .get_transaction_receipt(tx_hash)
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?;
+
+    let frame = match receipt {
+        Some(_) => call_trace(client, tx_hash).await,
+        None => None, // Pending, not traceable yet
+    };
+
+    let labels = address_db::get_db_labels().unwrap_or_default();
+    let mut trace = build_trace(&tx, receipt.as_ref(), frame, decoder, &labels).await;
+    fill_token_metadata(client, &mut trace.deltas).await;
+
+    Ok(trace)
+}
+
+/// @dev Fill the symbols and decimals of the tokens in the balance deltas
+/// @param client The provider
+/// @param deltas The balance deltas
+pub async fn fill_token_metadata<M: Middleware>(client: &M, deltas: &mut [BalanceDelta]) {
+    let mut tokens: HashMap<String, (String, Option<u32>)> = HashMap::new();
+    for delta in deltas.iter_mut().filter(|delta| delta.asset != ETHER) {
+        if !tokens.contains_key(&delta.asset) {
+            tokens.insert(delta.asset.clone(), token_metadata(client, &delta.asset).await);
+        }
+        let (symbol, decimals) = tokens[&delta.asset].clone();
+        delta.symbol = symbol;
+        if delta.decimals.is_none() {
+            delta.decimals = decimals;
+        }
+    }
+}
+
+/// @dev The `callTracer` trace of a tx, None if the node does not support it
+async fn call_trace<M: Middleware>(client: &M, tx_hash: H256) -> Option<CallFrame> {
+    let options = GethDebugTracingOptions {
+        tracer: Some(GethDebugTracerType::BuiltInTracer(GethDebugBuiltInTracerType::CallTracer)),
+        ..Default::default()
+    };
+
+    match client.debug_trace_transaction(tx_hash, options).await {
+        Ok(GethTrace::Known(GethTraceFrame::CallTracer(frame))) => Some(frame),
+        Ok(other) => {
+            eprintln!("Unexpected debug_traceTransaction result: {:?}", other);
+            None
+        }
+        Err(e) => {
+            eprintln!("debug_traceTransaction failed, only the top call is shown: {}", e);
+            None
+        }
+    }
+}
+
+/// @dev Decode a fetched tx: the call tree from the trace (or the top call), the events and the balance deltas.
+/// The symbols of the tokens are not filled
+/// @param tx The transaction
+/// @param receipt Its receipt, None if pending
+/// @param frame Its `callTracer` trace, None if not traced
+/// @param decoder Decode the calls and the events
+/// @param labels The labels of the addresses in db
+pub async fn build_trace(
+    tx: &Transaction,
+    receipt: Option<&TransactionReceipt>,
+    frame: Option<CallFrame>,
+    decoder: &mut Decoder,
+    labels: &HashMap<String, String>,
+) -> TxTrace {
+    let traced = frame.is_some();
+    let frame = frame.unwrap_or_else(|| CallFrame {
+        typ: String::from(if tx.to.is_some() { "CALL" } else { "CREATE" }),
+        from: tx.from,
+        to: tx.to.or_else(|| receipt.and_then(|r| r.contract_address)).map(NameOrAddress::Address),
+        value: Some(tx.value),
+        gas: tx.gas,
+        gas_used: receipt.and_then(|r| r.gas_used).unwrap_or_default(),
+        input: tx.input.clone(),
+        output: None,
+        error: match receipt.and_then(|r| r.status) {
+            Some(status) if status.is_zero() => Some(String::from("execution reverted")),
+            _ => None,
+        },
+        calls: None,
+        logs: None,
+    });
+
+    let root = decode_frame(&frame, decoder, labels).await;
+    let receipt_logs = receipt.map(|r| r.logs.as_slice()).unwrap_or_default();
+    let logs = decode_logs(receipt_logs, decoder, labels).await;
+    let deltas = balance_deltas(&frame, receipt_logs, labels);
+
+    TxTrace {
+        hash: format!("{:?}", tx.hash),
+        block: tx.block_number.map(|block| block.as_u64()),
+        from: format!("{:?}", tx.from),
+        to: tx.to.map(|to| format!("{:?}", to)).unwrap_or_default(),
+        status: receipt.and_then(|r| r.status).map(|status| !status.is_zero()),
+        gas_used: receipt.and_then(|r| r.gas_used).map(|gas| gas.as_u64()),
+        root,
+        traced,
+        logs,
+        deltas,
+    }
+}
+
+/// @dev Decode the event logs
+/// @param logs The logs, e.g. of a receipt
+/// @param decoder Decode the events
+/// @param labels The labels of the addresses in db
+pub async fn decode_logs(logs: &[Log], decoder: &mut Decoder, labels: &HashMap<String, String>) -> Vec<TraceLog> {
+    let mut decoded_logs = Vec::new();
+    for log in logs {
+        let address = format!("{:?}", log.address);
+        decoded_logs.push(TraceLog {
+            index: log.log_index.map_or(decoded_logs.len() as u64, |index| index.as_u64()),
+            label: labels.get(&address).cloned().unwrap_or_default(),
+            topics: log.topics.iter().map(|topic| format!("{:?}", topic)).collect(),
+            data: format!("0x{}", hex::encode(&log.data)),
+            decoded: decoder.decode_event(&address, &log.topics, &log.data).await,
+            address,
+        });
+    }
+    decoded_logs
+}
+
+/// @dev The ether moved by the calls and the tokens moved by the logs, per address. The symbols of the tokens are not filled
+/// @param frame The `callTracer` trace
+/// @param logs The logs of the successful calls
+/// @param labels The labels of the addresses in db
+pub fn balance_deltas(frame: &CallFrame, logs: &[Log], labels: &HashMap<String, String>) -> Vec<BalanceDelta> {
+    let mut changes: BTreeMap<(String, String), (I256, Option<u32>)> = BTreeMap::new();
+    ether_deltas(frame, &mut changes);
+    for log in logs {
+        token_deltas(log, &mut changes);
+    }
+
+    changes
+        .into_iter()
+        .filter(|(_, (delta, _))| !delta.is_zero())
+        .map(|((address, asset), (delta, decimals))| BalanceDelta {
+            label: labels.get(&address).cloned().unwrap_or_default(),
+            symbol: if asset == ETHER { asset.clone() } else { String::new() },
+            address,
+            asset,
+            delta: delta.to_string(),
+            decimals,
+        })
+        .collect()
+}
+
+/// @dev Decode a call frame and its inner calls
+pub(crate) fn decode_frame<'a>(
+    frame: &'a CallFrame,
+    decoder: &'a mut Decoder,
+    labels: &'a HashMap<String, String>,
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = TraceCall> + Send + 'a>> {
+    Box::pin(async move {
+        let to = frame_to(frame);
+        let input = format!("0x{}", hex::encode(&frame.input));
+        let decoded = if frame.typ.starts_with("CREATE") || to.is_empty() {
+            None
+        } else {
+            decoder.decode(&to, &input).await.ok().flatten()
+        };
+
+        let mut calls = Vec::new();
+        for inner in frame.calls.iter().flatten() {
+            calls.push(decode_frame(inner, decoder, labels).await);
+        }
+
+        TraceCall {
+            kind: frame.typ.clone(),
+            from: format!("{:?}", frame.from),
+            label: labels.get(&to).cloned().unwrap_or_default(),
+            to,
+            value: frame.value.unwrap_or_default().to_string(),
+            gas_used: frame.gas_used.low_u64(),
+            input,
+            output: frame.output.as_ref().map(|output| format!("0x{}", hex::encode(output))).unwrap_or_default(),
+            error: frame.error.clone().unwrap_or_default(),
+            decoded,
+            calls,
+        }
+    })
+}
+
+pub(crate) fn frame_to(frame: &CallFrame) -> String {
+    match &frame.to {
+        Some(NameOrAddress::Address(address)) => format!("{:?}", address),
+        Some(NameOrAddress::Name(name)) => name.clone(),
+        None => String::new(),
+    }
+}
+
+/// @dev The ether moved by the successful calls. DELEGATECALL / STATICCALL do not move ether, and a reverted call undoes its inner calls
+fn ether_deltas(frame: &CallFrame, changes: &mut BTreeMap<(String, String), (I256, Option<u32>)>) {
+    if frame.error.is_some() {
+        return;
+    }
+
+    let value = frame.value.unwrap_or_default();
+    let to = frame_to(frame);
+    if !value.is_zero() && !to.is_empty() && !matches!(frame.typ.as_str(), "DELEGATECALL" | "STATICCALL" | "CALLCODE") {
+        let value = I256::from_raw(value);
+        add(changes, format!("{:?}", frame.from), ETHER.to_string(), -value, Some(18));
+        add(changes, to, ETHER.to_string(), value, Some(18));
+    }
+
+    for inner in frame.calls.iter().flatten() {
+        ether_deltas(inner, changes);
+    }
+}
+
+/// @dev The tokens moved by `Transfer` (ERC20 / ERC721) and wrapped by WETH `Deposit` / `Withdrawal`
+fn token_deltas(log: &Log, changes: &mut BTreeMap<(String, String), (I256, Option<u32>)>) {
+    let Some(topic) = log.topics.first() else { return };
+    let topic = format!("{:?}", topic);
+    let token = format!("{:?}", log.address);
+    let address = |index: usize| format!("{:?}", Address::from(log.topics[index]));
+    let amount = || I256::from_raw(U256::from_big_endian(&log.data[..32.min(log.data.len())]));
+
+    if topic == tools::event_topic("Transfer(address,address,uint256)") {
+        match (log.topics.len(), log.data.len()) {
+            (3, 32) => {
+                // ERC20
+                add(changes, address(1), token.clone(), -amount(), None);
+                add(changes, address(2), token, amount(), None);
+            }
+            (4, 0) => {
+                // ERC721, one token
+                add(changes, address(1), token.clone(), I256::minus_one(), Some(0));
+                add(changes, address(2), token, I256::one(), Some(0));
+            }
+            _ => {}
+        }
+    } else if topic == tools::event_topic("Deposit(address,uint256)") && log.topics.len() == 2 && log.data.len() == 32 {
+        add(changes, address(1), token, amount(), None);
+    } else if topic == tools::event_topic("Withdrawal(address,uint256)") && log.topics.len() == 2 && log.data.len() == 32 {
+        add(changes, address(1), token, -amount(), None);
+    }
+}
+
+fn add(changes: &mut BTreeMap<(String, String), (I256, Option<u32>)>, address: String, asset: String, delta: I256, decimals: Option<u32>) {
+    let entry = changes.entry((address, asset)).or_insert((I256::zero(), decimals));
+    entry.0 = entry.0.saturating_add(delta);
+}
+
+/// @dev The `symbol()` and `decimals()` of a token, empty / None if it does not have them
+async fn token_metadata<M: Middleware>(client: &M, token: &str) -> (String, Option<u32>) {
+    let Ok(token) = token.parse::<Address>() else { return (String::new(), None) };
+    let call = |signature: &str| {
+        let selector = hex::decode(tools::function_sig(signature).trim_start_matches("0x")).unwrap_or_default();
+        TransactionRequest::new().to(token).data(Bytes::from(selector))
+    };
+
+    let symbol = match client.call(&call("symbol()").into(), None).await {
+        Ok(output) => match ethers::abi::decode(&[ParamType::String], &output) {
+            Ok(tokens) => match tokens.first() {
+                Some(Token::String(symbol)) => symbol.clone(),
+                _ => String::new(),
+            },
+            // Some old tokens return bytes32, e.g. MKR
+            Err(_) if output.len() == 32 => String::from_utf8_lossy(&output).trim_end_matches('\0').to_string(),
+            Err(_) => String::new(),
+        },
+        Err(_) => String::new(),
+    };
+    let decimals = match client.call(&call("decimals()").into(), None).await {
+        Ok(output) if output.len() == 32 => Some(U256::from_big_endian(&output).low_u32()),
+        _ => None,
+    };
+
+    (symbol, decimals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{Provider, Ws};
+
+    const A: &str = "0x00000000000000000000000000000000000000aa";
+    const B: &str = "0x00000000000000000000000000000000000000bb";
+    const C: &str = "0x00000000000000000000000000000000000000cc";
+    const TOKEN: &str = "0x00000000000000000000000000000000000000dd";
+
+    fn frame(typ: &str, from: &str, to: &str, value: u64, error: Option<&str>, calls: Vec<CallFrame>) -> CallFrame {
+        CallFrame {
+            typ: typ.to_string(),
+            from: from.parse().unwrap(),
+            to: Some(NameOrAddress::Address(to.parse().unwrap())),
+            value: Some(U256::from(value)),
+            gas: U256::zero(),
+            gas_used: U256::zero(),
+            input: Bytes::default(),
+            output: None,
+            error: error.map(String::from),
+            calls: Some(calls),
+            logs: None,
+        }
+    }
+
+    fn log(event: &str, addresses: &[&str], data: Vec<u8>) -> Log {
+        let mut topics = vec![event_topic(event)];
+        topics.extend(addresses.iter().map(|address| H256::from(address.parse::<Address>().unwrap())));
+        Log { address: TOKEN.parse().unwrap(), topics, data: Bytes::from(data), ..Default::default() }
+    }
+
+    fn event_topic(event: &str) -> H256 {
+        tools::event_topic(event).parse().unwrap()
+    }
+
+    fn word(amount: u64) -> Vec<u8> {
+        let mut word = [0u8; 32];
+        U256::from(amount).to_big_endian(&mut word);
+        word.to_vec()
+    }
+
+    fn delta<'a>(deltas: &'a [BalanceDelta], address: &str, asset: &str) -> Option<&'a BalanceDelta> {
+        deltas.iter().find(|delta| delta.address == address && delta.asset == asset)
+    }
+
+    #[test]
+    fn formats_the_amounts() {
+        assert_eq!(format_amount("-1500000", Some(6)), "-1.5");
+        assert_eq!(format_amount("1000000000000000000", Some(18)), "1");
+        assert_eq!(format_amount("1", Some(0)), "1");
+        assert_eq!(format_amount("123", None), "123");
+        assert_eq!(format_amount("not a number", Some(18)), "not a number");
+    }
+
+    #[test]
+    fn ether_deltas_skip_the_delegatecalls_and_the_reverted_calls() {
+        let root = frame(
+            "CALL",
+            A,
+            B,
+            1000,
+            None,
+            vec![
+                frame("CALL", B, C, 400, None, vec![]),
+                frame("DELEGATECALL", B, C, 1000, None, vec![]),
+                frame("CALL", B, C, 300, Some("execution reverted"), vec![frame("CALL", C, A, 300, None, vec![])]),
+            ],
+        );
+        let labels = HashMap::from([(B.to_string(), String::from("protocol: Vault"))]);
+        let deltas = balance_deltas(&root, &[], &labels);
+
+        assert_eq!(deltas.len(), 3);
+        assert_eq!(delta(&deltas, A, ETHER).unwrap().delta, "-1000");
+        assert_eq!(delta(&deltas, B, ETHER).unwrap().delta, "600");
+        assert_eq!(delta(&deltas, B, ETHER).unwrap().label, "protocol: Vault");
+        assert_eq!(delta(&deltas, C, ETHER).unwrap().delta, "400");
+        assert_eq!(delta(&deltas, C, ETHER).unwrap().amount(), "0.0000000000000004");
+    }
+
+    #[test]
+    fn token_deltas_of_the_transfers_and_weth() {
+        let logs = [
+            log("Transfer(address,address,uint256)", &[A, B], word(100)),
+            log("Transfer(address,address,uint256)", &[B, C], word(30)),
+            // ERC721, the token id is the third topic
+            log("Transfer(address,address,uint256)", &[C, A, "0x0000000000000000000000000000000000000007"], vec![]),
+            log("Deposit(address,uint256)", &[A], word(5)),
+            log("Withdrawal(address,uint256)", &[C], word(2)),
+            // Not a transfer
+            log("Approval(address,address,uint256)", &[A, B], word(1000)),
+        ];
+        let root = frame("CALL", A, TOKEN, 0, None, vec![]);
+        let deltas = balance_deltas(&root, &logs, &HashMap::new());
+
+        assert_eq!(delta(&deltas, A, TOKEN).unwrap().delta, "-94"); // -100 +1 +5
+        assert_eq!(delta(&deltas, B, TOKEN).unwrap().delta, "70");
+        assert_eq!(delta(&deltas, C, TOKEN).unwrap().delta, "27"); // +30 -1 -2
+        assert!(deltas.iter().all(|delta| delta.symbol.is_empty()));
+    }
+
+    #[test]
+    fn a_cancelled_delta_is_dropped() {
+        let logs = [
+            log("Transfer(address,address,uint256)", &[A, B], word(100)),
+            log("Transfer(address,address,uint256)", &[B, A], word(100)),
+        ];
+        let root = frame("CALL", A, TOKEN, 0, None, vec![]);
+        assert!(balance_deltas(&root, &logs, &HashMap::new()).is_empty());
+    }
+
+    /// @dev Needs `anvil` listening on `ws://127.0.0.1:8545`
+    #[tokio::test]
+    #[ignore = "needs anvil"]
+    async fn traces_a_transfer_on_anvil() {
+        let client = Provider::<Ws>::connect("ws://127.0.0.1:8545").await.unwrap();
+        let accounts = client.get_accounts().await.unwrap();
+        let value = U256::exp10(18);
+        let receipt = client
+            .send_transaction(TransactionRequest::new().from(accounts[0]).to(accounts[1]).value(value), None)
+            .await
+            .unwrap()
+            .await
+            .unwrap()
+            .unwrap();
+
+        let cache = std::env::temp_dir().join(format!("sechelper-abis-{}", std::process::id()));
+        let mut decoder = Decoder::new(None).unwrap().with_cache_dir(&cache.to_string_lossy());
+        let trace = trace_transaction(&client, &mut decoder, &format!("{:?}", receipt.transaction_hash)).await.unwrap();
+
+        assert!(trace.traced);
+        assert_eq!(trace.status, Some(true));
+        assert_eq!(trace.root.kind, "CALL");
+        let from = format!("{:?}", accounts[0]);
+        let to = format!("{:?}", accounts[1]);
+        assert_eq!(delta(&trace.deltas, &from, ETHER).unwrap().amount(), "-1");
+        assert_eq!(delta(&trace.deltas, &to, ETHER).unwrap().amount(), "1");
+
+        let _ = std::fs::remove_dir_all(cache);
+    }
+}
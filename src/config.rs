@@ -0,0 +1,487 @@
+use std::{collections::HashMap, env, fs, path::Path};
+use ethers::types::Address;
+use serde::Deserialize;
+use crate::error::{Error, Result};
+
+/// The config file used when `--config` is not given
+pub const DEFAULT_CONFIG_PATH: &str = "SecHelper.toml";
+
+/// @dev The config file, e.g.
+/// ```toml
+/// default_profile = "mainnet"
+///
+/// [profiles.mainnet]
+/// chain = "eth"
+/// rpc_url = "wss://ethereum-rpc.publicnode.com"
+/// etherscan_api_key = { env = "ETHERSCAN_API_KEY" }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// @dev One chain / environment in the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub chain: Option<String>, // "eth" or "bsc"
+    pub rpc_url: Option<String>, // WSS URL
+    pub fork_url: Option<String>, // WSS URL of a local fork (e.g. anvil) to simulate the suspicious txs on
+    pub etherscan_api_key: Option<Secret>,
+    pub smtp: Option<SmtpConfig>,
+    pub openai: Option<OpenAIConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<String>, // URLs to POST the alerts to
+    #[serde(default)]
+    pub watchlist: Vec<String>, // Addresses to watch
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    pub checkpoint_file: Option<String>, // Where the daemon records the last processed blocks
+    pub data_dir: Option<String>, // Where the recorded addresses and the imported selectors are kept, default the user data dir
+}
+
+/// @dev Email settings
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub sender: Option<String>, // Email from
+    pub server: Option<String>, // Email server smtp code
+    pub password: Option<Secret>, // Sender's email server password
+    #[serde(default)]
+    pub receivers: Vec<String>, // Which email addresses to receive
+}
+
+/// @dev OpenAI settings
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenAIConfig {
+    pub api_key: Option<Secret>,
+    pub base_url: Option<String>, // Any OpenAI-compatible API, e.g. `http://localhost:11434/v1/` for Ollama
+    pub model: Option<String>, // Default `gpt-3.5-turbo`
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u64>, // The max tokens of an answer
+    pub timeout: Option<u64>, // Seconds, default 120
+    pub context_tokens: Option<usize>, // The token budget of a chat, default 12000
+    pub stream: Option<bool>, // Receive the chat answers token by token, default true
+    #[serde(default)]
+    pub explain_alerts: bool, // Attach an AI incident hypothesis and a recommended action to the robot alerts
+}
+
+/// @dev A monitor rule, the daemon (`run`) starts one monitor for each rule
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Rule {
+    /// `message_robot()`: send email when the address has action
+    Message { address: String },
+    /// `warning_robot()`: send email when the address has too many `call` txs in the newest 240 blocks
+    Warning { address: String, call: String, limit: u32 },
+    /// `subscribe_event()`: print the `event` logs of the address
+    Event { address: String, event: String },
+    /// `monitor_mixing_service()`: record the users who interact with the mixing services
+    MixingService,
+    /// `deployment_robot()`: send email when a risky deployer deploys an unverified contract referencing the `protocols`
+    /// (default the `protocol` addresses in db) or the `selectors`
+    Deployment {
+        #[serde(default)]
+        protocols: Vec<String>,
+        #[serde(default)]
+        selectors: Vec<String>,
+    },
+    /// `poisoning_robot()`: send email when a lookalike of a real counterparty or a fake token transfers with the
+    /// `addresses` (default the `watchlist`), and record the offenders
+    Poisoning {
+        #[serde(default)]
+        addresses: Vec<String>,
+    },
+    /// `approval_robot()`: send email when the `addresses` (default the `watchlist`) approve a spender which is a hacker,
+    /// unverified or recently deployed
+    Approval {
+        #[serde(default)]
+        addresses: Vec<String>,
+    },
+}
+
+/// @dev A secret, given inline, read from an env var, or read from a file
+/// ```toml
+/// password = "inline"
+/// password = { env = "PASSWORD" }
+/// password = { file = "/run/secrets/smtp" }
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    Env { env: String },
+    File { file: String },
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Value(_) => write!(f, "Secret(***)"),
+            Secret::Env { env } => write!(f, "Secret(env: {})", env),
+            Secret::File { file } => write!(f, "Secret(file: {})", file),
+        }
+    }
+}
+
+impl Secret {
+    /// @dev Read the secret
+    pub fn resolve(&self) -> Result<String> {
+        match self {
+            Secret::Value(value) => Ok(value.clone()),
+            Secret::Env { env } => env::var(env)
+                .map_err(|_| Error::Config(format!("The env var `{}` is not set", env))),
+            Secret::File { file } => fs::read_to_string(file)
+                .map(|value| value.trim().to_string())
+                .map_err(|e| Error::Config(format!("Failed to read the secret file `{}`: {}", file, e))),
+        }
+    }
+}
+
+impl Config {
+    /// @dev Load and validate the config file
+    /// @param path The config file. If None, `SECHELPER_CONFIG` or `SecHelper.toml` is used, and a missing file means an empty config
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_string(), true),
+            None => match env::var("SECHELPER_CONFIG") {
+                Ok(path) => (path, true),
+                Err(_) => (DEFAULT_CONFIG_PATH.to_string(), false),
+            },
+        };
+
+        if !required && !Path::new(&path).exists() {
+            return Ok(Config::default());
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| Error::Config(format!("Failed to read the config file `{}`: {}", path, e)))?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| Error::Config(format!("Invalid config file `{}`: {}", path, e)))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// @dev Check the values which can be checked without touching the network or the secrets
+    pub fn validate(&self) -> Result<()> {
+        if let Some(name) = &self.default_profile {
+            if !self.profiles.contains_key(name) {
+                return Err(Error::Config(format!("default_profile `{}` is not defined in [profiles]", name)));
+            }
+        }
+
+        for (name, profile) in &self.profiles {
+            profile.validate().map_err(|e| match e {
+                Error::Config(message) => Error::Config(format!("profile `{}`: {}", name, message)),
+                e => e,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// @dev Select a profile, with the env var overrides applied
+    /// @param name The profile name. If None, `SECHELPER_PROFILE`, `default_profile` or the only profile is used
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        let name = name
+            .map(|name| name.to_string())
+            .or_else(|| env::var("SECHELPER_PROFILE").ok())
+            .or_else(|| self.default_profile.clone())
+            .or_else(|| {
+                if self.profiles.len() == 1 {
+                    self.profiles.keys().next().cloned()
+                } else {
+                    None
+                }
+            });
+
+        let mut profile = match name {
+            Some(name) => self
+                .profiles
+                .get(&name)
+                .cloned()
+                .ok_or_else(|| Error::Config(format!("Profile `{}` is not defined in the config file", name)))?,
+            None => Profile::default(),
+        };
+
+        profile.apply_env();
+        profile.validate()?; // The env var overrides are checked too
+        Ok(profile)
+    }
+}
+
+impl Profile {
+    /// @dev Override the values with the env vars from `.env.example`, empty env vars are ignored
+    fn apply_env(&mut self) {
+        if let Some(value) = env_var("WSS_RPC") {
+            self.rpc_url = Some(value);
+        }
+        if let Some(value) = env_var("FORK_RPC") {
+            self.fork_url = Some(value);
+        }
+        if let Some(value) = env_var("SECHELPER_DATA_DIR") {
+            self.data_dir = Some(value);
+        }
+        if env_var("ETHERSCAN_API_KEY").is_some() {
+            self.etherscan_api_key = Some(Secret::Env { env: String::from("ETHERSCAN_API_KEY") });
+        }
+
+        // The smtp section is only added when an smtp env var is set, it needs the receivers
+        if ["SENDER", "SMTP_SERVER", "SMTP_SEVER", "PASSWORD", "RECEIVER"].iter().any(|name| env_var(name).is_some()) {
+            let smtp = self.smtp.get_or_insert_with(SmtpConfig::default);
+            if let Some(value) = env_var("SENDER") {
+                smtp.sender = Some(value);
+            }
+            if let Some(value) = env_var("SMTP_SERVER").or_else(|| env_var("SMTP_SEVER")) {
+                smtp.server = Some(value);
+            }
+            if env_var("PASSWORD").is_some() {
+                smtp.password = Some(Secret::Env { env: String::from("PASSWORD") });
+            }
+            if let Some(value) = env_var("RECEIVER") {
+                smtp.receivers = vec![value];
+            }
+        }
+
+        let openai = self.openai.get_or_insert_with(OpenAIConfig::default);
+        if env_var("OPENAI_KEY").is_some() {
+            openai.api_key = Some(Secret::Env { env: String::from("OPENAI_KEY") });
+        }
+        if let Some(value) = env_var("OPENAI_BASE_URL") {
+            openai.base_url = Some(value);
+        }
+        if let Some(value) = env_var("OPENAI_MODEL") {
+            openai.model = Some(value);
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(chain) = &self.chain {
+            if chain != "eth" && chain != "bsc" {
+                return Err(Error::Config(format!("Unsupported chain `{}`, expected eth or bsc", chain)));
+            }
+        }
+        if let Some(rpc_url) = &self.rpc_url {
+            if !rpc_url.starts_with("ws://") && !rpc_url.starts_with("wss://") {
+                return Err(Error::Config(format!("rpc_url `{}` must be a ws:// or wss:// URL", rpc_url)));
+            }
+        }
+        if let Some(fork_url) = &self.fork_url {
+            if !fork_url.starts_with("ws://") && !fork_url.starts_with("wss://") {
+                return Err(Error::Config(format!("fork_url `{}` must be a ws:// or wss:// URL", fork_url)));
+            }
+        }
+        for url in &self.webhooks {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(Error::Config(format!("webhook `{}` must be a http:// or https:// URL", url)));
+            }
+        }
+        if let Some(smtp) = &self.smtp {
+            if smtp.receivers.is_empty() {
+                return Err(Error::Config(String::from("smtp.receivers is empty, set it or the `RECEIVER` env var")));
+            }
+            for email in smtp.sender.iter().chain(smtp.receivers.iter()) {
+                email
+                    .parse::<lettre::Address>()
+                    .map_err(|e| Error::Config(format!("Invalid email `{}`: {}", email, e)))?;
+            }
+        }
+        if let Some(openai) = &self.openai {
+            if let Some(base_url) = &openai.base_url {
+                if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+                    return Err(Error::Config(format!("openai.base_url `{}` must be a http:// or https:// URL", base_url)));
+                }
+            }
+            if openai.temperature.is_some_and(|temperature| !(0.0..=2.0).contains(&temperature)) {
+                return Err(Error::Config(String::from("openai.temperature must be between 0 and 2")));
+            }
+            if openai.timeout == Some(0) {
+                return Err(Error::Config(String::from("openai.timeout must be at least 1 second")));
+            }
+        }
+        for address in &self.watchlist {
+            check_address(address)?;
+        }
+        for rule in &self.rules {
+            match rule {
+                Rule::Message { address } => check_address(address)?,
+                Rule::Warning { address, call, .. } => {
+                    check_address(address)?;
+                    check_signature(call)?;
+                }
+                Rule::Event { address, event } => {
+                    check_address(address)?;
+                    check_signature(event)?;
+                }
+                Rule::MixingService => {}
+                Rule::Deployment { protocols, selectors } => {
+                    for address in protocols {
+                        check_address(address)?;
+                    }
+                    for signature in selectors {
+                        check_signature(signature)?;
+                    }
+                }
+                Rule::Poisoning { addresses } | Rule::Approval { addresses } => {
+                    if addresses.is_empty() && self.watchlist.is_empty() {
+                        return Err(Error::Config(String::from("`poisoning` and `approval` rules need `addresses` or a `watchlist`")));
+                    }
+                    for address in addresses {
+                        check_address(address)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// @return The WSS URL
+    pub fn rpc_url(&self) -> Result<String> {
+        self.rpc_url
+            .clone()
+            .ok_or_else(|| missing("rpc_url", "WSS_RPC"))
+    }
+
+    /// @return The WSS URL of the fork
+    pub fn fork_url(&self) -> Result<String> {
+        self.fork_url
+            .clone()
+            .ok_or_else(|| missing("fork_url", "FORK_RPC"))
+    }
+
+    /// @return The Etherscan API KEY
+    pub fn etherscan_api_key(&self) -> Result<String> {
+        self.etherscan_api_key
+            .as_ref()
+            .ok_or_else(|| missing("etherscan_api_key", "ETHERSCAN_API_KEY"))?
+            .resolve()
+    }
+
+    /// @return The SMTP settings with the password read
+    pub fn smtp(&self) -> Result<(String, String, String)> {
+        let smtp = self.smtp.clone().unwrap_or_default();
+        let sender = smtp.sender.ok_or_else(|| missing("smtp.sender", "SENDER"))?;
+        let server = smtp.server.ok_or_else(|| missing("smtp.server", "SMTP_SERVER"))?;
+        let password = smtp
+            .password
+            .ok_or_else(|| missing("smtp.password", "PASSWORD"))?
+            .resolve()?;
+
+        Ok((sender, password, server))
+    }
+
+    /// @return The OpenAI base url, default the official API
+    pub fn openai_base_url(&self) -> String {
+        self.openai
+            .as_ref()
+            .and_then(|openai| openai.base_url.clone())
+            .unwrap_or_else(|| String::from("https://api.openai.com/v1/"))
+    }
+
+    /// @return The OpenAI API KEY
+    pub fn openai_key(&self) -> Result<String> {
+        self.openai
+            .as_ref()
+            .and_then(|openai| openai.api_key.as_ref())
+            .ok_or_else(|| missing("openai.api_key", "OPENAI_KEY"))?
+            .resolve()
+    }
+}
+
+/// @dev Read an env var, None if it is not set or empty
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn check_address(address: &str) -> Result<()> {
+    address
+        .parse::<Address>()
+        .map(|_| ())
+        .map_err(|e| Error::Config(format!("Invalid address `{}`: {}", address, e)))
+}
+
+fn check_signature(signature: &str) -> Result<()> {
+    if !signature.contains('(') || !signature.ends_with(')') {
+        return Err(Error::Config(format!("Invalid signature `{}`, expected e.g. `transfer(address,uint256)`", signature)));
+    }
+    Ok(())
+}
+
+fn missing(key: &str, env: &str) -> Error {
+    Error::Config(format!("`{}` is not set, set it in the config file or the `{}` env var", key, env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config> {
+        let config: Config = toml::from_str(content).map_err(|e| Error::Config(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn error_of(content: &str) -> String {
+        match parse(content) {
+            Err(Error::Config(message)) => message,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_the_example_config() {
+        let config = parse(include_str!("../SecHelper.example.toml")).unwrap();
+        let profile = &config.profiles["mainnet"];
+        assert_eq!(profile.rules.len(), 7);
+        assert!(matches!(profile.rules[1], Rule::Warning { limit: 30, .. }));
+    }
+
+    #[test]
+    fn names_the_profile_and_the_key_of_an_error() {
+        assert!(error_of("default_profile = \"bsc\"\n[profiles.eth]").contains("`bsc`"));
+
+        let message = error_of("[profiles.eth]\nrpc_url = \"https://rpc\"");
+        assert!(message.contains("profile `eth`") && message.contains("rpc_url"), "{}", message);
+
+        let message = error_of("[profiles.eth]\nwatchlist = [\"0x1234\"]");
+        assert!(message.contains("0x1234"), "{}", message);
+
+        let message = error_of("[profiles.eth]\n[profiles.eth.openai]\ntemperature = 3.0");
+        assert!(message.contains("temperature"), "{}", message);
+
+        let message = error_of("[profiles.eth]\n[profiles.eth.smtp]\nsender = \"robot@example.com\"");
+        assert!(message.contains("smtp.receivers"), "{}", message);
+    }
+
+    #[test]
+    fn checks_the_rules() {
+        let message = error_of("[profiles.eth]\n[[profiles.eth.rules]]\nkind = \"poisoning\"");
+        assert!(message.contains("watchlist"), "{}", message);
+
+        let message = error_of("[profiles.eth]\n[[profiles.eth.rules]]\nkind = \"event\"\naddress = \"0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48\"\nevent = \"Transfer\"");
+        assert!(message.contains("Transfer"), "{}", message);
+
+        assert!(parse("[profiles.eth]\n[[profiles.eth.rules]]\nkind = \"liquidation\"").is_err());
+    }
+
+    #[test]
+    fn hides_and_reads_the_secrets() {
+        let secret = Secret::Value(String::from("hunter2"));
+        assert_eq!(format!("{:?}", secret), "Secret(***)");
+        assert_eq!(secret.resolve().unwrap(), "hunter2");
+
+        let path = env::temp_dir().join(format!("sechelper-secret-{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+        let secret = Secret::File { file: path.display().to_string() };
+        assert_eq!(secret.resolve().unwrap(), "from-file");
+        fs::remove_file(path).unwrap();
+
+        assert!(Secret::File { file: String::from("/nonexistent/secret") }.resolve().is_err());
+    }
+}
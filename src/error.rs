@@ -0,0 +1,125 @@
+use thiserror::Error;
+
+/// @dev The error type of SecHelper
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The HTTP request failed, or returned a non-success status code
+    #[error("HTTP request failed: {0}")]
+    Http(String),
+
+    /// Etherscan returned `status: "0"`, e.g. `{"status":"0","message":"NOTOK","result":"Invalid API Key"}`
+    #[error("Etherscan API error: {message}: {result}")]
+    Api { message: String, result: String },
+
+    /// Etherscan rate limit is reached, retry later
+    #[error("Rate limit reached: {0}")]
+    RateLimit(String),
+
+    /// The contract source code is not verified on etherscan
+    #[error("The contract {0} is not verified")]
+    Unverified(String),
+
+    /// The response can not be decoded
+    #[error("Decode error: {0}")]
+    Decode(String),
+
+    /// The node returned an error, or the connection to the node failed
+    #[error("RPC error: {0}")]
+    Rpc(String),
+
+    /// Reading or writing a local file (the address db, output files) failed
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    /// Sending a notification (e.g. an email) failed
+    #[error("Notification error: {0}")]
+    Notification(String),
+
+    /// The OpenAI API request failed, or the model answered something unusable
+    #[error("AI error: {0}")]
+    Ai(String),
+
+    /// The config file or the env vars are invalid or incomplete
+    #[error("Config error: {0}")]
+    Config(String),
+
+    /// The input from the user is invalid, e.g. a malformed address
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+}
+
+impl Error {
+    /// @dev Whether a long running monitor can log the error and go on.
+    /// Network, rate limit, API, RPC and notification failures are transient;
+    /// an invalid API key, config, invalid input, decoding and storage failures need the user to fix something.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Error::Api { result, .. } => !result.to_lowercase().contains("api key"),
+            Error::Http(_) | Error::RateLimit(_) | Error::Rpc(_) | Error::Notification(_) | Error::Ai(_) => true,
+            Error::Unverified(_) | Error::Decode(_) | Error::Storage(_) | Error::Config(_) | Error::InvalidInput(_) => false,
+        }
+    }
+}
+
+/// @dev The result type of SecHelper
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// @dev Used by the monitors: log a recoverable error and go on, return the others
+/// @param result The result of one step of the monitor
+/// @param context What the step is doing, printed with the error
+/// @return Some(value) if ok, None if a recoverable error is logged
+pub fn recover<T>(result: Result<T>, context: &str) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.is_recoverable() => {
+            eprintln!("{}: {}", context, e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e.without_url().to_string()) // The url may hold an api key
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<ethers::providers::ProviderError> for Error {
+    fn from(e: ethers::providers::ProviderError) -> Self {
+        Error::Rpc(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_the_recoverable_errors_only() {
+        assert_eq!(recover(Ok(1), "step").unwrap(), Some(1));
+        assert!(recover::<()>(Err(Error::RateLimit(String::from("slow down"))), "step").unwrap().is_none());
+        assert!(matches!(recover::<()>(Err(Error::Config(String::from("missing"))), "step"), Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn drops_the_url_of_the_http_errors() {
+        // Nothing listens on port 1, the request fails without leaving the host
+        let e = reqwest::get("http://127.0.0.1:1/api?apikey=SECRET").await.unwrap_err();
+        let e = Error::from(e);
+        assert!(matches!(e, Error::Http(_)));
+        assert!(!e.to_string().contains("SECRET"), "{}", e);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use crate::error::{Error, Result};
+
+/// The checkpoint file used when the profile does not set `checkpoint_file`
+pub const DEFAULT_CHECKPOINT_PATH: &str = "output/checkpoints.json";
+
+/// How many blocks a monitor catches up at most after a restart
+pub const MAX_CATCH_UP_BLOCKS: u64 = 1000;
+
+/// @dev The last processed block of every monitor, shared by the monitors of the daemon.
+/// Updates are kept in memory until `flush()` writes them to the checkpoint file.
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+    path: PathBuf,
+    blocks: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Checkpoints {
+    /// @dev Load the checkpoint file, a missing file means no checkpoints
+    /// @param path The checkpoint file
+    pub fn load(path: &str) -> Result<Self> {
+        let blocks = if Path::new(path).exists() {
+            let content = fs::read_to_string(path)
+                .map_err(|e| Error::Storage(format!("Failed to read {}: {}", path, e)))?;
+            serde_json::from_str(&content)
+                .map_err(|e| Error::Storage(format!("Failed to parse {}: {}", path, e)))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Checkpoints {
+            path: PathBuf::from(path),
+            blocks: Arc::new(Mutex::new(blocks)),
+        })
+    }
+
+    /// @dev The checkpoint of one monitor
+    /// @param name The monitor name, e.g. `message:0x...`
+    pub fn checkpoint(&self, name: &str) -> Checkpoint {
+        Checkpoint {
+            store: self.clone(),
+            name: name.to_string(),
+        }
+    }
+
+    /// @return The last processed block of the monitor
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.blocks.lock().ok()?.get(name).copied()
+    }
+
+    /// @dev Record the last processed block of the monitor
+    pub fn set(&self, name: &str, block: u64) {
+        if let Ok(mut blocks) = self.blocks.lock() {
+            blocks.insert(name.to_string(), block);
+        }
+    }
+
+    /// @dev Write the checkpoints to the checkpoint file
+    pub fn flush(&self) -> Result<()> {
+        let content = {
+            let blocks = self
+                .blocks
+                .lock()
+                .map_err(|_| Error::Storage(String::from("The checkpoints are poisoned")))?;
+            serde_json::to_string_pretty(&*blocks)?
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, content)
+            .map_err(|e| Error::Storage(format!("Failed to write {}: {}", self.path.display(), e)))
+    }
+}
+
+/// @dev The checkpoint of one monitor
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    store: Checkpoints,
+    name: String,
+}
+
+impl Checkpoint {
+    /// @dev The first block to process when the block `height` arrives:
+    /// the block after the checkpoint, at most `MAX_CATCH_UP_BLOCKS` back, or `height` if there is no checkpoint
+    pub fn start_block(&self, height: u64) -> u64 {
+        match self.store.get(&self.name) {
+            Some(block) if block < height => (block + 1).max(height.saturating_sub(MAX_CATCH_UP_BLOCKS)),
+            _ => height,
+        }
+    }
+
+    /// @dev Record the last processed block
+    pub fn save(&self, block: u64) {
+        self.store.set(&self.name, block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catches_up_from_the_checkpoint() {
+        let path = std::env::temp_dir().join(format!("sechelper-checkpoints-{}.json", std::process::id()));
+        let checkpoints = Checkpoints::load(&path.display().to_string()).unwrap();
+        let checkpoint = checkpoints.checkpoint("message:0xabc");
+        assert_eq!(checkpoint.start_block(100), 100);
+
+        checkpoint.save(90);
+        assert_eq!(checkpoint.start_block(100), 91);
+        assert_eq!(checkpoint.start_block(90), 90);
+        assert_eq!(checkpoint.start_block(90 + MAX_CATCH_UP_BLOCKS + 10), 100);
+
+        checkpoints.flush().unwrap();
+        let reloaded = Checkpoints::load(&path.display().to_string()).unwrap();
+        assert_eq!(reloaded.get("message:0xabc"), Some(90));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+use ethers::providers::{Provider, Ws};
+use tokio::task::JoinSet;
+use crate::ai::{client::ModelConfig, explain::Explainer};
+use crate::analyzer::simulate::Simulator;
+use crate::config::{Profile, Rule};
+use crate::error::{Error, Result};
+use crate::execute::{
+    checkpoint::{Checkpoints, DEFAULT_CHECKPOINT_PATH},
+    guardian::MessageRobot,
+};
+use crate::listener::listen::Listen;
+use crate::utils::{
+    notifier::{EmailNotifier, Notifier},
+    output::OutputFormat,
+};
+
+/// How long to wait before restarting a failed monitor, doubled on each failure
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/// The longest delay before restarting a failed monitor
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+/// How often the checkpoints are written to the checkpoint file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// How many times the shared connection reconnects
+const RECONNECTS: usize = 10;
+
+/// @dev One monitor started by the daemon
+#[derive(Debug, Clone)]
+pub enum Monitor {
+    Message { address: String },
+    Warning { address: String, call: String, limit: u32 },
+    Event { address: String, event: String },
+    Address { address: String },
+    MixingService,
+    Deployment { protocols: Vec<String>, selectors: Vec<String> },
+    Poisoning { addresses: Vec<String> },
+    Approval { addresses: Vec<String> },
+}
+
+impl Monitor {
+    /// @return The monitor name, used in the logs and as the checkpoint key
+    pub fn name(&self) -> String {
+        match self {
+            Monitor::Message { address } => format!("message:{}", address.to_lowercase()),
+            Monitor::Warning { address, call, .. } => format!("warning:{}:{}", address.to_lowercase(), call),
+            Monitor::Event { address, event } => format!("event:{}:{}", address.to_lowercase(), event),
+            Monitor::Address { address } => format!("address:{}", address.to_lowercase()),
+            Monitor::MixingService => String::from("mixing_service"),
+            Monitor::Deployment { protocols, .. } if protocols.is_empty() => String::from("deployment"),
+            Monitor::Deployment { protocols, .. } => format!("deployment:{}", protocols.join(",").to_lowercase()),
+            Monitor::Poisoning { addresses } => format!("poisoning:{}", addresses.join(",").to_lowercase()),
+            Monitor::Approval { addresses } => format!("approval:{}", addresses.join(",").to_lowercase()),
+        }
+    }
+}
+
+/// @dev What the monitors share: the connection, the notifier and the checkpoints
+struct Context {
+    api_key: String,
+    wss: String,
+    client: Provider<Ws>,
+    notifier: Notifier,
+    receiver: String, // The email addresses to receive, separated by `,`
+    checkpoints: Checkpoints,
+    simulator: Option<Simulator>, // The fork simulator, None if `fork_url` is not set
+    explainer: Option<Explainer>, // The alert explainer, None if `openai.explain_alerts` is not set
+    output: OutputFormat,
+}
+
+/// @dev Run all monitors of a profile concurrently under one supervisor
+pub struct Daemon {
+    profile: Profile,
+    output: OutputFormat,
+}
+
+impl Daemon {
+    /// @param profile The profile, its `rules` and `watchlist` define the monitors
+    /// @param output The output format of the listener monitors
+    pub fn new(profile: Profile, output: OutputFormat) -> Self {
+        Daemon { profile, output }
+    }
+
+    /// @dev The monitors defined in the profile: one for each rule, and a `subscribe_address()` for each watchlist address
+    pub fn monitors(&self) -> Vec<Monitor> {
+        let mut monitors: Vec<Monitor> = self
+            .profile
+            .rules
+            .iter()
+            .map(|rule| match rule.clone() {
+                Rule::Message { address } => Monitor::Message { address },
+                Rule::Warning { address, call, limit } => Monitor::Warning { address, call, limit },
+                Rule::Event { address, event } => Monitor::Event { address, event },
+                Rule::MixingService => Monitor::MixingService,
+                Rule::Deployment { protocols, selectors } => Monitor::Deployment { protocols, selectors },
+                Rule::Poisoning { addresses } if addresses.is_empty() => Monitor::Poisoning { addresses: self.profile.watchlist.clone() },
+                Rule::Poisoning { addresses } => Monitor::Poisoning { addresses },
+                Rule::Approval { addresses } if addresses.is_empty() => Monitor::Approval { addresses: self.profile.watchlist.clone() },
+                Rule::Approval { addresses } => Monitor::Approval { addresses },
+            })
+            .collect();
+
+        for address in &self.profile.watchlist {
+            monitors.push(Monitor::Address { address: address.clone() });
+        }
+
+        monitors
+    }
+
+    /// @dev Start every monitor as its own task, restart the failed ones,
+    /// and flush the checkpoints when SIGTERM or Ctrl-C is received
+    pub async fn run(&self) -> Result<()> {
+        let monitors = self.monitors();
+        if monitors.is_empty() {
+            return Err(Error::Config(String::from("No monitors to run, add `rules` or `watchlist` to the profile")));
+        }
+
+        let context = Arc::new(self.context(&monitors).await?);
+
+        let mut tasks = JoinSet::new();
+        for monitor in monitors {
+            eprintln!("[{}] start", monitor.name());
+            tasks.spawn(supervise(monitor, context.clone()));
+        }
+
+        let checkpoints = context.checkpoints.clone();
+        let flusher = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(FLUSH_INTERVAL).await;
+                if let Err(e) = checkpoints.flush() {
+                    eprintln!("flush checkpoints: {}", e);
+                }
+            }
+        });
+
+        tokio::select! {
+            _ = shutdown_signal() => eprintln!("Shutting down..."),
+            _ = async { while tasks.join_next().await.is_some() {} } => eprintln!("All monitors stopped"),
+        }
+
+        tasks.shutdown().await;
+        flusher.abort();
+        context.checkpoints.flush()?;
+        eprintln!("Checkpoints are flushed");
+
+        Ok(())
+    }
+
+    /// @dev Connect to the node once, and build the notifier once
+    async fn context(&self, monitors: &[Monitor]) -> Result<Context> {
+        let api_key = self.profile.etherscan_api_key()?;
+        let wss = self.profile.rpc_url()?;
+
+        let email = match self.profile.smtp() {
+            Ok((sender, password, smtp_server)) => Some(EmailNotifier::new(sender, password, smtp_server)),
+            Err(_) => None,
+        };
+        let receiver = self
+            .profile
+            .smtp
+            .as_ref()
+            .map(|smtp| smtp.receivers.join(","))
+            .unwrap_or_default();
+        let notifier = Notifier::new(email, self.profile.webhooks.clone());
+
+        let alerting = monitors
+            .iter()
+            .any(|m| matches!(m, Monitor::Message { .. } | Monitor::Warning { .. } | Monitor::Deployment { .. } | Monitor::Poisoning { .. } | Monitor::Approval { .. }));
+        if alerting && notifier.email.is_none() && notifier.webhooks.is_empty() {
+            return Err(Error::Config(String::from("`message`, `warning`, `deployment`, `poisoning` and `approval` rules need `smtp` or `webhooks` in the profile")));
+        }
+
+        let checkpoint_file = self
+            .profile
+            .checkpoint_file
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CHECKPOINT_PATH.to_string());
+        let checkpoints = Checkpoints::load(&checkpoint_file)?;
+
+        let client = Provider::<Ws>::connect_with_reconnects(wss.as_str(), RECONNECTS).await?;
+
+        let simulator = match &self.profile.fork_url {
+            Some(fork_url) => Some(
+                Simulator::connect(fork_url, Some(api_key.clone()))
+                    .await?
+                    .with_watched(&self.profile.watchlist)
+                    .with_rules(&self.profile.rules),
+            ),
+            None => None,
+        };
+
+        let explain_alerts = self.profile.openai.as_ref().is_some_and(|openai| openai.explain_alerts);
+        let explainer = if explain_alerts {
+            Some(Explainer::new(ModelConfig::from_profile(&self.profile)?)?)
+        } else {
+            None
+        };
+
+        Ok(Context {
+            api_key,
+            wss,
+            client,
+            notifier,
+            receiver,
+            checkpoints,
+            simulator,
+            explainer,
+            output: self.output,
+        })
+    }
+}
+
+/// @dev Run a monitor, restart it when it stops or fails with a recoverable error
+async fn supervise(monitor: Monitor, context: Arc<Context>) {
+    let name = monitor.name();
+    let mut delay = RESTART_DELAY;
+
+    loop {
+        let started = Instant::now();
+        match run_monitor(&monitor, &context).await {
+            Ok(()) => eprintln!("[{}] stopped", name),
+            Err(e) if e.is_recoverable() => eprintln!("[{}] failed: {}", name, e),
+            Err(e) => {
+                eprintln!("[{}] failed: {}, not restarting", name, e);
+                return;
+            }
+        }
+
+        // A monitor which ran for a while is restarted quickly again
+        if started.elapsed() > MAX_RESTART_DELAY {
+            delay = RESTART_DELAY;
+        }
+        eprintln!("[{}] restart in {}s", name, delay.as_secs());
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// @dev Run a monitor once with the shared context
+async fn run_monitor(monitor: &Monitor, context: &Context) -> Result<()> {
+    let checkpoint = context.checkpoints.checkpoint(&monitor.name());
+    let listener = || {
+        Listen::new(context.wss.clone(), context.api_key.clone(), context.output)
+            .with_client(context.client.clone())
+            .with_checkpoint(checkpoint.clone())
+    };
+    let robot = || {
+        let robot = MessageRobot::with_notifier(context.api_key.clone(), context.wss.clone(), context.notifier.clone())
+            .with_client(context.client.clone())
+            .with_checkpoint(checkpoint.clone());
+        let robot = match &context.simulator {
+            Some(simulator) => robot.with_simulator(simulator.clone()),
+            None => robot,
+        };
+        match &context.explainer {
+            Some(explainer) => robot.with_explainer(explainer.clone()),
+            None => robot,
+        }
+    };
+
+    match monitor {
+        Monitor::Message { address } => robot().message_robot(address.clone(), context.receiver.clone()).await,
+        Monitor::Warning { address, call, limit } => robot().warning_robot(address, call, context.receiver.clone(), *limit).await,
+        Monitor::Event { address, event } => listener().subscribe_event(address.clone(), event).await,
+        Monitor::Address { address } => listener().subscribe_address(address.clone()).await,
+        Monitor::MixingService => listener().monitor_mixing_service().await,
+        Monitor::Deployment { protocols, selectors } => robot().deployment_robot(protocols, selectors, context.receiver.clone()).await,
+        Monitor::Poisoning { addresses } => robot().poisoning_robot(addresses, context.receiver.clone()).await,
+        Monitor::Approval { addresses } => robot().approval_robot(addresses, context.receiver.clone()).await,
+    }
+}
+
+/// @dev Wait for Ctrl-C, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(e) => {
+                eprintln!("Failed to listen to SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_a_monitor_per_rule_and_watchlist_address() {
+        let profile = Profile {
+            watchlist: vec![String::from("0xAbC")],
+            rules: vec![
+                Rule::MixingService,
+                Rule::Deployment { protocols: Vec::new(), selectors: Vec::new() },
+                Rule::Poisoning { addresses: Vec::new() },
+            ],
+            ..Default::default()
+        };
+        let names: Vec<String> = Daemon::new(profile, OutputFormat::Table).monitors().iter().map(|m| m.name()).collect();
+        assert_eq!(names, vec!["mixing_service", "deployment", "poisoning:0xabc", "address:0xabc"]);
+    }
+}
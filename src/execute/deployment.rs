@@ -0,0 +1,426 @@
+use std::collections::{HashMap, HashSet};
+use ethers::{
+    providers::Middleware,
+    types::{
+        Address, BlockNumber, CallFrame, GethDebugBuiltInTracerType, GethDebugTracerType, GethDebugTracingOptions, GethTrace,
+        GethTraceFrame, NameOrAddress, Transaction, H256, U64,
+    },
+};
+use serde::{Deserialize, Serialize};
+use crate::analyzer::bytecode::{self, BytecodeSummary};
+use crate::error::{Error, Result};
+use crate::listener::fetcher::Fetch;
+use crate::utils::{address_db, output::Record, source, tools};
+
+/// How many blocks before the deployment are checked for the mixing service withdrawals to the deployer, ~1 week
+pub const MIXER_LOOKBACK: u64 = 50_000;
+
+/// The selectors which almost every contract calls, they do not link a contract to a protocol
+const COMMON_SELECTORS: [&str; 9] = [
+    "name()",
+    "symbol()",
+    "decimals()",
+    "totalSupply()",
+    "balanceOf(address)",
+    "allowance(address,address)",
+    "approve(address,uint256)",
+    "transfer(address,uint256)",
+    "transferFrom(address,address,uint256)",
+];
+
+/// @dev The protocols watched by `deployment_robot()`
+#[derive(Debug, Clone, Default)]
+pub struct DeploymentWatch {
+    pub protocols: HashSet<String>, // Lowercase addresses
+    pub selectors: HashMap<String, String>, // Selector => the protocol or signature it comes from
+}
+
+impl DeploymentWatch {
+    /// @dev Watch the protocols and the selectors of their dispatchers, and the extra function signatures
+    /// @param client The provider
+    /// @param protocols The protocol addresses, empty for the `protocol` addresses in db
+    /// @param signatures The extra functions, e.g. `flashLoan(address,address[],uint256[],uint256[],address,bytes,uint16)`
+    pub async fn new<M: Middleware>(client: &M, protocols: &[String], signatures: &[String]) -> Result<Self> {
+        let protocols = if protocols.is_empty() {
+            address_db::get_db_address("protocol")?
+        } else {
+            protocols.to_vec()
+        };
+
+        let common: HashSet<String> = COMMON_SELECTORS.iter().map(|s| tools::function_sig(s)).collect();
+        let mut watch = DeploymentWatch::default();
+        for protocol in protocols {
+            let protocol = protocol.to_lowercase();
+            match bytecode::analyze_address(client, &protocol).await {
+                Ok(summary) => {
+                    for selector in summary.selector_set() {
+                        if !common.contains(&selector) {
+                            watch.selectors.entry(selector).or_insert_with(|| protocol.clone());
+                        }
+                    }
+                }
+                Err(e) => eprintln!("analyze the bytecode of {}: {}", protocol, e), // Still watch the address
+            }
+            watch.protocols.insert(protocol);
+        }
+
+        for signature in signatures {
+            watch.selectors.insert(tools::function_sig(signature), signature.clone());
+        }
+
+        Ok(watch)
+    }
+
+    /// @return The watched protocols embedded in the bytecode
+    pub fn referenced_protocols(&self, summary: &BytecodeSummary) -> Vec<String> {
+        summary
+            .addresses
+            .iter()
+            .map(|embedded| embedded.address.to_lowercase())
+            .filter(|address| self.protocols.contains(address))
+            .collect()
+    }
+
+    /// @return The watched selectors pushed by the bytecode, with the protocol or signature they come from
+    pub fn referenced_selectors(&self, summary: &BytecodeSummary) -> Vec<String> {
+        summary
+            .referenced_selectors
+            .iter()
+            .filter_map(|selector| self.selectors.get(selector).map(|from| format!("{} ({})", selector, from)))
+            .collect()
+    }
+}
+
+/// @dev A contract created in a block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployment {
+    pub block: u64,
+    pub hash: String,
+    pub deployer: String,
+    pub contract: String,
+    pub deployer_risks: Vec<String>, // "hacker", "potential_hacker" or "mixer_funded"
+    pub verified: Option<bool>, // None if not checked
+    pub protocols: Vec<String>, // The watched protocols referenced by the bytecode
+    pub selectors: Vec<String>, // The watched selectors referenced by the bytecode
+    pub bytecode: BytecodeSummary,
+}
+
+impl Deployment {
+    /// @return True if an unverified contract of a risky deployer references a watched protocol
+    pub fn is_suspicious(&self) -> bool {
+        !self.deployer_risks.is_empty()
+            && self.verified == Some(false)
+            && (!self.protocols.is_empty() || !self.selectors.is_empty())
+    }
+}
+
+impl std::fmt::Display for Deployment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Contract: {}", self.contract)?;
+        writeln!(f, "Deployer: {} ({})", self.deployer, self.deployer_risks.join(", "))?;
+        writeln!(f, "Tx hash: {} (block {})", self.hash, self.block)?;
+        writeln!(f, "Verified: {}", self.verified.map_or(String::from("unknown"), |v| v.to_string()))?;
+        writeln!(f, "Referenced protocols: {}", self.protocols.join(", "))?;
+        writeln!(f, "Referenced selectors: {}", self.selectors.join(", "))?;
+        write!(
+            f,
+            "Bytecode: {} bytes, {} functions, DELEGATECALL {}, SELFDESTRUCT {}, CREATE {}, CREATE2 {}",
+            self.bytecode.size,
+            self.bytecode.selectors.len(),
+            self.bytecode.delegatecall,
+            self.bytecode.selfdestruct,
+            self.bytecode.create,
+            self.bytecode.create2,
+        )
+    }
+}
+
+impl Record for Deployment {
+    fn headers() -> Vec<&'static str> {
+        vec!["block", "hash", "deployer", "contract", "deployer_risks", "verified", "protocols", "selectors", "suspicious"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.block.to_string(),
+            self.hash.clone(),
+            self.deployer.clone(),
+            self.contract.clone(),
+            self.deployer_risks.join(" "),
+            self.verified.map(|v| v.to_string()).unwrap_or_default(),
+            self.protocols.join(" "),
+            self.selectors.join(" "),
+            self.is_suspicious().to_string(),
+        ]
+    }
+}
+
+/// @dev A contract created in a block, by a tx itself or by a contract (a factory, `CREATE2`)
+struct Creation {
+    hash: H256,
+    deployer: Address, // The sender of the tx
+    contract: Address,
+}
+
+/// @dev Find the contracts created in a block, by the txs or by factories, and analyze them.
+/// The deployer is only checked when the bytecode references a watched protocol,
+/// and the verification is only checked when the deployer is risky, to save the Etherscan calls.
+/// @notice The deployer of a contract created by a factory is the sender of the tx, not the factory
+/// @param client The provider
+/// @param api_key Etherscan API kEY
+/// @param block The block number
+/// @param watch The watched protocols
+/// @return The deployments of the block
+pub async fn block_deployments<M: Middleware>(
+    client: &M,
+    api_key: &str,
+    block: u64,
+    watch: &DeploymentWatch,
+) -> Result<Vec<Deployment>> {
+    let Some(txs) = client
+        .get_block_with_txs(BlockNumber::Number(U64::from(block)))
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?
+    else {
+        return Ok(Vec::new());
+    };
+
+    let creations = match traced_creations(client, block, &txs.transactions).await {
+        Some(creations) => creations,
+        None => fetched_creations(client, api_key, block, &txs.transactions).await?,
+    };
+
+    let mut deployments = Vec::new();
+    for creation in creations {
+        let contract = format!("{:?}", creation.contract);
+        let summary = match bytecode::analyze_address(client, &contract).await {
+            Ok(summary) => summary,
+            Err(Error::InvalidInput(_)) => continue, // No code, e.g. it self-destructed in the constructor
+            Err(e) => return Err(e),
+        };
+
+        let mut deployment = Deployment {
+            block,
+            hash: format!("{:?}", creation.hash),
+            deployer: format!("{:?}", creation.deployer),
+            contract,
+            deployer_risks: Vec::new(),
+            verified: None,
+            protocols: watch.referenced_protocols(&summary),
+            selectors: watch.referenced_selectors(&summary),
+            bytecode: summary,
+        };
+
+        if !deployment.protocols.is_empty() || !deployment.selectors.is_empty() {
+            deployment.deployer_risks = deployer_risks(api_key, &deployment.deployer, block).await?;
+        }
+        if !deployment.deployer_risks.is_empty() {
+            deployment.verified = Some(is_verified(api_key, &deployment.contract).await?);
+        }
+        deployments.push(deployment);
+    }
+
+    Ok(deployments)
+}
+
+/// @dev The successful `CREATE` / `CREATE2` frames of the `callTracer` traces of a block
+/// @return None if the node does not support `debug_traceBlockByNumber`
+async fn traced_creations<M: Middleware>(client: &M, block: u64, txs: &[Transaction]) -> Option<Vec<Creation>> {
+    let options = GethDebugTracingOptions {
+        tracer: Some(GethDebugTracerType::BuiltInTracer(GethDebugBuiltInTracerType::CallTracer)),
+        ..Default::default()
+    };
+    let traces = match client.debug_trace_block_by_number(Some(BlockNumber::Number(U64::from(block))), options).await {
+        Ok(traces) => traces,
+        Err(e) => {
+            eprintln!("debug_traceBlockByNumber failed, the creations are fetched from the receipts and etherscan: {}", e);
+            return None;
+        }
+    };
+    if traces.len() != txs.len() {
+        eprintln!("debug_traceBlockByNumber returned {} traces for {} txs", traces.len(), txs.len());
+        return None;
+    }
+
+    let mut creations = Vec::new();
+    for (tx, trace) in txs.iter().zip(traces) {
+        let frame = match trace {
+            GethTrace::Known(GethTraceFrame::CallTracer(frame)) => frame,
+            // geth wraps each trace as `{ "txHash": ..., "result": ... }`
+            GethTrace::Unknown(value) => serde_json::from_value::<CallFrame>(value.get("result").cloned().unwrap_or(value)).ok()?,
+            other => {
+                eprintln!("Unexpected debug_traceBlockByNumber result: {:?}", other);
+                return None;
+            }
+        };
+        let mut contracts = Vec::new();
+        created_contracts(&frame, &mut contracts);
+        creations.extend(contracts.into_iter().map(|contract| Creation { hash: tx.hash, deployer: tx.from, contract }));
+    }
+
+    Some(creations)
+}
+
+/// @dev Collect the contracts created by a frame and its inner frames. A reverted frame creates nothing
+fn created_contracts(frame: &CallFrame, contracts: &mut Vec<Address>) {
+    if frame.error.is_some() {
+        return;
+    }
+    if frame.typ.starts_with("CREATE") {
+        if let Some(NameOrAddress::Address(contract)) = &frame.to {
+            contracts.push(*contract);
+        }
+    }
+    for call in frame.calls.iter().flatten() {
+        created_contracts(call, contracts);
+    }
+}
+
+/// @dev The contracts created by the txs, from their receipts, and by factories, from the etherscan internal txs
+async fn fetched_creations<M: Middleware>(client: &M, api_key: &str, block: u64, txs: &[Transaction]) -> Result<Vec<Creation>> {
+    let mut creations = Vec::new();
+    for tx in txs.iter().filter(|tx| tx.to.is_none()) {
+        let Some(receipt) = client
+            .get_transaction_receipt(tx.hash)
+            .await
+            .map_err(|e| Error::Rpc(e.to_string()))?
+        else {
+            continue;
+        };
+        let Some(contract) = receipt.contract_address else { continue }; // The creation reverted
+        creations.push(Creation { hash: tx.hash, deployer: tx.from, contract });
+    }
+
+    let senders: HashMap<H256, Address> = txs.iter().map(|tx| (tx.hash, tx.from)).collect();
+    let internal_txs = Fetch::new(api_key.to_string()).fetch_block_internal_txs(block, block).await?;
+    for internal in internal_txs.iter().filter(|tx| !tx.isError && !tx.contractAddress.is_empty()) {
+        let (Ok(hash), Ok(contract)) = (internal.hash.parse::<H256>(), internal.contractAddress.parse::<Address>()) else { continue };
+        let Some(deployer) = senders.get(&hash) else { continue };
+        if creations.iter().any(|creation| creation.contract == contract) {
+            continue;
+        }
+        creations.push(Creation { hash, deployer: *deployer, contract });
+    }
+
+    Ok(creations)
+}
+
+/// @dev Link a deployer to the address db, and check if a mixing service sent it ether recently
+/// @param api_key Etherscan API kEY
+/// @param deployer The deployer address
+/// @param block The deployment block
+/// @return "hacker", "potential_hacker" and / or "mixer_funded", empty if not risky
+pub async fn deployer_risks(api_key: &str, deployer: &str, block: u64) -> Result<Vec<String>> {
+    let mut risks = Vec::new();
+    for category in ["hacker", "potential_hacker"] {
+        if address_db::get_db_address(category)?.iter().any(|address| address.eq_ignore_ascii_case(deployer)) {
+            risks.push(category.to_string());
+        }
+    }
+
+    let fetcher = Fetch::new(api_key.to_string());
+    if fetcher.is_funded_by_mixing_service(deployer, block.saturating_sub(MIXER_LOOKBACK), block).await? {
+        risks.push(String::from("mixer_funded"));
+    }
+
+    Ok(risks)
+}
+
+/// @dev A contract is unverified if Etherscan has no source code of it
+async fn is_verified(api_key: &str, address: &str) -> Result<bool> {
+    match source::fetch_contract_source(api_key, address).await {
+        Ok(_) => Ok(true),
+        Err(Error::Unverified(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::utils::hex;
+    use crate::utils::selector_db::SelectorDb;
+
+    #[test]
+    fn collects_the_factory_creations_except_the_reverted() {
+        // A tx calling a factory, which creates a contract with `CREATE2`, and a reverted `CREATE`
+        let trace = serde_json::json!({
+            "txHash": "0x5f2c0b4bb8e5f6fdbd5d49e8aab2a6b0b0a7d09b5d1e2a47ef2a0d3c3f5a9c11",
+            "result": {
+                "type": "CALL",
+                "from": "0x00000000000000000000000000000000000000aa",
+                "to": "0x00000000000000000000000000000000000000fa",
+                "gas": "0x100000",
+                "gasUsed": "0x8000",
+                "input": "0x",
+                "calls": [
+                    {
+                        "type": "CREATE2",
+                        "from": "0x00000000000000000000000000000000000000fa",
+                        "to": "0x00000000000000000000000000000000000000c1",
+                        "input": "0x",
+                        "calls": [{ "type": "CREATE", "from": "0x00000000000000000000000000000000000000c1", "to": "0x00000000000000000000000000000000000000c2", "input": "0x" }]
+                    },
+                    {
+                        "type": "CREATE",
+                        "from": "0x00000000000000000000000000000000000000fa",
+                        "to": "0x00000000000000000000000000000000000000c3",
+                        "input": "0x",
+                        "error": "execution reverted"
+                    }
+                ]
+            }
+        });
+        let frame: CallFrame = serde_json::from_value(trace["result"].clone()).unwrap();
+
+        let mut contracts = Vec::new();
+        created_contracts(&frame, &mut contracts);
+        let contracts: Vec<String> = contracts.iter().map(|c| format!("{:?}", c)).collect();
+        assert_eq!(
+            contracts,
+            vec!["0x00000000000000000000000000000000000000c1", "0x00000000000000000000000000000000000000c2"]
+        );
+    }
+
+    #[test]
+    fn suspicious_only_if_risky_unverified_and_referencing() {
+        let mut deployment = Deployment {
+            block: 1,
+            hash: String::new(),
+            deployer: String::from("0x00000000000000000000000000000000000000aa"),
+            contract: String::from("0x00000000000000000000000000000000000000c1"),
+            deployer_risks: vec![String::from("mixer_funded")],
+            verified: Some(false),
+            protocols: vec![String::from("0x7a250d5630b4cf539739df2c5dacb4c659f2488d")],
+            selectors: vec![],
+            bytecode: bytecode::analyze_code("0x00000000000000000000000000000000000000c1", &[], &SelectorDb::bundled().unwrap(), &HashMap::new()),
+        };
+        assert!(deployment.is_suspicious());
+
+        deployment.verified = None;
+        assert!(!deployment.is_suspicious());
+
+        deployment.verified = Some(false);
+        deployment.protocols.clear();
+        assert!(!deployment.is_suspicious());
+
+        deployment.selectors.push(String::from("0xab9c4b5d (flashLoan)"));
+        assert!(deployment.is_suspicious());
+
+        deployment.deployer_risks.clear();
+        assert!(!deployment.is_suspicious());
+    }
+
+    #[test]
+    fn the_watched_protocols_and_selectors_referenced() {
+        let watch = DeploymentWatch {
+            protocols: HashSet::from([String::from("0x7a250d5630b4cf539739df2c5dacb4c659f2488d")]),
+            selectors: HashMap::from([(String::from("0x095ea7b3"), String::from("approve(address,uint256)"))]),
+        };
+        let code = hex::decode("737a250d5630b4cf539739df2c5dacb4c659f2488d5063095ea7b3506312345678").unwrap();
+        let summary = bytecode::analyze_code("0x01", &code, &SelectorDb::bundled().unwrap(), &HashMap::new());
+
+        assert_eq!(watch.referenced_protocols(&summary), vec!["0x7a250d5630b4cf539739df2c5dacb4c659f2488d"]);
+        assert_eq!(watch.referenced_selectors(&summary), vec!["0x095ea7b3 (approve(address,uint256))"]);
+    }
+}
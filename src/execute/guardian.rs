@@ -1,21 +1,20 @@
-use crate::utils::tools;
-use crate::listener::fetcher;
-use ethers::{
-    core::types::BlockNumber,
-    providers::{Middleware, Provider, StreamExt, Ws},
-};
-use eyre::{Ok, Result};
-use std::{
-    thread,
-    time::Duration
-};
+use crate::ai::explain::Explainer;
+use crate::analyzer::{approvals, decoder::Decoder, simulate::Simulator};
+use crate::error::{recover, Result};
+use crate::execute::{checkpoint::Checkpoint, deployment::{self, DeploymentWatch}, poisoning::{self, PoisoningWatch}};
+use crate::utils::{notifier::{Alert, EmailNotifier, Notifier}, tools};
+use crate::listener::{fetcher, listen};
+use ethers::providers::{Middleware, Provider, StreamExt, Ws};
+use std::time::Duration;
 
 pub struct MessageRobot {
     API_KEY: String,
     WSS: String,
-    sender: String, // Email from
-    password: String, // Sender's email server password
-    smtp_server: String // Email server smtp code
+    notifier: Notifier,
+    client: Option<Provider<Ws>>, // A shared connection, None to connect to WSS
+    checkpoint: Option<Checkpoint>, // Where to record the last processed block
+    simulator: Option<Simulator>, // Where to simulate the suspicious txs, None to not simulate
+    explainer: Option<Explainer>, // Who explains the alerts, None to send them as they are
 }
 
 impl MessageRobot{
@@ -29,12 +28,57 @@ impl MessageRobot{
         MessageRobot {
             API_KEY: api_key,
             WSS: wss,
-            sender,
-            password,
-            smtp_server
+            notifier: Notifier::new(Some(EmailNotifier::new(sender, password, smtp_server)), Vec::new()),
+            client: None,
+            checkpoint: None,
+            simulator: None,
+            explainer: None,
         }
     }
 
+    /// @param api_key Etherscan API kEY
+    /// @param wss WSS URL
+    /// @param notifier Where to send the alerts
+    pub fn with_notifier(api_key: String, wss: String, notifier: Notifier) -> Self {
+        MessageRobot {
+            API_KEY: api_key,
+            WSS: wss,
+            notifier,
+            client: None,
+            checkpoint: None,
+            simulator: None,
+            explainer: None,
+        }
+    }
+
+    /// @dev Use a shared connection instead of connecting to WSS
+    /// @param client The shared connection
+    pub fn with_client(mut self, client: Provider<Ws>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// @dev Record the last processed block, and catch up the missed blocks after a restart
+    /// @param checkpoint The checkpoint of this robot
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    /// @dev Simulate the suspicious txs on a local fork, and attach the results to the alerts
+    /// @param simulator The fork simulator
+    pub fn with_simulator(mut self, simulator: Simulator) -> Self {
+        self.simulator = Some(simulator);
+        self
+    }
+
+    /// @dev Attach an AI incident hypothesis and a recommended action to the alerts
+    /// @param explainer The alert explainer
+    pub fn with_explainer(mut self, explainer: Explainer) -> Self {
+        self.explainer = Some(explainer);
+        self
+    }
+
     /// @dev Create a robot to monitor the address m, and send email to receiver when the m has action
     /// @param address Who to monitor
     /// @param receiver Which email address to receive
@@ -44,32 +88,40 @@ impl MessageRobot{
         receiver: String, 
     ) -> Result<()> {
         println!("Robot starts to monitor...");
-        let client =
-        Provider::<Ws>::connect(self.WSS.clone()).await?;
-    
-        let last_block = client.get_block(BlockNumber::Latest).await?.unwrap().number.unwrap();
+        let client = listen::connect(&self.WSS, &self.client).await?;
     
         let mut stream = client.subscribe_blocks().await?;
+        let mut decoder = Decoder::new(Some(self.API_KEY.clone()))?;
     
         while let Some(log) = stream.next().await {
             println!("block height: {:?}", log.number);
-            let height= *(log.number.unwrap().0.get(0).unwrap());
+            let Some(height) = log.number.map(|n| n.as_u64()) else { continue }; // Skip the pending block
+            let from_block = self.checkpoint.as_ref().map_or(height, |c| c.start_block(height));
 
             let fetcher = fetcher::Fetch::new(self.API_KEY.clone());
-            let txs = fetcher.fetch_address_all_txs( address.as_str(), height, height).await;
-
-            let mut hash = Vec::new();
-            for tx in txs.unwrap() {
-                hash.push(tx.hash);
+            let txs = fetcher.fetch_address_all_txs( address.as_str(), from_block, height).await;
+            let Some(mut txs) = recover(txs, &format!("block {}: fetch txs of {}", height, address))? else { continue };
+            if let Err(e) = decoder.decode_transactions(&mut txs).await {
+                eprintln!("block {}: decode txs: {}", height, e); // The alert is sent without the decoded calls
             }
 
-            if hash.len() > 0 {
-                let content = format!{"Attention! The {} you monitor has action! \nTx hash{:?}", address, hash};
+            if !txs.is_empty() {
+                let mut alert = Alert::new(
+                    String::from("message: the address has new txs"),
+                    address.clone(),
+                    format!{"Attention! The {} you monitor has action!", address},
+                );
+                alert.txs = txs.iter().map(tx_line).collect();
 
-                tools::send_email(self.sender.clone(), receiver.clone(), String::from("SecHelper Robot"), content, self.password.clone(), self.smtp_server.clone()).unwrap();
+                self.send_alert(&receiver, alert).await?;
+            }
+
+            if let Some(checkpoint) = &self.checkpoint {
+                checkpoint.save(height);
             }
         }
-    
+
+        eprintln!("The block subscription is closed");
         Ok(())
     }
 
@@ -82,29 +134,247 @@ impl MessageRobot{
     /// @param limit The max number of certain txs, rebot will send email as long as the txs number over your limit
     pub async fn warning_robot(&self, address: &str, event: &str, receiver: String, limit: u32) -> Result<()> {
         println!("Robot starts to monitor...");
-        let client = Provider::<Ws>::connect(self.WSS.clone()).await?;
+        let client = listen::connect(&self.WSS, &self.client).await?;
+
+        let selector = tools::function_sig(event);
+        let mut decoder = Decoder::new(Some(self.API_KEY.clone()))?;
 
         loop {
-            let last_block = client.get_block(BlockNumber::Latest).await?.unwrap().number.unwrap();
-            let fetcher = fetcher::Fetch::new(self.API_KEY.clone());
+            if let Some(txs) = self.fetch_recent_txs(&client, address).await? {
+                let mut matched = Vec::new();
+                for tx in txs {
+                    if tx.methodId == selector {
+                        matched.push(tx_line(&tx));
+                    } else if tx.input.len() > 10 && !tx.to.is_empty() {
+                        // The call may be wrapped, e.g. in a multicall or a Safe execTransaction
+                        let decoded = decoder.decode(&tx.to, &tx.input).await.ok().flatten();
+                        if let Some(decoded) = decoded.filter(|decoded| decoded.contains(event)) {
+                            matched.push(format!("{} {}", tx_line(&tx), decoded));
+                        }
+                    }
+                }
 
-            let txs = fetcher.fetch_address_all_txs(address, last_block.as_u64() - 240, last_block.as_u64()).await;
+                if matched.len() > limit as usize {
+                    let mut alert = Alert::new(
+                        format!("warning: more than {} `{}` txs in the newest 240 blocks", limit, event),
+                        address.to_string(),
+                        format!{"Warning! The {} you monitor may be in dangerous! \nResult: Too many `{}` txs, which over your limit({})", address, event, limit},
+                    );
+                    alert.txs = matched;
 
-            let mut count = 0;
-            for tx in txs.unwrap() {
-                if tx.methodId == tools::function_sig(event) {
-                    count = count + 1;
+                    self.send_alert(&receiver, alert).await?;
                 }
             }
 
-            if count > limit {
-                let content = format!{"Warning! The {} you monitor may be in dangerous! \nResult: Too many `{}` txs, which over your limit({})", address, event, limit};
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        }
+
+    }
+
+    /// @dev Create a robot to monitor the contract creations of each block, and send email to receiver when
+    /// an unverified contract of a risky deployer (hacker, potential_hacker or mixer funded) references a watched protocol
+    /// @param protocols The watched protocols, empty for the `protocol` addresses in db. Their selectors are watched too
+    /// @param selectors The extra watched functions, e.g. `flashLoan(address,address[],uint256[],uint256[],address,bytes,uint16)`
+    /// @param receiver Which email address to receive
+    pub async fn deployment_robot(&self, protocols: &[String], selectors: &[String], receiver: String) -> Result<()> {
+        let client = listen::connect(&self.WSS, &self.client).await?;
+        let watch = DeploymentWatch::new(&client, protocols, selectors).await?;
+        println!("Robot starts to monitor {} protocols and {} selectors...", watch.protocols.len(), watch.selectors.len());
+
+        let mut stream = client.subscribe_blocks().await?;
+
+        while let Some(log) = stream.next().await {
+            println!("block height: {:?}", log.number);
+            let Some(height) = log.number.map(|n| n.as_u64()) else { continue }; // Skip the pending block
+            let from_block = self.checkpoint.as_ref().map_or(height, |c| c.start_block(height));
+
+            let mut processed = height; // The blocks after a failed one are retried in the next round
+            for block in from_block..=height {
+                let deployments = deployment::block_deployments(&client, &self.API_KEY, block, &watch).await;
+                let Some(deployments) = recover(deployments, &format!("block {}: check the deployments", block))? else {
+                    processed = block.saturating_sub(1);
+                    break;
+                };
+
+                for deployment in deployments.iter().filter(|d| d.is_suspicious()) {
+                    let mut alert = Alert::new(
+                        String::from("deployment: a risky deployer deployed an unverified contract referencing a watched protocol"),
+                        deployment.contract.clone(),
+                        format!{"Warning! A risky deployer deployed an unverified contract referencing your protocol! \n{}", deployment},
+                    );
+                    if let Some(simulator) = &self.simulator {
+                        let selectors: Vec<String> = deployment.bytecode.selector_set().into_iter().collect();
+                        match simulator.probe_contract(&deployment.deployer, &deployment.contract, &selectors).await {
+                            Ok(probes) => {
+                                alert.details.extend(probes.harmful.iter().map(|s| s.to_string()));
+                                alert.details.extend(probes.errors.iter().map(|(selector, e)| format!("Probe of {} failed: {}", selector, e)));
+                            }
+                            Err(e) => eprintln!("simulate {}: {}", deployment.contract, e), // The alert is sent without the simulations
+                        }
+                    }
 
-                tools::send_email(self.sender.clone(), receiver.clone(), String::from("SecHelper Robot"), content, self.password.clone(), self.smtp_server.clone()).unwrap();
+                    self.send_alert(&receiver, alert).await?;
+                }
             }
 
-            thread::sleep(Duration::from_secs(30));
+            if let Some(checkpoint) = &self.checkpoint {
+                checkpoint.save(processed);
+            }
         }
 
+        eprintln!("The block subscription is closed");
+        Ok(())
     }
+
+    /// @dev Create a robot to monitor the token transfers of the watched addresses, and send email to receiver when
+    /// a lookalike of a real counterparty sends or "receives" (zero-value `transferFrom`) a zero-value or dust transfer,
+    /// or a fake token mimics a known symbol. The offenders are recorded as potential hackers
+    /// @param addresses The watched addresses, e.g. the treasury
+    /// @param receiver Which email address to receive
+    pub async fn poisoning_robot(&self, addresses: &[String], receiver: String) -> Result<()> {
+        let client = listen::connect(&self.WSS, &self.client).await?;
+        let fetcher = fetcher::Fetch::new(self.API_KEY.clone());
+        let latest = listen::latest_block(&client).await?;
+        let mut watch = PoisoningWatch::new(&fetcher, addresses, latest).await?;
+        println!("Robot starts to monitor {} addresses against poisoning...", addresses.len());
+
+        let mut stream = client.subscribe_blocks().await?;
+
+        while let Some(log) = stream.next().await {
+            println!("block height: {:?}", log.number);
+            let Some(height) = log.number.map(|n| n.as_u64()) else { continue }; // Skip the pending block
+            let from_block = self.checkpoint.as_ref().map_or(height, |c| c.start_block(height));
+
+            let mut failed = false; // The checkpoint stays put, so the failed addresses are retried in the next round
+            for address in watch.addresses() {
+                let txs = fetcher.fetch_address_normal_txs(&address, from_block, height).await;
+                let Some(txs) = recover(txs, &format!("fetch txs of {}", address))? else {
+                    failed = true;
+                    continue;
+                };
+                let transfers = fetcher.fetch_token_transfers(&address, from_block, height).await;
+                let Some(transfers) = recover(transfers, &format!("fetch token transfers of {}", address))? else {
+                    failed = true;
+                    continue;
+                };
+                let internal_txs = fetcher.fetch_address_internal_txs(&address, from_block, height).await;
+                let internal_txs = recover(internal_txs, &format!("fetch internal txs of {}", address))?.unwrap_or_default();
+
+                let attempts = watch.check(&address, &txs, &transfers);
+                watch.learn(&address, &txs, &internal_txs, &transfers);
+                if attempts.is_empty() {
+                    continue;
+                }
+
+                recover(poisoning::record_offenders(&attempts), "record the poisoning offenders")?;
+                let mut alert = Alert::new(
+                    String::from("poisoning: a lookalike address or a fake token transferred with a watched address"),
+                    address.clone(),
+                    format!{"Warning! Someone is poisoning the history of {}, check every address before copying it!", address},
+                );
+                alert.txs = attempts.iter().map(|attempt| attempt.hash.clone()).collect();
+                alert.details = attempts.iter().map(|attempt| attempt.to_string()).collect();
+
+                self.send_alert(&receiver, alert).await?;
+            }
+
+            if let Some(checkpoint) = self.checkpoint.as_ref().filter(|_| !failed) {
+                checkpoint.save(height);
+            }
+        }
+
+        eprintln!("The block subscription is closed");
+        Ok(())
+    }
+
+    /// @dev Create a robot to monitor the `Approval` and `ApprovalForAll` logs of the watched addresses, and send email
+    /// to receiver when one of them approves a spender which is a hacker, unverified or recently deployed
+    /// @param addresses The watched addresses, e.g. the treasury
+    /// @param receiver Which email address to receive
+    pub async fn approval_robot(&self, addresses: &[String], receiver: String) -> Result<()> {
+        println!("Robot starts to monitor the approvals of {} addresses...", addresses.len());
+        let client = listen::connect(&self.WSS, &self.client).await?;
+
+        let mut stream = client.subscribe_blocks().await?;
+
+        while let Some(log) = stream.next().await {
+            println!("block height: {:?}", log.number);
+            let Some(height) = log.number.map(|n| n.as_u64()) else { continue }; // Skip the pending block
+            let from_block = self.checkpoint.as_ref().map_or(height, |c| c.start_block(height));
+
+            let mut failed = false; // The checkpoint stays put, so the failed addresses are retried in the next round
+            for address in addresses {
+                let logs = approvals::fetch_approval_logs(&self.API_KEY, address, from_block, height).await;
+                let Some(logs) = recover(logs, &format!("fetch the approvals of {}", address))? else {
+                    failed = true;
+                    continue;
+                };
+                let mut new_approvals = approvals::current_approvals(&logs);
+                if new_approvals.is_empty() {
+                    continue;
+                }
+                let assessed = approvals::assess_spenders(&self.API_KEY, &mut new_approvals).await;
+                if recover(assessed, &format!("check the spenders of {}", address))?.is_none() {
+                    failed = true;
+                    continue;
+                }
+
+                let risky: Vec<&approvals::Approval> = new_approvals.iter().filter(|approval| approval.is_risky()).collect();
+                if risky.is_empty() {
+                    continue;
+                }
+                let mut alert = Alert::new(
+                    String::from("approval: a watched address approved a hacker, unverified or recently deployed spender"),
+                    address.clone(),
+                    format!{"Warning! The {} you monitor approved a risky spender, revoke it if it is not expected!", address},
+                );
+                alert.txs = risky.iter().map(|approval| approval.hash.clone()).collect();
+                alert.details = risky.iter().map(|approval| approval.to_string()).collect();
+
+                self.send_alert(&receiver, alert).await?;
+            }
+
+            if let Some(checkpoint) = self.checkpoint.as_ref().filter(|_| !failed) {
+                checkpoint.save(height);
+            }
+        }
+
+        eprintln!("The block subscription is closed");
+        Ok(())
+    }
+
+    /// @dev Explain the alert if an explainer is set, then send it
+    async fn send_alert(&self, receiver: &str, mut alert: Alert) -> Result<()> {
+        if let Some(explainer) = &self.explainer {
+            explainer.enrich(&mut alert).await;
+        }
+        recover(self.notifier.send_alert(receiver, &alert).await, "send alert")?;
+        Ok(())
+    }
+
+    /// @dev Fetch the txs of the newest 240 blocks for `warning_robot()`
+    /// @return None if a recoverable error is logged
+    async fn fetch_recent_txs(&self, client: &Provider<Ws>, address: &str) -> Result<Option<Vec<fetcher::TransactionInfo>>> {
+        let Some(last_block) = recover(listen::latest_block(client).await, "get the latest block")? else { return Ok(None) };
+        let fetcher = fetcher::Fetch::new(self.API_KEY.clone());
+
+        let txs = fetcher.fetch_address_all_txs(address, last_block.saturating_sub(240), last_block).await;
+        recover(txs, &format!("fetch txs of {}", address))
+    }
+}
+
+/// @dev A tx of an alert: the hash, the counterparties with their db labels, and the decoded call or the method id
+fn tx_line(tx: &fetcher::TransactionInfo) -> String {
+    let with_label = |address: &str, label: &str| {
+        if label.is_empty() {
+            address.to_string()
+        } else {
+            format!("{} ({})", address, label)
+        }
+    };
+    let call = match &tx.decoded {
+        Some(decoded) => decoded.to_string(),
+        None => tx.methodId.clone(),
+    };
+    format!("{}: {} -> {} value {} {}", tx.hash, with_label(&tx.from, &tx.from_label), with_label(&tx.to, &tx.to_label), tx.value, call)
 }
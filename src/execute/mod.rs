@@ -1 +1,5 @@
-pub mod guardian;
\ No newline at end of file
+pub mod guardian;
+pub mod checkpoint;
+pub mod daemon;
+pub mod deployment;
+pub mod poisoning;
\ No newline at end of file
@@ -0,0 +1,444 @@
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use crate::analyzer::trace::format_amount;
+use crate::error::Result;
+use crate::listener::fetcher::{Fetch, TokenTransfer, TransactionInfo};
+use crate::utils::{address_db, output::Record};
+
+/// How many blocks of history give the real counterparties of a watched address, ~1 month
+pub const COUNTERPARTY_LOOKBACK: u64 = 200_000;
+
+/// How many hex chars after `0x`, and at the end, a lookalike shares with the real address
+pub const PREFIX_CHARS: usize = 4;
+pub const SUFFIX_CHARS: usize = 4;
+
+/// A transfer of less than this many whole units (tokens or ether) is dust
+pub const DUST_AMOUNT: f64 = 0.01;
+
+/// The mainnet tokens fake tokens usually mimic. `ETH` is never a token, any `ETH` token is fake
+const KNOWN_TOKENS: [(&str, &str); 9] = [
+    ("USDT", "0xdac17f958d2ee523a2206206994597c13d831ec7"),
+    ("USDC", "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48"),
+    ("DAI", "0x6b175474e89094c44da98b954eedeac495271d0f"),
+    ("WETH", "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+    ("WBTC", "0x2260fac5e5542a773aa44fbcfedf7c193bc2c599"),
+    ("LINK", "0x514910771af9ca656af840dff83e8264ecf986ca"),
+    ("UNI", "0x1f9840a85d5af5bf1d1762f925bdaddc4201f984"),
+    ("STETH", "0xae7ab96520de3a18e5e111b5eaab095312d7fe84"),
+    ("ETH", ""),
+];
+
+/// @dev A transfer which tries to poison the history of a watched address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoisoningAttempt {
+    pub block: u64,
+    pub hash: String,
+    pub watched: String,
+    pub offender: String, // The lookalike address, or the sender of the fake token
+    pub kind: String, // "zero_value", "dust" or "fake_token"
+    pub mimics: String, // The real counterparty, or the real token(s) of the symbol
+    pub token: String, // The token address, `ETH` for ether
+    pub symbol: String,
+    pub amount: String, // In whole units
+}
+
+impl std::fmt::Display for PoisoningAttempt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind.as_str() {
+            "fake_token" => write!(
+                f,
+                "Fake token: {} {} ({}) between {} and {}, the real {} is {} (tx {}, block {})",
+                self.amount, self.symbol, self.token, self.offender, self.watched, self.symbol, self.mimics, self.hash, self.block
+            ),
+            kind => write!(
+                f,
+                "Lookalike {} transfer: {} {} between {} and {}, which mimics the counterparty {} (tx {}, block {})",
+                if kind == "dust" { "dust" } else { "zero-value" },
+                self.amount, self.symbol, self.offender, self.watched, self.mimics, self.hash, self.block
+            ),
+        }
+    }
+}
+
+impl Record for PoisoningAttempt {
+    fn headers() -> Vec<&'static str> {
+        vec!["block", "hash", "watched", "offender", "kind", "mimics", "token", "symbol", "amount"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.block.to_string(),
+            self.hash.clone(),
+            self.watched.clone(),
+            self.offender.clone(),
+            self.kind.clone(),
+            self.mimics.clone(),
+            self.token.clone(),
+            self.symbol.clone(),
+            self.amount.clone(),
+        ]
+    }
+}
+
+/// @dev The real counterparties and tokens of the addresses watched by `poisoning_robot()`
+#[derive(Debug, Clone, Default)]
+pub struct PoisoningWatch {
+    pub counterparties: HashMap<String, HashSet<String>>, // Watched address => the addresses it sent to
+    pub tokens: HashMap<String, HashSet<String>>, // Normalized symbol => the real token addresses
+}
+
+impl PoisoningWatch {
+    /// @dev Learn the real counterparties and tokens of the watched addresses from their recent history
+    /// @param fetcher The etherscan fetcher
+    /// @param addresses The watched addresses
+    /// @param block The latest block
+    pub async fn new(fetcher: &Fetch, addresses: &[String], block: u64) -> Result<Self> {
+        let mut watch = PoisoningWatch::default();
+        for (symbol, token) in KNOWN_TOKENS {
+            let tokens = watch.tokens.entry(symbol.to_string()).or_default();
+            if !token.is_empty() {
+                tokens.insert(token.to_string());
+            }
+        }
+
+        let start_block = block.saturating_sub(COUNTERPARTY_LOOKBACK);
+        for address in addresses {
+            let address = address.to_lowercase();
+            let txs = fetcher.fetch_address_normal_txs(&address, start_block, block).await?;
+            let internal_txs = fetcher.fetch_address_internal_txs(&address, start_block, block).await?;
+            let transfers = fetcher.fetch_token_transfers(&address, start_block, block).await?;
+            watch.counterparties.entry(address.clone()).or_default();
+            watch.learn(&address, &txs, &internal_txs, &transfers);
+        }
+
+        Ok(watch)
+    }
+
+    /// @return The watched addresses
+    pub fn addresses(&self) -> Vec<String> {
+        self.counterparties.keys().cloned().collect()
+    }
+
+    /// @dev Remember the counterparties and the tokens of the watched address: the receivers of the txs it sent, and of
+    /// its outgoing ether and token transfers above dust. A contract such as a Safe never sends a tx itself, its
+    /// transfers are internal txs or token transfers. The fake tokens of a known symbol are never learned
+    /// @param watched The watched address
+    /// @param txs Its normal txs
+    /// @param internal_txs Its internal txs
+    /// @param transfers Its token transfers
+    pub fn learn(&mut self, watched: &str, txs: &[TransactionInfo], internal_txs: &[TransactionInfo], transfers: &[TokenTransfer]) {
+        let watched = watched.to_lowercase();
+        let counterparties = self.counterparties.entry(watched.clone()).or_default();
+        let sent_by = |tx: &&TransactionInfo| tx.from.eq_ignore_ascii_case(&watched) && !tx.isError && !tx.to.is_empty();
+
+        for tx in txs.iter().filter(sent_by) {
+            counterparties.insert(tx.to.to_lowercase());
+        }
+        for tx in internal_txs.iter().filter(sent_by) {
+            if small_value(&tx.value, Some(18)).is_none() {
+                counterparties.insert(tx.to.to_lowercase());
+            }
+        }
+        for transfer in transfers.iter().filter(|transfer| transfer.from.eq_ignore_ascii_case(&watched)) {
+            let symbol = normalize_symbol(&transfer.tokenSymbol);
+            let token = transfer.token.to_lowercase();
+            let known = KNOWN_TOKENS.iter().any(|(known, _)| *known == symbol);
+            if known && !self.tokens.get(&symbol).is_some_and(|real| real.contains(&token)) {
+                continue; // A fake token can emit a transfer from anyone
+            }
+            if small_value(&transfer.value, transfer.tokenDecimal).is_some() {
+                continue;
+            }
+            counterparties.insert(transfer.to.to_lowercase());
+            if !symbol.is_empty() {
+                self.tokens.entry(symbol).or_default().insert(token);
+            }
+        }
+    }
+
+    /// @dev Find the poisoning attempts in new txs of a watched address: zero-value or dust transfers with a lookalike of
+    /// a real counterparty, and transfers of fake tokens mimicking a known symbol. The txs it sent itself are skipped
+    /// @param watched The watched address
+    /// @param txs Its new normal txs
+    /// @param transfers Its new token transfers
+    pub fn check(&self, watched: &str, txs: &[TransactionInfo], transfers: &[TokenTransfer]) -> Vec<PoisoningAttempt> {
+        let watched = watched.to_lowercase();
+        let sent = sent_hashes(&watched, txs);
+        let mut attempts = Vec::new();
+
+        for transfer in transfers.iter().filter(|transfer| !sent.contains(&transfer.hash)) {
+            let offender = if transfer.to.eq_ignore_ascii_case(&watched) { &transfer.from } else { &transfer.to };
+            let offender = offender.to_lowercase();
+            let attempt = |kind: &str, mimics: String| PoisoningAttempt {
+                block: transfer.blockNumber,
+                hash: transfer.hash.clone(),
+                watched: watched.clone(),
+                offender: offender.clone(),
+                kind: kind.to_string(),
+                mimics,
+                token: transfer.token.to_lowercase(),
+                symbol: transfer.tokenSymbol.clone(),
+                amount: transfer.amount(),
+            };
+
+            if let Some(kind) = small_value(&transfer.value, transfer.tokenDecimal) {
+                if let Some(real) = self.lookalike_of(&watched, &offender) {
+                    attempts.push(attempt(kind, real));
+                }
+            }
+
+            let symbol = normalize_symbol(&transfer.tokenSymbol);
+            if let Some(real) = self.tokens.get(&symbol) {
+                if !real.contains(&transfer.token.to_lowercase()) {
+                    let mut real: Vec<&str> = real.iter().map(|token| token.as_str()).collect();
+                    real.sort();
+                    let mimics = if real.is_empty() { String::from("ether, not a token") } else { real.join(", ") };
+                    attempts.push(attempt("fake_token", mimics));
+                }
+            }
+        }
+
+        // Dust ether from a lookalike
+        for tx in txs.iter().filter(|tx| tx.to.eq_ignore_ascii_case(&watched) && !tx.isError) {
+            let from = tx.from.to_lowercase();
+            let Some(kind) = small_value(&tx.value, Some(18)) else { continue };
+            if let Some(real) = self.lookalike_of(&watched, &from) {
+                attempts.push(PoisoningAttempt {
+                    block: tx.blockNumber,
+                    hash: tx.hash.clone(),
+                    watched: watched.clone(),
+                    offender: from,
+                    kind: kind.to_string(),
+                    mimics: real,
+                    token: String::from("ETH"),
+                    symbol: String::from("ETH"),
+                    amount: format_amount(&tx.value, Some(18)),
+                });
+            }
+        }
+
+        attempts
+    }
+
+    /// @return The real counterparty of the watched address which `address` looks like, None if it is not a lookalike
+    fn lookalike_of(&self, watched: &str, address: &str) -> Option<String> {
+        let counterparties = self.counterparties.get(watched)?;
+        if counterparties.contains(address) || address == watched {
+            return None;
+        }
+        let mut candidates = counterparties.iter().chain(std::iter::once(&watched.to_string())).cloned().collect::<Vec<String>>();
+        candidates.sort();
+        candidates.into_iter().find(|real| is_lookalike(address, real))
+    }
+}
+
+/// @dev Record the offenders as potential hackers in the address db, with the reason as their name
+/// @param attempts The poisoning attempts
+/// @return The newly recorded addresses
+pub fn record_offenders(attempts: &[PoisoningAttempt]) -> Result<Vec<String>> {
+    let mut known: HashSet<String> = address_db::get_db_labels()?.into_keys().collect();
+    let mut recorded = Vec::new();
+
+    for attempt in attempts {
+        let mut offenders = vec![(attempt.offender.clone(), format!("address poisoning of {}", attempt.watched))];
+        if attempt.kind == "fake_token" {
+            offenders.push((attempt.token.clone(), format!("fake {} token", attempt.symbol)));
+        }
+        for (address, name) in offenders {
+            if known.insert(address.clone()) {
+                address_db::write_addresses_db(address.clone())?;
+                address_db::write_address_detail(&address, &name)?;
+                recorded.push(address);
+            }
+        }
+    }
+
+    Ok(recorded)
+}
+
+/// @dev Two different addresses with the same first `PREFIX_CHARS` and last `SUFFIX_CHARS` hex chars
+pub fn is_lookalike(a: &str, b: &str) -> bool {
+    let (a, b) = (a.to_lowercase(), b.to_lowercase());
+    if a == b || a.len() != 42 || b.len() != 42 {
+        return false;
+    }
+    a[2..2 + PREFIX_CHARS] == b[2..2 + PREFIX_CHARS] && a[42 - SUFFIX_CHARS..] == b[42 - SUFFIX_CHARS..]
+}
+
+/// @dev Uppercase a token symbol, map the Cyrillic / Greek homoglyphs to Latin letters and drop the other chars,
+/// e.g. `USDТ` (Cyrillic `Т`) => `USDT`
+pub fn normalize_symbol(symbol: &str) -> String {
+    symbol
+        .chars()
+        .map(|c| match c {
+            'А' | 'а' | 'Α' | 'α' => 'A',
+            'В' | 'в' | 'Β' | 'β' => 'B',
+            'С' | 'с' | 'Ϲ' | 'ϲ' => 'C',
+            'Е' | 'е' | 'Ε' | 'ε' => 'E',
+            'Н' | 'н' | 'Η' => 'H',
+            'І' | 'і' | 'Ι' | 'ι' => 'I',
+            'К' | 'к' | 'Κ' | 'κ' => 'K',
+            'М' | 'м' | 'Μ' => 'M',
+            'О' | 'о' | 'Ο' | 'ο' => 'O',
+            'Р' | 'р' | 'Ρ' | 'ρ' => 'P',
+            'Ѕ' | 'ѕ' => 'S',
+            'Т' | 'т' | 'Τ' | 'τ' => 'T',
+            'Х' | 'х' | 'Χ' | 'χ' => 'X',
+            'У' | 'у' | 'Υ' | 'υ' => 'Y',
+            c => c.to_ascii_uppercase(),
+        })
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect()
+}
+
+/// @return "zero_value" or "dust" if the value is below `DUST_AMOUNT`, None if it is not
+fn small_value(value: &str, decimals: Option<u32>) -> Option<&'static str> {
+    if value.trim_start_matches('0').is_empty() {
+        return Some("zero_value");
+    }
+    let amount: f64 = format_amount(value, Some(decimals?)).parse().ok()?;
+    (amount < DUST_AMOUNT).then_some("dust")
+}
+
+/// @return The hashes of the txs sent by the address itself
+fn sent_hashes(address: &str, txs: &[TransactionInfo]) -> HashSet<String> {
+    txs.iter()
+        .filter(|tx| tx.from.eq_ignore_ascii_case(address))
+        .map(|tx| tx.hash.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAFE: &str = "0x5afe00000000000000000000000000000000cafe";
+    const REAL: &str = "0x1234aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa5678";
+    const LOOKALIKE: &str = "0x1234bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb5678";
+    const USDC: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+    const USDT: &str = "0xdac17f958d2ee523a2206206994597c13d831ec7";
+    const FAKE_USDT: &str = "0x00000000000000000000000000000000000000f1";
+
+    fn watch() -> PoisoningWatch {
+        let mut watch = PoisoningWatch::default();
+        for (symbol, token) in KNOWN_TOKENS {
+            let tokens = watch.tokens.entry(symbol.to_string()).or_default();
+            if !token.is_empty() {
+                tokens.insert(token.to_string());
+            }
+        }
+        watch
+    }
+
+    fn tx(hash: &str, from: &str, to: &str, value: &str) -> TransactionInfo {
+        serde_json::from_value(serde_json::json!({
+            "hash": hash, "from": from, "to": to, "value": value, "input": "0x", "methodId": "0x", "blockNumber": 10,
+        }))
+        .unwrap()
+    }
+
+    fn transfer(hash: &str, from: &str, to: &str, token: &str, symbol: &str, value: &str) -> TokenTransfer {
+        TokenTransfer {
+            hash: hash.to_string(),
+            blockNumber: 10,
+            timeStamp: 0,
+            from: from.to_string(),
+            to: to.to_string(),
+            token: token.to_string(),
+            tokenSymbol: symbol.to_string(),
+            tokenDecimal: Some(6),
+            value: value.to_string(),
+            from_label: String::new(),
+            to_label: String::new(),
+        }
+    }
+
+    #[test]
+    fn lookalikes_share_the_prefix_and_the_suffix() {
+        assert!(is_lookalike(LOOKALIKE, &REAL.to_uppercase().replace("0X", "0x")));
+        assert!(!is_lookalike(REAL, REAL));
+        assert!(!is_lookalike("0x1234bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb5679", REAL));
+        assert!(!is_lookalike("0x1235bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb5678", REAL));
+        assert!(!is_lookalike("0x12345678", REAL));
+    }
+
+    #[test]
+    fn normalizes_the_homoglyphs() {
+        assert_eq!(normalize_symbol("USDТ"), "USDT"); // Cyrillic `Т`
+        assert_eq!(normalize_symbol("UЅDС"), "USDC"); // Cyrillic `Ѕ` and `С`
+        assert_eq!(normalize_symbol("ΕΤΗ"), "ETH"); // Greek
+        assert_eq!(normalize_symbol("usdt "), "USDT");
+        assert_eq!(normalize_symbol("U.S.D.T"), "USDT");
+    }
+
+    #[test]
+    fn tells_the_zero_value_and_the_dust() {
+        assert_eq!(small_value("0", Some(18)), Some("zero_value"));
+        assert_eq!(small_value("000", None), Some("zero_value"));
+        assert_eq!(small_value("9999", Some(6)), Some("dust"));
+        assert_eq!(small_value("10000", Some(6)), None);
+        assert_eq!(small_value("1", None), None);
+    }
+
+    #[test]
+    fn learns_a_safe_from_its_internal_txs_and_token_transfers() {
+        let mut watch = watch();
+        // A Safe never sends a tx itself, its transfers are internal txs and token transfers
+        let internal_txs = [
+            tx("0x01", SAFE, REAL, "1000000000000000000"),
+            tx("0x02", SAFE, "0x00000000000000000000000000000000000000d1", "1000"),
+        ];
+        let transfers = [
+            transfer("0x03", SAFE, "0x00000000000000000000000000000000000000e1", USDC, "USDC", "5000000"),
+            transfer("0x04", SAFE, "0x00000000000000000000000000000000000000e2", FAKE_USDT, "USDT", "5000000"),
+            transfer("0x05", SAFE, "0x00000000000000000000000000000000000000e3", "0x00000000000000000000000000000000000000f2", "GOV", "5000000"),
+            transfer("0x06", "0x00000000000000000000000000000000000000e4", SAFE, USDC, "USDC", "5000000"),
+        ];
+        watch.learn(&SAFE.to_uppercase().replace("0X", "0x"), &[], &internal_txs, &transfers);
+
+        let mut counterparties: Vec<&str> = watch.counterparties[SAFE].iter().map(|address| address.as_str()).collect();
+        counterparties.sort();
+        assert_eq!(
+            counterparties,
+            vec![
+                "0x00000000000000000000000000000000000000e1",
+                "0x00000000000000000000000000000000000000e3",
+                REAL,
+            ]
+        );
+        assert!(!watch.tokens["USDT"].contains(FAKE_USDT));
+        assert!(watch.tokens["GOV"].contains("0x00000000000000000000000000000000000000f2"));
+    }
+
+    #[test]
+    fn finds_the_poisoning_attempts() {
+        let mut watch = watch();
+        watch.learn(SAFE, &[tx("0x01", SAFE, REAL, "1")], &[], &[]);
+
+        let txs = [
+            tx("0x11", LOOKALIKE, SAFE, "100"), // Dust ether
+            tx("0x12", SAFE, LOOKALIKE, "0"), // Sent by the watched address itself
+        ];
+        let transfers = [
+            transfer("0x13", SAFE, LOOKALIKE, USDC, "USDC", "0"), // A zero-value `transferFrom` of the watched address
+            transfer("0x14", "0x00000000000000000000000000000000000000e5", SAFE, FAKE_USDT, "USDТ", "1000000"),
+            transfer("0x12", SAFE, LOOKALIKE, USDC, "USDC", "0"),
+            transfer("0x15", REAL, SAFE, USDT, "USDT", "0"), // Zero value from the real counterparty
+        ];
+        let attempts = watch.check(SAFE, &txs, &transfers);
+        let found: Vec<(&str, &str, &str)> = attempts.iter().map(|a| (a.hash.as_str(), a.kind.as_str(), a.mimics.as_str())).collect();
+        assert_eq!(
+            found,
+            vec![("0x13", "zero_value", REAL), ("0x14", "fake_token", USDT), ("0x11", "dust", REAL)]
+        );
+        assert_eq!(attempts[1].offender, "0x00000000000000000000000000000000000000e5");
+        assert_eq!(attempts[1].amount, "1");
+    }
+
+    #[test]
+    fn any_eth_token_is_fake() {
+        let attempts = watch().check(SAFE, &[], &[transfer("0x21", FAKE_USDT, SAFE, FAKE_USDT, "ETH", "1000000")]);
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].mimics, "ether, not a token");
+    }
+}
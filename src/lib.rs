@@ -0,0 +1,18 @@
+#![allow(non_snake_case)]
+//! SecHelper: a library for assisting in monitoring, analyzing, and alerting blockchain security threats.
+//!
+//! The `SecHelper` binary is a thin CLI over this library.
+pub mod ai;
+pub mod analyzer;
+pub mod config;
+pub mod error;
+pub mod execute;
+pub mod listener;
+pub mod utils;
+
+pub use config::Config;
+pub use error::{Error, Result};
+pub use execute::guardian::MessageRobot;
+pub use listener::fetcher::{Fetch, TransactionInfo};
+pub use listener::listen::Listen;
+pub use utils::{address_db, notifier::EmailNotifier, output::OutputFormat};
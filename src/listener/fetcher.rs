@@ -1,20 +1,133 @@
-#![allow(dead_code)]
-use std::error::Error;
-use reqwest::get;
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
-use crate::utils::tools;
+use crate::analyzer::{decoder::{DecodedCall, Decoder}, trace::format_amount};
+use crate::error::{Error, Result};
+use crate::utils::{address_db, etherscan, output::Record};
 
 /// @dev：Used to parse the data returned by ETHERSCAN
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionInfo {
     pub hash: String,
     pub from: String,
     pub to: String,
     pub value: String,
     pub input: String,
-    pub methodId: String
+    pub methodId: String,
+    #[serde(default)]
+    pub blockNumber: u64,
+    #[serde(default)]
+    pub timeStamp: u64, // Unix seconds
+    #[serde(default)]
+    pub contractAddress: String, // The created contract, empty if the tx is not a creation
+    #[serde(default)]
+    pub isError: bool,
+    #[serde(default)]
+    pub from_label: String, // The label of `from` in db
+    #[serde(default)]
+    pub to_label: String, // The label of `to` in db
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<DecodedCall>, // The decoded input, filled by `Fetch::decode_txs()`
+}
+
+impl Record for TransactionInfo {
+    fn headers() -> Vec<&'static str> {
+        vec!["hash", "from", "from_label", "to", "to_label", "value", "methodId", "decoded", "input"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            self.from.clone(),
+            self.from_label.clone(),
+            self.to.clone(),
+            self.to_label.clone(),
+            self.value.clone(),
+            self.methodId.clone(),
+            self.decoded.as_ref().map(|decoded| decoded.to_string()).unwrap_or_default(),
+            self.input.clone(),
+        ]
+    }
+}
+
+/// @dev An ERC20 transfer returned by the etherscan `tokentx` api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenTransfer {
+    pub hash: String,
+    pub blockNumber: u64,
+    pub timeStamp: u64, // Unix seconds
+    pub from: String,
+    pub to: String,
+    pub token: String, // The token address
+    pub tokenSymbol: String,
+    pub tokenDecimal: Option<u32>,
+    pub value: String, // In the smallest unit
+    #[serde(default)]
+    pub from_label: String, // The label of `from` in db
+    #[serde(default)]
+    pub to_label: String, // The label of `to` in db
+}
+
+impl TokenTransfer {
+    /// @return The value in whole units, e.g. `1.5`
+    pub fn amount(&self) -> String {
+        format_amount(&self.value, self.tokenDecimal)
+    }
+}
+
+impl Record for TokenTransfer {
+    fn headers() -> Vec<&'static str> {
+        vec!["hash", "blockNumber", "from", "from_label", "to", "to_label", "token", "tokenSymbol", "value", "amount"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            self.blockNumber.to_string(),
+            self.from.clone(),
+            self.from_label.clone(),
+            self.to.clone(),
+            self.to_label.clone(),
+            self.token.clone(),
+            self.tokenSymbol.clone(),
+            self.value.clone(),
+            self.amount(),
+        ]
+    }
+}
+
+/// @dev The result of `is_invoke_mixing_service()`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MixingServiceCheck {
+    pub address: String,
+    pub start_block: u64,
+    pub end_block: u64,
+    pub invoked: bool,
 }
 
+impl Record for MixingServiceCheck {
+    fn headers() -> Vec<&'static str> {
+        vec!["address", "start_block", "end_block", "invoked"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.address.clone(),
+            self.start_block.to_string(),
+            self.end_block.to_string(),
+            self.invoked.to_string(),
+        ]
+    }
+}
+
+/// How many of the first txs are searched for the funding of an address
+const FIRST_TXS: u64 = 100;
+
+/// An end block after the latest one, as in the etherscan docs
+pub const LATEST_BLOCK: u64 = 99_999_999;
+
+/// The most results etherscan returns for one request
+pub const MAX_RESULTS: usize = 10_000;
+
 pub struct Fetch {
     API_KEY: String,
 }
@@ -35,104 +148,13 @@ impl Fetch {
         address: &str,
         start_block: u64,
         end_block: u64,
-    ) -> Result<Vec<TransactionInfo>, Box<dyn Error>> {
-        let mut transaction_infos = Vec::new();
-        
-        let url_normal = format!("https://api.etherscan.io/api?module=account&action=txlist&address={}&startblock={}&endblock={}&sort=asc&apikey={}",
-            address, 
-            start_block, 
-            end_block, 
-            self.API_KEY.clone()
-        );
-        let url_internal = format!("https://api.etherscan.io/api?module=account&action=txlistinternal&address={}&startblock={}&endblock={}&sort=asc&apikey={}",
-            address, 
-            start_block, 
-            end_block, 
-            self.API_KEY.clone()
-        );
-
-        let response_normal = get(&url_normal).await?;
-        let response_internal = get(&url_internal).await?;
-
-        if response_normal.status().is_success() {
-            let body = response_normal.text().await?;
-            let json_data: serde_json::Value = serde_json::from_str(&body)?;
-
-            if let Some(transactions) = json_data["result"].as_array() {
-                
-
-                for transaction in transactions {
-                    let hash = transaction["hash"].as_str().unwrap().to_string();
-                    let from = transaction["from"].as_str().unwrap().to_string();
-                    let to = transaction["to"].as_str().unwrap().to_string();
-                    let value = transaction["value"].as_str().unwrap().to_string();
-                    let input = transaction["input"].as_str().unwrap().to_string();
-                    let methodId = transaction["methodId"].as_str().unwrap().to_string();
-
-                    let transaction_info = TransactionInfo {
-                        hash,
-                        from,
-                        to,
-                        value,
-                        input,
-                        methodId
-                    };
-
-                    println!("{:?}", transaction_info);
-
-                    transaction_infos.push(transaction_info);
-                }
-            }
-        } else {
-            return Err(format!(
-                "HTTP request failed with status code: {}",
-                response_normal.status()
-            )
-            .into());
-        }
-
-        if response_internal.status().is_success() {
-            let body = response_internal.text().await?;
-            let json_data: serde_json::Value = serde_json::from_str(&body)?;
-
-            if let Some(transactions) = json_data["result"].as_array() {
-                
-
-                for transaction in transactions {
-                    let hash = transaction["hash"].as_str().unwrap().to_string();
-                    let from = transaction["from"].as_str().unwrap().to_string();
-                    let to = transaction["to"].as_str().unwrap().to_string();
-                    let value = transaction["value"].as_str().unwrap().to_string();
-                    let input = transaction["input"].as_str().unwrap().to_string();
-                    let methodId = transaction["methodId"].as_str().unwrap().to_string();
-
-                    let transaction_info = TransactionInfo {
-                        hash,
-                        from,
-                        to,
-                        value,
-                        input,
-                        methodId
-                    };
-
-                    println!("{:?}", transaction_info);
-
-                    transaction_infos.push(transaction_info);
-                }
-            }
-        } else {
-            return Err(format!(
-                "HTTP request failed with status code: {}",
-                response_internal.status()
-            )
-            .into());
-        }
-
-        return Ok(transaction_infos);
+    ) -> Result<Vec<TransactionInfo>> {
+        let mut transaction_infos = self.fetch_address_normal_txs(address, start_block, end_block).await?;
+        transaction_infos.extend(self.fetch_address_internal_txs(address, start_block, end_block).await?);
 
+        Ok(transaction_infos)
     }
 
-
     /// @dev Check that if an address is invoke to mixing service
     /// @param address The address's txs you fetch
     /// @param start_block The blocko fetch txs from
@@ -143,74 +165,49 @@ impl Fetch {
         address: &str,
         start_block: u64,
         end_block: u64,
-    ) -> Result<bool, Box<dyn Error>>{
-        let mut is_invoke = false;
-        
-        let url_normal = format!("https://api.etherscan.io/api?module=account&action=txlist&address={}&startblock={}&endblock={}&sort=asc&apikey={}",
-            address, 
-            start_block, 
-            end_block, 
-            self.API_KEY.clone()
-        );
-        let url_internal = format!("https://api.etherscan.io/api?module=account&action=txlistinternal&address={}&startblock={}&endblock={}&sort=asc&apikey={}",
-            address, 
-            start_block, 
-            end_block, 
-            self.API_KEY.clone()
-        );
+    ) -> Result<bool> {
+        let addresses = address_db::get_db_address("mixing_service")?;
+        let txs = self.fetch_address_all_txs(address, start_block, end_block).await?;
 
-        let response_normal = get(&url_normal).await?;
-        let response_internal = get(&url_internal).await?;
-
-        let addresses = tools::get_db_address("mixing_service");
-
-        if response_normal.status().is_success() {
-            let body = response_normal.text().await?;
-            let json_data: serde_json::Value = serde_json::from_str(&body)?;
-
-            if let Some(transactions) = json_data["result"].as_array() {
-                for transaction in transactions {
-                    let from = transaction["from"].as_str().unwrap();
-                    let to = transaction["to"].as_str().unwrap();
-                    for addr in &addresses {
-                        if addr.to_lowercase() == to.to_lowercase() || addr.to_lowercase() == from.to_lowercase() {
-                            is_invoke = true;
-                        }
-                    }
-                }
-            }
-        } else {
-            return Err(format!(
-                "HTTP request failed with status code: {}",
-                response_internal.status()
-            )
-            .into());
-        }
+        let is_invoke = txs.iter().any(|tx| {
+            addresses.iter().any(|addr| {
+                addr.to_lowercase() == tx.to.to_lowercase() || addr.to_lowercase() == tx.from.to_lowercase()
+            })
+        });
 
-        if response_internal.status().is_success() {
-            let body = response_internal.text().await?;
-            let json_data: serde_json::Value = serde_json::from_str(&body)?;
-
-            if let Some(transactions) = json_data["result"].as_array() {
-                for transaction in transactions {
-                    let from = transaction["from"].as_str().unwrap();
-                    let to = transaction["to"].as_str().unwrap();
-                    for addr in &addresses {
-                        if addr.to_lowercase() == to.to_lowercase() || addr.to_lowercase() == from.to_lowercase() {
-                            is_invoke = true;
-                        }
-                    }
-                }
-            }
-        } else {
-            return Err(format!(
-                "HTTP request failed with status code: {}",
-                response_internal.status()
-            )
-            .into());
-        }
+        Ok(is_invoke)
+    }
+
+    /// @dev Check that if an address received ether from a mixing service, e.g. a Tornado Cash withdrawal to it
+    /// @param address The address's txs you fetch
+    /// @param start_block The blocko fetch txs from
+    /// @param end_block The blocko fetch txs to
+    /// @return True or false
+    pub async fn is_funded_by_mixing_service(
+        &self,
+        address: &str,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<bool> {
+        let addresses = address_db::get_db_address("mixing_service")?;
+        let txs = self.fetch_address_internal_txs(address, start_block, end_block).await?;
+
+        let is_funded = txs.iter().any(|tx| {
+            tx.to.eq_ignore_ascii_case(address)
+                && !tx.isError
+                && !tx.value.trim_start_matches('0').is_empty()
+                && addresses.iter().any(|addr| addr.eq_ignore_ascii_case(&tx.from))
+        });
+
+        Ok(is_funded)
+    }
 
-        return Ok(is_invoke);
+    /// @dev Decode the inputs of txs into their `decoded` field, with the verified ABIs of the called contracts
+    /// (cached in `output/abis/`) or the selector db. The calls inside multicall / execute / execTransaction are decoded too
+    /// @param txs The txs to decode
+    pub async fn decode_txs(&self, txs: &mut [TransactionInfo]) -> Result<()> {
+        let mut decoder = Decoder::new(Some(self.API_KEY.clone()))?;
+        decoder.decode_transactions(txs).await
     }
 
     /// @dev Obtain normal transactions for a certain address
@@ -223,57 +220,8 @@ impl Fetch {
         address: &str,
         start_block: u64,
         end_block: u64,
-    ) -> Result<Vec<TransactionInfo>, Box<dyn Error>> {
-        
-        let url = format!("https://api.etherscan.io/api?module=account&action=txlist&address={}&startblock={}&endblock={}&sort=asc&apikey={}",
-            address, 
-            start_block, 
-            end_block, 
-            self.API_KEY.clone()
-        );
-
-        let response = get(&url).await?;
-
-        if response.status().is_success() {
-            let body = response.text().await?;
-            let json_data: serde_json::Value = serde_json::from_str(&body)?;
-
-            if let Some(transactions) = json_data["result"].as_array() {
-                let mut transaction_infos = Vec::new();
-
-                for transaction in transactions {
-                    let hash = transaction["hash"].as_str().unwrap().to_string();
-                    let from = transaction["from"].as_str().unwrap().to_string();
-                    let to = transaction["to"].as_str().unwrap().to_string();
-                    let value = transaction["value"].as_str().unwrap().to_string();
-                    let input = transaction["input"].as_str().unwrap().to_string();
-                    let methodId = transaction["methodId"].as_str().unwrap().to_string();
-
-                    let transaction_info = TransactionInfo {
-                        hash,
-                        from,
-                        to,
-                        value,
-                        input,
-                        methodId
-                    };
-
-                    println!("{:?}", transaction_info);
-
-                    transaction_infos.push(transaction_info);
-                }
-
-                return Ok(transaction_infos);
-            }
-        } else {
-            return Err(format!(
-                "HTTP request failed with status code: {}",
-                response.status()
-            )
-            .into());
-        }
-
-        Ok(Vec::new())
+    ) -> Result<Vec<TransactionInfo>> {
+        self.fetch_txs("txlist", address, start_block, end_block, "asc", None).await
     }
 
     /// @dev Obtain internal transactions for a certain address
@@ -286,56 +234,249 @@ impl Fetch {
         address: &str,
         start_block: u64,
         end_block: u64,
-    ) -> Result<Vec<TransactionInfo>, Box<dyn Error>> {
-        
-        let url = format!("https://api.etherscan.io/api?module=account&action=txlistinternal&address={}&startblock={}&endblock={}&sort=asc&apikey={}",
-            address, 
+    ) -> Result<Vec<TransactionInfo>> {
+        self.fetch_txs("txlistinternal", address, start_block, end_block, "asc", None).await
+    }
+
+    /// @dev Obtain the internal transactions of all addresses in a block range, e.g. the contracts created by factories
+    /// @param start_block The blocko fetch txs from
+    /// @param end_block The blocko fetch txs to
+    /// @return A vector of txs, at most 10000
+    pub async fn fetch_block_internal_txs(&self, start_block: u64, end_block: u64) -> Result<Vec<TransactionInfo>> {
+        self.fetch_txs("txlistinternal", "", start_block, end_block, "asc", None).await
+    }
+
+    /// @dev Obtain the ERC20 transfers from or to a certain address
+    /// @param address The address's transfers you fetch
+    /// @param start_block The blocko fetch transfers from
+    /// @param end_block The blocko fetch transfers to
+    /// @return A vector of transfers, the oldest first
+    pub async fn fetch_token_transfers(
+        &self,
+        address: &str,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<Vec<TokenTransfer>> {
+        let url = format!("https://api.etherscan.io/api?module=account&action=tokentx&address={}&startblock={}&endblock={}&sort=asc&apikey={}",
+            address,
+            start_block,
+            end_block,
+            self.API_KEY.clone()
+        );
+
+        let result = etherscan::get_result(&url).await?;
+        let transfers = result
+            .as_array()
+            .ok_or_else(|| Error::Decode(format!("Expected an array of transfers, got: {}", result)))?;
+        let labels = address_db::get_db_labels()?;
+
+        transfers.iter().map(|transfer| parse_token_transfer(transfer, &labels)).collect()
+    }
+
+    /// @dev Obtain the first ether received by a certain address, from a normal or an internal transaction
+    /// @param address The address
+    /// @param end_block The block to look until
+    /// @return The tx, None if it received no ether
+    pub async fn fetch_first_funding(&self, address: &str, end_block: u64) -> Result<Option<TransactionInfo>> {
+        let mut txs = self.fetch_txs("txlist", address, 0, end_block, "asc", Some(FIRST_TXS)).await?;
+        txs.extend(self.fetch_txs("txlistinternal", address, 0, end_block, "asc", Some(FIRST_TXS)).await?);
+        txs.sort_by_key(|tx| (tx.blockNumber, tx.timeStamp));
+
+        Ok(txs
+            .into_iter()
+            .find(|tx| tx.to.eq_ignore_ascii_case(address) && !tx.isError && tx.value != "0" && !tx.value.is_empty()))
+    }
+
+    /// @dev Obtain the latest normal transaction of a certain address
+    /// @param address The address
+    /// @return The tx, None if it has none
+    pub async fn fetch_last_tx(&self, address: &str) -> Result<Option<TransactionInfo>> {
+        let txs = self.fetch_txs("txlist", address, 0, LATEST_BLOCK, "desc", Some(1)).await?;
+        Ok(txs.into_iter().next())
+    }
+
+    /// @dev Obtain the first normal transaction of a certain address, the creation tx for a contract
+    /// @param address The address
+    /// @return The tx, None if it has none
+    pub async fn fetch_first_tx(&self, address: &str) -> Result<Option<TransactionInfo>> {
+        let txs = self.fetch_txs("txlist", address, 0, LATEST_BLOCK, "asc", Some(1)).await?;
+        Ok(txs.into_iter().next())
+    }
+
+    /// @dev Obtain the ether balance of a certain address
+    /// @param address The address
+    /// @return The balance in wei
+    pub async fn fetch_balance(&self, address: &str) -> Result<String> {
+        let url = format!("https://api.etherscan.io/api?module=account&action=balance&address={}&tag=latest&apikey={}",
+            address,
+            self.API_KEY.clone()
+        );
+
+        let result = etherscan::get_result(&url).await?;
+        result
+            .as_str()
+            .map(|balance| balance.to_string())
+            .ok_or_else(|| Error::Decode(format!("Expected a balance, got: {}", result)))
+    }
+
+    /// @dev Obtain the runtime bytecode of a certain address, with the etherscan `eth_getCode` proxy
+    /// @param address The address
+    /// @return The hex bytecode, `0x` for an EOA
+    pub async fn fetch_code(&self, address: &str) -> Result<String> {
+        let url = format!("https://api.etherscan.io/api?module=proxy&action=eth_getCode&address={}&tag=latest&apikey={}",
+            address,
+            self.API_KEY.clone()
+        );
+
+        let result = etherscan::get_result(&url).await?;
+        result
+            .as_str()
+            .map(|code| code.to_string())
+            .ok_or_else(|| Error::Decode(format!("Expected a bytecode, got: {}", result)))
+    }
+
+    /// @dev Call a contract at the latest block, with the etherscan `eth_call` proxy
+    /// @param to The contract address
+    /// @param data The hex calldata
+    /// @return The hex return data
+    pub async fn fetch_call(&self, to: &str, data: &str) -> Result<String> {
+        let url = format!("https://api.etherscan.io/api?module=proxy&action=eth_call&to={}&data={}&tag=latest&apikey={}",
+            to,
+            data,
+            self.API_KEY.clone()
+        );
+
+        let result = etherscan::get_result(&url).await?;
+        result
+            .as_str()
+            .map(|data| data.to_string())
+            .ok_or_else(|| Error::Decode(format!("Expected the return data, got: {}", result)))
+    }
+
+    /// @dev Obtain the deployer and the creation tx of a contract
+    /// @param address The contract address
+    /// @return The deployer and the tx hash, None if etherscan does not know it (e.g. an EOA or a genesis contract)
+    pub async fn fetch_contract_creation(&self, address: &str) -> Result<Option<(String, String)>> {
+        let url = format!("https://api.etherscan.io/api?module=contract&action=getcontractcreation&contractaddresses={}&apikey={}",
+            address,
+            self.API_KEY.clone()
+        );
+
+        let result = etherscan::get_result(&url).await?;
+        let creation = result.as_array().and_then(|creations| creations.first()).map(|creation| {
+            (
+                creation["contractCreator"].as_str().unwrap_or_default().to_lowercase(),
+                creation["txHash"].as_str().unwrap_or_default().to_string(),
+            )
+        });
+        Ok(creation.filter(|(creator, _)| !creator.is_empty()))
+    }
+
+    /// @dev Request an etherscan account api and parse the txs
+    /// @param action "txlist" or "txlistinternal"
+    /// @param sort "asc" or "desc"
+    /// @param limit Only the first `limit` txs, None for all (at most 10000)
+    async fn fetch_txs(
+        &self,
+        action: &str,
+        address: &str,
+        start_block: u64,
+        end_block: u64,
+        sort: &str,
+        limit: Option<u64>,
+    ) -> Result<Vec<TransactionInfo>> {
+        let mut url = format!("https://api.etherscan.io/api?module=account&action={}&startblock={}&endblock={}&sort={}&apikey={}",
+            action,
             start_block, 
             end_block, 
+            sort,
             self.API_KEY.clone()
         );
+        if !address.is_empty() {
+            url.push_str(&format!("&address={}", address));
+        }
+        if let Some(limit) = limit {
+            url.push_str(&format!("&page=1&offset={}", limit));
+        }
 
-        let response = get(&url).await?;
-
-        if response.status().is_success() {
-            let body = response.text().await?;
-            let json_data: serde_json::Value = serde_json::from_str(&body)?;
-
-            if let Some(transactions) = json_data["result"].as_array() {
-                let mut transaction_infos = Vec::new();
-
-                for transaction in transactions {
-                    let hash = transaction["hash"].as_str().unwrap().to_string();
-                    let from = transaction["from"].as_str().unwrap().to_string();
-                    let to = transaction["to"].as_str().unwrap().to_string();
-                    let value = transaction["value"].as_str().unwrap().to_string();
-                    let input = transaction["input"].as_str().unwrap().to_string();
-                    let methodId = transaction["methodId"].as_str().unwrap().to_string();
-
-                    let transaction_info = TransactionInfo {
-                        hash,
-                        from,
-                        to,
-                        value,
-                        input,
-                        methodId
-                    };
-
-                    println!("{:?}", transaction_info);
-
-                    transaction_infos.push(transaction_info);
-                }
-
-                return Ok(transaction_infos);
-            }
-        } else {
-            return Err(format!(
-                "HTTP request failed with status code: {}",
-                response.status()
-            )
-            .into());
+        let result = etherscan::get_result(&url).await?;
+        let transactions = result
+            .as_array()
+            .ok_or_else(|| Error::Decode(format!("Expected an array of txs, got: {}", result)))?;
+        let labels = address_db::get_db_labels()?;
+
+        let mut transaction_infos = Vec::new();
+        for transaction in transactions {
+            transaction_infos.push(parse_transaction(transaction, &labels)?);
         }
 
-        Ok(Vec::new())
+        Ok(transaction_infos)
     }
 }
+
+/// @dev Parse a tx returned by etherscan
+/// @notice Internal txs have no `methodId`, and contract creations have an empty `to`, so only `hash` and `from` are required
+/// @param transaction A tx in the `result` array
+/// @param labels The labels from the address db
+fn parse_transaction(transaction: &serde_json::Value, labels: &HashMap<String, String>) -> Result<TransactionInfo> {
+    let field = |name: &str| -> Result<String> {
+        transaction[name]
+            .as_str()
+            .map(|value| value.to_string())
+            .ok_or_else(|| Error::Decode(format!("Missing field `{}` in etherscan tx: {}", name, transaction)))
+    };
+    let optional_field = |name: &str| -> String {
+        transaction[name].as_str().unwrap_or_default().to_string()
+    };
+
+    let from = field("from")?;
+    let to = optional_field("to");
+
+    Ok(TransactionInfo {
+        hash: field("hash")?,
+        from_label: labels.get(&from.to_lowercase()).cloned().unwrap_or_default(),
+        to_label: labels.get(&to.to_lowercase()).cloned().unwrap_or_default(),
+        from,
+        to,
+        value: optional_field("value"),
+        input: optional_field("input"),
+        methodId: optional_field("methodId"),
+        blockNumber: optional_field("blockNumber").parse().unwrap_or_default(),
+        timeStamp: optional_field("timeStamp").parse().unwrap_or_default(),
+        contractAddress: optional_field("contractAddress"),
+        isError: optional_field("isError") == "1",
+        decoded: None,
+    })
+}
+
+/// @dev Parse an ERC20 transfer returned by etherscan
+/// @param transfer A transfer in the `result` array
+/// @param labels The labels from the address db
+fn parse_token_transfer(transfer: &serde_json::Value, labels: &HashMap<String, String>) -> Result<TokenTransfer> {
+    let field = |name: &str| -> Result<String> {
+        transfer[name]
+            .as_str()
+            .map(|value| value.to_string())
+            .ok_or_else(|| Error::Decode(format!("Missing field `{}` in etherscan transfer: {}", name, transfer)))
+    };
+    let optional_field = |name: &str| -> String {
+        transfer[name].as_str().unwrap_or_default().to_string()
+    };
+
+    let from = field("from")?;
+    let to = field("to")?;
+
+    Ok(TokenTransfer {
+        hash: field("hash")?,
+        blockNumber: optional_field("blockNumber").parse().unwrap_or_default(),
+        timeStamp: optional_field("timeStamp").parse().unwrap_or_default(),
+        from_label: labels.get(&from.to_lowercase()).cloned().unwrap_or_default(),
+        to_label: labels.get(&to.to_lowercase()).cloned().unwrap_or_default(),
+        from,
+        to,
+        token: field("contractAddress")?,
+        tokenSymbol: optional_field("tokenSymbol"),
+        tokenDecimal: optional_field("tokenDecimal").parse().ok(),
+        value: optional_field("value"),
+    })
+}
@@ -3,130 +3,284 @@ use ethers::{
         abi::AbiDecode,
         types::{Address, BlockNumber, Filter, U256},
     },
+    utils::hex,
     providers::{Middleware, Provider, StreamExt, Ws},
 };
 use serde::{Deserialize, Serialize};
-use eyre::Result;
+use crate::error::{recover, Error, Result};
+use crate::execute::checkpoint::Checkpoint;
 use crate::listener::fetcher;
-use crate::utils::tools;
+use crate::utils::{
+    address_db,
+    output::{OutputFormat, Record, Stream},
+};
 
-/// @dev：Used to parse the data For addresses.json
+/// @dev A decoded log printed by `subscribe_event()`
 #[derive(Debug, Serialize, Deserialize)]
-struct AddressData {
-    eth: Data,
-    bsc: Data
+pub struct EventInfo {
+    pub block: u64,
+    pub tx: String,
+    pub address: String,
+    pub address_label: String, // The label of `address` in db
+    pub topics: Vec<String>, // topic1..topicN decoded as uint256
+    pub data: String, // data decoded as uint256, or the raw hex if it is not a single word
+}
+
+impl Record for EventInfo {
+    fn headers() -> Vec<&'static str> {
+        vec!["block", "tx", "address", "address_label", "topics", "data"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.block.to_string(),
+            self.tx.clone(),
+            self.address.clone(),
+            self.address_label.clone(),
+            self.topics.join(" "),
+            self.data.clone(),
+        ]
+    }
 }
 
-/// @dev：Used to parse the data For addresses.json
+/// @dev A user who interacts with a mixing service, printed by `monitor_mixing_service()`
 #[derive(Debug, Serialize, Deserialize)]
-struct Data {
-    hacker: Vec<String>,
-    protocol: Vec<String>,
-    mixing_service: Vec<String>,
-    potential_hacker: Vec<String>
+pub struct MixingServiceUser {
+    pub block: u64,
+    pub hash: String,
+    pub mixing_service: String,
+    pub mixing_service_label: String, // The label of `mixing_service` in db
+    pub user: String,
+}
+
+impl Record for MixingServiceUser {
+    fn headers() -> Vec<&'static str> {
+        vec!["block", "hash", "mixing_service", "mixing_service_label", "user"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.block.to_string(),
+            self.hash.clone(),
+            self.mixing_service.clone(),
+            self.mixing_service_label.clone(),
+            self.user.clone(),
+        ]
+    }
 }
 
 pub struct Listen {
     pub WSS: String,
     pub API_Key: String,
+    pub output: OutputFormat,
+    client: Option<Provider<Ws>>, // A shared connection, None to connect to WSS
+    checkpoint: Option<Checkpoint>, // Where to record the last processed block
 }
 
 impl Listen {
 
     /// @param wss WSS URL
     /// @param api_key Etherscan API kEY
-    pub fn new(wss: String, api_key: String) -> Self {
+    /// @param output The output format of the records
+    pub fn new(wss: String, api_key: String, output: OutputFormat) -> Self {
         Listen {
             WSS: wss,
             API_Key: api_key,
+            output,
+            client: None,
+            checkpoint: None,
         }
     }
 
+    /// @dev Use a shared connection instead of connecting to WSS
+    /// @param client The shared connection
+    pub fn with_client(mut self, client: Provider<Ws>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// @dev Record the last processed block, and catch up the missed blocks after a restart
+    /// @param checkpoint The checkpoint of this monitor
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    /// @dev The shared connection, or a new connection to WSS
+    async fn connect(&self) -> Result<Provider<Ws>> {
+        connect(&self.WSS, &self.client).await
+    }
+
     /// @dev Monitor a certain address if it has ERC20 transfer tx
     /// @param address The address to monitor
     /// @param event The event signature. E.g. `Transfer(address,address,uint256)`
     pub async fn subscribe_event(&self, address: String, event: &str) -> Result<()> {
-        let client =
-        Provider::<Ws>::connect(self.WSS.clone()).await?;
+        let client = self.connect().await?;
     
-        let last_block = client.get_block(BlockNumber::Latest).await?.unwrap().number.unwrap();
-        println!("last_block: {last_block}");
+        let last_block = latest_block(&client).await?;
+        eprintln!("last_block: {last_block}");
+
+        let address = address
+            .parse::<Address>()
+            .map_err(|e| Error::InvalidInput(format!("Invalid address `{}`: {}", address, e)))?;
     
         let event_filter =
             Filter::new()
                 .to_block(last_block + 999999999) // To which block, we just plus 999999999 because we assume that the program will not run continuously for such a long time
                 .event(event) 
-                .address(address.parse::<Address>().unwrap()); // The address we monitor
+                .address(address); // The address we monitor
     
+        let labels = address_db::get_db_labels()?;
+        let mut output = Stream::new(self.output);
         let mut stream = client.subscribe_logs(&event_filter).await?;
-    
-        while let Some(log) = stream.next().await {
-            println!(
-                "block: {:?}, tx: {:?}, adddress: {:?}, topic1: {:?}, topic2: {:?}, topic3: {:?}, data: {:?}",
-                log.block_number,
-                log.transaction_hash,
-                log.address,
-                U256::decode(log.topics[0]),
-                U256::decode(log.topics[1]),
-                U256::decode(log.topics[2]),
-                U256::decode(log.data)
-            );
+
+        // Catch up the logs missed since the checkpoint
+        let mut logs = Vec::new();
+        let mut checkpoint = self.checkpoint.as_ref();
+        if let Some(from_block) = checkpoint.map(|c| c.start_block(last_block)).filter(|&b| b < last_block) {
+            let missed = client.get_logs(&event_filter.clone().from_block(from_block).to_block(last_block)).await;
+            match recover(missed.map_err(|e| Error::Rpc(e.to_string())), "catch up the missed logs")? {
+                Some(missed) => logs = missed,
+                None => checkpoint = None, // Keep the checkpoint, so the missed blocks are caught up after the next restart
+            }
         }
+        let mut missed = logs.into_iter();
+        let mut caught_up = Some(last_block); // Saved once the missed logs are emitted
     
+        loop {
+            let log = match missed.next() {
+                Some(log) => log,
+                None => {
+                    if let (Some(checkpoint), Some(block)) = (checkpoint, caught_up.take()) {
+                        checkpoint.save(block);
+                    }
+                    match stream.next().await {
+                        Some(log) => log,
+                        None => break,
+                    }
+                }
+            };
+            let data = match U256::decode(&log.data) {
+                Ok(value) => value.to_string(),
+                Err(_) => format!("0x{}", hex::encode(&log.data)),
+            };
+
+            output.emit(&EventInfo {
+                block: log.block_number.map(|n| n.as_u64()).unwrap_or_default(),
+                tx: log.transaction_hash.map(|h| format!("{:?}", h)).unwrap_or_default(),
+                address: format!("{:?}", log.address),
+                address_label: labels.get(&format!("{:?}", log.address)).cloned().unwrap_or_default(),
+                topics: log.topics.iter().skip(1).map(|t| U256::from_big_endian(t.as_bytes()).to_string()).collect(),
+                data,
+            });
+
+            if let (Some(checkpoint), Some(block)) = (checkpoint, log.block_number) {
+                checkpoint.save(block.as_u64());
+            }
+        }
+
+        eprintln!("The log subscription is closed");
         Ok(())
     }
     
     /// @dev Subscribe a certain address's all new txs
     /// @param address The address to subscribe
     pub async fn subscribe_address(&self, address: String) -> Result<()> {
-        let client = Provider::<Ws>::connect(self.WSS.clone()).await?;
-    
-        let last_block = client.get_block(BlockNumber::Latest).await?.unwrap().number.unwrap();
+        let client = self.connect().await?;
     
+        let mut output = Stream::new(self.output);
         let mut stream = client.subscribe_blocks().await?;
     
         while let Some(log) = stream.next().await {
-            println!("block height: {:?}", log.number);
-            let height= *(log.number.unwrap().0.get(0).unwrap());
+            eprintln!("block height: {:?}", log.number);
+            let Some(height) = log.number.map(|n| n.as_u64()) else { continue }; // Skip the pending block
+
+            let from_block = self.checkpoint.as_ref().map_or(height, |c| c.start_block(height));
 
             let fetcher = fetcher::Fetch::new(self.API_Key.clone());
-            let txs = fetcher.fetch_address_all_txs( address.as_str(), height, height).await;
+            let txs = fetcher.fetch_address_all_txs( address.as_str(), from_block, height).await;
+            let Some(txs) = recover(txs, &format!("block {}: fetch txs of {}", height, address))? else { continue };
 
-            for tx in txs.unwrap() {
-                println!("{:?}", tx);
+            for tx in txs {
+                output.emit(&tx);
+            }
+
+            if let Some(checkpoint) = &self.checkpoint {
+                checkpoint.save(height);
             }
         }
-    
+
+        eprintln!("The block subscription is closed");
         Ok(())
     }
     
     /// @dev Monitor mixing service, record the users who interact with it
     pub async fn monitor_mixing_service(&self) -> Result<()> {
-        println!("Start monitor mixing service");
+        eprintln!("Start monitor mixing service");
 
-        let mixing_services = tools::get_db_address("mixing_service");
-    
-        let client = Provider::<Ws>::connect(self.WSS.clone()).await?;
+        let mixing_services = address_db::get_db_address("mixing_service")?;
+        let labels = address_db::get_db_labels()?;
+        let mut output = Stream::new(self.output);
     
-        let last_block = client.get_block(BlockNumber::Latest).await?.unwrap().number.unwrap();
+        let client = self.connect().await?;
     
         let mut stream = client.subscribe_blocks().await?;
     
         while let Some(log) = stream.next().await {
-            println!("block height: {:?}", log.number);
-            let height= *(log.number.unwrap().0.get(0).unwrap());
+            eprintln!("block height: {:?}", log.number);
+            let Some(height) = log.number.map(|n| n.as_u64()) else { continue }; // Skip the pending block
+            let from_block = self.checkpoint.as_ref().map_or(height, |c| c.start_block(height));
+            let mut failed = false; // The checkpoint stays put, so the failed services are retried in the next round
             for address in &mixing_services { // Traverse all mixeing service addresses
                 let fetcher = fetcher::Fetch::new(self.API_Key.clone());
-                let txs = fetcher.fetch_address_all_txs( (*address).as_str(), height, height).await;
+                let txs = fetcher.fetch_address_all_txs( (*address).as_str(), from_block, height).await;
+                let Some(txs) = recover(txs, &format!("block {}: fetch txs of {}", height, address))? else {
+                    failed = true;
+                    continue;
+                };
     
-                for tx in txs.unwrap() { // If there is a mixing service tx in the new block
+                for tx in txs { // If there is a mixing service tx in the new block
+                    output.emit(&MixingServiceUser {
+                        block: height,
+                        hash: tx.hash.clone(),
+                        mixing_service: address.clone(),
+                        mixing_service_label: labels.get(&address.to_lowercase()).cloned().unwrap_or_default(),
+                        user: tx.from.clone(),
+                    });
+
                     // Record the user
-                    tools::write_addresses_db(tx.from);
+                    address_db::write_addresses_db(tx.from)?;
                 }
             }
+
+            if let Some(checkpoint) = self.checkpoint.as_ref().filter(|_| !failed) {
+                checkpoint.save(height);
+            }
         }
-    
+
+        eprintln!("The block subscription is closed");
         Ok(())
     }
-    
-}
\ No newline at end of file
+}
+
+/// @dev Use the shared connection if given, else connect to WSS
+/// @param wss WSS URL
+/// @param client The shared connection
+pub(crate) async fn connect(wss: &str, client: &Option<Provider<Ws>>) -> Result<Provider<Ws>> {
+    match client {
+        Some(client) => Ok(client.clone()),
+        None => Ok(Provider::<Ws>::connect(wss).await?),
+    }
+}
+
+/// @dev Get the latest block number
+/// @param client The provider
+pub(crate) async fn latest_block<M: Middleware>(client: &M) -> Result<u64> {
+    client
+        .get_block(BlockNumber::Latest)
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?
+        .and_then(|block| block.number)
+        .map(|number| number.as_u64())
+        .ok_or_else(|| Error::Rpc(String::from("The latest block is not available")))
+}
@@ -1,51 +1,122 @@
 #![allow(non_snake_case)]
-#![allow(unused_variables)]
-mod execute;
-mod listener;
-mod utils;
-mod ai;
-
+use ethers::{providers::{Provider, Ws}, types::U256};
 use structopt::StructOpt;
+use sechelper::{
+    ai,
+    analyzer::{approvals, bytecode, decoder::Decoder, diff, profile, report, scanner, simulate::{CallRequest, Simulator}, trace},
+    config::Config,
+    execute::daemon::Daemon,
+    listener::fetcher::MixingServiceCheck,
+    utils::{notifier::{EmailNotifier, Notifier}, output, proxy, selector_db::SelectorDb, source, tools},
+    Fetch, Listen, MessageRobot, OutputFormat,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "SecHelper", about = "A tool for assisting in monitoring, analyzing, and alerting blockchain security threats.")]
+struct Opt {
+    /// Output format: json, jsonl, csv or table
+    #[structopt(long = "output", default_value = "table", global = true)] // OPTIONS
+    output: OutputFormat,
+
+    /// The config file, default `SecHelper.toml` (or `SECHELPER_CONFIG`)
+    #[structopt(long = "config", global = true)] // OPTIONS
+    config: Option<String>,
+
+    /// The profile in the config file, default `default_profile` (or `SECHELPER_PROFILE`)
+    #[structopt(long = "profile", global = true)] // OPTIONS
+    profile: Option<String>,
+
+    #[structopt(subcommand)]
+    cli: Cli,
+}
+
+#[derive(Debug, StructOpt)]
 enum Cli {
     /// You AI security helper
     AI {
         /**********  OPTIONS    ***********/
-        /// OpenAI API KEY
+        /// OpenAI API KEY, default from the config file or `OPENAI_KEY`
         #[structopt(short = "k", long = "key")] // OPTIONS
-        openai_key: String,
+        openai_key: Option<String>,
         
-        /// A domestic(For china) proxy springboard for accessing OpenAI
+        /// Any OpenAI-compatible API, e.g. a domestic(For china) proxy springboard or `http://localhost:11434/v1/` for Ollama,
+        /// default from the config file or `OPENAI_BASE_URL`
         #[structopt(short = "b", long = "baseurl")] // OPTIONS
-        openai_base_url: String,
+        openai_base_url: Option<String>,
+
+        /// The chat model, default from the config file, `OPENAI_MODEL` or gpt-3.5-turbo
+        #[structopt(short = "m", long = "model")] // OPTIONS
+        model: Option<String>,
+
+        /// The sampling temperature between 0 and 2, default from the config file or the server default
+        #[structopt(long = "temperature")] // OPTIONS
+        temperature: Option<f32>,
+
+        /// The max tokens of an answer, default from the config file or the server default
+        #[structopt(long = "max_tokens")] // OPTIONS
+        max_tokens: Option<u64>,
+
+        /// The request timeout in seconds, default from the config file or 120
+        #[structopt(long = "timeout")] // OPTIONS
+        timeout: Option<u64>,
+
+        /// The token budget of the chat, the older turns are summarised beyond it. Default from the config file or 12000
+        #[structopt(long = "context_tokens")] // OPTIONS
+        context_tokens: Option<usize>,
+
+        /// Resume the session with this name, or start it. It is saved to `output/sessions/<name>.json` after each answer
+        #[structopt(short = "s", long = "session")] // OPTIONS
+        session: Option<String>,
+
+        /// Answer this question and exit. The piped stdin is appended to it, e.g. `cat Vault.sol | SecHelper ai -p "Review it"`
+        #[structopt(short = "p", long = "prompt")] // OPTIONS
+        prompt: Option<String>,
+
+        /// For --prompt / piped stdin: a JSON schema (a file or inline JSON) the answer must follow, the answer is printed as JSON
+        #[structopt(long = "schema")] // OPTIONS
+        schema: Option<String>,
+
+        /// Etherscan API kEY for the chain data tools, default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "e", long = "etherscan_key")] // OPTIONS
+        etherscan_key: Option<String>,
+
+        /**********  FLAGS    ***********/
+        /// Wait for the whole answer instead of streaming it, for the servers which do not support streaming
+        #[structopt(long = "no_stream")] // FLAGS
+        no_stream: bool,
+
+        /// Without a subcommand, chat with the assistant
+        #[structopt(subcommand)]
+        cmd: Option<AiCommand>,
     },
 
     /// Robot to monitor
     Guardian {
         /**********  OPTIONS    ***********/
-        /// Etherscan API kEY
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
         #[structopt(short = "k", long = "key")] // OPTIONS
-        key: String,
+        key: Option<String>,
         
-        /// WSS URL
+        /// WSS URL, default from the config file or `WSS_RPC`
         #[structopt(short = "w", long = "wss")] // OPTIONS
-        wss: String,
+        wss: Option<String>,
 
-        /// Email from
+        /// Email from, default from the config file or `SENDER`.
+        /// The password is only read from the config file or `PASSWORD`
         #[structopt(long = "sender")] // OPTIONS
-        sender: String,
-
-        /// Sender's email server password
-        #[structopt(short = "p", long = "password")] // OPTIONS
-        password: String,
+        sender: Option<String>,
 
-        /// Email server smtp code
+        /// Email server smtp code, default from the config file or `SMTP_SERVER`
         #[structopt(short = "ss", long = "smtp_server")] // OPTIONS
-        smtp_server: String,
+        smtp_server: Option<String>,
 
-        /// Who to monitor
+        /// WSS URL of a local fork (e.g. anvil) to simulate the suspicious txs on, default from the config file or `FORK_RPC`.
+        /// For deployment_robot()
+        #[structopt(long = "fork")] // OPTIONS
+        fork: Option<String>,
+
+        /// Who to monitor. For deployment_robot(), the protocols separated by `,`, or `protocol` for the protocols in db.
+        /// For poisoning_robot() and approval_robot(), the addresses separated by `,`
         #[structopt()] // ARGS
         address: String,        
         
@@ -53,7 +124,7 @@ enum Cli {
         #[structopt()] // ARGS
         receiver: String,     
 
-        /// The function you call. For warning_robot()
+        /// The function you call. For warning_robot(), and a watched function for deployment_robot()
         #[structopt(default_value = "None")] // ARGS
         call: String,        
         
@@ -69,14 +140,30 @@ enum Cli {
         /// warning_robot
         #[structopt(long = "warning_robot")] // FLAGS
         warning_robot: bool,        
+
+        /// deployment_robot
+        #[structopt(long = "deployment_robot")] // FLAGS
+        deployment_robot: bool,
+
+        /// poisoning_robot
+        #[structopt(long = "poisoning_robot")] // FLAGS
+        poisoning_robot: bool,
+
+        /// approval_robot
+        #[structopt(long = "approval_robot")] // FLAGS
+        approval_robot: bool,
+
+        /// Attach an AI incident hypothesis and a recommended action to the alerts, needs the OpenAI API KEY in the config file or `OPENAI_KEY`
+        #[structopt(long = "explain")] // FLAGS
+        explain: bool,
     },
 
     /// Fetch Blockchain data
     Fetcher {
         /**********  OPTIONS    ***********/
-        /// Etherscan API kEY
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
         #[structopt(short = "k", long = "key")] // OPTIONS
-        key: String,
+        key: Option<String>,
 
         /// The address's txs you fetch
         #[structopt()] // ARGS
@@ -90,6 +177,10 @@ enum Cli {
         #[structopt(short = "e", long = "end")] // OPTIONS
         end_block: u64,
 
+        /// Write the result into this file instead of stdout
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
         /// Obtain all transactions for a certain address
         #[structopt(short = "a", long = "all")] // FLAGS
         all: bool,
@@ -102,21 +193,293 @@ enum Cli {
         #[structopt(short = "i", long = "internal")] // FLAGS
         internal: bool,
 
+        /// Obtain the ERC20 transfers of a certain address
+        #[structopt(short = "t", long = "tokens")] // FLAGS
+        tokens: bool,
+
         /// Check that if an address is invoke to mixing service
         #[structopt(long = "mix")] // FLAGS
         is_invoke_mixing_service: bool,
+
+        /// Decode the input of the txs with the verified ABIs or the selector db
+        #[structopt(short = "d", long = "decode")] // FLAGS
+        decode: bool,
+    },
+
+    /// Download the verified source of a contract into `output/<address>/`, with its ABI and compiler settings in `metadata.json`.
+    /// If it is a proxy, the implementation is downloaded too and the relationship is written to `proxy.json`
+    Source {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// WSS URL to read the proxy slots, default from the config file or `WSS_RPC`.
+        /// Without it only the etherscan `Implementation` field is used
+        #[structopt(short = "w", long = "wss")] // OPTIONS
+        wss: Option<String>,
+
+        /// Do not resolve the implementation of a proxy
+        #[structopt(long = "no_proxy")] // FLAGS
+        no_proxy: bool,
+
+        /// The output folder
+        #[structopt(long = "out_dir", default_value = "./output")] // OPTIONS
+        out_dir: String,
+
+        /// The contract address
+        #[structopt()] // ARGS
+        address: String,
+    },
+
+    /// Scan the downloaded sources for common risk patterns
+    Scan {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY to download the source if `output/<address>/` does not exist,
+        /// default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// The output folder of the `source` command
+        #[structopt(long = "out_dir", default_value = "./output")] // OPTIONS
+        out_dir: String,
+
+        /// Write the result into this file instead of stdout
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// A contract address, or a folder / file of solidity sources
+        #[structopt()] // ARGS
+        target: String,
+
+        /// Print a SARIF log instead of `--output`
+        #[structopt(long = "sarif")] // FLAGS
+        sarif: bool,
+
+        /// Also scan the libraries (`@openzeppelin/...`, `lib/...`, `node_modules/...`)
+        #[structopt(long = "include_libs")] // FLAGS
+        include_libs: bool,
+    },
+
+    /// Compare the verified sources of two implementations: the files, the function selectors and the storage layout
+    Diff {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// Compare the implementations before and after an `Upgraded` event of this proxy instead
+        #[structopt(long = "proxy")] // OPTIONS
+        proxy: Option<String>,
+
+        /// The upgrade transaction of `--proxy`, default the latest upgrade
+        #[structopt(long = "tx")] // OPTIONS
+        tx: Option<String>,
+
+        /// The lines of context of the unified diff
+        #[structopt(long = "context", default_value = "3")] // OPTIONS
+        context: usize,
+
+        /// Write the unified diff into this patch file instead of stdout
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// The old implementation
+        #[structopt()] // ARGS
+        old: Option<String>,
+
+        /// The new implementation
+        #[structopt()] // ARGS
+        new: Option<String>,
+    },
+
+    /// Disassemble the runtime bytecode of a contract (e.g. an unverified attack contract): its dispatcher selectors,
+    /// DELEGATECALL / SELFDESTRUCT / CREATE2 and embedded addresses
+    Bytecode {
+        /**********  OPTIONS    ***********/
+        /// WSS URL, default from the config file or `WSS_RPC`
+        #[structopt(short = "w", long = "wss")] // OPTIONS
+        wss: Option<String>,
+
+        /// The contract address
+        #[structopt()] // ARGS
+        address: String,
+
+        /// Print every instruction instead of the summary
+        #[structopt(long = "disasm")] // FLAGS
+        disasm: bool,
+    },
+
+    /// Explain a transaction: its decoded call tree (`debug_traceTransaction`), events, value transfers and token balance changes
+    Tx {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY to fetch the ABIs, default from the config file or `ETHERSCAN_API_KEY`.
+        /// Without it, only the cached ABIs and the selector db are used
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// WSS URL of a node supporting `debug_traceTransaction`, e.g. anvil `ws://127.0.0.1:8545`.
+        /// Default from the config file or `WSS_RPC`
+        #[structopt(short = "w", long = "wss")] // OPTIONS
+        wss: Option<String>,
+
+        /// Write the balance changes into a file
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// The tx hash
+        #[structopt()] // ARGS
+        hash: String,
+    },
+
+    /// Profile an address: EOA or contract, verification, deployer, activity, tx counts, top counterparties,
+    /// token holdings, funding chain, mixer exposure and a risk score
+    Profile {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// Write the top counterparties into this file
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// The address
+        #[structopt()] // ARGS
+        address: String,
+    },
+
+    /// List the current ERC20 allowances and approvals for all of an address, rebuilt from its `Approval` and
+    /// `ApprovalForAll` logs, with the spenders which are hackers, unverified or recently deployed flagged
+    Approvals {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// Write the approvals into this file
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// The owner, e.g. a treasury
+        #[structopt()] // ARGS
+        address: String,
+
+        /**********  FLAGS    ***********/
+        /// Only list the risky approvals
+        #[structopt(long = "risky")] // FLAGS
+        risky: bool,
+    },
+
+    /// Write an incident report: the funding of the attacker (mixer check), its contracts, the decoded exploit txs,
+    /// the loss per asset, a timeline and the fund flows with a graph. Written to `output/<victim>/incident-<start>-<end>.md`
+    Report {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// WSS URL of a node supporting `debug_traceTransaction` for the whole call trees.
+        /// Default from the config file or `WSS_RPC`
+        #[structopt(short = "w", long = "wss")] // OPTIONS
+        wss: Option<String>,
+
+        /// The first block of the incident
+        #[structopt(short = "s", long = "start")] // OPTIONS
+        start_block: u64,
+
+        /// The last block of the incident, the fund flows are followed until it
+        #[structopt(short = "e", long = "end")] // OPTIONS
+        end_block: u64,
+
+        /// The report format: md or html
+        #[structopt(long = "format", default_value = "md")] // OPTIONS
+        format: report::ReportFormat,
+
+        /// Write the report into this file instead
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// The attacker address
+        #[structopt()] // ARGS
+        attacker: String,
+
+        /// The victim contract
+        #[structopt()] // ARGS
+        victim: String,
+
+        /**********  FLAGS    ***********/
+        /// Add an AI executive summary, needs the OpenAI API KEY in the config file or `OPENAI_KEY`
+        #[structopt(long = "summary")] // FLAGS
+        summary: bool,
+    },
+
+    /// Simulate a tx or a call on a local fork (e.g. `anvil --fork-url <rpc>`): its call tree, the balance changes
+    /// of the watched contracts, and the Guardian rules which would fire
+    Simulate {
+        /**********  OPTIONS    ***********/
+        /// Etherscan API kEY to fetch the ABIs, default from the config file or `ETHERSCAN_API_KEY`
+        #[structopt(short = "k", long = "key")] // OPTIONS
+        key: Option<String>,
+
+        /// WSS URL of the fork, default from the config file or `FORK_RPC`
+        #[structopt(long = "fork")] // OPTIONS
+        fork: Option<String>,
+
+        /// Replay this tx at the latest block of the fork
+        #[structopt(long = "tx")] // OPTIONS
+        tx: Option<String>,
+
+        /// The caller
+        #[structopt(long = "from")] // OPTIONS
+        from: Option<String>,
+
+        /// The ether sent, in wei
+        #[structopt(long = "value", default_value = "0")] // OPTIONS
+        value: String,
+
+        /// The calldata in hex
+        #[structopt(long = "data", default_value = "0x")] // OPTIONS
+        data: String,
+
+        /// The called contract, with `--from`
+        #[structopt()] // ARGS
+        to: Option<String>,
+    },
+
+    /// Look up function selectors / event topics in the offline selector db, or hash signatures
+    Sig {
+        /**********  OPTIONS    ***********/
+        /// Import a 4byte / OpenChain dump (json, csv or text) into the selector db
+        #[structopt(long = "import")] // OPTIONS
+        import: Option<String>,
+
+        /// Import the ABIs of the contracts downloaded into this folder (`<dir>/*/metadata.json`)
+        #[structopt(long = "abis")] // OPTIONS
+        abis: Option<String>,
+
+        /// Selectors (`0xa9059cbb`), topics, or signatures (`transfer(address,uint256)`) to hash
+        #[structopt()] // ARGS
+        values: Vec<String>,
+
+        /// Hash the signatures as events instead of functions
+        #[structopt(long = "event")] // FLAGS
+        event: bool,
     },
 
+    /// Run every monitor defined in the config profile (`rules` and `watchlist`) until SIGTERM / Ctrl-C
+    Run,
+
     /// Listen Blockchain data
     Listener {
         /**********  OPTIONS    ***********/
-        /// Etherscan API kEY
+        /// Etherscan API kEY, default from the config file or `ETHERSCAN_API_KEY`
         #[structopt(short = "k", long = "key")] // OPTIONS
-        key: String,
+        key: Option<String>,
         
-        /// WSS URL
+        /// WSS URL, default from the config file or `WSS_RPC`
         #[structopt(short = "w", long = "wss")] // OPTIONS
-        wss: String,
+        wss: Option<String>,
 
         /// The address to monitor
         #[structopt()] // ARGS
@@ -140,56 +503,446 @@ enum Cli {
     },    
 }
 
+#[derive(Debug, StructOpt)]
+enum AiCommand {
+    /// Review the verified source of a contract, the Markdown report is written to `output/<address>/audit.md`
+    Audit {
+        /**********  OPTIONS    ***********/
+        /// Write the findings into this file instead of stdout
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// The contract address
+        #[structopt()] // ARGS
+        address: String,
+    },
+
+    /// List the saved sessions, the latest first
+    Sessions {
+        /**********  OPTIONS    ***********/
+        /// Write the result into this file instead of stdout
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// Only the sessions with a message containing this text, e.g. an address or a tx hash
+        #[structopt(long = "search")] // OPTIONS
+        search: Option<String>,
+    },
+
+    /// Export a saved session in Markdown
+    Export {
+        /**********  OPTIONS    ***********/
+        /// Write the Markdown into this file instead of stdout
+        #[structopt(long = "out_file")] // OPTIONS
+        out_file: Option<String>,
+
+        /// The session name
+        #[structopt()] // ARGS
+        name: String,
+    },
+}
+
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::from_args();
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    match cli {
-        Cli::AI { openai_key, openai_base_url} => {
-            let ai = ai::chatgpt::AI::new(openai_key, openai_base_url);
-            ai.chatgpt().await;
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let opt = Opt::from_args();
+
+    // Fail early if the config file is invalid
+    let config = Config::load(opt.config.as_deref())?;
+    let profile = config.profile(opt.profile.as_deref())?;
+    if let Some(dir) = &profile.data_dir {
+        sechelper::utils::data_dir::set_data_dir(dir);
+    }
+
+    match opt.cli {
+        Cli::AI { openai_key, openai_base_url, model, temperature, max_tokens, timeout, context_tokens, session, prompt, schema, no_stream, etherscan_key, cmd} => {
+            let mut config = ai::client::ModelConfig::from_profile(&profile)?;
+            config.api_key = openai_key.or(config.api_key);
+            config.base_url = openai_base_url.unwrap_or(config.base_url);
+            config.model = model.unwrap_or(config.model);
+            config.temperature = temperature.or(config.temperature);
+            config.max_tokens = max_tokens.or(config.max_tokens);
+            if let Some(timeout) = timeout {
+                config.timeout = std::time::Duration::from_secs(timeout);
+            }
+            config.context_tokens = context_tokens.unwrap_or(config.context_tokens);
+            config.stream = config.stream && !no_stream;
+            let mut ai = ai::chatgpt::AI::new(config)?;
+            if let Some(etherscan_key) = etherscan_key.or_else(|| profile.etherscan_api_key().ok()) {
+                ai = ai.with_etherscan_key(etherscan_key);
+            }
+            match cmd {
+                Some(AiCommand::Audit { out_file, address }) => {
+                    let (report, path) = ai.audit(&address).await?;
+                    eprintln!("{} findings, the report is written to {}", report.findings.len(), path);
+                    output::print_records(opt.output, &report.findings, out_file.as_deref())?;
+                },
+                Some(AiCommand::Sessions { out_file, search }) => {
+                    let sessions = ai::session::list(ai::session::DEFAULT_SESSION_DIR, search.as_deref())?;
+                    output::print_records(opt.output, &sessions, out_file.as_deref())?;
+                },
+                Some(AiCommand::Export { out_file, name }) => {
+                    let markdown = ai::session::Session::load(ai::session::DEFAULT_SESSION_DIR, &name)?.to_markdown();
+                    match out_file {
+                        Some(path) => std::fs::write(path, markdown)?,
+                        None => println!("{}", markdown),
+                    }
+                },
+                None => {
+                    let piped = !std::io::IsTerminal::is_terminal(&std::io::stdin());
+                    if prompt.is_none() && !piped {
+                        if schema.is_some() {
+                            return Err("--schema needs --prompt or a piped stdin".into());
+                        }
+                        ai.chatgpt(session).await?;
+                    } else {
+                        let mut question = prompt.unwrap_or_default();
+                        if piped {
+                            let mut input = String::new();
+                            std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)?;
+                            question = format!("{}\n\n{}", question, input).trim().to_string();
+                        }
+                        if question.is_empty() {
+                            return Err("Nothing to ask, give --prompt or pipe the question into stdin".into());
+                        }
+                        let schema = match schema {
+                            Some(schema) if std::path::Path::new(&schema).exists() => Some(serde_json::from_str(&std::fs::read_to_string(schema)?)?),
+                            Some(schema) => Some(serde_json::from_str(&schema)?),
+                            None => None,
+                        };
+                        ai.one_shot(question, schema, session).await?;
+                    }
+                },
+            }
         },
-        Cli::Guardian { key, wss, sender, password, smtp_server, address, receiver, call, limit, message_robot, warning_robot} => {
-            let guardian = execute::guardian::MessageRobot::new(key, wss, sender, password, smtp_server);
+        Cli::Guardian { key, wss, sender, smtp_server, fork, address, receiver, call, limit, message_robot, warning_robot, deployment_robot, poisoning_robot, approval_robot, explain} => {
+            let key = or_config(key, || profile.etherscan_api_key())?;
+            let wss = or_config(wss, || profile.rpc_url())?;
+            // The email is optional when the alerts go to webhooks
+            let email = match profile.smtp() {
+                Ok((config_sender, password, config_smtp_server)) => {
+                    Some(EmailNotifier::new(sender.unwrap_or(config_sender), password, smtp_server.unwrap_or(config_smtp_server)))
+                }
+                Err(e) if profile.webhooks.is_empty() => return Err(e.into()),
+                Err(_) => None,
+            };
+            let mut guardian = MessageRobot::with_notifier(key.clone(), wss, Notifier::new(email, profile.webhooks.clone()));
+            if let Some(fork) = fork.or_else(|| profile.fork_url().ok()) {
+                let simulator = Simulator::connect(&fork, Some(key)).await?
+                    .with_watched(&profile.watchlist)
+                    .with_rules(&profile.rules);
+                guardian = guardian.with_simulator(simulator);
+            }
+            if explain || profile.openai.as_ref().is_some_and(|openai| openai.explain_alerts) {
+                guardian = guardian.with_explainer(ai::explain::Explainer::new(ai::client::ModelConfig::from_profile(&profile)?)?);
+            }
 
-            if warning_robot == true { // warning_robot
+            if warning_robot { // warning_robot
                 guardian.warning_robot(address.as_str(), call.as_str(), receiver, limit).await?;
-            } else if message_robot == true{ // message_robot
+            } else if message_robot { // message_robot
                 guardian.message_robot(address, receiver).await?;
-            } else {
-
+            } else if deployment_robot { // deployment_robot
+                let protocols: Vec<String> = if address == "protocol" {
+                    Vec::new()
+                } else {
+                    address.split(',').map(|address| address.trim().to_string()).collect()
+                };
+                let selectors: Vec<String> = if call == "None" { Vec::new() } else { vec![call] };
+                guardian.deployment_robot(&protocols, &selectors, receiver).await?;
+            } else if poisoning_robot { // poisoning_robot
+                let addresses: Vec<String> = address.split(',').map(|address| address.trim().to_string()).collect();
+                guardian.poisoning_robot(&addresses, receiver).await?;
+            } else if approval_robot { // approval_robot
+                let addresses: Vec<String> = address.split(',').map(|address| address.trim().to_string()).collect();
+                guardian.approval_robot(&addresses, receiver).await?;
             }
         },
-        Cli::Fetcher { key, address, start_block, end_block, all, normal, internal, is_invoke_mixing_service} => {
-            let fetcher = listener::fetcher::Fetch::new(key);
-
-            if  all == true {
-                fetcher.fetch_address_all_txs(address.as_str(), start_block, end_block).await?;
-            } else if normal == true {
-                fetcher.fetch_address_normal_txs(address.as_str(), start_block, end_block).await?;
-            } else if internal == true {
-                fetcher.fetch_address_internal_txs(address.as_str(), start_block, end_block).await?;
-            } else if is_invoke_mixing_service == true {
+        Cli::Fetcher { key, address, start_block, end_block, out_file, all, normal, internal, tokens, is_invoke_mixing_service, decode} => {
+            let fetcher = Fetch::new(or_config(key, || profile.etherscan_api_key())?);
+            let out_file = out_file.as_deref();
+
+            if all || normal || internal {
+                let mut txs = if all {
+                    fetcher.fetch_address_all_txs(address.as_str(), start_block, end_block).await?
+                } else if normal {
+                    fetcher.fetch_address_normal_txs(address.as_str(), start_block, end_block).await?
+                } else {
+                    fetcher.fetch_address_internal_txs(address.as_str(), start_block, end_block).await?
+                };
+                if decode {
+                    fetcher.decode_txs(&mut txs).await?;
+                }
+                output::print_records(opt.output, &txs, out_file)?;
+            } else if tokens {
+                let transfers = fetcher.fetch_token_transfers(address.as_str(), start_block, end_block).await?;
+                output::print_records(opt.output, &transfers, out_file)?;
+            } else if is_invoke_mixing_service {
                 let mix = fetcher.is_invoke_mixing_service(address.as_str(), start_block, end_block).await?;
-                if mix == true {
-                    println!("The {} is invoke mixing service!", address)
-                }else {
-                    println!("The {} is not invoke mixing service:)", address)
+                if opt.output == OutputFormat::Table && out_file.is_none() {
+                    if mix {
+                        println!("The {} is invoke mixing service!", address)
+                    }else {
+                        println!("The {} is not invoke mixing service:)", address)
+                    }
+                } else {
+                    let check = MixingServiceCheck { address, start_block, end_block, invoked: mix };
+                    output::print_records(opt.output, &[check], out_file)?;
                 }
             } else {
                 println!("Not valid")
             }
 
         },
+        Cli::Source { key, wss, no_proxy, out_dir, address } => {
+            let key = or_config(key, || profile.etherscan_api_key())?;
+
+            let written = if no_proxy {
+                let contract = source::fetch_contract_source(&key, &address).await?;
+                source::write_contract_source(&contract, &out_dir)?
+            } else {
+                let client = match wss.or_else(|| profile.rpc_url().ok()) {
+                    Some(wss) => Some(Provider::<Ws>::connect(wss).await?),
+                    None => None,
+                };
+                let sources = proxy::get_contract_source_with_proxy(&key, client.as_ref(), &address, &out_dir).await?;
+                for link in &sources.links {
+                    eprintln!("{} is a {:?} proxy of {}", link.proxy, link.kind, link.implementation);
+                }
+                for unverified in &sources.unverified {
+                    eprintln!("{} is not verified", unverified);
+                }
+                sources.written
+            };
+            output::print_records(opt.output, &written, None)?;
+        },
+        Cli::Scan { key, out_dir, out_file, target, sarif, include_libs } => {
+            let dir = if std::path::Path::new(&target).exists() {
+                target
+            } else {
+                let dir = std::path::Path::new(&out_dir).join(&target).display().to_string();
+                if !std::path::Path::new(&dir).exists() {
+                    let key = or_config(key, || profile.etherscan_api_key())?;
+                    let contract = source::fetch_contract_source(&key, &target).await?;
+                    source::write_contract_source(&contract, &out_dir)?;
+                }
+                dir
+            };
+
+            let findings = scanner::scan_dir(&dir, include_libs)?;
+            if sarif {
+                let log = serde_json::to_string_pretty(&scanner::to_sarif(&findings, &dir))?;
+                match out_file {
+                    Some(path) => std::fs::write(path, log)?,
+                    None => println!("{}", log),
+                }
+            } else {
+                output::print_records(opt.output, &findings, out_file.as_deref())?;
+            }
+        },
+        Cli::Diff { key, proxy, tx, context, out_file, old, new } => {
+            let key = or_config(key, || profile.etherscan_api_key())?;
+            let (old, new) = match (proxy, old, new) {
+                (Some(proxy), _, _) => {
+                    let (old, new) = diff::find_upgrade(&key, &proxy, tx.as_deref()).await?;
+                    eprintln!("{} was upgraded from {} to {}", proxy, old, new);
+                    (old, new)
+                },
+                (None, Some(old), Some(new)) => (old, new),
+                _ => return Err("Give two implementations, or `--proxy <address>`".into()),
+            };
+
+            let result = diff::diff_implementations(&key, &old, &new, context).await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+                OutputFormat::Jsonl => println!("{}", serde_json::to_string(&result)?),
+                OutputFormat::Csv | OutputFormat::Table => {
+                    match out_file {
+                        Some(path) => std::fs::write(path, result.patch())?,
+                        None => print!("{}", result.patch()),
+                    }
+                    println!();
+                    output::print_records(opt.output, &result.selectors, None)?;
+                    println!();
+                    output::print_records(opt.output, &result.storage, None)?;
+                },
+            }
+            if !result.is_storage_safe() {
+                eprintln!("WARNING: the storage layout changed, the upgrade can corrupt the existing state");
+            }
+        },
+        Cli::Bytecode { wss, address, disasm } => {
+            let wss = or_config(wss, || profile.rpc_url())?;
+            let client = Provider::<Ws>::connect(wss).await?;
+
+            if disasm {
+                let code = bytecode::get_code(&client, &address).await?;
+                output::print_records(opt.output, &bytecode::disassemble(&code), None)?;
+            } else {
+                let summary = bytecode::analyze_address(&client, &address).await?;
+                match opt.output {
+                    OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&summary)?),
+                    OutputFormat::Jsonl => println!("{}", serde_json::to_string(&summary)?),
+                    OutputFormat::Csv | OutputFormat::Table => {
+                        println!("address: {}", summary.address);
+                        println!("size: {} bytes", summary.size);
+                        println!("DELEGATECALL: {}, SELFDESTRUCT: {}, CREATE: {}, CREATE2: {}", summary.delegatecall, summary.selfdestruct, summary.create, summary.create2);
+                        for embedded in &summary.addresses {
+                            println!("embedded address: {} {}", embedded.address, embedded.label);
+                        }
+                        println!();
+                        output::print_records(opt.output, &summary.selectors, None)?;
+                    },
+                }
+            }
+        },
+        Cli::Tx { key, wss, out_file, hash } => {
+            let wss = or_config(wss, || profile.rpc_url())?;
+            let client = Provider::<Ws>::connect(wss).await?;
+            let mut decoder = Decoder::new(key.or_else(|| profile.etherscan_api_key().ok()))?;
+
+            let result = trace::trace_transaction(&client, &mut decoder, &hash).await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+                OutputFormat::Jsonl => println!("{}", serde_json::to_string(&result)?),
+                OutputFormat::Csv => output::print_records(opt.output, &result.deltas, out_file.as_deref())?,
+                OutputFormat::Table => {
+                    print!("{}", result);
+                    if out_file.is_some() {
+                        output::print_records(opt.output, &result.deltas, out_file.as_deref())?;
+                    }
+                },
+            }
+        },
+        Cli::Profile { key, out_file, address } => {
+            let key = or_config(key, || profile.etherscan_api_key())?;
+
+            let result = profile::profile_address(&key, &address).await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+                OutputFormat::Jsonl => println!("{}", serde_json::to_string(&result)?),
+                OutputFormat::Csv => output::print_records(opt.output, &result.counterparties, out_file.as_deref())?,
+                OutputFormat::Table => {
+                    print!("{}", result);
+                    if out_file.is_some() {
+                        output::print_records(opt.output, &result.counterparties, out_file.as_deref())?;
+                    }
+                },
+            }
+        },
+        Cli::Approvals { key, out_file, address, risky } => {
+            let key = or_config(key, || profile.etherscan_api_key())?;
+
+            let mut result = approvals::scan_approvals(&key, &address).await?;
+            if risky {
+                result.retain(|approval| approval.is_risky());
+            }
+            output::print_records(opt.output, &result, out_file.as_deref())?;
+        },
+        Cli::Report { key, wss, start_block, end_block, format, out_file, attacker, victim, summary } => {
+            let key = or_config(key, || profile.etherscan_api_key())?;
+            let wss = or_config(wss, || profile.rpc_url())?;
+            let client = Provider::<Ws>::connect(wss).await?;
+
+            let mut result = report::build_report(&client, key, &attacker, &victim, start_block, end_block).await?;
+            if summary {
+                let client = ai::client::ChatClient::new(ai::client::ModelConfig::from_profile(&profile)?)?;
+                if let Err(e) = result.summarize(&client).await {
+                    eprintln!("The executive summary failed: {}", e);
+                }
+            }
+            let path = result.write(format, out_file.as_deref())?;
+            eprintln!("The report is written to {}", path);
+
+            match opt.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+                OutputFormat::Jsonl => println!("{}", serde_json::to_string(&result)?),
+                OutputFormat::Csv | OutputFormat::Table => output::print_records(opt.output, &result.timeline, None)?,
+            }
+        },
+        Cli::Simulate { key, fork, tx, from, value, data, to } => {
+            let fork = or_config(fork, || profile.fork_url())?;
+            let simulator = Simulator::connect(&fork, key.or_else(|| profile.etherscan_api_key().ok())).await?
+                .with_watched(&profile.watchlist)
+                .with_rules(&profile.rules);
+
+            let call = match (tx, from, to) {
+                (Some(tx), _, _) => CallRequest::from_tx(simulator.client(), &tx).await?,
+                (None, Some(from), Some(to)) => {
+                    let value = U256::from_dec_str(&value).map_err(|e| format!("Invalid value {}: {}", value, e))?;
+                    CallRequest::new(&from, &to, value, &data)?
+                },
+                _ => return Err("Give `--tx <hash>`, or `--from <address> <to>`".into()),
+            };
+
+            let result = simulator.simulate(&call).await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&result)?),
+                OutputFormat::Jsonl => println!("{}", serde_json::to_string(&result)?),
+                OutputFormat::Csv => output::print_records(opt.output, &result.deltas, None)?,
+                OutputFormat::Table => {
+                    print!("{}", result);
+                    println!();
+                    output::print_records(opt.output, &result.deltas, None)?;
+                    if !result.rules.is_empty() {
+                        println!();
+                        output::print_records(opt.output, &result.rules, None)?;
+                    }
+                },
+            }
+        },
+        Cli::Sig { import, abis, values, event } => {
+            let mut db = SelectorDb::load()?;
+            let mut added = 0;
+            if let Some(path) = import {
+                added += db.import(&path)?;
+            }
+            if let Some(dir) = abis {
+                added += db.extend_from_metadata(&dir)?;
+            }
+
+            let mut matches = Vec::new();
+            for value in values {
+                if value.contains('(') {
+                    // A signature, hash it and remember it
+                    let (hash, new) = if event {
+                        (tools::event_topic(&value), db.insert_event(&value))
+                    } else {
+                        (tools::function_sig(&value), db.insert_function(&value))
+                    };
+                    added += new as usize;
+                    matches.extend(db.lookup(&hash)?);
+                } else {
+                    matches.extend(db.lookup(&value)?);
+                }
+            }
+
+            if added > 0 {
+                db.save()?;
+                eprintln!("{} signatures added to the selector db", added);
+            }
+            output::print_records(opt.output, &matches, None)?;
+        },
+        Cli::Run => {
+            Daemon::new(profile, opt.output).run().await?;
+        },
         Cli::Listener { key, wss, address, event,  subscribe_event, subscribe_address, monitor_mixing_service} => {
-            let listener = listener::listen::Listen::new(wss, key);
+            let key = or_config(key, || profile.etherscan_api_key())?;
+            let wss = or_config(wss, || profile.rpc_url())?;
+            let listener = Listen::new(wss, key, opt.output);
 
-            if subscribe_address == true {
+            if subscribe_address {
                 listener.subscribe_address(address).await?;
-            } else if subscribe_event == true {
+            } else if subscribe_event {
                 listener.subscribe_event(address, event.as_str()).await?;
-            } else if monitor_mixing_service == true {
+            } else if monitor_mixing_service {
                 listener.monitor_mixing_service().await?;
             } else {
                 println!("Invalid")
@@ -199,3 +952,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// @dev Use the CLI flag if given, else the value from the config file / env vars
+fn or_config(flag: Option<String>, from_config: impl FnOnce() -> sechelper::Result<String>) -> sechelper::Result<String> {
+    match flag {
+        Some(value) => Ok(value),
+        None => from_config(),
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::utils::data_dir;
+
+/// The address db shipped with the binary
+const BUNDLED_ADDRESSES: &str = include_str!("addresses.json");
+const BUNDLED_DETAILS: &str = include_str!("addresses_details.txt");
+
+/// The files in the data dir which keep the recorded addresses and their names
+const USER_ADDRESSES_FILE: &str = "addresses.json";
+const USER_DETAILS_FILE: &str = "addresses_details.txt";
+
+/// Serializes the writes of the monitors running concurrently
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// @dev Used to parse the data For addresses.json
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AddressData {
+    #[serde(default)]
+    eth: Data,
+    #[serde(default)]
+    bsc: Data
+}
+
+/// @dev Used to parse the data For addresses.json
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Data {
+    #[serde(default)]
+    hacker: Vec<String>,
+    #[serde(default)]
+    protocol: Vec<String>,
+    #[serde(default)]
+    mixing_service: Vec<String>,
+    #[serde(default)]
+    potential_hacker: Vec<String>
+}
+
+impl Data {
+    fn extend(&mut self, other: Data) {
+        self.hacker.extend(other.hacker);
+        self.protocol.extend(other.protocol);
+        self.mixing_service.extend(other.mixing_service);
+        self.potential_hacker.extend(other.potential_hacker);
+    }
+}
+
+/// @dev Read the bundled addresses.json and the one in the data dir
+fn read_db() -> Result<AddressData> {
+    let mut data: AddressData = serde_json::from_str(BUNDLED_ADDRESSES)
+        .map_err(|e| Error::Storage(format!("Failed to parse the bundled addresses: {}", e)))?;
+    let user = read_user_db()?;
+    data.eth.extend(user.eth);
+    data.bsc.extend(user.bsc);
+    Ok(data)
+}
+
+/// @dev Read addresses.json in the data dir, empty if nothing was recorded yet
+fn read_user_db() -> Result<AddressData> {
+    let path = user_path(USER_ADDRESSES_FILE);
+    if !path.exists() {
+        return Ok(AddressData::default());
+    }
+
+    let content = fs::read_to_string(&path)
+        .map_err(|e| Error::Storage(format!("Failed to read {}: {}", path.display(), e)))?;
+    serde_json::from_str(&content)
+        .map_err(|e| Error::Storage(format!("Failed to parse {}: {}", path.display(), e)))
+}
+
+fn user_path(name: &str) -> PathBuf {
+    data_dir::data_path(name)
+}
+
+/// @notice We currently only focus on Ethereum
+/// @dev Get the address from db(a json file)
+/// @param option: "hacker", "protocol", "mixing_service" or "potential_hacker", the other returns a new vector
+pub fn get_db_address(option: &str) -> Result<Vec<String>> {
+    let json_data = read_db()?;
+
+    let addresses = match option {
+        "hacker" => json_data.eth.hacker,
+        "protocol" => json_data.eth.protocol,
+        "mixing_service" => json_data.eth.mixing_service,
+        "potential_hacker" => json_data.eth.potential_hacker,
+        _ => Vec::new(),
+    };
+
+    Ok(addresses)
+}
+
+/// @notice We currently only focus on Ethereum
+/// @dev Get the labels of all addresses in db, e.g. `protocol: UniswapV2Router`
+/// @return A map from lowercase address to its label
+pub fn get_db_labels() -> Result<HashMap<String, String>> {
+    let mut names = HashMap::new();
+    let user_details = fs::read_to_string(user_path(USER_DETAILS_FILE)).unwrap_or_default();
+    for details in [BUNDLED_DETAILS, user_details.as_str()] {
+        for line in details.lines() {
+            let mut parts = line.trim().splitn(2, char::is_whitespace);
+            if let (Some(address), Some(name)) = (parts.next(), parts.next()) {
+                if address.starts_with("0x") {
+                    names.insert(address.to_lowercase(), name.trim().to_string());
+                }
+            }
+        }
+    }
+
+    let json_data = read_db()?;
+    let categories = [
+        ("hacker", json_data.eth.hacker),
+        ("protocol", json_data.eth.protocol),
+        ("mixing_service", json_data.eth.mixing_service),
+        ("potential_hacker", json_data.eth.potential_hacker),
+    ];
+
+    let mut labels = HashMap::new();
+    for (category, addresses) in categories {
+        for address in addresses {
+            let address = address.to_lowercase();
+            let label = match names.get(&address) {
+                Some(name) => format!("{}: {}", category, name),
+                None => category.to_string(),
+            };
+            labels.insert(address, label);
+        }
+    }
+
+    Ok(labels)
+}
+
+/// @dev Write an address to addresses.json in the data dir as a potential hacker, unless it is already in the db.
+/// The writes are serialized, and the file is replaced atomically
+/// @param address The address to write
+pub fn write_addresses_db(address: String) -> Result<()> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if get_db_labels()?.contains_key(&address.to_lowercase()) {
+        return Ok(());
+    }
+    let mut data = read_user_db()?;
+    data.eth.potential_hacker.push(address);
+
+    create_data_dir()?;
+    write_atomically(&user_path(USER_ADDRESSES_FILE), &serde_json::to_string_pretty(&data)?)
+}
+
+/// @dev Append the name of an address to addresses_details.txt in the data dir, shown in its label.
+/// An address which already has a name in the data dir is skipped
+/// @param address The address
+/// @param name e.g. `address poisoning of 0x...`
+pub fn write_address_detail(address: &str, name: &str) -> Result<()> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let path = user_path(USER_DETAILS_FILE);
+    let details = fs::read_to_string(&path).unwrap_or_default();
+    let named = details
+        .lines()
+        .any(|line| line.split_whitespace().next().is_some_and(|known| known.eq_ignore_ascii_case(address)));
+    if named {
+        return Ok(());
+    }
+
+    create_data_dir()?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| Error::Storage(format!("Failed to open {} for writing: {}", path.display(), e)))?;
+
+    writeln!(file, "{} {}", address, name)
+        .map_err(|e| Error::Storage(format!("Failed to write {}: {}", path.display(), e)))
+}
+
+fn create_data_dir() -> Result<()> {
+    let dir = data_dir::data_dir();
+    fs::create_dir_all(&dir)
+        .map_err(|e| Error::Storage(format!("Failed to create the data dir {}: {}", dir.display(), e)))
+}
+
+/// @dev Write a temp file next to the target, then rename it over the target, so a reader never sees half a file
+fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    let temp = path.with_extension(format!("tmp.{}", std::process::id()));
+    fs::write(&temp, content)
+        .map_err(|e| Error::Storage(format!("Failed to write {}: {}", temp.display(), e)))?;
+    fs::rename(&temp, path)
+        .map_err(|e| Error::Storage(format!("Failed to replace {}: {}", path.display(), e)))
+}
@@ -0,0 +1,42 @@
+use std::{env, path::PathBuf, sync::OnceLock};
+
+/// The folder under the user data dir
+const APP_DIR: &str = "sechelper";
+
+/// The data dir set at startup, e.g. from the config file
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// @dev Set where the writable data (the address db and the imported selectors) is kept, once at startup.
+/// The later calls are ignored
+/// @param dir The data dir
+pub fn set_data_dir(dir: &str) {
+    let _ = DATA_DIR.set(PathBuf::from(dir));
+}
+
+/// @return The data dir: the one set at startup, or the user data dir, i.e. `$XDG_DATA_HOME/sechelper`,
+/// `~/.local/share/sechelper` or `%APPDATA%\sechelper`
+pub fn data_dir() -> PathBuf {
+    DATA_DIR.get().cloned().unwrap_or_else(default_data_dir)
+}
+
+/// @return A file in the data dir
+/// @param name The file name, e.g. `selectors.json`
+pub fn data_path(name: &str) -> PathBuf {
+    data_dir().join(name)
+}
+
+/// @return The user data dir, `./.sechelper` if no home is known
+pub fn default_data_dir() -> PathBuf {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty()).map(PathBuf::from);
+
+    if let Some(dir) = var("XDG_DATA_HOME") {
+        return dir.join(APP_DIR);
+    }
+    if let Some(dir) = var("APPDATA") {
+        return dir.join(APP_DIR);
+    }
+    match var("HOME") {
+        Some(home) => home.join(".local").join("share").join(APP_DIR),
+        None => PathBuf::from(".").join(format!(".{}", APP_DIR)),
+    }
+}
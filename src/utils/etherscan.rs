@@ -0,0 +1,117 @@
+use std::time::Duration;
+use reqwest::get;
+use crate::error::{Error, Result};
+
+/// How many times a rate limited request is retried
+const RATE_LIMIT_RETRIES: u64 = 3;
+
+/// @dev Request an etherscan api, check the `status` and return the `result`
+/// @notice Etherscan answers errors with HTTP 200, e.g. `{"status":"0","message":"NOTOK","result":"Invalid API Key"}`.
+///         "No transactions found" and "No records found" are not errors, they return an empty array.
+/// @param url The full api url, including the api key
+/// @return The `result` field
+pub async fn get_result(url: &str) -> Result<serde_json::Value> {
+    let mut attempt = 0;
+    loop {
+        match request(url).await {
+            Err(Error::RateLimit(message)) if attempt < RATE_LIMIT_RETRIES => {
+                attempt += 1;
+                eprintln!("Etherscan rate limit reached ({}), retry {}/{}", message, attempt, RATE_LIMIT_RETRIES);
+                tokio::time::sleep(Duration::from_secs(attempt)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn request(url: &str) -> Result<serde_json::Value> {
+    let response = get(url).await?;
+
+    if response.status().as_u16() == 429 {
+        return Err(Error::RateLimit(String::from("HTTP 429 Too Many Requests")));
+    }
+    if !response.status().is_success() {
+        return Err(Error::Http(format!(
+            "HTTP request failed with status code: {}",
+            response.status()
+        )));
+    }
+
+    let body = response.text().await?;
+    let json_data: serde_json::Value = serde_json::from_str(&body)?;
+
+    check_response(json_data)
+}
+
+/// @dev Check the `status` of an etherscan response
+/// @param json_data The whole response
+/// @return The `result` field
+pub fn check_response(mut json_data: serde_json::Value) -> Result<serde_json::Value> {
+    let result = json_data["result"].take();
+
+    if json_data["status"].as_str() != Some("0") {
+        return Ok(result);
+    }
+
+    let message = json_data["message"].as_str().unwrap_or_default().to_string();
+    let result_text = match &result {
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+
+    if message.starts_with("No transactions found") || message.starts_with("No records found") {
+        return Ok(serde_json::Value::Array(Vec::new()));
+    }
+    if result_text.to_lowercase().contains("rate limit") {
+        return Err(Error::RateLimit(result_text));
+    }
+
+    Err(Error::Api {
+        message,
+        result: result_text,
+    })
+}
+
+/// @dev The latest block number, with the etherscan `eth_blockNumber` proxy
+/// @param api_key ETHERSCAN API KEY
+pub async fn block_number(api_key: &str) -> Result<u64> {
+    let url = format!("https://api.etherscan.io/api?module=proxy&action=eth_blockNumber&apikey={}", api_key);
+
+    let result = get_result(&url).await?;
+    let hex = result.as_str().unwrap_or_default();
+    u64::from_str_radix(hex.trim_start_matches("0x"), 16)
+        .map_err(|e| Error::Decode(format!("Invalid block number {}: {}", result, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn returns_the_result_of_a_successful_response() {
+        let result = check_response(json!({"status": "1", "message": "OK", "result": [{"hash": "0x01"}]})).unwrap();
+        assert_eq!(result, json!([{"hash": "0x01"}]));
+
+        // The proxy module has no status
+        assert_eq!(check_response(json!({"jsonrpc": "2.0", "result": "0x10"})).unwrap(), json!("0x10"));
+    }
+
+    #[test]
+    fn treats_no_records_as_empty() {
+        let result = check_response(json!({"status": "0", "message": "No transactions found", "result": []})).unwrap();
+        assert_eq!(result, json!([]));
+    }
+
+    #[test]
+    fn tells_the_rate_limit_from_the_api_errors() {
+        let rate_limit = json!({"status": "0", "message": "NOTOK", "result": "Max rate limit reached"});
+        assert!(matches!(check_response(rate_limit), Err(Error::RateLimit(_))));
+
+        let invalid_key = json!({"status": "0", "message": "NOTOK", "result": "Invalid API Key"});
+        match check_response(invalid_key) {
+            Err(e @ Error::Api { .. }) => assert!(!e.is_recoverable()),
+            other => panic!("expected an api error, got {:?}", other),
+        }
+    }
+}
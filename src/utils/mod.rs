@@ -1 +1,9 @@
-pub mod tools;
\ No newline at end of file
+pub mod tools;
+pub mod output;
+pub mod address_db;
+pub mod notifier;
+pub mod etherscan;
+pub mod source;
+pub mod proxy;
+pub mod selector_db;
+pub mod data_dir;
\ No newline at end of file
@@ -0,0 +1,223 @@
+use lettre::{transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
+use serde::Serialize;
+use crate::error::{Error, Result};
+
+/// @dev An alert of a robot
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Alert {
+    pub title: String,
+    pub rule: String, // The triggering rule, e.g. "warning: too many `removeLiquidity(...)` txs"
+    pub address: String, // The monitored address or contract
+    pub message: String,
+    pub txs: Vec<String>, // The (decoded) txs with the db labels of their counterparties
+    pub details: Vec<String>, // e.g. the fork simulations
+    pub explanation: Option<String>, // The AI incident hypothesis and recommended action
+}
+
+impl Alert {
+    /// @param rule The triggering rule
+    /// @param address The monitored address or contract
+    /// @param message What happened
+    pub fn new(rule: String, address: String, message: String) -> Self {
+        Alert {
+            title: String::from("SecHelper Robot"),
+            rule,
+            address,
+            message,
+            ..Default::default()
+        }
+    }
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.txs.is_empty() {
+            write!(f, "\n\nTxs:\n{}", self.txs.join("\n"))?;
+        }
+        for detail in &self.details {
+            write!(f, "\n\n{}", detail)?;
+        }
+        if let Some(explanation) = &self.explanation {
+            write!(f, "\n\nAI analysis (unverified):\n{}", explanation)?;
+        }
+        Ok(())
+    }
+}
+
+/// @dev Send emails through a SMTP server
+#[derive(Debug, Clone)]
+pub struct EmailNotifier {
+    pub sender: String, // Email from
+    password: String, // Sender's email server password
+    pub smtp_server: String, // Email server smtp code
+}
+
+impl EmailNotifier {
+    /// @param sender Email from
+    /// @param password Sender's email server password
+    /// @param smtp_server Email server smtp code
+    pub fn new(sender: String, password: String, smtp_server: String) -> Self {
+        EmailNotifier {
+            sender,
+            password,
+            smtp_server,
+        }
+    }
+
+    /// @dev Send an email
+    /// @param receiver The email addresses to receive, separated by `,`
+    /// @param title The email title
+    /// @param content The email content
+    pub fn send(&self, receiver: &str, title: &str, content: String) -> Result<()> {
+        send_email(
+            self.sender.clone(),
+            receiver.to_string(),
+            title.to_string(),
+            content,
+            self.password.clone(),
+            self.smtp_server.clone(),
+        )
+    }
+}
+
+/// @dev Send the alerts to all configured channels: email and webhooks
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    pub email: Option<EmailNotifier>,
+    pub webhooks: Vec<String>, // URLs to POST the alerts to
+}
+
+impl Notifier {
+    /// @param email The email notifier, None to disable email
+    /// @param webhooks URLs to POST the alerts to
+    pub fn new(email: Option<EmailNotifier>, webhooks: Vec<String>) -> Self {
+        Notifier { email, webhooks }
+    }
+
+    /// @dev Send an alert to every channel, every channel is tried even if one of them fails
+    /// @param receiver The email address to receive
+    /// @param title The alert title
+    /// @param content The alert content
+    /// @return The last error if any channel fails
+    pub async fn send(&self, receiver: &str, title: &str, content: String) -> Result<()> {
+        let mut result = Ok(());
+
+        if let Some(email) = &self.email {
+            if let Err(e) = email.send(receiver, title, content.clone()) {
+                result = Err(e);
+            }
+        }
+        for url in &self.webhooks {
+            if let Err(e) = send_webhook(url, title, &content).await {
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+
+    /// @dev Send an alert to every channel
+    /// @param receiver The email address to receive
+    /// @param alert The alert
+    pub async fn send_alert(&self, receiver: &str, alert: &Alert) -> Result<()> {
+        self.send(receiver, &alert.title, alert.to_string()).await
+    }
+}
+
+/// @dev POST an alert as json `{"title": ..., "text": ...}` to a webhook
+/// @param url The webhook URL
+/// @param title The alert title
+/// @param content The alert content
+pub async fn send_webhook(url: &str, title: &str, content: &str) -> Result<()> {
+    let body = serde_json::json!({
+        "title": title,
+        "text": content,
+    });
+
+    let response = reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| Error::Notification(format!("Could not call the webhook {}: {}", redact_url(url), e.without_url())))?;
+
+    if !response.status().is_success() {
+        return Err(Error::Notification(format!(
+            "The webhook {} failed with status code: {}",
+            redact_url(url),
+            response.status()
+        )));
+    }
+
+    Ok(())
+}
+
+/// @dev The scheme and host of a webhook URL for the errors, its path and query hold the token
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => format!("{}://{}", url.scheme(), url.host_str().unwrap_or_default()),
+        Err(_) => String::from("<invalid url>"),
+    }
+}
+
+/// @dev Send an email
+/// @param sender Email from
+/// @param receiver The email addresses to receive, separated by `,`
+/// @param title The email title
+/// @param content The email content
+/// @param password Sender's email server password
+/// @param smtp_server Email server smtp code
+pub fn send_email(
+    sender: String,
+    receiver: String,
+    title: String,
+    content: String,
+    password: String,
+    smtp_server: String
+) -> Result<()> {
+
+    let mut builder = Message::builder()
+        .from(sender.parse().map_err(|e| Error::InvalidInput(format!("Invalid sender `{}`: {}", sender, e)))?);
+    for receiver in receiver.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        builder = builder.to(receiver.parse().map_err(|e| Error::InvalidInput(format!("Invalid receiver `{}`: {}", receiver, e)))?);
+    }
+
+    let email = builder
+        .subject(title)
+        .body(content)
+        .map_err(|e| Error::Notification(e.to_string()))?;
+
+    let creds = Credentials::new(sender, password);
+
+    let mailer = SmtpTransport::relay(smtp_server.as_str())
+        .map_err(|e| Error::Notification(e.to_string()))?
+        .credentials(creds)
+        .build();
+
+    mailer
+        .send(&email)
+        .map_err(|e| Error::Notification(format!("Could not send the email: {}", e)))?;
+
+    eprintln!("Email sent successfully");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_the_webhook_token() {
+        let url = "https://hooks.slack.com/services/T000/B000/secret?token=secret";
+        assert_eq!(redact_url(url), "https://hooks.slack.com");
+        assert_eq!(redact_url("secret"), "<invalid url>");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_webhook_token_out_of_the_errors() {
+        let e = send_webhook("http://127.0.0.1:1/secret-token", "title", "content").await.unwrap_err();
+        assert!(!e.to_string().contains("secret-token"), "{}", e);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, stdout, Write},
+    str::FromStr,
+};
+use serde::Serialize;
+
+/// @dev The output formats every command can print
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Json,
+    Jsonl,
+    Csv,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "csv" => Ok(OutputFormat::Csv),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Invalid output format `{}`, expected json, jsonl, csv or table", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Table => "table",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// @dev A record which can be printed as one row of csv/table, or as one json object
+pub trait Record: Serialize {
+    /// @return The column names
+    fn headers() -> Vec<&'static str>;
+
+    /// @return The column values, in the same order as `headers()`
+    fn row(&self) -> Vec<String>;
+}
+
+/// @dev Print a batch of records to stdout, or to a file if `out_file` is given
+/// @param format The output format
+/// @param records The records to print
+/// @param out_file The file to write into, None for stdout
+pub fn print_records<R: Record>(format: OutputFormat, records: &[R], out_file: Option<&str>) -> io::Result<()> {
+    match out_file {
+        Some(path) => {
+            let mut file = File::create(path)?;
+            write_records(&mut file, format, records)
+        }
+        None => write_records(&mut stdout().lock(), format, records),
+    }
+}
+
+/// @dev Write a batch of records
+/// @param writer Where to write
+/// @param format The output format
+/// @param records The records to write
+pub fn write_records<R: Record, W: Write>(writer: &mut W, format: OutputFormat, records: &[R]) -> io::Result<()> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, records)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Jsonl => {
+            for record in records {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)?;
+            }
+        }
+        OutputFormat::Csv => {
+            writeln!(writer, "{}", csv_line(&R::headers().iter().map(|h| h.to_string()).collect::<Vec<_>>()))?;
+            for record in records {
+                writeln!(writer, "{}", csv_line(&record.row()))?;
+            }
+        }
+        OutputFormat::Table => {
+            let headers: Vec<String> = R::headers().iter().map(|h| h.to_string()).collect();
+            let rows: Vec<Vec<String>> = records.iter().map(|r| r.row()).collect();
+
+            let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+            for row in &rows {
+                for (i, cell) in row.iter().enumerate() {
+                    widths[i] = widths[i].max(cell.len());
+                }
+            }
+
+            writeln!(writer, "{}", table_line(&headers, &widths))?;
+            writeln!(writer, "{}", widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>().join("-+-"))?;
+            for row in &rows {
+                writeln!(writer, "{}", table_line(row, &widths))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// @dev Print records one by one for the streaming commands (the listeners).
+/// `json` and `jsonl` both print newline-delimited json, csv and table print the header once.
+pub struct Stream {
+    format: OutputFormat,
+    header_printed: bool,
+}
+
+impl Stream {
+    /// @param format The output format
+    pub fn new(format: OutputFormat) -> Self {
+        Stream {
+            format,
+            header_printed: false,
+        }
+    }
+
+    /// @dev Print one record to stdout
+    /// @param record The record to print
+    pub fn emit<R: Record>(&mut self, record: &R) {
+        let line = match self.format {
+            OutputFormat::Json | OutputFormat::Jsonl => match serde_json::to_string(record) {
+                Ok(line) => line,
+                Err(e) => {
+                    eprintln!("serialize record error: {}", e);
+                    return;
+                }
+            },
+            OutputFormat::Csv => {
+                if !self.header_printed {
+                    println!("{}", csv_line(&R::headers().iter().map(|h| h.to_string()).collect::<Vec<_>>()));
+                    self.header_printed = true;
+                }
+                csv_line(&record.row())
+            }
+            OutputFormat::Table => {
+                if !self.header_printed {
+                    println!("{}", R::headers().join(" | "));
+                    self.header_printed = true;
+                }
+                record.row().join(" | ")
+            }
+        };
+
+        println!("{}", line);
+    }
+}
+
+/// @dev Join the cells as a csv line, quote the cell if needed
+fn csv_line(cells: &[String]) -> String {
+    cells
+        .iter()
+        .map(|cell| {
+            if cell.contains(',') || cell.contains('"') || cell.contains('\n') {
+                format!("\"{}\"", cell.replace('"', "\"\""))
+            } else {
+                cell.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// @dev Join the cells as a table line, pad each cell to the column width
+fn table_line(cells: &[String], widths: &[usize]) -> String {
+    cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Row {
+        name: String,
+        value: u64,
+    }
+
+    impl Record for Row {
+        fn headers() -> Vec<&'static str> {
+            vec!["name", "value"]
+        }
+
+        fn row(&self) -> Vec<String> {
+            vec![self.name.clone(), self.value.to_string()]
+        }
+    }
+
+    fn write(format: OutputFormat, records: &[Row]) -> String {
+        let mut buffer = Vec::new();
+        write_records(&mut buffer, format, records).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn rows() -> Vec<Row> {
+        vec![Row { name: String::from("a, \"b\""), value: 1 }, Row { name: String::from("c"), value: 22 }]
+    }
+
+    #[test]
+    fn parses_the_formats() {
+        assert_eq!("JSONL".parse::<OutputFormat>(), Ok(OutputFormat::Jsonl));
+        assert_eq!(OutputFormat::Csv.to_string().parse::<OutputFormat>(), Ok(OutputFormat::Csv));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn quotes_the_csv_cells() {
+        assert_eq!(write(OutputFormat::Csv, &rows()), "name,value\n\"a, \"\"b\"\"\",1\nc,22\n");
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let output = write(OutputFormat::Jsonl, &rows());
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["value"], 22);
+
+        let array: serde_json::Value = serde_json::from_str(&write(OutputFormat::Json, &rows())).unwrap();
+        assert_eq!(array.as_array().map(|a| a.len()), Some(2));
+    }
+
+    #[test]
+    fn pads_the_table_columns() {
+        let output = write(OutputFormat::Table, &rows());
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "name   | value");
+        assert_eq!(lines[1], "-------+------");
+        assert_eq!(lines[3], "c      | 22   ");
+    }
+}
@@ -0,0 +1,291 @@
+use std::{fs, path::Path};
+use ethers::{
+    providers::Middleware,
+    types::{transaction::eip2718::TypedTransaction, Address, Bytes, TransactionRequest, H256},
+};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::utils::{
+    source::{self, ContractSource, WrittenFile},
+    tools,
+};
+
+/// How many proxies are followed at most, e.g. clone -> beacon proxy -> implementation
+const MAX_DEPTH: usize = 5;
+
+/// EIP-1967 `bytes32(uint256(keccak256('eip1967.proxy.implementation')) - 1)`, used by transparent and UUPS proxies
+const EIP1967_IMPLEMENTATION_SLOT: &str = "0x360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc";
+/// EIP-1967 `bytes32(uint256(keccak256('eip1967.proxy.beacon')) - 1)`
+const EIP1967_BEACON_SLOT: &str = "0xa3f0ad74e5423aebfd80d3ef4346578335a9a72aeaee59ff6cb3582b35133d50";
+/// EIP-1822 (UUPS) `keccak256("PROXIABLE")`
+const EIP1822_SLOT: &str = "0xc5f16f0fcc639fa48a6947836d9850f504798523bf8c9a3a87d5876cf622bcf7";
+/// OpenZeppelin (zos) transparent proxy `keccak256("org.zeppelinos.proxy.implementation")`
+const ZEPPELINOS_SLOT: &str = "0x7050c9e0f4ca769c69bd3a8ef740bc37934f8e2c036e5a723fd8ee048ed3f8c3";
+/// EIP-1167 minimal proxy runtime code, the implementation address sits between the prefix and the suffix
+const EIP1167_PREFIX: &str = "363d3d373d3d3d363d73";
+const EIP1167_SUFFIX: &str = "5af43d82803e903d91602b57fd5bf3";
+
+/// @dev How the implementation of a proxy was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    /// The EIP-1967 implementation slot (transparent and UUPS proxies)
+    Eip1967,
+    /// The EIP-1967 beacon slot, the implementation is `beacon.implementation()`
+    Beacon,
+    /// The EIP-1822 `PROXIABLE` slot (old UUPS proxies)
+    Eip1822,
+    /// The OpenZeppelin (zos) implementation slot
+    ZeppelinOs,
+    /// An EIP-1167 minimal proxy (clone)
+    Eip1167,
+    /// The `Implementation` field from etherscan
+    Etherscan,
+}
+
+/// @dev A proxy and its implementation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyLink {
+    pub proxy: String,
+    pub kind: ProxyKind,
+    pub beacon: Option<String>,
+    pub implementation: String,
+}
+
+/// @dev The sources downloaded by `get_contract_source_with_proxy()`, the relationship is written to `output/<address>/proxy.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxySources {
+    pub address: String,
+    pub links: Vec<ProxyLink>, // From the address to the final implementation
+    #[serde(skip)]
+    pub sources: Vec<ContractSource>, // The verified ones in the same order, each beacon after its proxy
+    pub unverified: Vec<String>,
+    #[serde(skip)]
+    pub written: Vec<WrittenFile>,
+}
+
+impl ProxySources {
+    /// @return The final implementation, or the address itself if it is not a proxy
+    pub fn implementation(&self) -> &str {
+        self.links.last().map_or(self.address.as_str(), |link| link.implementation.as_str())
+    }
+}
+
+/// @dev Find the implementation of a proxy through its storage slots and code
+/// @param client The provider
+/// @param address The proxy address
+/// @return None if the address is not a proxy
+pub async fn resolve_implementation<M: Middleware>(client: &M, address: &str) -> Result<Option<ProxyLink>> {
+    let proxy = parse_address(address)?;
+    let link = |kind, beacon: Option<Address>, implementation: Address| ProxyLink {
+        proxy: address.to_string(),
+        kind,
+        beacon: beacon.map(|beacon| format!("{:?}", beacon)),
+        implementation: format!("{:?}", implementation),
+    };
+
+    if let Some(implementation) = read_address_slot(client, proxy, EIP1967_IMPLEMENTATION_SLOT).await? {
+        return Ok(Some(link(ProxyKind::Eip1967, None, implementation)));
+    }
+    if let Some(beacon) = read_address_slot(client, proxy, EIP1967_BEACON_SLOT).await? {
+        let implementation = call_implementation(client, beacon).await?;
+        return Ok(Some(link(ProxyKind::Beacon, Some(beacon), implementation)));
+    }
+    if let Some(implementation) = read_address_slot(client, proxy, EIP1822_SLOT).await? {
+        return Ok(Some(link(ProxyKind::Eip1822, None, implementation)));
+    }
+    if let Some(implementation) = read_address_slot(client, proxy, ZEPPELINOS_SLOT).await? {
+        return Ok(Some(link(ProxyKind::ZeppelinOs, None, implementation)));
+    }
+
+    let code = client
+        .get_code(proxy, None)
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?;
+    if let Some(implementation) = eip1167_implementation(&code) {
+        return Ok(Some(link(ProxyKind::Eip1167, None, implementation)));
+    }
+
+    Ok(None)
+}
+
+/// @dev Download the sources of a contract, and of its implementations if it is a proxy (recursing through beacons and clones).
+/// The proxy chain is written to `<output_dir>/<address>/proxy.json`.
+/// @param api_key ETHERSCAN API KEY
+/// @param client The provider to read the proxy slots, None to only use the etherscan `Implementation` field
+/// @param address The contract address
+/// @param output_dir The output folder
+pub async fn get_contract_source_with_proxy<M: Middleware>(
+    api_key: &str,
+    client: Option<&M>,
+    address: &str,
+    output_dir: &str,
+) -> Result<ProxySources> {
+    let mut result = ProxySources {
+        address: address.to_string(),
+        links: Vec::new(),
+        sources: Vec::new(),
+        unverified: Vec::new(),
+        written: Vec::new(),
+    };
+
+    let mut current = address.to_string();
+    for _ in 0..MAX_DEPTH {
+        let verified = download_source(api_key, &current, output_dir, &mut result).await?;
+
+        let mut link = match client {
+            Some(client) => resolve_implementation(client, &current).await?,
+            None => None,
+        };
+        if link.is_none() && verified {
+            link = result.sources.last().and_then(etherscan_link);
+        }
+
+        // The beacon holds the upgrade logic, its source is kept next to the implementation
+        if let Some(beacon) = link.as_ref().and_then(|link| link.beacon.clone()) {
+            download_source(api_key, &beacon, output_dir, &mut result).await?;
+        }
+
+        match link {
+            // A proxy pointing to itself is the end of the chain
+            Some(link) if !link.implementation.eq_ignore_ascii_case(&current) && !is_visited(&result, &link.implementation) => {
+                current = link.implementation.clone();
+                result.links.push(link);
+            }
+            _ => break,
+        }
+    }
+
+    if result.sources.is_empty() {
+        return Err(Error::Unverified(address.to_string()));
+    }
+
+    if !result.links.is_empty() {
+        let path = Path::new(output_dir).join(address).join("proxy.json");
+        let content = serde_json::to_string_pretty(&result)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, &content)
+            .map_err(|e| Error::Storage(format!("write file {} error: {}", path.display(), e)))?;
+        result.written.push(WrittenFile {
+            address: address.to_string(),
+            path: path.display().to_string(),
+            bytes: content.len(),
+        });
+    }
+
+    Ok(result)
+}
+
+/// @dev The implementation from the etherscan `Implementation` field
+fn etherscan_link(source: &ContractSource) -> Option<ProxyLink> {
+    let implementation = source.metadata.implementation.trim();
+    if !source.metadata.proxy || implementation.is_empty() {
+        return None;
+    }
+
+    Some(ProxyLink {
+        proxy: source.metadata.address.clone(),
+        kind: ProxyKind::Etherscan,
+        beacon: None,
+        implementation: implementation.to_string(),
+    })
+}
+
+/// @dev Download and write the source of a contract, an unverified one is recorded in `result.unverified`
+/// @return Whether the contract is verified
+async fn download_source(api_key: &str, address: &str, output_dir: &str, result: &mut ProxySources) -> Result<bool> {
+    match source::fetch_contract_source(api_key, address).await {
+        Ok(source) => {
+            result.written.extend(source::write_contract_source(&source, output_dir)?);
+            result.sources.push(source);
+            Ok(true)
+        }
+        Err(Error::Unverified(_)) => {
+            result.unverified.push(address.to_string());
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn is_visited(result: &ProxySources, address: &str) -> bool {
+    result.address.eq_ignore_ascii_case(address)
+        || result.links.iter().any(|link| link.proxy.eq_ignore_ascii_case(address))
+}
+
+/// @dev Read an address from a storage slot, None if the slot is empty
+async fn read_address_slot<M: Middleware>(client: &M, address: Address, slot: &str) -> Result<Option<Address>> {
+    let slot: H256 = slot
+        .parse()
+        .map_err(|e| Error::Decode(format!("Invalid slot {}: {}", slot, e)))?;
+    let value = client
+        .get_storage_at(address, slot, None)
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?;
+
+    let implementation = Address::from_slice(&value.as_bytes()[12..]);
+    Ok(if implementation.is_zero() { None } else { Some(implementation) })
+}
+
+/// @dev Call `implementation()` of a beacon
+async fn call_implementation<M: Middleware>(client: &M, beacon: Address) -> Result<Address> {
+    let selector = tools::function_sig("implementation()");
+    let data: Bytes = selector
+        .parse()
+        .map_err(|e| Error::Decode(format!("Invalid selector {}: {:?}", selector, e)))?;
+    let tx: TypedTransaction = TransactionRequest::new().to(beacon).data(data).into();
+
+    let output = client
+        .call(&tx, None)
+        .await
+        .map_err(|e| Error::Rpc(e.to_string()))?;
+    if output.len() < 32 {
+        return Err(Error::Decode(format!("Invalid implementation() output of beacon {:?}: {}", beacon, output)));
+    }
+
+    Ok(Address::from_slice(&output[12..32]))
+}
+
+/// @dev The implementation of an EIP-1167 minimal proxy
+fn eip1167_implementation(code: &[u8]) -> Option<Address> {
+    let code = ethers::utils::hex::encode(code);
+    let implementation = code.strip_prefix(EIP1167_PREFIX)?.strip_suffix(EIP1167_SUFFIX)?;
+    if implementation.len() != 40 {
+        return None;
+    }
+
+    implementation.parse().ok()
+}
+
+fn parse_address(address: &str) -> Result<Address> {
+    address
+        .parse()
+        .map_err(|e| Error::InvalidInput(format!("Invalid address `{}`: {}", address, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{types::U256, utils::{hex, keccak256}};
+
+    #[test]
+    fn derives_the_proxy_slots() {
+        let eip1967 = |label: &str| format!("{:#066x}", U256::from(keccak256(label)) - 1);
+        assert_eq!(eip1967("eip1967.proxy.implementation"), EIP1967_IMPLEMENTATION_SLOT);
+        assert_eq!(eip1967("eip1967.proxy.beacon"), EIP1967_BEACON_SLOT);
+        assert_eq!(format!("0x{}", hex::encode(keccak256("PROXIABLE"))), EIP1822_SLOT);
+        assert_eq!(format!("0x{}", hex::encode(keccak256("org.zeppelinos.proxy.implementation"))), ZEPPELINOS_SLOT);
+    }
+
+    #[test]
+    fn reads_the_implementation_of_a_minimal_proxy() {
+        let implementation = "bebebebebebebebebebebebebebebebebebebebe";
+        let code = hex::decode(format!("{}{}{}", EIP1167_PREFIX, implementation, EIP1167_SUFFIX)).unwrap();
+        assert_eq!(eip1167_implementation(&code), Some(implementation.parse().unwrap()));
+
+        assert_eq!(eip1167_implementation(&code[..code.len() - 1]), None);
+        assert_eq!(eip1167_implementation(&hex::decode("6080604052").unwrap()), None);
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    sync::LazyLock,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::utils::{data_dir, output::Record, tools};
+
+/// The selectors shipped with the binary
+const BUNDLED_SELECTORS: &str = include_str!("selectors.json");
+
+/// The file in the data dir which keeps the imported and the learned signatures
+const USER_SELECTORS_FILE: &str = "selectors.json";
+
+/// `transfer(address,uint256)` and a selector / topic in a line of a text dump
+static SIGNATURE_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[A-Za-z_$][\w$]*\([^\s\x22']*\)").unwrap());
+static HASH_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b0x([0-9a-fA-F]{64}|[0-9a-fA-F]{8})\b").unwrap());
+
+/// @dev What a selector / topic belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureKind {
+    /// A 4-byte function (or custom error) selector
+    Function,
+    /// A 32-byte event topic
+    Event,
+}
+
+/// @dev One signature found for a selector / topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignatureMatch {
+    pub hash: String,
+    pub kind: SignatureKind,
+    pub signature: String, // Empty if unknown
+    pub collisions: usize, // How many signatures share the hash
+}
+
+impl Record for SignatureMatch {
+    fn headers() -> Vec<&'static str> {
+        vec!["hash", "kind", "signature", "collisions"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            format!("{:?}", self.kind).to_lowercase(),
+            self.signature.clone(),
+            self.collisions.to_string(),
+        ]
+    }
+}
+
+/// @dev The offline selector db: function selectors and event topics to their text signatures.
+/// A hash can have several signatures (collisions), they are all kept.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SelectorDb {
+    #[serde(default)]
+    pub functions: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub events: BTreeMap<String, Vec<String>>,
+}
+
+impl SelectorDb {
+    /// @dev Load the bundled selectors and the ones imported into the data dir
+    pub fn load() -> Result<Self> {
+        let mut db = Self::bundled()?;
+        db.merge(Self::load_user()?);
+        Ok(db)
+    }
+
+    /// @dev The selectors shipped with the binary
+    pub fn bundled() -> Result<Self> {
+        serde_json::from_str(BUNDLED_SELECTORS)
+            .map_err(|e| Error::Storage(format!("Failed to parse the bundled selectors: {}", e)))
+    }
+
+    /// @dev Load the selectors imported into the data dir, none if nothing was imported yet
+    pub fn load_user() -> Result<Self> {
+        let path = data_dir::data_path(USER_SELECTORS_FILE);
+        if !path.exists() {
+            return Ok(SelectorDb::default());
+        }
+        Self::load_from(&path.to_string_lossy())
+    }
+
+    /// @dev Load a db file
+    /// @param path The db file
+    pub fn load_from(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| Error::Storage(format!("Failed to read {}: {}", path, e)))?;
+        serde_json::from_str(&content)
+            .map_err(|e| Error::Storage(format!("Failed to parse {}: {}", path, e)))
+    }
+
+    /// @dev Write the signatures which are not bundled to the data dir
+    pub fn save(&self) -> Result<()> {
+        let user = self.without(&Self::bundled()?);
+        user.save_to(&data_dir::data_path(USER_SELECTORS_FILE).to_string_lossy())
+    }
+
+    /// @dev Add the signatures of another db
+    pub fn merge(&mut self, other: SelectorDb) {
+        for (hash, signatures) in other.functions {
+            for signature in signatures {
+                insert(&mut self.functions, hash.clone(), signature);
+            }
+        }
+        for (hash, signatures) in other.events {
+            for signature in signatures {
+                insert(&mut self.events, hash.clone(), signature);
+            }
+        }
+    }
+
+    /// @return The signatures which are not in another db
+    pub fn without(&self, other: &SelectorDb) -> SelectorDb {
+        let difference = |table: &BTreeMap<String, Vec<String>>, other: &BTreeMap<String, Vec<String>>| {
+            table
+                .iter()
+                .filter_map(|(hash, signatures)| {
+                    let known = other.get(hash);
+                    let new: Vec<String> = signatures
+                        .iter()
+                        .filter(|signature| !known.is_some_and(|known| known.contains(signature)))
+                        .cloned()
+                        .collect();
+                    (!new.is_empty()).then(|| (hash.clone(), new))
+                })
+                .collect()
+        };
+        SelectorDb {
+            functions: difference(&self.functions, &other.functions),
+            events: difference(&self.events, &other.events),
+        }
+    }
+
+    /// @dev Write the db to a file
+    /// @param path The db file
+    pub fn save_to(&self, path: &str) -> Result<()> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path, content)
+            .map_err(|e| Error::Storage(format!("Failed to write {}: {}", path, e)))
+    }
+
+    /// @dev Find the signatures of a 4-byte selector (`0xa9059cbb`) or a 32-byte topic
+    /// @param hash The selector or topic, with or without `0x`
+    /// @return The matches, one with an empty signature if unknown
+    pub fn lookup(&self, hash: &str) -> Result<Vec<SignatureMatch>> {
+        let hash = normalize_hash(hash)?;
+        let (kind, table) = match hash.len() {
+            10 => (SignatureKind::Function, &self.functions),
+            66 => (SignatureKind::Event, &self.events),
+            _ => return Err(Error::InvalidInput(format!("`{}` is neither a 4-byte selector nor a 32-byte topic", hash))),
+        };
+
+        let signatures = table.get(&hash).cloned().unwrap_or_default();
+        if signatures.is_empty() {
+            return Ok(vec![SignatureMatch { hash, kind, signature: String::new(), collisions: 0 }]);
+        }
+
+        let collisions = signatures.len();
+        Ok(signatures
+            .into_iter()
+            .map(|signature| SignatureMatch { hash: hash.clone(), kind, signature, collisions })
+            .collect())
+    }
+
+    /// @return The first signature of a selector / topic, None if unknown
+    pub fn signature(&self, hash: &str) -> Option<String> {
+        let hash = normalize_hash(hash).ok()?;
+        let table = if hash.len() == 66 { &self.events } else { &self.functions };
+        table.get(&hash)?.first().cloned()
+    }
+
+    /// @dev Add a function / error signature, e.g. `transfer(address,uint256)`
+    /// @return True if it was not in the db
+    pub fn insert_function(&mut self, signature: &str) -> bool {
+        let signature = normalize_signature(signature);
+        insert(&mut self.functions, tools::function_sig(&signature), signature)
+    }
+
+    /// @dev Add an event signature, e.g. `Transfer(address,address,uint256)`
+    /// @return True if it was not in the db
+    pub fn insert_event(&mut self, signature: &str) -> bool {
+        let signature = normalize_signature(signature);
+        insert(&mut self.events, tools::event_topic(&signature), signature)
+    }
+
+    /// @dev Add the functions, errors and events of an ABI
+    /// @param abi The ABI json
+    /// @return How many signatures were added
+    pub fn extend_from_abi(&mut self, abi: &serde_json::Value) -> usize {
+        let mut added = 0;
+        for item in abi.as_array().into_iter().flatten() {
+            let Some(signature) = tools::abi_signature(item) else { continue };
+            let new = match item["type"].as_str() {
+                Some("function") | Some("error") => self.insert_function(&signature),
+                Some("event") => self.insert_event(&signature),
+                _ => false,
+            };
+            added += new as usize;
+        }
+        added
+    }
+
+    /// @dev Add the ABIs of the contracts downloaded by the `source` command, i.e. `<dir>/*/metadata.json`
+    /// @param dir The output folder
+    /// @return How many signatures were added
+    pub fn extend_from_metadata(&mut self, dir: &str) -> Result<usize> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| Error::Storage(format!("read dir {} error: {}", dir, e)))?;
+
+        let mut added = 0;
+        for entry in entries {
+            let path = entry?.path().join("metadata.json");
+            let Ok(content) = fs::read_to_string(&path) else { continue };
+            let metadata: serde_json::Value = serde_json::from_str(&content)
+                .map_err(|e| Error::Storage(format!("Failed to parse {}: {}", path.display(), e)))?;
+            added += self.extend_from_abi(&metadata["abi"]);
+        }
+        Ok(added)
+    }
+
+    /// @dev Import a signature dump. Supported formats:
+    /// - the 4byte.directory api json (`{"results": [{"text_signature": ..., "hex_signature": ...}]}`) or a list of its results
+    /// - the OpenChain export json (`{"result": {"function": {hash: [{"name": ...}]}, "event": {...}}}`)
+    /// - text / csv, one signature per line, optionally with its hash (e.g. the 4byte csv dump)
+    ///
+    /// The hashes are recomputed from the signatures, the lines whose given hash does not match are skipped.
+    /// @param path The dump file
+    /// @return How many signatures were added
+    pub fn import(&mut self, path: &str) -> Result<usize> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| Error::Storage(format!("Failed to read {}: {}", path, e)))?;
+
+        match serde_json::from_str::<serde_json::Value>(&content) {
+            Ok(json) if json.is_object() || json.is_array() => Ok(self.import_json(&json)),
+            _ => Ok(self.import_text(&content)),
+        }
+    }
+
+    fn import_json(&mut self, json: &serde_json::Value) -> usize {
+        let mut added = 0;
+
+        // OpenChain
+        for (key, kind) in [("function", SignatureKind::Function), ("event", SignatureKind::Event)] {
+            for (hash, signatures) in json["result"][key].as_object().into_iter().flatten() {
+                for signature in signatures.as_array().into_iter().flatten() {
+                    if let Some(name) = signature["name"].as_str() {
+                        added += self.insert_checked(kind, name, Some(hash)) as usize;
+                    }
+                }
+            }
+        }
+
+        // 4byte
+        let results = json.get("results").unwrap_or(json);
+        for result in results.as_array().into_iter().flatten() {
+            let Some(signature) = result["text_signature"].as_str() else { continue };
+            let hash = result["hex_signature"].as_str();
+            let kind = match hash.map(|hash| hash.trim_start_matches("0x").len()) {
+                Some(64) => SignatureKind::Event,
+                _ => SignatureKind::Function,
+            };
+            added += self.insert_checked(kind, signature, hash) as usize;
+        }
+
+        added
+    }
+
+    fn import_text(&mut self, content: &str) -> usize {
+        let mut added = 0;
+
+        for line in content.lines() {
+            let Some(signature) = SIGNATURE_PATTERN.find(line) else { continue };
+            let hash = HASH_PATTERN.find(line).map(|hash| hash.as_str());
+            let kind = match hash.map(|hash| hash.len()) {
+                Some(66) => SignatureKind::Event,
+                _ => SignatureKind::Function,
+            };
+            added += self.insert_checked(kind, signature.as_str(), hash) as usize;
+        }
+
+        added
+    }
+
+    /// @dev Add a signature if it hashes to the given hash
+    fn insert_checked(&mut self, kind: SignatureKind, signature: &str, hash: Option<&str>) -> bool {
+        let signature = normalize_signature(signature);
+        let expected = match kind {
+            SignatureKind::Function => tools::function_sig(&signature),
+            SignatureKind::Event => tools::event_topic(&signature),
+        };
+        if let Some(hash) = hash {
+            if normalize_hash(hash).ok().as_deref() != Some(expected.as_str()) {
+                return false;
+            }
+        }
+
+        match kind {
+            SignatureKind::Function => insert(&mut self.functions, expected, signature),
+            SignatureKind::Event => insert(&mut self.events, expected, signature),
+        }
+    }
+}
+
+/// @dev Find the signatures of a selector / topic in the bundled and the imported selectors
+/// @param hash The selector or topic
+pub fn lookup(hash: &str) -> Result<Vec<SignatureMatch>> {
+    SelectorDb::load()?.lookup(hash)
+}
+
+/// @dev Add the new signatures of an ABI to the data dir, called when a verified source is downloaded
+/// @param abi The ABI json
+/// @return How many signatures were added
+pub fn write_abi_signatures(abi: &serde_json::Value) -> Result<usize> {
+    let mut db = SelectorDb::load()?;
+    let added = db.extend_from_abi(abi);
+    if added > 0 {
+        db.save()?;
+    }
+    Ok(added)
+}
+
+fn insert(table: &mut BTreeMap<String, Vec<String>>, hash: String, signature: String) -> bool {
+    let signatures = table.entry(hash).or_default();
+    if signatures.contains(&signature) {
+        return false;
+    }
+    signatures.push(signature);
+    true
+}
+
+/// @dev `0xA9059CBB` / `a9059cbb` => `0xa9059cbb`
+fn normalize_hash(hash: &str) -> Result<String> {
+    let hex = hash.trim().trim_start_matches("0x").to_lowercase();
+    if (hex.len() != 8 && hex.len() != 64) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidInput(format!("Invalid selector or topic `{}`", hash)));
+    }
+    Ok(format!("0x{}", hex))
+}
+
+/// @dev Remove the whitespaces, e.g. `transfer(address, uint256)`
+fn normalize_signature(signature: &str) -> String {
+    signature.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+    /// @dev Import a dump written to a temporary file
+    fn import(name: &str, content: &str) -> (SelectorDb, usize) {
+        let path = std::env::temp_dir().join(format!("sechelper-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let mut db = SelectorDb::default();
+        let added = db.import(&path.to_string_lossy()).unwrap();
+        fs::remove_file(path).unwrap();
+        (db, added)
+    }
+
+    #[test]
+    fn looks_up_the_bundled_selectors() {
+        let db = SelectorDb::bundled().unwrap();
+        assert_eq!(db.signature("0xA9059CBB").as_deref(), Some("transfer(address,uint256)"));
+        assert_eq!(db.signature(TRANSFER_TOPIC).as_deref(), Some("Transfer(address,address,uint256)"));
+
+        let unknown = db.lookup("0x00000001").unwrap();
+        assert_eq!((unknown[0].signature.as_str(), unknown[0].collisions), ("", 0));
+        assert!(db.lookup("0x1234").is_err());
+    }
+
+    #[test]
+    fn keeps_the_collisions() {
+        let mut db = SelectorDb::default();
+        assert!(db.insert_function("transfer(address, uint256)"));
+        assert!(!db.insert_function("transfer(address,uint256)"));
+        assert!(db.insert_function("many_msg_babbage(bytes1)"));
+
+        let matches = db.lookup("a9059cbb").unwrap();
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|m| m.collisions == 2 && m.kind == SignatureKind::Function));
+    }
+
+    #[test]
+    fn imports_the_4byte_json() {
+        let dump = json!({"results": [
+            {"text_signature": "approve(address,uint256)", "hex_signature": "0x095ea7b3"},
+            {"text_signature": "fake(uint256)", "hex_signature": "0x095ea7b3"},
+            {"text_signature": "Transfer(address,address,uint256)", "hex_signature": TRANSFER_TOPIC},
+        ]});
+        let (db, added) = import("4byte.json", &dump.to_string());
+        assert_eq!(added, 2);
+        assert_eq!(db.signature("0x095ea7b3").as_deref(), Some("approve(address,uint256)"));
+        assert!(db.events.contains_key(TRANSFER_TOPIC));
+    }
+
+    #[test]
+    fn imports_the_openchain_json() {
+        let dump = json!({"ok": true, "result": {
+            "function": {"0xa9059cbb": [{"name": "transfer(address,uint256)", "filtered": false}]},
+            "event": {TRANSFER_TOPIC: [{"name": "Transfer(address,address,uint256)"}]},
+        }});
+        let (db, added) = import("openchain.json", &dump.to_string());
+        assert_eq!(added, 2);
+        assert_eq!(db.signature(TRANSFER_TOPIC).as_deref(), Some("Transfer(address,address,uint256)"));
+    }
+
+    #[test]
+    fn imports_the_text_and_csv_dumps() {
+        let dump = "id,text_signature,hex_signature\n1,approve(address,uint256),0x095ea7b3\n2,fake(uint256),0x095ea7b3\nbalanceOf(address)\nnot a signature\n";
+        let (db, added) = import("dump.csv", dump);
+        assert_eq!(added, 2);
+        assert!(db.signature("0x70a08231").is_some());
+        assert_eq!(db.functions.get("0x095ea7b3").map(|s| s.len()), Some(1));
+    }
+
+    #[test]
+    fn keeps_only_the_additions_to_the_bundled_db() {
+        let bundled = SelectorDb::bundled().unwrap();
+        let mut db = SelectorDb::default();
+        db.insert_function("transfer(address,uint256)");
+        db.insert_function("sweepAll(address)");
+        db.merge(SelectorDb::bundled().unwrap());
+
+        let user = db.without(&bundled);
+        assert_eq!(user.functions.len(), 1);
+        assert_eq!(user.signature(&tools::function_sig("sweepAll(address)")).as_deref(), Some("sweepAll(address)"));
+        assert!(user.events.is_empty());
+    }
+
+    #[test]
+    fn fails_to_load_a_missing_file() {
+        assert!(matches!(SelectorDb::load_from("/nonexistent/selectors.json"), Err(Error::Storage(_))));
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::utils::{etherscan, output::Record, selector_db};
+
+/// The folder the sources are written into
+pub const DEFAULT_OUTPUT_DIR: &str = "./output";
+
+/// @dev How etherscan returned the `SourceCode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFormat {
+    /// The flattened source in one file
+    SingleFile,
+    /// `{"A.sol": {"content": "..."}, ...}`
+    MultiFile,
+    /// `{{"language": "Solidity", "sources": {...}, "settings": {...}}}`
+    StandardJson,
+}
+
+/// @dev One source file of a verified contract
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub path: String, // The path relative to `output/<address>/`
+    pub content: String,
+}
+
+/// @dev The compiler settings and the ABI of a verified contract, written to `metadata.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceMetadata {
+    pub address: String,
+    pub contract_name: String,
+    pub language: String, // "Solidity" or "Vyper"
+    pub format: SourceFormat,
+    pub compiler_version: String,
+    pub optimization_used: bool,
+    pub runs: u64,
+    pub evm_version: String,
+    pub license: String,
+    pub constructor_arguments: String,
+    pub libraries: serde_json::Value, // The `Library` field, or `settings.libraries` of the standard json
+    pub settings: serde_json::Value, // `settings` of the standard json, null for the other formats
+    pub proxy: bool,
+    pub implementation: String,
+    pub abi: serde_json::Value,
+}
+
+/// @dev A verified contract
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContractSource {
+    pub metadata: SourceMetadata,
+    pub files: Vec<SourceFile>,
+}
+
+/// @dev A written file, printed by the `source` command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrittenFile {
+    pub address: String,
+    pub path: String,
+    pub bytes: usize,
+}
+
+impl Record for WrittenFile {
+    fn headers() -> Vec<&'static str> {
+        vec!["address", "path", "bytes"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.address.clone(), self.path.clone(), self.bytes.to_string()]
+    }
+}
+
+/// @dev Obtain the source code of a verified contract and recreate its directory tree under `output/<address>/`,
+/// with the compiler settings and the ABI in `output/<address>/metadata.json`
+/// @param api_key ETHERSCAN API KEY
+/// @param address Which contract address' sourcecode you want to get
+/// @return The source and the written files
+pub async fn get_contract_solidity_code(api_key: String, address: &str) -> Result<(ContractSource, Vec<WrittenFile>)> {
+    let source = fetch_contract_source(&api_key, address).await?;
+    let written = write_contract_source(&source, DEFAULT_OUTPUT_DIR)?;
+
+    Ok((source, written))
+}
+
+/// @dev Obtain the source code of a verified contract without writing it
+/// @param api_key ETHERSCAN API KEY
+/// @param address The contract address
+pub async fn fetch_contract_source(api_key: &str, address: &str) -> Result<ContractSource> {
+    let url = format!("https://api.etherscan.io/api?module=contract&action=getsourcecode&address={}&apikey={}",
+        address,
+        api_key
+    );
+
+    let result = etherscan::get_result(&url).await?;
+    let detail = result
+        .as_array()
+        .and_then(|details| details.first())
+        .ok_or_else(|| Error::Decode(format!("Expected an array of contract details, got: {}", result)))?;
+
+    parse_contract_source(address, detail)
+}
+
+/// @dev Parse one item of the `getsourcecode` result
+/// @param address The contract address
+/// @param detail The item
+pub fn parse_contract_source(address: &str, detail: &serde_json::Value) -> Result<ContractSource> {
+    let field = |name: &str| -> String { detail[name].as_str().unwrap_or_default().to_string() };
+
+    let source_code = field("SourceCode");
+    if source_code.trim().is_empty() {
+        return Err(Error::Unverified(address.to_string()));
+    }
+
+    let contract_name = field("ContractName");
+    let compiler_version = field("CompilerVersion");
+    let is_vyper = compiler_version.to_lowercase().starts_with("vyper");
+    let extension = if is_vyper { "vy" } else { "sol" };
+
+    let (format, files, settings, language) = split_source_code(&source_code, &contract_name, extension)?;
+    let language = language.unwrap_or_else(|| String::from(if is_vyper { "Vyper" } else { "Solidity" }));
+
+    let libraries = match settings.get("libraries") {
+        Some(libraries) => libraries.clone(),
+        None => serde_json::Value::String(field("Library")),
+    };
+    let abi = serde_json::from_str(&field("ABI")).unwrap_or(serde_json::Value::Null);
+
+    Ok(ContractSource {
+        metadata: SourceMetadata {
+            address: address.to_string(),
+            contract_name,
+            language,
+            format,
+            compiler_version,
+            optimization_used: field("OptimizationUsed") == "1",
+            runs: field("Runs").parse().unwrap_or_default(),
+            evm_version: field("EVMVersion"),
+            license: field("LicenseType"),
+            constructor_arguments: field("ConstructorArguments"),
+            libraries,
+            settings,
+            proxy: field("Proxy") == "1",
+            implementation: field("Implementation"),
+            abi,
+        },
+        files,
+    })
+}
+
+/// @dev Detect the format of `SourceCode` and split it into files
+/// @return The format, the files, `settings` and `language` of the standard json
+fn split_source_code(
+    source_code: &str,
+    contract_name: &str,
+    extension: &str,
+) -> Result<(SourceFormat, Vec<SourceFile>, serde_json::Value, Option<String>)> {
+    let trimmed = source_code.trim();
+
+    // Standard json input is wrapped in one more pair of braces
+    if trimmed.starts_with("{{") && trimmed.ends_with("}}") {
+        let json: serde_json::Value = serde_json::from_str(&trimmed[1..trimmed.len() - 1])?;
+        let files = source_files(&json["sources"])?;
+        let language = json["language"].as_str().map(|language| language.to_string());
+        return Ok((SourceFormat::StandardJson, files, json["settings"].clone(), language));
+    }
+
+    if trimmed.starts_with('{') {
+        if let Ok(json) = serde_json::from_str::<serde_json::Value>(trimmed) {
+            if json.is_object() {
+                return Ok((SourceFormat::MultiFile, source_files(&json)?, serde_json::Value::Null, None));
+            }
+        }
+    }
+
+    let file = SourceFile {
+        path: format!("{}.{}", contract_name, extension),
+        content: source_code.replace("\r\n", "\n"),
+    };
+    Ok((SourceFormat::SingleFile, vec![file], serde_json::Value::Null, None))
+}
+
+/// @dev Parse `{"path": {"content": "..."}}`
+fn source_files(sources: &serde_json::Value) -> Result<Vec<SourceFile>> {
+    let sources = sources
+        .as_object()
+        .ok_or_else(|| Error::Decode(String::from("Expected an object of source files")))?;
+
+    sources
+        .iter()
+        .map(|(path, source)| {
+            let content = source["content"]
+                .as_str()
+                .ok_or_else(|| Error::Decode(format!("Missing `content` of source file {}", path)))?;
+            Ok(SourceFile {
+                path: path.clone(),
+                content: content.replace("\r\n", "\n"),
+            })
+        })
+        .collect()
+}
+
+/// @dev Write the source files and `metadata.json` under `<output_dir>/<address>/`
+/// @param source The contract source
+/// @param output_dir The output folder
+/// @return The written files
+pub fn write_contract_source(source: &ContractSource, output_dir: &str) -> Result<Vec<WrittenFile>> {
+    let dir = Path::new(output_dir).join(&source.metadata.address);
+    let mut written = Vec::new();
+
+    for file in &source.files {
+        let path = dir.join(safe_relative_path(&file.path));
+        write(&path, &file.content)?;
+        written.push(WrittenFile {
+            address: source.metadata.address.clone(),
+            path: path.display().to_string(),
+            bytes: file.content.len(),
+        });
+    }
+
+    let path = dir.join("metadata.json");
+    let metadata = serde_json::to_string_pretty(&source.metadata)?;
+    write(&path, &metadata)?;
+    written.push(WrittenFile {
+        address: source.metadata.address.clone(),
+        path: path.display().to_string(),
+        bytes: metadata.len(),
+    });
+
+    // The selector db learns the signatures of every downloaded contract
+    if let Err(e) = selector_db::write_abi_signatures(&source.metadata.abi) {
+        eprintln!("Failed to add the ABI of {} to the selector db: {}", source.metadata.address, e);
+    }
+
+    Ok(written)
+}
+
+/// @dev Keep a source path inside the output folder: drop the root, `.` and `..`
+fn safe_relative_path(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+fn write(path: &Path, content: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| Error::Storage(format!("create dir {} error: {}", dir.display(), e)))?;
+    }
+    fs::write(path, content)
+        .map_err(|e| Error::Storage(format!("write file {} error: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ADDRESS: &str = "0x0000000000000000000000000000000000000001";
+
+    fn detail(source_code: &str) -> serde_json::Value {
+        json!({
+            "SourceCode": source_code,
+            "ContractName": "Vault",
+            "CompilerVersion": "v0.8.20+commit.a1b79de6",
+            "OptimizationUsed": "1",
+            "Runs": "200",
+            "ABI": "[]",
+            "Library": "",
+            "Proxy": "0",
+        })
+    }
+
+    #[test]
+    fn splits_the_three_source_formats() {
+        let single = parse_contract_source(ADDRESS, &detail("contract Vault {}\r\n")).unwrap();
+        assert_eq!(single.metadata.format, SourceFormat::SingleFile);
+        assert_eq!(single.files[0].path, "Vault.sol");
+        assert_eq!(single.files[0].content, "contract Vault {}\n");
+        assert_eq!(single.metadata.runs, 200);
+        assert!(single.metadata.optimization_used);
+
+        let multi = parse_contract_source(ADDRESS, &detail(r#"{"src/A.sol": {"content": "a"}, "src/B.sol": {"content": "b"}}"#)).unwrap();
+        assert_eq!(multi.metadata.format, SourceFormat::MultiFile);
+        assert_eq!(multi.files.len(), 2);
+
+        let standard = r#"{{"language": "Solidity", "sources": {"src/A.sol": {"content": "a"}}, "settings": {"optimizer": {"enabled": true}, "libraries": {"L": "0x01"}}}}"#;
+        let standard = parse_contract_source(ADDRESS, &detail(standard)).unwrap();
+        assert_eq!(standard.metadata.format, SourceFormat::StandardJson);
+        assert_eq!(standard.metadata.language, "Solidity");
+        assert_eq!(standard.metadata.libraries, json!({"L": "0x01"}));
+        assert_eq!(standard.metadata.settings["optimizer"]["enabled"], true);
+    }
+
+    #[test]
+    fn rejects_an_unverified_contract() {
+        assert!(matches!(parse_contract_source(ADDRESS, &detail(" ")), Err(Error::Unverified(_))));
+    }
+
+    #[test]
+    fn keeps_the_paths_inside_the_output_folder() {
+        assert_eq!(safe_relative_path("/etc/../src/./A.sol"), PathBuf::from("etc/src/A.sol"));
+        assert_eq!(safe_relative_path("@openzeppelin/contracts/Ownable.sol"), PathBuf::from("@openzeppelin/contracts/Ownable.sol"));
+    }
+}
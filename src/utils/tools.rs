@@ -1,209 +1,97 @@
-#![allow(dead_code)]
-use std::{
-    fs::{File, OpenOptions},
-    io::{BufReader, BufWriter},
-    io::Write,
-    path::Path,
-    fs,
-};
-use reqwest::get;
-use serde::{Deserialize, Serialize};
-use lettre::{transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
-use std::error::Error;
 use ethers::utils::keccak256;
 use ethers::utils::hex;
 
-/// @dev Used to parse the data For addresses.json
-#[derive(Debug, Serialize, Deserialize)]
-struct AddressData {
-    eth: Data,
-    bsc: Data
-}
-
-/// @dev Used to parse the data For addresses.json
-#[derive(Debug, Serialize, Deserialize)]
-struct Data {
-    hacker: Vec<String>,
-    protocol: Vec<String>,
-    mixing_service: Vec<String>,
-    potential_hacker: Vec<String>
-}
-
-/// @dev Used to parse the data For addresses.json
+/// @dev Get a function's signature
 /// @param functionName The function you call. E.g. `transfer(address,uint256)`
 pub fn function_sig(functionName: &str) -> String {
     let data = functionName.as_bytes();
     let hash = keccak256(data);
-    let hash = hex::encode(&hash);
+    let hash = hex::encode(hash);
 
-    let first_four_bytes = &hash.as_bytes()[..8];
-    let result_string = std::str::from_utf8(first_four_bytes).unwrap();
-
-    return format!("0x{}", result_string.to_string());
+    format!("0x{}", &hash[..8])
 }
 
 
-/// @notice We currently only focus on Ethereum
-/// @dev Get the address from db(a json file)
-/// @param option: "hacker", "protocol", "mixing_service" or "potential_hacker", the other returns a new vector
-pub fn get_db_address(option: &str) -> Vec<String>{
-    let file = File::open("src/utils/addresses.json").expect("Failed to open file");
-    let reader = BufReader::new(file);
-
-    let json_data: AddressData = serde_json::from_reader(reader).expect("Failed to parse JSON");
+/// @dev Get an event's topic0
+/// @param eventName The event. E.g. `Transfer(address,address,uint256)`
+pub fn event_topic(eventName: &str) -> String {
+    let hash = keccak256(eventName.as_bytes());
 
-    if option == "hacker" {
-        return json_data.eth.hacker;
-    }else if option == "protocol" {
-        return json_data.eth.protocol;
-    } else if option == "mixing_service" {
-        return json_data.eth.mixing_service;
-    } else if option == "potential_hacker" {
-        return json_data.eth.potential_hacker;
-    } else {
-        return Vec::new();
-    }
+    format!("0x{}", hex::encode(hash))
 }
 
-/// @dev Send an email
-/// @param sender Email from
-/// @param receiver The email address to receive
-/// @param title The email title
-/// @param content The email content
-/// @param password Sender's email server password
-/// @param smtp_server Email server smtp code
-pub fn send_email(
-    sender: String,
-    receiver: String,
-    title: String,
-    content: String,
-    password: String,
-    smtp_server: String
-) -> Result<(), Box<dyn Error>> {
-
-    let email = Message::builder()
-        .from(sender.parse()?)
-        .to(receiver.parse()?)
-        .subject(title)
-        .body(content)?;
+/// @dev Get the canonical signature of a function / event / error of an ABI, e.g. `transfer(address,uint256)`
+/// @param item One item of the ABI json
+/// @return None if the item has no name (constructor, fallback, receive)
+pub fn abi_signature(item: &serde_json::Value) -> Option<String> {
+    let name = item["name"].as_str()?;
+    let inputs = item["inputs"].as_array().map(|inputs| abi_types(inputs)).unwrap_or_default();
 
-    let creds = Credentials::new(sender, password);
-
-    let mailer = SmtpTransport::relay(smtp_server.as_str())?
-        .credentials(creds)
-        .build();
-
-    match mailer.send(&email) {
-        Ok(_) => println!("Email sent successfully"),
-        Err(e) => eprintln!("Could not send the email: {:?}", e),
-    }
-
-    Ok(())
+    Some(format!("{}({})", name, inputs))
 }
 
-/// @dev Write an address to addresses.json
-/// @param address The address to write
-pub fn write_addresses_db(address: String) {
-    let json_file_path = Path::new("src/utils/addresses.json");
-    let file = File::open(json_file_path).expect("Failed to open file");
-    let reader = BufReader::new(file);
-
-    let mut data: AddressData = serde_json::from_reader(reader).expect("Failed to parse JSON");
-
-    data.eth.potential_hacker.push(address);
-
-    let new_file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(json_file_path)
-        .expect("Failed to open file for writing");
-    let mut writer = BufWriter::new(new_file);
-
-    serde_json::to_writer_pretty(&mut writer, &data).expect("Failed to write JSON to file");
+/// @dev The comma separated canonical types of ABI params, tuples are expanded
+fn abi_types(params: &[serde_json::Value]) -> String {
+    params
+        .iter()
+        .map(|param| {
+            let kind = param["type"].as_str().unwrap_or_default();
+            match kind.strip_prefix("tuple") {
+                Some(suffix) => {
+                    let components = param["components"].as_array().map(|c| abi_types(c)).unwrap_or_default();
+                    format!("({}){}", components, suffix)
+                }
+                None => kind.to_string(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
 }
 
-/// @notice This function is not complete yet
-/// @dev Obtain the solidity source code of a verified contract and output it to the output folder
-/// @param api_key ETHERSCAN API KEY
-/// @param address Which contract address' sourcecode you want to get 
-/// @TODO A single page like this can be pulled down normally: 0xB20bd5D04BE54f870D5C0d3cA85d82b34B836405.
-///       But this type of paginated contract is not yet completed and needs to be further separated when 
-///       pulled down: https://etherscan.io/address/0x80d69e79258FE9D056c822461c4eb0B4ca8802E2#code
-pub async fn get_contract_solidity_code(
-    api_key: String,
-    address: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    
-    let url = format!("https://api.etherscan.io/api?module=contract&action=getsourcecode&address={}&apikey={}",
-        address, 
-        api_key
-    );
-
-    let response = get(&url).await?;
-
-    if response.status().is_success() {
-        let body = response.text().await?;
-        let json_data: serde_json::Value = serde_json::from_str(&body)?;
-
-        if let Some(contract_details) = json_data["result"].as_array() {
-
-            let SourceCode = contract_details[0]["SourceCode"].as_str().unwrap().to_string();
-            let ContractName = contract_details[0]["ContractName"].as_str().unwrap().to_string();
-            let CompilerVersion = contract_details[0]["CompilerVersion"].as_str().unwrap().to_string();
-            let ConstructorArguments = contract_details[0]["ConstructorArguments"].as_str().unwrap().to_string();
-
-            let content = format!("// address: {}\r\n// version: {}\r\n// constructor arguments: {}\r\n\r\n{}",address, CompilerVersion,ConstructorArguments,SourceCode);
-
-            write_file(ContractName, content);
-        }
-    } else {
-        return Err(format!(
-            "HTTP request failed with status code: {}",
-            response.status()
-        )
-        .into());
-    }
-
-    Ok(())
+/// @dev Format unix seconds as `YYYY-MM-DD HH:MM UTC`
+pub fn format_time(secs: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, secs % 86_400 / 3600, secs % 3600 / 60)
 }
 
-/// @dev Write a file
-/// @param file_name File name
-/// @param output The output path
-fn write_file(file_name: String, output: String) {
-    let output_dir = "./output";
-    // Create a new file
-    match fs::metadata(output_dir) {
-        Ok(metadata) => {
-            if metadata.is_dir() {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-            } else {
-                match fs::create_dir(output_dir) {
-                    Ok(_) => {},
-                    Err(err) => eprintln!("create output dir fail:{}", err),
-                }
-            }
-        }
-        Err(_) => {
-            match fs::create_dir(output_dir) {
-                Ok(_) => {},
-                Err(err) => eprintln!("create output dir fail:{}", err),
-            }
-        }
+    #[test]
+    fn hashes_the_signatures() {
+        assert_eq!(function_sig("transfer(address,uint256)"), "0xa9059cbb");
+        assert_eq!(event_topic("Transfer(address,address,uint256)"), "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef");
     }
 
-    // write file
-    let path = format!("./output/{}.sol", file_name);
-    let mut file = match File::create(path) {
-        Ok(file) => file,
-        Err(e) => {
-            eprintln!("create file error: {}", e);
-            return;
-        }
-    };
+    #[test]
+    fn expands_the_abi_tuples() {
+        let item = json!({
+            "type": "function",
+            "name": "swap",
+            "inputs": [
+                {"name": "orders", "type": "tuple[]", "components": [{"type": "address"}, {"type": "uint256[2]"}]},
+                {"name": "data", "type": "bytes"}
+            ]
+        });
+        assert_eq!(abi_signature(&item).as_deref(), Some("swap((address,uint256[2])[],bytes)"));
+        assert_eq!(abi_signature(&json!({"type": "fallback"})), None);
+    }
 
-    match file.write_all(output.replace("\r\n", "\n").as_bytes()) {
-        Ok(_) => {},
-        Err(e) => eprintln!("write file errer: {}", e),
+    #[test]
+    fn formats_unix_seconds() {
+        assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_time(1_709_251_200 + 3_660), "2024-03-01 01:01 UTC");
     }
-}
\ No newline at end of file
+}